@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_files_device_uuid;
+ALTER TABLE files DROP COLUMN device_uuid;
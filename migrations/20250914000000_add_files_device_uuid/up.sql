@@ -0,0 +1,4 @@
+-- Record which device holds each object so it can be addressed through Storage
+ALTER TABLE files ADD COLUMN device_uuid TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_files_device_uuid ON files(device_uuid);
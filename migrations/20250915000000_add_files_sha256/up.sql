@@ -0,0 +1,2 @@
+-- Content digest recorded at upload for download-time verification
+ALTER TABLE files ADD COLUMN sha256 TEXT;
@@ -0,0 +1,2 @@
+-- Write-once retention: deletes are refused until this epoch second
+ALTER TABLE files ADD COLUMN retain_until BIGINT;
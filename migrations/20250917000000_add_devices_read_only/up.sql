@@ -0,0 +1,2 @@
+-- Read-only devices keep serving downloads but are never picked for uploads
+ALTER TABLE devices ADD COLUMN read_only INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_files_tenant_key;
+ALTER TABLE files DROP COLUMN tenant;
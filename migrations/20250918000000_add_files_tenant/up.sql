@@ -0,0 +1,4 @@
+-- Optional namespace owning the object; NULL for uploads made without a tenant
+ALTER TABLE files ADD COLUMN tenant TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_files_tenant_key ON files(tenant, key);
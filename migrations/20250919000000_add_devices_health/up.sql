@@ -0,0 +1,2 @@
+-- Last SMART verdict for the device: passed / failed / unknown (NULL = never checked)
+ALTER TABLE devices ADD COLUMN health TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE devices DROP COLUMN free_bytes;
+ALTER TABLE devices DROP COLUMN total_bytes;
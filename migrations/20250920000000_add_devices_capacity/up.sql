@@ -0,0 +1,3 @@
+-- Filesystem capacity of the mounted device, refreshed by the mounter (bytes)
+ALTER TABLE devices ADD COLUMN total_bytes BIGINT;
+ALTER TABLE devices ADD COLUMN free_bytes BIGINT;
@@ -0,0 +1,4 @@
+-- Epoch seconds after which the object is gone (upload-time ?ttl=); NULL = never
+ALTER TABLE files ADD COLUMN expires_at BIGINT;
+
+CREATE INDEX IF NOT EXISTS idx_files_expires_at ON files(expires_at);
@@ -0,0 +1,2 @@
+-- 0 when the client sent no filename and one was generated from the key
+ALTER TABLE files ADD COLUMN filename_supplied INTEGER NOT NULL DEFAULT 1;
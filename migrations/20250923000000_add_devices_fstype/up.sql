@@ -0,0 +1,2 @@
+-- Filesystem type reported by blkid, refreshed on demand
+ALTER TABLE devices ADD COLUMN fstype TEXT;
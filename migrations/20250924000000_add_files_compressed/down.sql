@@ -0,0 +1,2 @@
+ALTER TABLE files DROP COLUMN uncompressed_size;
+ALTER TABLE files DROP COLUMN compressed;
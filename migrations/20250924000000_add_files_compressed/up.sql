@@ -0,0 +1,3 @@
+-- Objects stored gzipped on disk; `size` stays the on-disk length
+ALTER TABLE files ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE files ADD COLUMN uncompressed_size BIGINT;
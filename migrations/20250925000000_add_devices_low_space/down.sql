@@ -0,0 +1 @@
+ALTER TABLE devices DROP COLUMN low_space;
@@ -0,0 +1,2 @@
+-- Set by the mounter while free space is below its floor; re-evaluated every scan
+ALTER TABLE devices ADD COLUMN low_space INTEGER NOT NULL DEFAULT 0;
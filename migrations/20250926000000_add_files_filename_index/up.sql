@@ -0,0 +1,2 @@
+-- Versioned-by-filename uploads look up a tenant's live copies of one name
+CREATE INDEX IF NOT EXISTS idx_files_tenant_filename ON files(tenant, filename);
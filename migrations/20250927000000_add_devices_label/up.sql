@@ -0,0 +1,2 @@
+-- Filesystem label reported by blkid, used to name mount directories
+ALTER TABLE devices ADD COLUMN label TEXT;
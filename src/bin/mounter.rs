@@ -1,20 +1,90 @@
 use anyhow::Result;
 use clap::Parser;
 use log::info;
-use std::{fs, path::PathBuf, sync::Arc};
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
 use storage_plus::{
-    db::establish_pool, logging::init_logging, mounter::Mounter, repo::device_repo::new_device_repo,
+    config::{AutoJoinPolicy, Config},
+    db::{check_db_placement, establish_pool_with_retry},
+    diagnostics,
+    logging::init_logging,
+    mounter::Mounter,
+    repo::device_repo::new_device_repo,
 };
 
 #[derive(Debug, Parser)]
 #[command(author, version, about = "Udev device manager with SQLite tracking", long_about = None)]
 struct Args {
-    #[arg(long, default_value = "/mnt/storage_pool")]
-    storage_root: PathBuf,
-    #[arg(long, default_value = "/var/lib/storage-plus/storage-plus.db")]
-    db_path: PathBuf,
-    #[arg(long, default_value_t = 5)]
-    scan_interval_secs: u64,
+    /// JSON config file; CLI flags override its values
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Storage root directory [default: /mnt/storage_pool]
+    #[arg(long)]
+    storage_root: Option<PathBuf>,
+    /// SQLite db file path [default: /var/lib/storage-plus/storage-plus.db]
+    #[arg(long)]
+    db_path: Option<PathBuf>,
+    /// Attempts at opening the DB at startup before giving up [default: 5]
+    #[arg(long)]
+    db_connect_attempts: Option<u32>,
+    /// Initial delay between DB open attempts in seconds, doubling each retry [default: 2]
+    #[arg(long)]
+    db_connect_interval_secs: Option<u64>,
+    /// Refuse to start when the database is on a pool drive (default: warn only)
+    #[arg(long, default_value_t = false)]
+    strict_db_placement: bool,
+    /// Reconciliation interval in seconds [default: 5]
+    #[arg(long)]
+    scan_interval_secs: Option<u64>,
+    /// Device name prefixes to track, comma separated [default: sd,nvme,mmcblk]
+    #[arg(long, value_delimiter = ',')]
+    device_prefixes: Option<Vec<String>>,
+    /// Serve JSON diagnostics on 127.0.0.1:<port> (disabled when unset)
+    #[arg(long)]
+    diagnostics_port: Option<u16>,
+    /// Never mount these devices, matched by filesystem UUID or drive serial (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    device_blacklist: Option<Vec<String>>,
+    /// Join newly detected devices automatically [default: manual]
+    #[arg(long, value_enum)]
+    auto_join: Option<AutoJoinPolicy>,
+    /// Filesystem label regex for `--auto-join match-label`
+    #[arg(long)]
+    auto_join_label: Option<String>,
+    /// Stop uploading to devices with less than this many bytes free (checked every scan)
+    #[arg(long)]
+    min_free_bytes: Option<u64>,
+    /// Kill mount/umount/mkfs after this many seconds; 0 waits forever [default: 60]
+    #[arg(long)]
+    mount_timeout_secs: Option<u64>,
+    /// Delete devices that have been removed for longer than this many seconds [default: keep]
+    #[arg(long)]
+    removed_device_retention_secs: Option<u64>,
+    /// Wait this many seconds after start before the first mount pass [default: 0]
+    #[arg(long)]
+    boot_grace_secs: Option<u64>,
+    /// Re-poll blkid this many seconds for a new device's filesystem UUID [default: 0]
+    #[arg(long)]
+    uuid_settle_timeout_secs: Option<u64>,
+    /// Retry unmounting a removed, still busy device this many seconds before a lazy
+    /// unmount [default: 0, try once]
+    #[arg(long)]
+    unmount_grace_secs: Option<u64>,
+    /// Re-read each mounted device's capacity at most this often; 0 = every scan [default: 60]
+    #[arg(long)]
+    capacity_refresh_secs: Option<u64>,
+    /// Poll `smartctl -H` on each scan; failing drives are flagged read-only
+    #[arg(long, default_value_t = false)]
+    enable_smart: bool,
+    /// Safe mode: never run `mkfs` on any device
+    #[arg(long, default_value_t = false)]
+    never_format: bool,
+    /// Mount devices at {storage_root}/{label} (falling back to the UUID), with
+    /// {storage_root}/{uuid} kept as a symlink
+    #[arg(long, default_value_t = false)]
+    label_mount_dirs: bool,
+    /// Write a probe file after mounting; devices that refuse are flagged read-only
+    #[arg(long, default_value_t = false)]
+    verify_mount_writable: bool,
     #[arg(
         long,
         default_value_t = false,
@@ -23,28 +93,83 @@ struct Args {
     migrate_only: bool,
 }
 
+impl Args {
+    fn overrides(&self) -> Config {
+        Config {
+            storage_root: self.storage_root.clone(),
+            db_path: self.db_path.clone(),
+            db_connect_attempts: self.db_connect_attempts,
+            db_connect_interval_secs: self.db_connect_interval_secs,
+            strict_db_placement: self.strict_db_placement.then_some(true),
+            scan_interval_secs: self.scan_interval_secs,
+            device_prefixes: self.device_prefixes.clone(),
+            diagnostics_port: self.diagnostics_port,
+            enable_smart: self.enable_smart.then_some(true),
+            device_blacklist: self.device_blacklist.clone(),
+            min_free_bytes: self.min_free_bytes,
+            auto_join: self.auto_join,
+            auto_join_label: self.auto_join_label.clone(),
+            never_format: self.never_format.then_some(true),
+            mount_timeout_secs: self.mount_timeout_secs,
+            removed_device_retention_secs: self.removed_device_retention_secs,
+            boot_grace_secs: self.boot_grace_secs,
+            uuid_settle_timeout_secs: self.uuid_settle_timeout_secs,
+            unmount_grace_secs: self.unmount_grace_secs,
+            label_mount_dirs: self.label_mount_dirs.then_some(true),
+            verify_mount_writable: self.verify_mount_writable.then_some(true),
+            capacity_refresh_secs: self.capacity_refresh_secs,
+            ..Default::default()
+        }
+    }
+}
+
 fn main() -> Result<()> {
     init_logging();
     let args = Args::parse();
-    fs::create_dir_all(&args.storage_root)?;
-    if let Some(parent) = args.db_path.parent() {
-        fs::create_dir_all(parent)?;
-    }
-    let pool = establish_pool(&args.db_path)?;
+    let file_cfg = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let cfg = file_cfg.merge(args.overrides());
+    let storage_root = cfg.storage_root();
+    let db_path = cfg.db_path();
+    fs::create_dir_all(&storage_root)?;
+    check_db_placement(&db_path, &storage_root, cfg.strict_db_placement())?;
+    let pool = establish_pool_with_retry(
+        &db_path,
+        cfg.pool_size(),
+        cfg.connection_options(),
+        cfg.db_connect_attempts(),
+        Duration::from_secs(cfg.db_connect_interval_secs()),
+    )?;
     let device_repo = new_device_repo(pool.clone());
     info!(
         "starting udev monitor + scheduler storage_root={:?} db={:?}",
-        args.storage_root, args.db_path
+        storage_root, db_path
     );
     if args.migrate_only {
         info!("migrations applied, exiting due to --migrate-only flag");
         return Ok(());
     }
-    let mounter = Arc::new(Mounter::new(
-        device_repo,
-        args.storage_root.clone(),
-        args.scan_interval_secs,
-    ));
+    let mounter = Arc::new(
+        Mounter::new(device_repo, storage_root, cfg.scan_interval_secs())
+            .with_device_prefixes(cfg.device_prefixes())
+            .with_smart(cfg.enable_smart())
+            .with_blacklist(cfg.device_blacklist())
+            .with_auto_join(cfg.auto_join()?)
+            .with_min_free_bytes(cfg.min_free_bytes())
+            .with_never_format(cfg.never_format())
+            .with_command_timeout(cfg.mount_timeout())
+            .with_removed_retention(cfg.removed_device_retention_secs())
+            .with_boot_settle(cfg.boot_grace(), cfg.uuid_settle_timeout())
+            .with_unmount_grace(cfg.unmount_grace())
+            .with_label_mount_dirs(cfg.label_mount_dirs())
+            .with_write_probe(cfg.verify_mount_writable())
+            .with_capacity_interval(cfg.capacity_refresh_interval()),
+    );
+    if let Some(port) = cfg.diagnostics_port {
+        diagnostics::spawn(mounter.clone(), port)?;
+    }
     mounter.start_scheduler();
     mounter.run_udev_loop()
 }
@@ -1,44 +1,301 @@
-use std::{fs, path::PathBuf};
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use clap::Parser;
 use log::info;
 use storage_plus::{
-    db::establish_pool,
+    config::{Config, FilenamePolicy},
+    db::{check_db_placement, establish_pool_with_retry},
     logging::init_logging,
+    migrate_layout,
     repo::device_repo::new_device_repo,
     repo::file_repo::new_file_repo,
+    selector::DeviceSelection,
     server::{self, ServerConfig},
+    storage::{Layout, StorageImpl},
 };
 
 #[derive(Parser, Debug, Clone)]
 struct Args {
-    /// Storage root directory for uploaded files
-    #[arg(long, default_value = "/mnt/storage_pool")]
-    storage_root: PathBuf,
-    /// SQLite db file path
-    #[arg(long, default_value = "/var/lib/storage-plus/storage-plus.db")]
-    db_path: PathBuf,
-    /// Bind address
-    #[arg(long, default_value = "127.0.0.1:8080")]
-    addr: String,
-    /// Device UUID cache TTL in seconds
-    #[arg(long, default_value_t = 30)]
-    device_cache_ttl_secs: u64,
+    /// JSON config file; CLI flags override its values
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Storage root directory for uploaded files [default: /mnt/storage_pool]
+    #[arg(long)]
+    storage_root: Option<PathBuf>,
+    /// SQLite db file path [default: /var/lib/storage-plus/storage-plus.db]
+    #[arg(long)]
+    db_path: Option<PathBuf>,
+    /// Bind address [default: 127.0.0.1:8080]
+    #[arg(long)]
+    addr: Option<String>,
+    /// Listen on this Unix domain socket instead of TCP
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
+    /// Octal permissions for the Unix socket file, e.g. 660
+    #[arg(long)]
+    unix_socket_mode: Option<String>,
+    /// Device UUID cache TTL in seconds; 0 disables the cache [default: 30]
+    #[arg(long)]
+    device_cache_ttl_secs: Option<u64>,
+    /// Maximum number of pooled DB connections [default: 4]
+    #[arg(long)]
+    pool_size: Option<u32>,
+    /// Enforce SQLite foreign keys on every connection [default: true]
+    #[arg(long)]
+    sqlite_foreign_keys: Option<bool>,
+    /// SQLite page cache per connection; negative = KiB [default: -8000]
+    #[arg(long, allow_hyphen_values = true)]
+    sqlite_cache_size: Option<i64>,
+    /// How long a connection waits on a locked database before failing; 0 fails at once
+    /// [default: 5000]
+    #[arg(long)]
+    sqlite_busy_timeout_ms: Option<u32>,
+    /// Attempts at opening the DB at startup before giving up [default: 5]
+    #[arg(long)]
+    db_connect_attempts: Option<u32>,
+    /// Initial delay between DB open attempts in seconds, doubling each retry [default: 2]
+    #[arg(long)]
+    db_connect_interval_secs: Option<u64>,
+    /// Refuse to start when the database is on a pool drive (default: warn only)
+    #[arg(long, default_value_t = false)]
+    strict_db_placement: bool,
+    /// Verify stored SHA-256 while streaming downloads (costs CPU; disables Range requests)
+    #[arg(long, default_value_t = false)]
+    verify_downloads: bool,
+    /// Send `Cache-Control: max-age=<secs>, immutable` on downloads (off when unset)
+    #[arg(long)]
+    download_cache_max_age_secs: Option<u64>,
+    /// Pace each download to at most this many bytes per second (unlimited when unset;
+    /// disables Range requests)
+    #[arg(long)]
+    download_rate_limit: Option<u64>,
+    /// Write-once mode: never overwrite objects, refuse deletes within the retention window
+    #[arg(long, default_value_t = false)]
+    write_once: bool,
+    /// Retention window in seconds for write-once uploads [default: 0]
+    #[arg(long)]
+    retention_secs: Option<u64>,
+    /// Evict freshly written objects from the page cache (for low-RAM hosts)
+    #[arg(long, default_value_t = false)]
+    drop_cache_after_write: bool,
+    /// Store uploads gzipped when that makes them smaller; downloads are inflated for
+    /// clients that don't accept gzip
+    #[arg(long, default_value_t = false)]
+    compress_uploads: bool,
+    /// Keep only the newest N uploads of each filename; older ones are deleted [default: off]
+    #[arg(long)]
+    keep_versions: Option<u32>,
+    /// Refuse zero-byte uploads with 400 instead of storing an empty object
+    #[arg(long, default_value_t = false)]
+    reject_empty_uploads: bool,
+    /// Abort uploads that send nothing for this many seconds with 408; 0 = never [default: 60]
+    #[arg(long)]
+    upload_idle_timeout_secs: Option<u64>,
+    /// Add an X-Storage-Device header naming the serving drive to downloads (debugging aid;
+    /// exposes the drive layout to clients)
+    #[arg(long, default_value_t = false)]
+    expose_device_header: bool,
+    /// Log HTTP requests: `common`, `combined`, or an actix Logger format string
+    #[arg(long)]
+    access_log: Option<String>,
+    /// Skip devices already holding this many objects when uploading (unlimited when unset)
+    #[arg(long)]
+    max_objects_per_device: Option<u64>,
+    /// Directory layout for new uploads below each device [default: flat]
+    #[arg(long, value_enum)]
+    layout: Option<Layout>,
+    /// How uploads are spread over the usable devices [default: random]
+    #[arg(long, value_enum)]
+    device_selection: Option<DeviceSelection>,
+    /// Flag a device read-only after this many read/write I/O errors within
+    /// `--io-error-window-secs` (never when unset)
+    #[arg(long)]
+    io_error_threshold: Option<u32>,
+    /// Window over which device I/O errors are counted, in seconds [default: 300]
+    #[arg(long)]
+    io_error_window_secs: Option<u64>,
+    /// Prefix of in-progress upload files, for external cleanup tooling [default: none]
+    #[arg(long)]
+    temp_prefix: Option<String>,
+    /// Suffix of in-progress upload files; keys matching the pattern are refused
+    /// [default: .part]
+    #[arg(long)]
+    temp_suffix: Option<String>,
+    /// Fail uploads whose temp file already exists instead of truncating it (O_EXCL)
+    #[arg(long, default_value_t = false)]
+    exclusive_temp_files: bool,
+    /// Write one upload at a time to each device, avoiding seek thrashing on spinning disks
+    #[arg(long, default_value_t = false)]
+    serialize_device_writes: bool,
+    /// Answer uploads with 201 Created and `Location: /files/{key}` instead of 200
+    #[arg(long, default_value_t = false)]
+    created_responses: bool,
+    /// Answer downloads from an unplugged device with 503 + Retry-After and of deleted
+    /// objects with 410, rather than 404
+    #[arg(long, default_value_t = false)]
+    outage_aware_downloads: bool,
+    /// Leading bytes of an upload held in memory to sniff its type before anything is
+    /// written [default: 16]
+    #[arg(long)]
+    sniff_buffer_bytes: Option<usize>,
+    /// Retry the rename publishing an upload this many times, with backoff, when it fails
+    /// transiently (e.g. EBUSY on a network mount) [default: 0]
+    #[arg(long)]
+    publish_retries: Option<u32>,
+    /// Always upload to this device while it is mounted and has space
+    #[arg(long)]
+    primary_device_uuid: Option<String>,
+    /// Require `Authorization: Bearer <token>` on every request (signed links excepted)
+    #[arg(long)]
+    api_token: Option<String>,
+    /// Bearer token for one tenant as TENANT=TOKEN; requests using it act for that tenant
+    /// only (repeatable)
+    #[arg(long = "tenant-token", value_parser = parse_tenant_token)]
+    tenant_tokens: Vec<(String, String)>,
+    /// Secret for signing time-limited download URLs (signed URLs disabled when unset)
+    #[arg(long)]
+    signing_secret: Option<String>,
+    /// Accepted upload content-type prefixes, comma separated (e.g. image/,video/) [default: all]
+    #[arg(long, value_delimiter = ',')]
+    allowed_content_types: Option<Vec<String>>,
+    /// Longest accepted upload filename in bytes [default: 255]
+    #[arg(long)]
+    max_filename_len: Option<usize>,
+    /// Handling of over-long filenames [default: reject]
+    #[arg(long, value_enum)]
+    filename_policy: Option<FilenamePolicy>,
+    /// Multipart field name of the file part [default: file]
+    #[arg(long)]
+    upload_field: Option<String>,
+    /// Seconds between sweeps for uploads whose ?ttl= ran out [default: 60]
+    #[arg(long)]
+    expiry_sweep_interval_secs: Option<u64>,
+    /// Move stored objects from layout FROM to layout TO, update their rows, and exit
+    #[arg(long, num_args = 2, value_names = ["FROM", "TO"], value_enum)]
+    migrate_layout: Option<Vec<Layout>>,
+    /// Longest object key accepted on `PUT /files/{key}`, in bytes [default: 128]
+    #[arg(long)]
+    max_key_len: Option<usize>,
+    /// Warn about DB calls slower than this many milliseconds (off when unset)
+    #[arg(long)]
+    slow_query_ms: Option<u64>,
+    /// Threads in each blocking pool running DB calls and upload finalization; about
+    /// `--pool-size` suits a small board [default: tokio's]
+    #[arg(long)]
+    blocking_threads: Option<usize>,
+    /// Check every N seconds that the storage root still exists as a directory and refuse
+    /// uploads (503) while it doesn't (off when unset)
+    #[arg(long)]
+    root_mount_check_secs: Option<u64>,
+    /// Refuse uploads (503) to a device whose mount point is no longer mounted [default: true]
+    #[arg(long)]
+    verify_mounts: Option<bool>,
     /// Run migrations and exit (for testing/deployment)
     #[arg(long, default_value_t = false)]
     migrate_only: bool,
 }
 
-#[actix_web::main]
-async fn main() -> Result<()> {
+fn parse_tenant_token(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(tenant, token)| (tenant.to_string(), token.to_string()))
+        .ok_or_else(|| format!("expected TENANT=TOKEN, got {s:?}"))
+}
+
+impl Args {
+    fn overrides(&self) -> Config {
+        Config {
+            storage_root: self.storage_root.clone(),
+            db_path: self.db_path.clone(),
+            pool_size: self.pool_size,
+            db_connect_attempts: self.db_connect_attempts,
+            db_connect_interval_secs: self.db_connect_interval_secs,
+            strict_db_placement: self.strict_db_placement.then_some(true),
+            sqlite_foreign_keys: self.sqlite_foreign_keys,
+            sqlite_cache_size: self.sqlite_cache_size,
+            sqlite_busy_timeout_ms: self.sqlite_busy_timeout_ms,
+            addr: self.addr.clone(),
+            unix_socket: self.unix_socket.clone(),
+            unix_socket_mode: self.unix_socket_mode.clone(),
+            device_cache_ttl_secs: self.device_cache_ttl_secs,
+            verify_downloads: self.verify_downloads.then_some(true),
+            download_cache_max_age_secs: self.download_cache_max_age_secs,
+            download_rate_limit: self.download_rate_limit,
+            write_once: self.write_once.then_some(true),
+            retention_secs: self.retention_secs,
+            drop_cache_after_write: self.drop_cache_after_write.then_some(true),
+            compress_uploads: self.compress_uploads.then_some(true),
+            keep_versions: self.keep_versions,
+            primary_device_uuid: self.primary_device_uuid.clone(),
+            api_token: self.api_token.clone(),
+            tenant_tokens: (!self.tenant_tokens.is_empty())
+                .then(|| self.tenant_tokens.iter().cloned().collect()),
+            signing_secret: self.signing_secret.clone(),
+            allowed_content_types: self.allowed_content_types.clone(),
+            max_filename_len: self.max_filename_len,
+            filename_policy: self.filename_policy,
+            upload_field: self.upload_field.clone(),
+            expiry_sweep_interval_secs: self.expiry_sweep_interval_secs,
+            verify_mounts: self.verify_mounts,
+            max_key_len: self.max_key_len,
+            slow_query_ms: self.slow_query_ms,
+            blocking_threads: self.blocking_threads,
+            root_mount_check_secs: self.root_mount_check_secs,
+            reject_empty_uploads: self.reject_empty_uploads.then_some(true),
+            upload_idle_timeout_secs: self.upload_idle_timeout_secs,
+            expose_device_header: self.expose_device_header.then_some(true),
+            access_log: self.access_log.clone(),
+            max_objects_per_device: self.max_objects_per_device,
+            layout: self.layout,
+            device_selection: self.device_selection,
+            io_error_threshold: self.io_error_threshold,
+            io_error_window_secs: self.io_error_window_secs,
+            temp_prefix: self.temp_prefix.clone(),
+            temp_suffix: self.temp_suffix.clone(),
+            exclusive_temp_files: self.exclusive_temp_files.then_some(true),
+            serialize_device_writes: self.serialize_device_writes.then_some(true),
+            created_responses: self.created_responses.then_some(true),
+            outage_aware_downloads: self.outage_aware_downloads.then_some(true),
+            sniff_buffer_bytes: self.sniff_buffer_bytes,
+            publish_retries: self.publish_retries,
+            ..Default::default()
+        }
+    }
+}
+
+fn main() -> Result<()> {
     init_logging();
     let args = Args::parse();
-    fs::create_dir_all(&args.storage_root)?;
-    if let Some(parent) = args.db_path.parent() {
-        fs::create_dir_all(parent)?;
-    }
-    let pool = establish_pool(&args.db_path)?;
+    let file_cfg = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let cfg = file_cfg.merge(args.overrides());
+    // what `#[actix_web::main]` builds, with the blocking pool capped
+    let blocking_threads = cfg.blocking_threads();
+    actix_web::rt::System::with_tokio_rt(move || {
+        let mut rt = tokio::runtime::Builder::new_current_thread();
+        rt.enable_all();
+        if let Some(n) = blocking_threads {
+            rt.max_blocking_threads(n);
+        }
+        rt.build().expect("build tokio runtime")
+    })
+    .block_on(serve(args, cfg))
+}
+
+async fn serve(args: Args, cfg: Config) -> Result<()> {
+    let storage_root = cfg.storage_root();
+    let db_path = cfg.db_path();
+    fs::create_dir_all(&storage_root)?;
+    check_db_placement(&db_path, &storage_root, cfg.strict_db_placement())?;
+    let pool = establish_pool_with_retry(
+        &db_path,
+        cfg.pool_size(),
+        cfg.connection_options(),
+        cfg.db_connect_attempts(),
+        Duration::from_secs(cfg.db_connect_interval_secs()),
+    )?;
     let file_repo = new_file_repo(pool.clone());
     let device_repo = new_device_repo(pool);
 
@@ -47,10 +304,67 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    let cfg = ServerConfig {
-        storage_root: args.storage_root.clone(),
-        addr: args.addr.clone(),
-        device_cache_ttl_secs: args.device_cache_ttl_secs,
+    if let Some(layouts) = &args.migrate_layout {
+        let storage = StorageImpl::new(&storage_root);
+        let report = migrate_layout(
+            Arc::new(file_repo),
+            &storage,
+            layouts[0],
+            layouts[1],
+            cfg.slow_query_ms.map(Duration::from_millis),
+        )
+        .await?;
+        info!(
+            "layout migration done: {} moved, {} already in place, {} missing",
+            report.moved, report.skipped, report.missing
+        );
+        return Ok(());
+    }
+
+    let server_cfg = ServerConfig {
+        storage_root,
+        addr: cfg.addr(),
+        unix_socket: cfg.unix_socket.clone(),
+        unix_socket_mode: cfg.unix_socket_mode()?,
+        device_cache_ttl_secs: cfg.device_cache_ttl_secs(),
+        verify_downloads: cfg.verify_downloads(),
+        download_cache_max_age_secs: cfg.download_cache_max_age_secs,
+        download_rate_limit: cfg.download_rate_limit,
+        write_once: cfg.write_once(),
+        retention_secs: cfg.retention_secs(),
+        drop_cache_after_write: cfg.drop_cache_after_write(),
+        compress_uploads: cfg.compress_uploads(),
+        keep_versions: cfg.keep_versions(),
+        primary_device_uuid: cfg.primary_device_uuid.clone(),
+        api_token: cfg.api_token.clone(),
+        tenant_tokens: cfg.tenant_tokens()?,
+        signing_secret: cfg.signing_secret.clone(),
+        allowed_content_types: cfg.allowed_content_types(),
+        max_filename_len: cfg.max_filename_len(),
+        filename_policy: cfg.filename_policy(),
+        upload_field: cfg.upload_field(),
+        expiry_sweep_interval_secs: cfg.expiry_sweep_interval_secs(),
+        verify_mounts: cfg.verify_mounts(),
+        max_key_len: cfg.max_key_len(),
+        slow_query_ms: cfg.slow_query_ms,
+        blocking_threads: cfg.blocking_threads(),
+        root_mount_check_secs: cfg.root_mount_check_secs.filter(|&s| s > 0),
+        reject_empty_uploads: cfg.reject_empty_uploads(),
+        upload_idle_timeout: cfg.upload_idle_timeout(),
+        expose_device_header: cfg.expose_device_header(),
+        access_log_format: cfg.access_log_format(),
+        max_objects_per_device: cfg.max_objects_per_device,
+        layout: cfg.layout(),
+        device_selection: cfg.device_selection(),
+        io_error_threshold: cfg.io_error_threshold(),
+        io_error_window: cfg.io_error_window(),
+        temp_names: cfg.temp_names()?,
+        exclusive_temp_files: cfg.exclusive_temp_files(),
+        serialize_device_writes: cfg.serialize_device_writes(),
+        created_responses: cfg.created_responses(),
+        outage_aware_downloads: cfg.outage_aware_downloads(),
+        sniff_buffer_bytes: cfg.sniff_buffer_bytes(),
+        publish_retries: cfg.publish_retries(),
     };
-    server::run(cfg, file_repo, device_repo).await
+    server::run(server_cfg, file_repo, device_repo).await
 }
@@ -0,0 +1,139 @@
+//! Objects stored gzipped on disk: compressing uploads, negotiating whether a client
+//! takes them as-is, and inflating them on the fly for clients that don't.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+use actix_web::web::Bytes;
+use flate2::{Compression, write::GzDecoder, write::GzEncoder};
+use futures_util::{Stream, StreamExt};
+use tokio::io::AsyncRead;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Whether an `Accept-Encoding` value admits gzip (explicitly or via `*`) with a
+/// non-zero quality.
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut parts = coding.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let wanted = ["gzip", "x-gzip", "*"]
+            .iter()
+            .any(|c| name.eq_ignore_ascii_case(c));
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        wanted && q > 0.0
+    })
+}
+
+/// Inflate a gzipped byte stream chunk by chunk. A truncated or corrupt member ends the
+/// stream with an error, like any other read failure mid-download.
+pub fn gunzip<S>(body: S) -> impl Stream<Item = io::Result<Bytes>>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    futures_util::stream::unfold(
+        (body, Some(GzDecoder::new(Vec::new()))),
+        |(mut body, decoder)| async move {
+            let mut decoder = decoder?;
+            loop {
+                match body.next().await {
+                    Some(Ok(chunk)) => {
+                        if let Err(e) = decoder.write_all(&chunk) {
+                            return Some((Err(e), (body, None)));
+                        }
+                        let out = std::mem::take(decoder.get_mut());
+                        if !out.is_empty() {
+                            return Some((Ok(Bytes::from(out)), (body, Some(decoder))));
+                        }
+                    }
+                    Some(Err(e)) => return Some((Err(e), (body, None))),
+                    None => {
+                        return match decoder.finish() {
+                            Ok(rest) if rest.is_empty() => None,
+                            Ok(rest) => Some((Ok(Bytes::from(rest)), (body, None))),
+                            Err(e) => Some((Err(e), (body, None))),
+                        };
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Reader over the inflated bytes of a gzipped `reader`.
+pub fn inflate<R>(reader: R) -> impl AsyncRead + Send + Unpin
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    StreamReader::new(Box::pin(gunzip(ReaderStream::new(reader))))
+}
+
+/// Gzip `src` into a new file at `dst` (blocking). Returns the compressed length.
+pub fn gzip_file(src: &Path, dst: &Path) -> io::Result<u64> {
+    let mut input = File::open(src)?;
+    let mut enc = GzEncoder::new(File::create(dst)?, Compression::default());
+    io::copy(&mut input, &mut enc)?;
+    let out = enc.finish()?;
+    out.sync_all()?;
+    Ok(out.metadata()?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gzip_acceptance_honours_quality() {
+        assert!(accepts_gzip("gzip"));
+        assert!(accepts_gzip("br, GZIP;q=0.5"));
+        assert!(accepts_gzip("*"));
+        assert!(!accepts_gzip("gzip;q=0"));
+        assert!(!accepts_gzip("identity, br"));
+        assert!(!accepts_gzip(""));
+    }
+
+    #[tokio::test]
+    async fn gunzip_inflates_across_chunk_boundaries() {
+        let plain = b"hello hello hello hello compressed world".repeat(50);
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(&plain).unwrap();
+        let gz = enc.finish().unwrap();
+        let chunks: Vec<io::Result<Bytes>> = gz
+            .chunks(7)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+
+        let out: Vec<u8> = gunzip(futures_util::stream::iter(chunks))
+            .map(|c| c.unwrap().to_vec())
+            .concat()
+            .await;
+        assert_eq!(out, plain);
+
+        let truncated = vec![Ok(Bytes::copy_from_slice(&gz[..gz.len() / 2]))];
+        let items: Vec<_> = gunzip(futures_util::stream::iter(truncated))
+            .collect()
+            .await;
+        assert!(items.last().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn gzipped_files_read_back_inflated() {
+        use tokio::io::AsyncReadExt;
+
+        let dir = crate::test_support::temp_dir("gz");
+        let plain = b"round and round ".repeat(64);
+        std::fs::write(dir.join("plain"), &plain).unwrap();
+        let len = gzip_file(&dir.join("plain"), &dir.join("packed")).unwrap();
+        assert!(len < plain.len() as u64);
+
+        let packed = tokio::fs::File::open(dir.join("packed")).await.unwrap();
+        let mut out = Vec::new();
+        inflate(packed).read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, plain);
+    }
+}
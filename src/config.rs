@@ -0,0 +1,706 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+use regex::Regex;
+
+use crate::db::ConnectionOptions;
+use crate::mounter::AutoJoin;
+use crate::selector::DeviceSelection;
+use crate::sniff;
+use crate::storage::{DEFAULT_TEMP_SUFFIX, Layout, TempNames};
+
+pub const DEFAULT_STORAGE_ROOT: &str = "/mnt/storage_pool";
+pub const DEFAULT_DB_PATH: &str = "/var/lib/storage-plus/storage-plus.db";
+pub const DEFAULT_POOL_SIZE: u32 = 4;
+pub const DEFAULT_ADDR: &str = "127.0.0.1:8080";
+pub const DEFAULT_DEVICE_CACHE_TTL_SECS: u64 = 30;
+pub const DEFAULT_SCAN_INTERVAL_SECS: u64 = 5;
+pub const DEFAULT_MOUNT_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_CAPACITY_REFRESH_SECS: u64 = 60;
+pub const DEFAULT_MAX_FILENAME_LEN: usize = 255;
+pub const DEFAULT_UPLOAD_FIELD: &str = "file";
+pub const DEFAULT_MAX_KEY_LEN: usize = 128;
+pub const DEFAULT_EXPIRY_SWEEP_INTERVAL_SECS: u64 = 60;
+pub const DEFAULT_UPLOAD_IDLE_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_IO_ERROR_WINDOW_SECS: u64 = 300;
+/// actix `Logger` formats for the `common` and `combined` access-log presets. actix has
+/// no ident or auth user, so those CLF fields are always `-`, and `%t` is an RFC 3339
+/// timestamp rather than Apache's `10/Oct/2000:13:55:36 -0700`.
+pub const ACCESS_LOG_COMMON: &str = r#"%a - - [%t] "%r" %s %b"#;
+pub const ACCESS_LOG_COMBINED: &str = r#"%a - - [%t] "%r" %s %b "%{Referer}i" "%{User-Agent}i""#;
+pub const DEFAULT_DB_CONNECT_ATTEMPTS: u32 = 5;
+pub const DEFAULT_DB_CONNECT_INTERVAL_SECS: u64 = 2;
+
+/// What to do with an uploaded filename longer than `max_filename_len`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FilenamePolicy {
+    /// Refuse the upload with 400.
+    #[default]
+    Reject,
+    /// Shorten the name, keeping its extension.
+    Truncate,
+}
+
+/// Which newly detected devices the mounter joins to the pool without a manual join.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AutoJoinPolicy {
+    /// Only devices joined explicitly.
+    #[default]
+    Manual,
+    /// Every device seen for the first time.
+    AllNew,
+    /// New devices whose filesystem label matches `auto_join_label`.
+    MatchLabel,
+}
+
+/// Settings shared by both binaries, loadable from a JSON file via `--config`.
+///
+/// Every field is optional: precedence is CLI flag > config file > built-in default.
+/// Binaries build an override `Config` from their flags and `merge` it over the file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub storage_root: Option<PathBuf>,
+    pub db_path: Option<PathBuf>,
+    pub pool_size: Option<u32>,
+    pub db_connect_attempts: Option<u32>,
+    pub db_connect_interval_secs: Option<u64>,
+    pub strict_db_placement: Option<bool>,
+    pub sqlite_foreign_keys: Option<bool>,
+    pub sqlite_cache_size: Option<i64>,
+    pub sqlite_busy_timeout_ms: Option<u32>,
+
+    // server
+    pub addr: Option<String>,
+    pub unix_socket: Option<PathBuf>,
+    /// Octal permission bits for the Unix socket file, e.g. "660".
+    pub unix_socket_mode: Option<String>,
+    pub device_cache_ttl_secs: Option<u64>,
+    pub verify_downloads: Option<bool>,
+    pub download_cache_max_age_secs: Option<u64>,
+    pub download_rate_limit: Option<u64>,
+    pub write_once: Option<bool>,
+    pub retention_secs: Option<u64>,
+    pub drop_cache_after_write: Option<bool>,
+    pub compress_uploads: Option<bool>,
+    /// Versioned-by-filename mode: live copies kept per (tenant, filename); off when unset.
+    pub keep_versions: Option<u32>,
+    pub primary_device_uuid: Option<String>,
+    pub api_token: Option<String>,
+    /// Tenant name → bearer token for tenant-scoped access.
+    pub tenant_tokens: Option<HashMap<String, String>>,
+    pub signing_secret: Option<String>,
+    pub allowed_content_types: Option<Vec<String>>,
+    pub max_filename_len: Option<usize>,
+    pub filename_policy: Option<FilenamePolicy>,
+    pub upload_field: Option<String>,
+    pub expiry_sweep_interval_secs: Option<u64>,
+    pub verify_mounts: Option<bool>,
+    pub max_key_len: Option<usize>,
+    pub slow_query_ms: Option<u64>,
+    /// Blocking-pool threads per HTTP worker (0 or unset = tokio's default).
+    pub blocking_threads: Option<usize>,
+    pub root_mount_check_secs: Option<u64>,
+    pub reject_empty_uploads: Option<bool>,
+    pub upload_idle_timeout_secs: Option<u64>,
+    pub expose_device_header: Option<bool>,
+    /// HTTP access log: `common`, `combined`, or an actix `Logger` format string.
+    pub access_log: Option<String>,
+    pub max_objects_per_device: Option<u64>,
+    /// Where new uploads go below their device directory: `flat` or `dated`.
+    pub layout: Option<Layout>,
+    /// Upload target policy: `random`, `round-robin` or `most-free`.
+    pub device_selection: Option<DeviceSelection>,
+    /// I/O errors within `io_error_window_secs` that flag a device read-only (0 or unset = never).
+    pub io_error_threshold: Option<u32>,
+    pub io_error_window_secs: Option<u64>,
+    /// In-progress uploads are named `{temp_prefix}{key}.{uuid}{temp_suffix}`.
+    pub temp_prefix: Option<String>,
+    pub temp_suffix: Option<String>,
+    /// Create temp files with `O_EXCL`: an existing one fails the upload instead of
+    /// being truncated.
+    pub exclusive_temp_files: Option<bool>,
+    /// One upload at a time writes to each device (for spinning disks).
+    pub serialize_device_writes: Option<bool>,
+    /// Answer uploads with 201 Created and a Location header.
+    pub created_responses: Option<bool>,
+    /// 503 for objects on an offline device and 410 for deleted ones, instead of 404.
+    pub outage_aware_downloads: Option<bool>,
+    /// Leading bytes of an upload held in memory for sniffing its type.
+    pub sniff_buffer_bytes: Option<usize>,
+    /// Retries of a publish rename that failed transiently.
+    pub publish_retries: Option<u32>,
+
+    // mounter
+    pub scan_interval_secs: Option<u64>,
+    pub device_prefixes: Option<Vec<String>>,
+    pub diagnostics_port: Option<u16>,
+    pub enable_smart: Option<bool>,
+    pub device_blacklist: Option<Vec<String>>,
+    pub min_free_bytes: Option<u64>,
+    pub never_format: Option<bool>,
+    pub mount_timeout_secs: Option<u64>,
+    pub removed_device_retention_secs: Option<u64>,
+    pub boot_grace_secs: Option<u64>,
+    pub uuid_settle_timeout_secs: Option<u64>,
+    pub unmount_grace_secs: Option<u64>,
+    pub label_mount_dirs: Option<bool>,
+    pub verify_mount_writable: Option<bool>,
+    pub capacity_refresh_secs: Option<u64>,
+    pub auto_join: Option<AutoJoinPolicy>,
+    pub auto_join_label: Option<String>,
+}
+
+impl Config {
+    /// Parse a JSON config file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let raw = fs::read_to_string(path).with_context(|| format!("read config {:?}", path))?;
+        serde_json::from_str(&raw).with_context(|| format!("parse config {:?}", path))
+    }
+
+    /// Overlay `overrides` on top of `self`; fields set in `overrides` win.
+    pub fn merge(self, overrides: Config) -> Config {
+        Config {
+            storage_root: overrides.storage_root.or(self.storage_root),
+            db_path: overrides.db_path.or(self.db_path),
+            pool_size: overrides.pool_size.or(self.pool_size),
+            db_connect_attempts: overrides.db_connect_attempts.or(self.db_connect_attempts),
+            db_connect_interval_secs: overrides
+                .db_connect_interval_secs
+                .or(self.db_connect_interval_secs),
+            strict_db_placement: overrides.strict_db_placement.or(self.strict_db_placement),
+            sqlite_foreign_keys: overrides.sqlite_foreign_keys.or(self.sqlite_foreign_keys),
+            sqlite_cache_size: overrides.sqlite_cache_size.or(self.sqlite_cache_size),
+            sqlite_busy_timeout_ms: overrides
+                .sqlite_busy_timeout_ms
+                .or(self.sqlite_busy_timeout_ms),
+            addr: overrides.addr.or(self.addr),
+            unix_socket: overrides.unix_socket.or(self.unix_socket),
+            unix_socket_mode: overrides.unix_socket_mode.or(self.unix_socket_mode),
+            device_cache_ttl_secs: overrides
+                .device_cache_ttl_secs
+                .or(self.device_cache_ttl_secs),
+            verify_downloads: overrides.verify_downloads.or(self.verify_downloads),
+            download_cache_max_age_secs: overrides
+                .download_cache_max_age_secs
+                .or(self.download_cache_max_age_secs),
+            download_rate_limit: overrides.download_rate_limit.or(self.download_rate_limit),
+            write_once: overrides.write_once.or(self.write_once),
+            retention_secs: overrides.retention_secs.or(self.retention_secs),
+            drop_cache_after_write: overrides
+                .drop_cache_after_write
+                .or(self.drop_cache_after_write),
+            compress_uploads: overrides.compress_uploads.or(self.compress_uploads),
+            keep_versions: overrides.keep_versions.or(self.keep_versions),
+            primary_device_uuid: overrides.primary_device_uuid.or(self.primary_device_uuid),
+            api_token: overrides.api_token.or(self.api_token),
+            tenant_tokens: overrides.tenant_tokens.or(self.tenant_tokens),
+            signing_secret: overrides.signing_secret.or(self.signing_secret),
+            allowed_content_types: overrides
+                .allowed_content_types
+                .or(self.allowed_content_types),
+            max_filename_len: overrides.max_filename_len.or(self.max_filename_len),
+            filename_policy: overrides.filename_policy.or(self.filename_policy),
+            upload_field: overrides.upload_field.or(self.upload_field),
+            expiry_sweep_interval_secs: overrides
+                .expiry_sweep_interval_secs
+                .or(self.expiry_sweep_interval_secs),
+            verify_mounts: overrides.verify_mounts.or(self.verify_mounts),
+            max_key_len: overrides.max_key_len.or(self.max_key_len),
+            slow_query_ms: overrides.slow_query_ms.or(self.slow_query_ms),
+            blocking_threads: overrides.blocking_threads.or(self.blocking_threads),
+            root_mount_check_secs: overrides
+                .root_mount_check_secs
+                .or(self.root_mount_check_secs),
+            reject_empty_uploads: overrides.reject_empty_uploads.or(self.reject_empty_uploads),
+            upload_idle_timeout_secs: overrides
+                .upload_idle_timeout_secs
+                .or(self.upload_idle_timeout_secs),
+            expose_device_header: overrides.expose_device_header.or(self.expose_device_header),
+            access_log: overrides.access_log.or(self.access_log),
+            max_objects_per_device: overrides
+                .max_objects_per_device
+                .or(self.max_objects_per_device),
+            layout: overrides.layout.or(self.layout),
+            device_selection: overrides.device_selection.or(self.device_selection),
+            io_error_threshold: overrides.io_error_threshold.or(self.io_error_threshold),
+            io_error_window_secs: overrides.io_error_window_secs.or(self.io_error_window_secs),
+            temp_prefix: overrides.temp_prefix.or(self.temp_prefix),
+            temp_suffix: overrides.temp_suffix.or(self.temp_suffix),
+            exclusive_temp_files: overrides.exclusive_temp_files.or(self.exclusive_temp_files),
+            serialize_device_writes: overrides
+                .serialize_device_writes
+                .or(self.serialize_device_writes),
+            created_responses: overrides.created_responses.or(self.created_responses),
+            outage_aware_downloads: overrides
+                .outage_aware_downloads
+                .or(self.outage_aware_downloads),
+            sniff_buffer_bytes: overrides.sniff_buffer_bytes.or(self.sniff_buffer_bytes),
+            publish_retries: overrides.publish_retries.or(self.publish_retries),
+            scan_interval_secs: overrides.scan_interval_secs.or(self.scan_interval_secs),
+            device_prefixes: overrides.device_prefixes.or(self.device_prefixes),
+            diagnostics_port: overrides.diagnostics_port.or(self.diagnostics_port),
+            enable_smart: overrides.enable_smart.or(self.enable_smart),
+            device_blacklist: overrides.device_blacklist.or(self.device_blacklist),
+            min_free_bytes: overrides.min_free_bytes.or(self.min_free_bytes),
+            never_format: overrides.never_format.or(self.never_format),
+            mount_timeout_secs: overrides.mount_timeout_secs.or(self.mount_timeout_secs),
+            removed_device_retention_secs: overrides
+                .removed_device_retention_secs
+                .or(self.removed_device_retention_secs),
+            boot_grace_secs: overrides.boot_grace_secs.or(self.boot_grace_secs),
+            uuid_settle_timeout_secs: overrides
+                .uuid_settle_timeout_secs
+                .or(self.uuid_settle_timeout_secs),
+            unmount_grace_secs: overrides.unmount_grace_secs.or(self.unmount_grace_secs),
+            label_mount_dirs: overrides.label_mount_dirs.or(self.label_mount_dirs),
+            verify_mount_writable: overrides
+                .verify_mount_writable
+                .or(self.verify_mount_writable),
+            capacity_refresh_secs: overrides
+                .capacity_refresh_secs
+                .or(self.capacity_refresh_secs),
+            auto_join: overrides.auto_join.or(self.auto_join),
+            auto_join_label: overrides.auto_join_label.or(self.auto_join_label),
+        }
+    }
+
+    pub fn storage_root(&self) -> PathBuf {
+        self.storage_root
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_STORAGE_ROOT))
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        self.db_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DB_PATH))
+    }
+
+    /// Connections in the pool (at least 1; r2d2 panics on an empty pool).
+    pub fn pool_size(&self) -> u32 {
+        self.pool_size.unwrap_or(DEFAULT_POOL_SIZE).max(1)
+    }
+
+    /// Cap on the blocking thread pool of each HTTP worker and of the main runtime, which
+    /// run every DB call and upload finalization. None leaves tokio's default (512 per
+    /// runtime, split across the workers). Threads beyond `pool_size` only queue for a
+    /// connection, so about `pool_size` is plenty for a small board on a single disk.
+    pub fn blocking_threads(&self) -> Option<usize> {
+        self.blocking_threads.filter(|&n| n > 0)
+    }
+
+    /// Attempts at opening the database before giving up (at least 1).
+    pub fn db_connect_attempts(&self) -> u32 {
+        self.db_connect_attempts
+            .unwrap_or(DEFAULT_DB_CONNECT_ATTEMPTS)
+            .max(1)
+    }
+
+    /// Delay before the first retry; doubles after each failed attempt.
+    pub fn db_connect_interval_secs(&self) -> u64 {
+        self.db_connect_interval_secs
+            .unwrap_or(DEFAULT_DB_CONNECT_INTERVAL_SECS)
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout.unwrap_or_default()
+    }
+
+    pub fn device_selection(&self) -> DeviceSelection {
+        self.device_selection.unwrap_or_default()
+    }
+
+    pub fn io_error_threshold(&self) -> Option<u32> {
+        self.io_error_threshold.filter(|&n| n > 0)
+    }
+
+    /// Naming of in-progress uploads. The suffix may not be empty (every key would look like
+    /// a temp file) and neither part may contain a path separator.
+    pub fn temp_names(&self) -> Result<TempNames> {
+        let prefix = self.temp_prefix.clone().unwrap_or_default();
+        let suffix = self
+            .temp_suffix
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TEMP_SUFFIX.to_string());
+        if suffix.is_empty() {
+            bail!("temp_suffix must not be empty");
+        }
+        if format!("{prefix}{suffix}").contains(['/', '\\', '\0']) {
+            bail!("temp_prefix and temp_suffix must not contain path separators");
+        }
+        Ok(TempNames::new(prefix, suffix))
+    }
+
+    /// Span over which device I/O errors are counted against `io_error_threshold`.
+    pub fn io_error_window(&self) -> Duration {
+        Duration::from_secs(
+            self.io_error_window_secs
+                .unwrap_or(DEFAULT_IO_ERROR_WINDOW_SECS)
+                .max(1),
+        )
+    }
+
+    /// Refuse to start with the database on a pool drive instead of only warning.
+    pub fn strict_db_placement(&self) -> bool {
+        self.strict_db_placement.unwrap_or(false)
+    }
+
+    /// Pragmas applied to every pooled SQLite connection.
+    pub fn connection_options(&self) -> ConnectionOptions {
+        let defaults = ConnectionOptions::default();
+        ConnectionOptions {
+            foreign_keys: self.sqlite_foreign_keys.unwrap_or(defaults.foreign_keys),
+            cache_size: self.sqlite_cache_size.unwrap_or(defaults.cache_size),
+            busy_timeout_ms: self
+                .sqlite_busy_timeout_ms
+                .unwrap_or(defaults.busy_timeout_ms),
+        }
+    }
+
+    pub fn addr(&self) -> String {
+        self.addr
+            .clone()
+            .unwrap_or_else(|| DEFAULT_ADDR.to_string())
+    }
+
+    /// Parsed `unix_socket_mode`; None leaves the permissions from the umask.
+    pub fn unix_socket_mode(&self) -> Result<Option<u32>> {
+        self.unix_socket_mode
+            .as_deref()
+            .map(|m| {
+                u32::from_str_radix(m, 8)
+                    .map_err(|_| anyhow!("unix_socket_mode must be octal, got {m:?}"))
+            })
+            .transpose()
+    }
+
+    /// How long the upload device list is cached; 0 queries the DB on every upload.
+    pub fn device_cache_ttl_secs(&self) -> u64 {
+        self.device_cache_ttl_secs
+            .unwrap_or(DEFAULT_DEVICE_CACHE_TTL_SECS)
+    }
+
+    pub fn verify_downloads(&self) -> bool {
+        self.verify_downloads.unwrap_or(false)
+    }
+
+    pub fn write_once(&self) -> bool {
+        self.write_once.unwrap_or(false)
+    }
+
+    pub fn retention_secs(&self) -> u64 {
+        self.retention_secs.unwrap_or(0)
+    }
+
+    pub fn drop_cache_after_write(&self) -> bool {
+        self.drop_cache_after_write.unwrap_or(false)
+    }
+
+    pub fn compress_uploads(&self) -> bool {
+        self.compress_uploads.unwrap_or(false)
+    }
+
+    pub fn exclusive_temp_files(&self) -> bool {
+        self.exclusive_temp_files.unwrap_or(false)
+    }
+
+    pub fn serialize_device_writes(&self) -> bool {
+        self.serialize_device_writes.unwrap_or(false)
+    }
+
+    pub fn created_responses(&self) -> bool {
+        self.created_responses.unwrap_or(false)
+    }
+
+    pub fn outage_aware_downloads(&self) -> bool {
+        self.outage_aware_downloads.unwrap_or(false)
+    }
+
+    /// Bytes buffered for content sniffing; never fewer than the sniffer needs.
+    pub fn sniff_buffer_bytes(&self) -> usize {
+        self.sniff_buffer_bytes
+            .unwrap_or(sniff::SNIFF_LEN)
+            .max(sniff::SNIFF_LEN)
+    }
+
+    pub fn publish_retries(&self) -> u32 {
+        self.publish_retries.unwrap_or(0)
+    }
+
+    /// Copies of a filename kept by versioned uploads; None (configured as 0) is off.
+    pub fn keep_versions(&self) -> Option<u32> {
+        self.keep_versions.filter(|&n| n > 0)
+    }
+
+    /// Answer zero-byte uploads with 400 instead of storing an empty object.
+    pub fn reject_empty_uploads(&self) -> bool {
+        self.reject_empty_uploads.unwrap_or(false)
+    }
+
+    /// Name the serving device in an `X-Storage-Device` header on downloads.
+    pub fn expose_device_header(&self) -> bool {
+        self.expose_device_header.unwrap_or(false)
+    }
+
+    /// actix `Logger` format for HTTP access lines, with the `common`/`combined` presets
+    /// expanded; None when no access log is wanted.
+    pub fn access_log_format(&self) -> Option<String> {
+        let format = self.access_log.as_deref()?;
+        Some(match format {
+            "common" => ACCESS_LOG_COMMON.to_string(),
+            "combined" => ACCESS_LOG_COMBINED.to_string(),
+            custom => custom.to_string(),
+        })
+    }
+
+    /// How long an upload may go without sending a chunk before it is aborted with 408;
+    /// None (configured as 0) waits forever.
+    pub fn upload_idle_timeout(&self) -> Option<Duration> {
+        match self
+            .upload_idle_timeout_secs
+            .unwrap_or(DEFAULT_UPLOAD_IDLE_TIMEOUT_SECS)
+        {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// Content-type prefixes accepted on upload; empty allows everything.
+    /// Tenant tokens, checked up front: tenants become path segments and a token must
+    /// not be shared between tenants (or with `api_token`).
+    pub fn tenant_tokens(&self) -> Result<HashMap<String, String>> {
+        let tokens = self.tenant_tokens.clone().unwrap_or_default();
+        let mut seen: Vec<&str> = self.api_token.as_deref().into_iter().collect();
+        for (tenant, token) in &tokens {
+            crate::storage::StorageImpl::ensure_segment(tenant, "tenant")?;
+            if token.is_empty() || seen.contains(&token.as_str()) {
+                return Err(anyhow!("tenant {tenant:?} needs its own non-empty token"));
+            }
+            seen.push(token);
+        }
+        Ok(tokens)
+    }
+
+    pub fn allowed_content_types(&self) -> Vec<String> {
+        self.allowed_content_types.clone().unwrap_or_default()
+    }
+
+    /// Longest accepted filename in bytes.
+    pub fn max_filename_len(&self) -> usize {
+        self.max_filename_len
+            .unwrap_or(DEFAULT_MAX_FILENAME_LEN)
+            .max(1)
+    }
+
+    pub fn filename_policy(&self) -> FilenamePolicy {
+        self.filename_policy.unwrap_or_default()
+    }
+
+    /// Multipart field carrying the uploaded file.
+    pub fn upload_field(&self) -> String {
+        self.upload_field
+            .clone()
+            .unwrap_or_else(|| DEFAULT_UPLOAD_FIELD.to_string())
+    }
+
+    /// How often expired (TTL) objects are swept, at least once a second.
+    pub fn expiry_sweep_interval_secs(&self) -> u64 {
+        self.expiry_sweep_interval_secs
+            .unwrap_or(DEFAULT_EXPIRY_SWEEP_INTERVAL_SECS)
+            .max(1)
+    }
+
+    pub fn max_key_len(&self) -> usize {
+        self.max_key_len.unwrap_or(DEFAULT_MAX_KEY_LEN)
+    }
+
+    /// Re-check the target device is mounted before each upload's first write.
+    pub fn verify_mounts(&self) -> bool {
+        self.verify_mounts.unwrap_or(true)
+    }
+
+    pub fn scan_interval_secs(&self) -> u64 {
+        self.scan_interval_secs
+            .unwrap_or(DEFAULT_SCAN_INTERVAL_SECS)
+    }
+
+    /// Safe mode: the mounter never runs `mkfs`, even if formatting is requested.
+    pub fn never_format(&self) -> bool {
+        self.never_format.unwrap_or(false)
+    }
+
+    /// Limit on a single mount/umount/mkfs run; None (configured as 0) waits indefinitely.
+    pub fn mount_timeout(&self) -> Option<Duration> {
+        match self
+            .mount_timeout_secs
+            .unwrap_or(DEFAULT_MOUNT_TIMEOUT_SECS)
+        {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// How long removed devices stay in the table; 0 keeps them forever.
+    pub fn removed_device_retention_secs(&self) -> u64 {
+        self.removed_device_retention_secs.unwrap_or(0)
+    }
+
+    /// Delay before the mounter's first mount pass; zero starts right away.
+    pub fn boot_grace(&self) -> Duration {
+        Duration::from_secs(self.boot_grace_secs.unwrap_or(0))
+    }
+
+    /// How long a device added without a filesystem UUID is re-polled for one.
+    pub fn uuid_settle_timeout(&self) -> Duration {
+        Duration::from_secs(self.uuid_settle_timeout_secs.unwrap_or(0))
+    }
+
+    /// How long a removed but busy device is retried before a lazy unmount; zero tries once.
+    pub fn unmount_grace(&self) -> Duration {
+        Duration::from_secs(self.unmount_grace_secs.unwrap_or(0))
+    }
+
+    /// Name new mount directories after the filesystem label instead of the UUID.
+    pub fn label_mount_dirs(&self) -> bool {
+        self.label_mount_dirs.unwrap_or(false)
+    }
+
+    /// Probe each new mount for writability, flagging read-only filesystems.
+    pub fn verify_mount_writable(&self) -> bool {
+        self.verify_mount_writable.unwrap_or(false)
+    }
+
+    /// How often a mounted device's capacity is re-read; 0 reads it on every scan.
+    pub fn capacity_refresh_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.capacity_refresh_secs
+                .unwrap_or(DEFAULT_CAPACITY_REFRESH_SECS),
+        )
+    }
+
+    pub fn enable_smart(&self) -> bool {
+        self.enable_smart.unwrap_or(false)
+    }
+
+    /// Filesystem UUIDs or drive serials that must never be mounted into the pool.
+    pub fn device_blacklist(&self) -> Vec<String> {
+        self.device_blacklist.clone().unwrap_or_default()
+    }
+
+    /// Free-space floor below which a mounted device takes no uploads (0 = off).
+    pub fn min_free_bytes(&self) -> u64 {
+        self.min_free_bytes.unwrap_or(0)
+    }
+
+    /// Auto-join policy; `match-label` requires `auto_join_label` to be a valid regex.
+    pub fn auto_join(&self) -> Result<AutoJoin> {
+        Ok(match self.auto_join.unwrap_or_default() {
+            AutoJoinPolicy::Manual => AutoJoin::Manual,
+            AutoJoinPolicy::AllNew => AutoJoin::AllNew,
+            AutoJoinPolicy::MatchLabel => {
+                let pattern = self
+                    .auto_join_label
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("auto_join match-label needs auto_join_label"))?;
+                let re = Regex::new(pattern)
+                    .with_context(|| format!("invalid auto_join_label {:?}", pattern))?;
+                AutoJoin::MatchLabel(re)
+            }
+        })
+    }
+
+    pub fn device_prefixes(&self) -> Vec<String> {
+        self.device_prefixes.clone().unwrap_or_else(|| {
+            crate::mounter::DEFAULT_DEVICE_PREFIXES
+                .iter()
+                .map(|p| p.to_string())
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+
+    #[test]
+    fn parses_config_file() {
+        let path = temp_dir("config").join("config.json");
+        fs::write(
+            &path,
+            r#"{"storage_root": "/srv/pool", "addr": "0.0.0.0:9000", "pool_size": 8,
+                "device_prefixes": ["nvme"], "blocking_threads": 4}"#,
+        )
+        .unwrap();
+        let cfg = Config::load(&path).unwrap();
+        assert_eq!(cfg.storage_root(), PathBuf::from("/srv/pool"));
+        assert_eq!(cfg.addr(), "0.0.0.0:9000");
+        assert_eq!(cfg.pool_size(), 8);
+        assert_eq!(cfg.device_prefixes(), vec!["nvme".to_string()]);
+        assert_eq!(cfg.blocking_threads(), Some(4));
+
+        let empty_pool = Config {
+            pool_size: Some(0),
+            blocking_threads: Some(0),
+            ..Config::default()
+        };
+        assert_eq!(empty_pool.pool_size(), 1);
+        assert_eq!(empty_pool.blocking_threads(), None);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let path = temp_dir("config").join("config.json");
+        fs::write(&path, r#"{"storage_rot": "/srv/pool"}"#).unwrap();
+        assert!(Config::load(&path).is_err());
+    }
+
+    #[test]
+    fn cli_overrides_file_and_defaults_fill_gaps() {
+        let file = Config {
+            storage_root: Some("/srv/pool".into()),
+            addr: Some("0.0.0.0:9000".into()),
+            ..Default::default()
+        };
+        let cli = Config {
+            addr: Some("127.0.0.1:1234".into()),
+            ..Default::default()
+        };
+        let cfg = file.merge(cli);
+        assert_eq!(cfg.addr(), "127.0.0.1:1234");
+        assert_eq!(cfg.storage_root(), PathBuf::from("/srv/pool"));
+        assert_eq!(cfg.db_path(), PathBuf::from(DEFAULT_DB_PATH));
+        assert_eq!(cfg.device_cache_ttl_secs(), DEFAULT_DEVICE_CACHE_TTL_SECS);
+        assert_eq!(cfg.scan_interval_secs(), DEFAULT_SCAN_INTERVAL_SECS);
+    }
+
+    #[test]
+    fn temp_names_need_a_safe_non_empty_suffix() {
+        assert_eq!(
+            Config::default().temp_names().unwrap(),
+            TempNames::default()
+        );
+        let names = |prefix: &str, suffix: &str| {
+            Config {
+                temp_prefix: Some(prefix.into()),
+                temp_suffix: Some(suffix.into()),
+                ..Config::default()
+            }
+            .temp_names()
+        };
+        assert_eq!(names(".", ".tmp").unwrap(), TempNames::new(".", ".tmp"));
+        assert!(names("", "").is_err());
+        assert!(names("in/", ".tmp").is_err());
+    }
+}
@@ -1,8 +1,14 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
 use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
+use log::warn;
 use std::path::Path;
+use std::{fs, thread, time::Duration};
+
+use crate::config::DEFAULT_POOL_SIZE;
+use crate::system;
 
 // 使用嵌入式 migrations，避免运行时查找当前工作目录导致的找不到 migrations 目录问题。
 // 如果之前遇到 rust-analyzer 对 proc-macro 的问题，现在可以再尝试；若仍有 IDE 报错，可在构建/运行时不受影响。
@@ -11,11 +17,71 @@ pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 
 pub type Pool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
 
+/// How long a connection waits on a locked database before failing with SQLITE_BUSY.
+const BUSY_TIMEOUT_MS: u32 = 5000;
+/// SQLite page cache per connection; negative values are KiB, so about 8 MB.
+pub const DEFAULT_CACHE_SIZE: i64 = -8000;
+
+/// Per-connection settings applied when the pool opens a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionOptions {
+    /// Concurrent writers queue up this long instead of failing with "database is locked".
+    pub busy_timeout_ms: u32,
+    /// SQLite leaves foreign key enforcement off unless asked, per connection.
+    pub foreign_keys: bool,
+    /// `PRAGMA cache_size`: pages if positive, KiB if negative.
+    pub cache_size: i64,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            busy_timeout_ms: BUSY_TIMEOUT_MS,
+            foreign_keys: true,
+            cache_size: DEFAULT_CACHE_SIZE,
+        }
+    }
+}
+
+impl ConnectionOptions {
+    fn pragmas(&self) -> String {
+        format!(
+            "PRAGMA busy_timeout = {}; PRAGMA foreign_keys = {}; PRAGMA cache_size = {};",
+            self.busy_timeout_ms,
+            if self.foreign_keys { "ON" } else { "OFF" },
+            self.cache_size
+        )
+    }
+}
+
+impl r2d2::CustomizeConnection<SqliteConnection, r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> std::result::Result<(), r2d2::Error> {
+        conn.batch_execute(&self.pragmas())
+            .map_err(r2d2::Error::QueryError)
+    }
+}
+
 pub fn establish_pool(db_path: &Path) -> Result<Pool> {
+    establish_pool_with_size(db_path, DEFAULT_POOL_SIZE)
+}
+
+pub fn establish_pool_with_size(db_path: &Path, max_size: u32) -> Result<Pool> {
+    establish_pool_with_options(db_path, max_size, ConnectionOptions::default())
+}
+
+/// Open a migrated pool whose connections all get `options` applied.
+pub fn establish_pool_with_options(
+    db_path: &Path,
+    max_size: u32,
+    options: ConnectionOptions,
+) -> Result<Pool> {
     let db_path_str = db_path.to_string_lossy().to_string();
     let database_url = format!("sqlite://{}", db_path_str);
     let manager = ConnectionManager::<SqliteConnection>::new(database_url);
-    let pool = r2d2::Pool::builder().max_size(4).build(manager)?;
+    let pool = r2d2::Pool::builder()
+        .max_size(max_size)
+        .connection_customizer(Box::new(options))
+        .build(manager)?;
     {
         let mut conn = pool.get()?;
         run_migrations(&mut conn)?;
@@ -23,8 +89,274 @@ pub fn establish_pool(db_path: &Path) -> Result<Pool> {
     Ok(pool)
 }
 
+/// Open the pool like `establish_pool_with_options`, creating the DB's parent directory
+/// first, and retry with exponential backoff starting at `interval` so a filesystem that
+/// isn't ready yet at boot can come up. Fails after `attempts` tries with the last error.
+pub fn establish_pool_with_retry(
+    db_path: &Path,
+    max_size: u32,
+    options: ConnectionOptions,
+    attempts: u32,
+    interval: Duration,
+) -> Result<Pool> {
+    retry_with_backoff(attempts, interval, thread::sleep, || {
+        if let Some(parent) = db_path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("create {:?}", parent))?;
+        }
+        establish_pool_with_options(db_path, max_size, options)
+    })
+    .with_context(|| {
+        format!(
+            "database {:?} unavailable after {} attempts",
+            db_path, attempts
+        )
+    })
+}
+
+fn retry_with_backoff<T>(
+    attempts: u32,
+    interval: Duration,
+    mut sleep: impl FnMut(Duration),
+    mut op: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut delay = interval;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt >= attempts => return Err(e),
+            Err(e) => {
+                warn!(
+                    "database not ready (attempt {attempt}/{attempts}): {e:#}; retrying in {delay:?}"
+                );
+                sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Whether the database would go down with a pool drive: it sits on a device mounted
+/// below `storage_root`, or `storage_root` is itself a mount and the database shares its
+/// filesystem. Unplugging that drive would take every device's metadata with it.
+pub fn db_on_pool_drive(db_path: &Path, storage_root: &Path) -> std::io::Result<bool> {
+    let shared = system::same_filesystem(db_path, storage_root)?;
+    if db_path.starts_with(storage_root) {
+        return Ok(!shared || system::is_mount_point(storage_root)?);
+    }
+    Ok(shared && system::is_mount_point(storage_root)?)
+}
+
+/// Warn loudly (or, when `strict`, fail) if the database lives on a pool drive rather
+/// than the root filesystem. A placement that can't be determined is only logged.
+pub fn check_db_placement(db_path: &Path, storage_root: &Path, strict: bool) -> Result<()> {
+    match db_on_pool_drive(db_path, storage_root) {
+        Ok(false) => Ok(()),
+        Ok(true) if strict => anyhow::bail!(
+            "database {:?} is on a removable pool drive under {:?}; move it to the root \
+             filesystem or drop --strict-db-placement",
+            db_path,
+            storage_root
+        ),
+        Ok(true) => {
+            warn!(
+                "!!! database {:?} is on a removable pool drive under {:?}: unplugging that \
+                 drive takes the metadata for every device with it; keep the database on \
+                 the root filesystem !!!",
+                db_path, storage_root
+            );
+            Ok(())
+        }
+        Err(e) => {
+            warn!("could not check where database {:?} lives: {e}", db_path);
+            Ok(())
+        }
+    }
+}
+
+/// Versions of the migrations applied to `conn`'s database, oldest first.
+pub fn applied_migrations(conn: &mut SqliteConnection) -> Result<Vec<String>> {
+    let mut versions: Vec<String> = conn
+        .applied_migrations()
+        .map_err(|e| anyhow::anyhow!("migration query error: {e}"))?
+        .into_iter()
+        .map(|v| v.to_string())
+        .collect();
+    versions.sort();
+    Ok(versions)
+}
+
 fn run_migrations(conn: &mut SqliteConnection) -> Result<()> {
     conn.run_pending_migrations(MIGRATIONS)
         .map(|_| ())
         .map_err(|e| anyhow::anyhow!("migration error: {e}"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn retries_until_success() {
+        let mut calls = 0;
+        let mut slept = Vec::new();
+        let res = retry_with_backoff(
+            5,
+            Duration::from_secs(1),
+            |d| slept.push(d),
+            || {
+                calls += 1;
+                if calls < 3 {
+                    anyhow::bail!("read-only file system")
+                }
+                Ok(calls)
+            },
+        );
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(slept, vec![Duration::from_secs(1), Duration::from_secs(2)]);
+    }
+
+    #[test]
+    fn db_beside_a_plain_storage_root_is_fine() {
+        let root = temp_dir("db-root");
+        assert!(!db_on_pool_drive(&root.join("meta.db"), &root).unwrap());
+        assert!(!db_on_pool_drive(&temp_dir("db-dir").join("meta.db"), &root).unwrap());
+        assert!(check_db_placement(&root.join("meta.db"), &root, true).is_ok());
+        // a root that is a mount point of its own, with the db on it
+        let proc_root = Path::new("/proc");
+        assert!(db_on_pool_drive(&proc_root.join("meta.db"), proc_root).unwrap());
+        assert!(check_db_placement(&proc_root.join("meta.db"), proc_root, true).is_err());
+    }
+
+    #[test]
+    fn gives_up_after_budget() {
+        // parent "directory" is a regular file, so it can never be created
+        let blocker = temp_dir("db").join("blocker");
+        fs::write(&blocker, b"").unwrap();
+        let err = establish_pool_with_retry(
+            &blocker.join("x.db"),
+            1,
+            ConnectionOptions::default(),
+            2,
+            Duration::ZERO,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unavailable after 2 attempts"));
+    }
+
+    #[derive(QueryableByName)]
+    struct PragmaValue {
+        #[diesel(sql_type = diesel::sql_types::BigInt)]
+        value: i64,
+    }
+
+    /// Value of pragma `name`, whose table-valued form returns it in `column`.
+    fn pragma(conn: &mut SqliteConnection, name: &str, column: &str) -> i64 {
+        diesel::sql_query(format!("SELECT {column} AS value FROM pragma_{name}()"))
+            .get_result::<PragmaValue>(conn)
+            .unwrap()
+            .value
+    }
+
+    #[test]
+    fn pragmas_apply_to_pooled_connections() {
+        let path = temp_dir("db").join("p.db");
+        let options = ConnectionOptions {
+            cache_size: -4000,
+            ..Default::default()
+        };
+        let pool = establish_pool_with_options(&path, 2, options).unwrap();
+        // hold one connection so the second comes from a fresh acquire
+        let _first = pool.get().unwrap();
+        let mut conn = pool.get().unwrap();
+        assert_eq!(pragma(&mut conn, "foreign_keys", "foreign_keys"), 1);
+        assert_eq!(pragma(&mut conn, "cache_size", "cache_size"), -4000);
+        assert_eq!(
+            pragma(&mut conn, "busy_timeout", "timeout"),
+            BUSY_TIMEOUT_MS as i64
+        );
+
+        let off = ConnectionOptions {
+            foreign_keys: false,
+            busy_timeout_ms: 0,
+            ..Default::default()
+        };
+        let pool = establish_pool_with_options(&path, 1, off).unwrap();
+        let mut conn = pool.get().unwrap();
+        assert_eq!(pragma(&mut conn, "foreign_keys", "foreign_keys"), 0);
+        assert_eq!(pragma(&mut conn, "busy_timeout", "timeout"), 0);
+    }
+
+    /// `(table, column, SQL type, nullable)` as created by the migrations.
+    #[derive(QueryableByName)]
+    struct ColumnInfo {
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        tbl: String,
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        name: String,
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        kind: String,
+        #[diesel(sql_type = diesel::sql_types::Bool)]
+        nullable: bool,
+    }
+
+    type Column = (String, String, String, bool);
+
+    /// Columns declared in `schema.rs`, with diesel types spelled as the SQL the
+    /// migrations use for them.
+    fn declared_columns() -> BTreeSet<Column> {
+        let table_re = regex::Regex::new(r"^\s*(\w+) \(\w+\) \{").unwrap();
+        let column_re = regex::Regex::new(r"^\s*(\w+) -> (Nullable<)?(\w+)>?,").unwrap();
+        let mut table = None;
+        let mut out = BTreeSet::new();
+        for line in include_str!("schema.rs").lines() {
+            if let Some(c) = table_re.captures(line) {
+                table = Some(c[1].to_string());
+            } else if let Some(c) = column_re.captures(line) {
+                let kind = match &c[3] {
+                    "Integer" => "INTEGER",
+                    "BigInt" => "BIGINT",
+                    "Text" => "TEXT",
+                    other => panic!("no SQL spelling for diesel type {other}"),
+                };
+                let table = table.clone().expect("column outside a table");
+                out.insert((
+                    table,
+                    c[1].to_string(),
+                    kind.to_string(),
+                    c.get(2).is_some(),
+                ));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn schema_matches_migrations() {
+        let pool = establish_pool(&temp_dir("db").join("s.db")).unwrap();
+        let migrated: BTreeSet<Column> = diesel::sql_query(
+            "SELECT m.name AS tbl, p.name AS name, upper(p.type) AS kind, \
+             (p.\"notnull\" = 0 AND p.pk = 0) AS nullable \
+             FROM sqlite_master m JOIN pragma_table_info(m.name) p \
+             WHERE m.type = 'table' AND m.name NOT LIKE 'sqlite_%' \
+             AND m.name != '__diesel_schema_migrations'",
+        )
+        .load::<ColumnInfo>(&mut pool.get().unwrap())
+        .unwrap()
+        .into_iter()
+        .map(|c| (c.tbl, c.name, c.kind, c.nullable))
+        .collect();
+        let declared = declared_columns();
+        assert!(!declared.is_empty());
+        let missing: Vec<_> = migrated.difference(&declared).collect();
+        let stale: Vec<_> = declared.difference(&migrated).collect();
+        assert!(
+            missing.is_empty() && stale.is_empty(),
+            "schema.rs is out of date with migrations/\n  not declared: {missing:?}\n  \
+             not in the database: {stale:?}"
+        );
+    }
+}
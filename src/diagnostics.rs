@@ -0,0 +1,143 @@
+use std::{net::TcpListener, sync::Arc, thread};
+
+use actix_web::{App, HttpResponse, HttpServer, get, post, web};
+use anyhow::Result;
+use log::{error, info};
+
+use crate::mounter::{Mounter, UuidChanged};
+
+#[get("/diagnostics")]
+async fn diagnostics(mounter: web::Data<Arc<Mounter>>) -> actix_web::Result<HttpResponse> {
+    let m = mounter.get_ref().clone();
+    let diag = web::block(move || m.diagnostics())
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .map_err(|e| {
+            error!("diagnostics error: {e}");
+            actix_web::error::ErrorInternalServerError("db error")
+        })?;
+    Ok(HttpResponse::Ok().json(diag))
+}
+
+/// Re-probe a device's UUID, filesystem type and capacity after it changed in place.
+/// 409 if it was reformatted with a new UUID, which takes a remove and re-join.
+#[post("/devices/{uuid}/refresh")]
+async fn refresh_device(
+    mounter: web::Data<Arc<Mounter>>,
+    path: web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    let uuid = path.into_inner();
+    let m = mounter.get_ref().clone();
+    let device = web::block(move || m.refresh_device(&uuid))
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .map_err(|e| {
+            if let Some(changed) = e.downcast_ref::<UuidChanged>() {
+                return actix_web::error::ErrorConflict(changed.to_string());
+            }
+            error!("refresh error: {e}");
+            actix_web::error::ErrorInternalServerError("refresh failed")
+        })?;
+    match device {
+        Some(device) => Ok(HttpResponse::Ok().json(device)),
+        None => Err(actix_web::error::ErrorNotFound("no such device")),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(diagnostics).service(refresh_device);
+}
+
+/// Serve mounter diagnostics as JSON on `127.0.0.1:{port}` from a background thread.
+/// Bound to loopback only: the mounter runs as root and has no auth of its own.
+pub fn spawn(mounter: Arc<Mounter>, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    info!("diagnostics listening on http://127.0.0.1:{}", port);
+    thread::spawn(move || {
+        let res = actix_web::rt::System::new().block_on(async move {
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::new(mounter.clone()))
+                    .configure(configure)
+            })
+            .workers(1)
+            .listen(listener)?
+            .run()
+            .await
+        });
+        if let Err(e) = res {
+            error!("diagnostics server stopped: {e}");
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::device_repo::new_device_repo;
+    use crate::test_support::{FakeSystem, seed_device, temp_dir, temp_pool};
+    use actix_web::test;
+
+    #[actix_web::test]
+    async fn diagnostics_lists_tracked_devices() {
+        let pool = temp_pool();
+        seed_device(&pool, "/dev/sda1", "u1");
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("mount", false, "");
+        let mounter = Arc::new(
+            Mounter::new(new_device_repo(pool), temp_dir("mnt"), 5).with_system(sys.clone()),
+        );
+        let m = mounter.clone();
+        web::block(move || m.process_pending())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(mounter))
+                .configure(configure),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/diagnostics").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["devices"][0]["devnode"], "/dev/sda1");
+        assert_eq!(body["devices"][0]["uuid"], "u1");
+        assert_eq!(body["mount_failures"]["/dev/sda1"], 1);
+        assert!(body["last_scan_at"].is_i64());
+    }
+
+    #[actix_web::test]
+    async fn refresh_of_unknown_device_is_not_found() {
+        let pool = temp_pool();
+        seed_device(&pool, "/dev/sda1", "ab12");
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("blkid -s UUID -o value /dev/sda1", true, "ab12\n");
+        let mounter = Arc::new(
+            Mounter::new(new_device_repo(pool), temp_dir("mnt"), 5).with_system(sys.clone()),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(mounter))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/devices/nope/refresh")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+        let req = test::TestRequest::post()
+            .uri("/devices/ab12/refresh")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["uuid"], "ab12");
+
+        sys.set_output("blkid -s UUID -o value /dev/sda1", true, "cd34\n");
+        let req = test::TestRequest::post()
+            .uri("/devices/ab12/refresh")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 409);
+    }
+}
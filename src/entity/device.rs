@@ -1,7 +1,8 @@
 use crate::schema::devices;
 use diesel::prelude::*;
+use serde::Serialize;
 
-#[derive(Debug, Queryable, Identifiable)]
+#[derive(Debug, Queryable, Identifiable, Serialize)]
 #[diesel(table_name = devices)]
 pub struct Device {
     pub id: i32,
@@ -12,6 +13,15 @@ pub struct Device {
     pub mount_success: i32,
     pub mount_path: Option<String>,
     pub last_seen: i64,
+    pub read_only: i32,
+    pub health: Option<String>,
+    pub total_bytes: Option<i64>,
+    pub free_bytes: Option<i64>,
+    pub fstype: Option<String>,
+    /// Free space is below the mounter's floor; kept out of upload selection until it recovers.
+    pub low_space: i32,
+    /// Filesystem label from `blkid`, if the filesystem has one.
+    pub label: Option<String>,
 }
 
 #[derive(Insertable)]
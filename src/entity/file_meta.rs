@@ -12,6 +12,36 @@ pub struct FileMeta {
     pub path: String,
     pub created_at: i64,
     pub deleted: i32,
+    pub device_uuid: Option<String>,
+    pub sha256: Option<String>,
+    pub retain_until: Option<i64>,
+    pub tenant: Option<String>,
+    pub expires_at: Option<i64>,
+    pub filename_supplied: i32,
+    /// 1 if the bytes at `path` are gzipped; `size` is then the compressed length, while
+    /// `sha256` and `uncompressed_size` describe the inflated content.
+    pub compressed: i32,
+    pub uncompressed_size: Option<i64>,
+}
+
+impl FileMeta {
+    /// Length of the object as clients see it, i.e. after decompression.
+    pub fn content_size(&self) -> i64 {
+        match self.compressed {
+            0 => self.size,
+            _ => self.uncompressed_size.unwrap_or(self.size),
+        }
+    }
+
+    /// True while a write-once retention window forbids deleting this object.
+    pub fn is_retained(&self, now: i64) -> bool {
+        self.retain_until.is_some_and(|until| until > now)
+    }
+
+    /// True once the upload-time TTL has run out.
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
 }
 
 #[derive(Insertable)]
@@ -24,4 +54,12 @@ pub struct NewFileMeta<'a> {
     pub path: &'a str,
     pub created_at: i64,
     pub deleted: i32,
+    pub device_uuid: Option<&'a str>,
+    pub sha256: Option<&'a str>,
+    pub retain_until: Option<i64>,
+    pub tenant: Option<&'a str>,
+    pub expires_at: Option<i64>,
+    pub filename_supplied: i32,
+    pub compressed: i32,
+    pub uncompressed_size: Option<i64>,
 }
@@ -0,0 +1,605 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
+
+use actix_web::web::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use log::warn;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
+
+use crate::entity::file_meta::FileMeta;
+use crate::storage::civil_date;
+
+const BLOCK: usize = 512;
+
+/// One object to place in an archive under `name`.
+#[derive(Debug, Clone)]
+pub struct ExportEntry {
+    pub name: String,
+    /// Where the bytes are (`files.path`), whatever layout they were stored under.
+    pub path: String,
+    pub key: String,
+    pub mtime: i64,
+}
+
+/// Make an original filename safe as a flat archive entry name.
+fn sanitize(filename: &str, key: &str) -> String {
+    let name: String = filename
+        .chars()
+        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => key.to_string(),
+        _ => name,
+    }
+}
+
+/// `photo.jpg` -> `photo (2).jpg`; names without an extension get the suffix appended.
+fn numbered(name: &str, n: usize) -> String {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => format!("{} ({}){}", &name[..dot], n, &name[dot..]),
+        _ => format!("{} ({})", name, n),
+    }
+}
+
+/// How archive entries that share a filename are told apart (`?duplicates=` on exports).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateNames {
+    /// The first keeps its name, later ones become `name (2).ext`, `name (3).ext`, in
+    /// listing order.
+    #[default]
+    Numbered,
+    /// Every entry whose name is shared becomes `{key}_{name}`, whatever the order.
+    Key,
+}
+
+/// Archive entries for `files` named after their original filenames, de-duplicated per
+/// `duplicates`. Objects stored gzipped are archived as stored, so their names gain a
+/// `.gz` suffix.
+pub fn entries(files: Vec<FileMeta>, duplicates: DuplicateNames) -> Vec<ExportEntry> {
+    let bases: Vec<String> = files
+        .iter()
+        .map(|f| {
+            let mut base = sanitize(&f.filename, &f.key);
+            if f.compressed != 0 {
+                base.push_str(".gz");
+            }
+            base
+        })
+        .collect();
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for base in &bases {
+        *seen.entry(base.as_str()).or_default() += 1;
+    }
+    let shared: HashSet<String> = seen
+        .into_iter()
+        .filter(|(_, n)| *n > 1)
+        .map(|(base, _)| base.to_string())
+        .collect();
+    let mut used: HashSet<String> = HashSet::new();
+    let mut out = Vec::with_capacity(files.len());
+    for (f, base) in files.into_iter().zip(bases) {
+        let base = match duplicates {
+            DuplicateNames::Key if shared.contains(&base) => format!("{}_{}", f.key, base),
+            _ => base,
+        };
+        // numbering also settles whatever `Key` leaves colliding (a filename that
+        // already looks like `{key}_{name}`)
+        let mut name = base.clone();
+        let mut n = 1;
+        while used.contains(&name) {
+            n += 1;
+            name = numbered(&base, n);
+        }
+        used.insert(name.clone());
+        out.push(ExportEntry {
+            name,
+            path: f.path,
+            key: f.key,
+            mtime: f.created_at,
+        });
+    }
+    out
+}
+
+fn header_block(header: &tar::Header) -> Bytes {
+    Bytes::copy_from_slice(header.as_bytes())
+}
+
+fn zero_padding(size: u64) -> Bytes {
+    let rem = (size as usize) % BLOCK;
+    if rem == 0 {
+        Bytes::new()
+    } else {
+        Bytes::from(vec![0u8; BLOCK - rem])
+    }
+}
+
+/// Header block(s) for an entry; names over 100 bytes get a GNU long-name record first.
+fn entry_headers(name: &str, size: u64, mtime: i64) -> io::Result<Vec<Bytes>> {
+    let mut blocks = Vec::new();
+    let mut header = tar::Header::new_gnu();
+    if name.len() > 100 {
+        let mut long = tar::Header::new_gnu();
+        long.set_entry_type(tar::EntryType::GNULongName);
+        long.as_gnu_mut()
+            .expect("gnu header")
+            .name
+            .get_mut(..13)
+            .expect("name field")
+            .copy_from_slice(b"././@LongLink");
+        long.set_mode(0o644);
+        long.set_size(name.len() as u64 + 1);
+        long.set_cksum();
+        blocks.push(header_block(&long));
+        let mut data = name.as_bytes().to_vec();
+        data.push(0);
+        blocks.push(Bytes::from(data));
+        blocks.push(zero_padding(name.len() as u64 + 1));
+        // the real header carries a truncated copy; readers use the long name
+        let mut cut = 100;
+        while !name.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        header.as_gnu_mut().expect("gnu header").name[..cut]
+            .copy_from_slice(&name.as_bytes()[..cut]);
+    } else {
+        header.set_path(name)?;
+    }
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_size(size);
+    header.set_mtime(mtime.max(0) as u64);
+    header.set_cksum();
+    blocks.push(header_block(&header));
+    Ok(blocks)
+}
+
+fn entry_stream(entry: ExportEntry) -> BoxStream<'static, io::Result<Bytes>> {
+    stream::once(async move {
+        let file = match File::open(&entry.path).await {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("export: skipping {} ({}): {}", entry.name, entry.key, e);
+                return stream::empty().boxed();
+            }
+        };
+        // size from the file itself so the header always matches the bytes we send
+        let size = match file.metadata().await {
+            Ok(m) => m.len(),
+            Err(e) => return stream::once(async move { Err(e) }).boxed(),
+        };
+        let headers = match entry_headers(&entry.name, size, entry.mtime) {
+            Ok(h) => h,
+            Err(e) => return stream::once(async move { Err(e) }).boxed(),
+        };
+        stream::iter(headers.into_iter().map(Ok))
+            .chain(ReaderStream::new(file.take(size)))
+            .chain(stream::once(async move { Ok(zero_padding(size)) }))
+            .boxed()
+    })
+    .flatten()
+    .boxed()
+}
+
+/// Lazily stream a tar archive of `entries`. Objects are opened one at a time and copied
+/// in chunks, so memory stays bounded regardless of archive size.
+pub fn tar_stream(
+    entries: Vec<ExportEntry>,
+) -> impl futures_util::Stream<Item = io::Result<Bytes>> {
+    stream::iter(entries)
+        .map(entry_stream)
+        .flatten()
+        .chain(stream::once(async {
+            Ok(Bytes::from(vec![0u8; 2 * BLOCK]))
+        }))
+}
+
+/// Sizes and offsets at or above this need zip64 records.
+const ZIP32_MAX: u64 = 0xFFFF_FFFF;
+/// Stored (no compression), sizes and CRC in a trailing data descriptor, UTF-8 names.
+const ZIP_FLAGS: u16 = 0x0808;
+
+/// MS-DOS (time, date) of an epoch second, in UTC; the format can't go before 1980.
+fn dos_datetime(epoch_secs: i64) -> (u16, u16) {
+    let (year, month, day) = civil_date(epoch_secs);
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let secs = epoch_secs.rem_euclid(86_400);
+    let time = ((secs / 3600) << 11) | (((secs % 3600) / 60) << 5) | ((secs % 60) / 2);
+    let date = ((year.min(2107) - 1980) << 9) as u32 | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+/// An object whose bytes are being streamed into the archive.
+struct ZipMember {
+    name: String,
+    reader: ReaderStream<tokio::io::Take<File>>,
+    crc: crc32fast::Hasher,
+    /// Size read from the file when it was opened; the header promised this much.
+    size: u64,
+    written: u64,
+    header_offset: u64,
+    modified: (u16, u16),
+}
+
+impl ZipMember {
+    fn zip64(&self) -> bool {
+        self.size >= ZIP32_MAX || self.header_offset >= ZIP32_MAX
+    }
+
+    fn local_header(&self) -> Bytes {
+        let zip64 = self.zip64();
+        let mut out = Vec::with_capacity(30 + self.name.len() + 20);
+        out.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        out.extend_from_slice(&(if zip64 { 45u16 } else { 20 }).to_le_bytes());
+        out.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&self.modified.0.to_le_bytes());
+        out.extend_from_slice(&self.modified.1.to_le_bytes());
+        // CRC and sizes follow in the data descriptor
+        out.extend_from_slice(&[0u8; 4]);
+        let size = if zip64 { ZIP32_MAX } else { 0 } as u32;
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&(self.name.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(if zip64 { 20u16 } else { 0 }).to_le_bytes());
+        out.extend_from_slice(self.name.as_bytes());
+        if zip64 {
+            out.extend_from_slice(&1u16.to_le_bytes());
+            out.extend_from_slice(&16u16.to_le_bytes());
+            out.extend_from_slice(&[0u8; 16]);
+        }
+        Bytes::from(out)
+    }
+
+    fn data_descriptor(&self, crc: u32) -> Bytes {
+        let mut out = Vec::with_capacity(24);
+        out.extend_from_slice(&0x0807_4b50u32.to_le_bytes());
+        out.extend_from_slice(&crc.to_le_bytes());
+        if self.zip64() {
+            out.extend_from_slice(&self.size.to_le_bytes());
+            out.extend_from_slice(&self.size.to_le_bytes());
+        } else {
+            out.extend_from_slice(&(self.size as u32).to_le_bytes());
+            out.extend_from_slice(&(self.size as u32).to_le_bytes());
+        }
+        Bytes::from(out)
+    }
+
+    /// This member's central directory record.
+    fn central_record(&self, crc: u32, out: &mut Vec<u8>) {
+        let big_size = self.size >= ZIP32_MAX;
+        let big_offset = self.header_offset >= ZIP32_MAX;
+        let mut extra = Vec::new();
+        if big_size {
+            extra.extend_from_slice(&self.size.to_le_bytes());
+            extra.extend_from_slice(&self.size.to_le_bytes());
+        }
+        if big_offset {
+            extra.extend_from_slice(&self.header_offset.to_le_bytes());
+        }
+        let version: u16 = if self.zip64() { 45 } else { 20 };
+        out.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        // made by Unix, so readers honour the permission bits below
+        out.extend_from_slice(&((3 << 8) | version).to_le_bytes());
+        out.extend_from_slice(&version.to_le_bytes());
+        out.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&self.modified.0.to_le_bytes());
+        out.extend_from_slice(&self.modified.1.to_le_bytes());
+        out.extend_from_slice(&crc.to_le_bytes());
+        let size = self.size.min(ZIP32_MAX) as u32;
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&(self.name.len() as u16).to_le_bytes());
+        let extra_len = if extra.is_empty() { 0 } else { 4 + extra.len() };
+        out.extend_from_slice(&(extra_len as u16).to_le_bytes());
+        // comment length, disk number, internal attributes
+        out.extend_from_slice(&[0u8; 6]);
+        out.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+        out.extend_from_slice(&(self.header_offset.min(ZIP32_MAX) as u32).to_le_bytes());
+        out.extend_from_slice(self.name.as_bytes());
+        if !extra.is_empty() {
+            out.extend_from_slice(&1u16.to_le_bytes());
+            out.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            out.extend_from_slice(&extra);
+        }
+    }
+}
+
+/// State of a zip being streamed: the member in progress and the central directory
+/// records of those already written, which make up the archive's tail.
+struct ZipWriter {
+    entries: std::vec::IntoIter<ExportEntry>,
+    current: Option<ZipMember>,
+    offset: u64,
+    central: Vec<u8>,
+    count: u64,
+    finished: bool,
+}
+
+impl ZipWriter {
+    async fn next_chunk(&mut self) -> Option<io::Result<Bytes>> {
+        loop {
+            if let Some(member) = &mut self.current {
+                match member.reader.next().await {
+                    Some(Ok(chunk)) => {
+                        member.crc.update(&chunk);
+                        member.written += chunk.len() as u64;
+                        self.offset += chunk.len() as u64;
+                        return Some(Ok(chunk));
+                    }
+                    Some(Err(e)) => {
+                        self.finished = true;
+                        return Some(Err(e));
+                    }
+                    None => {
+                        let member = self.current.take().expect("member in progress");
+                        if member.written != member.size {
+                            self.finished = true;
+                            return Some(Err(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                format!("{} shrank while being archived", member.name),
+                            )));
+                        }
+                        let crc = member.crc.clone().finalize();
+                        member.central_record(crc, &mut self.central);
+                        self.count += 1;
+                        let descriptor = member.data_descriptor(crc);
+                        self.offset += descriptor.len() as u64;
+                        return Some(Ok(descriptor));
+                    }
+                }
+            }
+            if self.finished {
+                return None;
+            }
+            let Some(entry) = self.entries.next() else {
+                self.finished = true;
+                return Some(Ok(self.end_of_archive()));
+            };
+            let file = match File::open(&entry.path).await {
+                Ok(f) => f,
+                Err(e) => {
+                    warn!("zip: skipping {} ({}): {}", entry.name, entry.key, e);
+                    continue;
+                }
+            };
+            // size from the file itself so the records always match the bytes we send
+            let size = match file.metadata().await {
+                Ok(m) => m.len(),
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+            };
+            let member = ZipMember {
+                reader: ReaderStream::new(file.take(size)),
+                crc: crc32fast::Hasher::new(),
+                size,
+                written: 0,
+                header_offset: self.offset,
+                modified: dos_datetime(entry.mtime),
+                name: entry.name,
+            };
+            let header = member.local_header();
+            self.offset += header.len() as u64;
+            self.current = Some(member);
+            return Some(Ok(header));
+        }
+    }
+
+    /// Central directory and end records, with zip64 ones when the counts or offsets
+    /// don't fit the classic fields.
+    fn end_of_archive(&mut self) -> Bytes {
+        let mut out = std::mem::take(&mut self.central);
+        let cd_size = out.len() as u64;
+        let cd_offset = self.offset;
+        if self.count >= 0xFFFF || cd_size >= ZIP32_MAX || cd_offset >= ZIP32_MAX {
+            let record_offset = cd_offset + cd_size;
+            out.extend_from_slice(&0x0606_4b50u32.to_le_bytes());
+            out.extend_from_slice(&44u64.to_le_bytes());
+            out.extend_from_slice(&((3u16 << 8) | 45).to_le_bytes());
+            out.extend_from_slice(&45u16.to_le_bytes());
+            out.extend_from_slice(&[0u8; 8]);
+            out.extend_from_slice(&self.count.to_le_bytes());
+            out.extend_from_slice(&self.count.to_le_bytes());
+            out.extend_from_slice(&cd_size.to_le_bytes());
+            out.extend_from_slice(&cd_offset.to_le_bytes());
+            out.extend_from_slice(&0x0706_4b50u32.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&record_offset.to_le_bytes());
+            out.extend_from_slice(&1u32.to_le_bytes());
+        }
+        let count = self.count.min(0xFFFF) as u16;
+        out.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        out.extend_from_slice(&[0u8; 4]);
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&(cd_size.min(ZIP32_MAX) as u32).to_le_bytes());
+        out.extend_from_slice(&(cd_offset.min(ZIP32_MAX) as u32).to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        Bytes::from(out)
+    }
+}
+
+/// Lazily stream a zip archive of `entries`, stored uncompressed. Like `tar_stream`,
+/// objects are opened one at a time and copied in chunks; only the central directory
+/// (a small record per object) is held until the end. Objects that can't be opened are
+/// skipped with a warning.
+pub fn zip_stream(
+    entries: Vec<ExportEntry>,
+) -> impl futures_util::Stream<Item = io::Result<Bytes>> {
+    let writer = ZipWriter {
+        entries: entries.into_iter(),
+        current: None,
+        offset: 0,
+        central: Vec::new(),
+        count: 0,
+        finished: false,
+    };
+    stream::unfold(writer, |mut writer| async move {
+        let chunk = writer.next_chunk().await?;
+        Some((chunk, writer))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Layout, Storage, StorageImpl, layout_path};
+    use crate::test_support::temp_dir;
+    use std::io::Read;
+
+    fn meta(key: &str, filename: &str) -> FileMeta {
+        FileMeta {
+            id: 0,
+            key: key.into(),
+            filename: filename.into(),
+            content_type: None,
+            size: 0,
+            path: String::new(),
+            created_at: 1_700_000_000,
+            deleted: 0,
+            device_uuid: Some("dev".into()),
+            sha256: None,
+            retain_until: None,
+            tenant: None,
+            expires_at: None,
+            filename_supplied: 1,
+            compressed: 0,
+            uncompressed_size: None,
+        }
+    }
+
+    fn names(files: Vec<FileMeta>, duplicates: DuplicateNames) -> Vec<String> {
+        entries(files, duplicates)
+            .into_iter()
+            .map(|e| e.name)
+            .collect()
+    }
+
+    #[test]
+    fn duplicate_names_are_numbered() {
+        let files = || {
+            vec![
+                meta("k1", "a.jpg"),
+                meta("k2", "a.jpg"),
+                meta("k3", "a.jpg"),
+                meta("k4", "dir/b"),
+                FileMeta {
+                    compressed: 1,
+                    ..meta("k5", "a.jpg")
+                },
+            ]
+        };
+        let expected = vec!["a.jpg", "a (2).jpg", "a (3).jpg", "dir_b", "a.jpg.gz"];
+        assert_eq!(names(files(), DuplicateNames::Numbered), expected);
+        // same listing, same names
+        assert_eq!(names(files(), DuplicateNames::Numbered), expected);
+    }
+
+    #[test]
+    fn duplicate_names_can_be_prefixed_with_their_keys() {
+        let files = vec![
+            meta("k1", "a.jpg"),
+            meta("k2", "a.jpg"),
+            meta("k3", "a.jpg"),
+            meta("k4", "b.jpg"),
+            // already named like a prefixed duplicate
+            meta("k5", "k1_a.jpg"),
+        ];
+        assert_eq!(
+            names(files, DuplicateNames::Key),
+            vec!["k1_a.jpg", "k2_a.jpg", "k3_a.jpg", "b.jpg", "k1_a (2).jpg"]
+        );
+    }
+
+    #[tokio::test]
+    async fn tar_contains_all_entries() {
+        let storage = StorageImpl::new(temp_dir("export"));
+        let mut a: &[u8] = b"first file";
+        let b = vec![7u8; 1500];
+        let (path_a, _) = storage.write_stream("dev", "k1", &mut a).await.unwrap();
+        // stored under the dated layout, so only the row's path finds it
+        let path_b =
+            layout_path(&storage, Layout::Dated, None, "dev", "k2", 1_700_000_000).unwrap();
+        std::fs::create_dir_all(path_b.parent().unwrap()).unwrap();
+        std::fs::write(&path_b, &b).unwrap();
+
+        let long_name = format!("{}.bin", "x".repeat(120));
+        let row = |key: &str, filename: &str, path: &std::path::Path| FileMeta {
+            path: path.to_string_lossy().into_owned(),
+            ..meta(key, filename)
+        };
+        let list = entries(
+            vec![row("k1", "a.txt", &path_a), row("k2", &long_name, &path_b)],
+            DuplicateNames::Numbered,
+        );
+        let chunks: Vec<Bytes> = tar_stream(list).map(|c| c.unwrap()).collect().await;
+        let archive: Vec<u8> = chunks.concat();
+
+        let mut ar = tar::Archive::new(archive.as_slice());
+        let mut got = Vec::new();
+        for entry in ar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().to_string();
+            let mut body = Vec::new();
+            entry.read_to_end(&mut body).unwrap();
+            got.push((name, body));
+        }
+        assert_eq!(got.len(), 2);
+        assert_eq!(got[0], ("a.txt".to_string(), b"first file".to_vec()));
+        assert_eq!(got[1].0, long_name);
+        assert_eq!(got[1].1, b);
+    }
+
+    #[tokio::test]
+    async fn zip_contains_all_entries_and_skips_missing_files() {
+        let storage = StorageImpl::new(temp_dir("export-zip"));
+        let mut a: &[u8] = b"first file";
+        let mut b: &[u8] = &[9u8; 70_000];
+        let (path_a, _) = storage.write_stream("dev", "k1", &mut a).await.unwrap();
+        let (path_b, _) = storage.write_stream("dev", "k2", &mut b).await.unwrap();
+        let row = |key: &str, filename: &str, path: &std::path::Path| FileMeta {
+            path: path.to_string_lossy().into_owned(),
+            ..meta(key, filename)
+        };
+        let list = entries(
+            vec![
+                row("k1", "a.txt", &path_a),
+                row("k3", "gone.txt", &path_a.with_file_name("gone")),
+                row("k2", "a.txt", &path_b),
+            ],
+            DuplicateNames::Numbered,
+        );
+        let chunks: Vec<Bytes> = zip_stream(list).map(|c| c.unwrap()).collect().await;
+        let archive: Vec<u8> = chunks.concat();
+
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(archive)).unwrap();
+        assert_eq!(zip.len(), 2);
+        let mut got = Vec::new();
+        for i in 0..zip.len() {
+            let mut entry = zip.by_index(i).unwrap();
+            let mut body = Vec::new();
+            entry.read_to_end(&mut body).unwrap();
+            got.push((entry.name().to_string(), body));
+        }
+        assert_eq!(got[0], ("a.txt".to_string(), b"first file".to_vec()));
+        assert_eq!(got[1], ("a (2).txt".to_string(), vec![9u8; 70_000]));
+    }
+
+    #[test]
+    fn dos_datetime_is_utc() {
+        // 2023-11-14 22:13:20 UTC
+        let (time, date) = dos_datetime(1_700_000_000);
+        assert_eq!(time, (22 << 11) | (13 << 5) | 10);
+        assert_eq!(date, ((2023 - 1980) << 9) | (11 << 5) | 14);
+    }
+}
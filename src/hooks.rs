@@ -0,0 +1,46 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// What the server knows about an upload once its bytes are in the temp file.
+#[derive(Debug, Clone)]
+pub struct UploadMeta {
+    pub key: String,
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub device_uuid: String,
+    pub size: i64,
+    /// Hex SHA-256 of the bytes as received.
+    pub sha256: String,
+}
+
+/// Outcome of a `PostUploadHook`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookDecision {
+    /// Publish the temp file as is.
+    Accept,
+    /// Discard the upload; the reason is returned to the client with a 422.
+    Reject(String),
+    /// Publish the file at this path instead of the received bytes (e.g. a transcode).
+    /// The file is moved into place, so it should live on the same filesystem as the temp.
+    Replace(PathBuf),
+}
+
+/// Validation/transformation step run between the temp write and the final publish
+/// (virus scan, image transcode, ...).
+#[async_trait]
+pub trait PostUploadHook: Send + Sync {
+    async fn process(&self, temp_path: &Path, meta: &UploadMeta) -> Result<HookDecision>;
+}
+
+/// Default hook: accepts every upload unchanged.
+#[derive(Debug, Clone, Default)]
+pub struct NoopHook;
+
+#[async_trait]
+impl PostUploadHook for NoopHook {
+    async fn process(&self, _temp_path: &Path, _meta: &UploadMeta) -> Result<HookDecision> {
+        Ok(HookDecision::Accept)
+    }
+}
@@ -0,0 +1,302 @@
+//! Streaming reader for tar uploads, the inverse of `export`: members are parsed as
+//! they arrive so an archive of any size can be imported with bounded memory.
+
+use std::{
+    io,
+    path::{Component, Path},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf, Take};
+
+const BLOCK: u64 = 512;
+/// Longest GNU long-name or pax header accepted; anything bigger is not a sane archive.
+const MAX_META_LEN: u64 = 64 * 1024;
+
+/// Header of one archive member, as read by `TarEntries::next_entry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarEntry {
+    /// Path as stored, after GNU long-name and pax `path` records are applied.
+    pub path: String,
+    pub size: u64,
+    /// Plain file; directories, links and devices are reported but carry no object.
+    pub regular: bool,
+}
+
+/// Reads a tar archive member by member from an async stream, without buffering entry
+/// bodies: each entry's data is read through `data()` (or skipped by the next call to
+/// `next_entry`), so memory stays bounded whatever the archive size.
+pub struct TarEntries<R> {
+    reader: R,
+    /// Unread bytes of the current entry's data.
+    remaining: u64,
+    /// Zero fill after the current entry's data, up to the next block boundary.
+    padding: u64,
+    done: bool,
+}
+
+fn padding_for(size: u64) -> u64 {
+    (BLOCK - size % BLOCK) % BLOCK
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Whether a header block's checksum field matches its contents.
+fn checksum_ok(block: &[u8; BLOCK as usize], header: &tar::Header) -> bool {
+    let sum: u64 = block
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            if (148..156).contains(&i) {
+                32
+            } else {
+                *b as u64
+            }
+        })
+        .sum();
+    header.cksum().is_ok_and(|c| c as u64 == sum)
+}
+
+/// `path` from a pax extended header (`"<len> <key>=<value>\n"` records), if present.
+fn pax_path(data: &[u8]) -> Option<String> {
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|b| *b == b' ')?;
+        let len: usize = std::str::from_utf8(&rest[..space]).ok()?.parse().ok()?;
+        let record = rest.get(space + 1..len)?;
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            return Some(String::from_utf8_lossy(value).into_owned());
+        }
+        rest = &rest[len..];
+    }
+    None
+}
+
+impl<R: AsyncRead + Unpin> TarEntries<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            remaining: 0,
+            padding: 0,
+            done: false,
+        }
+    }
+
+    async fn skip(&mut self, n: u64) -> io::Result<()> {
+        let skipped =
+            tokio::io::copy(&mut (&mut self.reader).take(n), &mut tokio::io::sink()).await?;
+        if skipped < n {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    /// Body of a metadata member (long name, pax header), read whole.
+    async fn read_meta(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        if size > MAX_META_LEN {
+            return Err(invalid(format!("{size}-byte tar metadata record")));
+        }
+        let mut data = vec![0u8; size as usize];
+        self.reader.read_exact(&mut data).await?;
+        self.skip(padding_for(size)).await?;
+        Ok(data)
+    }
+
+    /// Advance to the next member, skipping whatever of the current one wasn't read.
+    /// Returns None at the end-of-archive marker (or a clean end of stream).
+    pub async fn next_entry(&mut self) -> io::Result<Option<TarEntry>> {
+        if self.done {
+            return Ok(None);
+        }
+        self.skip(self.remaining + self.padding).await?;
+        self.remaining = 0;
+        self.padding = 0;
+        let mut long_name: Option<String> = None;
+        loop {
+            let mut block = [0u8; BLOCK as usize];
+            let mut filled = 0;
+            while filled < block.len() {
+                let n = self.reader.read(&mut block[filled..]).await?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            if filled == 0 || block.iter().all(|b| *b == 0) {
+                self.done = true;
+                return Ok(None);
+            }
+            if filled < block.len() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let header = tar::Header::from_byte_slice(&block);
+            if !checksum_ok(&block, header) {
+                return Err(invalid("bad tar header checksum"));
+            }
+            let size = header
+                .entry_size()
+                .map_err(|e| invalid(format!("bad tar entry size: {e}")))?;
+            match header.entry_type() {
+                tar::EntryType::GNULongName => {
+                    let data = self.read_meta(size).await?;
+                    let name = data.split(|b| *b == 0).next().unwrap_or_default();
+                    long_name = Some(String::from_utf8_lossy(name).into_owned());
+                }
+                tar::EntryType::XHeader => {
+                    let data = self.read_meta(size).await?;
+                    if let Some(path) = pax_path(&data) {
+                        long_name = Some(path);
+                    }
+                }
+                tar::EntryType::XGlobalHeader => {
+                    self.skip(size + padding_for(size)).await?;
+                }
+                kind => {
+                    let path = long_name.unwrap_or_else(|| {
+                        String::from_utf8_lossy(&header.path_bytes()).into_owned()
+                    });
+                    // links, directories and device nodes carry no data whatever size
+                    // they claim; anything else (sparse, vendor types) is skipped over
+                    let size = match kind {
+                        tar::EntryType::Link
+                        | tar::EntryType::Symlink
+                        | tar::EntryType::Directory
+                        | tar::EntryType::Char
+                        | tar::EntryType::Block
+                        | tar::EntryType::Fifo => 0,
+                        _ => size,
+                    };
+                    let regular = kind.is_file();
+                    self.remaining = size;
+                    self.padding = padding_for(size);
+                    return Ok(Some(TarEntry {
+                        path,
+                        size,
+                        regular,
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Reader over the current entry's data; ends after `size` bytes.
+    pub fn data(&mut self) -> EntryData<'_, R> {
+        EntryData {
+            inner: (&mut self.reader).take(self.remaining),
+            remaining: &mut self.remaining,
+        }
+    }
+}
+
+/// Data of the entry last returned by `TarEntries::next_entry`. A stream that ends
+/// before the entry does is an error rather than a short read.
+pub struct EntryData<'a, R> {
+    inner: Take<&'a mut R>,
+    remaining: &'a mut u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for EntryData<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let read = buf.filled().len() - before;
+            if read == 0 && this.inner.limit() > 0 && buf.remaining() > 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            *this.remaining = this.inner.limit();
+        }
+        res
+    }
+}
+
+/// Object filename for an archive member: its last path component. None for paths that
+/// are absolute or climb out with `..`, which an extractor must never honour.
+pub fn safe_filename(path: &str) -> Option<&str> {
+    let p = Path::new(path);
+    if p.components().any(|c| {
+        matches!(
+            c,
+            Component::RootDir | Component::Prefix(_) | Component::ParentDir
+        )
+    }) {
+        return None;
+    }
+    p.file_name()?.to_str().filter(|n| !n.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        let mut out = header.as_bytes().to_vec();
+        out.extend_from_slice(data);
+        out.resize(out.len() + padding_for(data.len() as u64) as usize, 0);
+        out
+    }
+
+    #[tokio::test]
+    async fn entries_stream_with_long_names_and_skipped_bodies() {
+        let long_name = format!("dir/{}.bin", "y".repeat(150));
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        builder
+            .append_data(&mut header, &long_name, &b"abc"[..])
+            .unwrap();
+        let mut archive = builder.into_inner().unwrap();
+        archive.truncate(archive.len() - 2 * BLOCK as usize);
+        archive.extend(member("skipped.bin", &[1u8; 700]));
+        archive.extend(member("../escape", b"no"));
+        archive.extend(member("last.txt", b"tail"));
+        archive.extend(vec![0u8; 2 * BLOCK as usize]);
+
+        let mut entries = TarEntries::new(archive.as_slice());
+        let first = entries.next_entry().await.unwrap().unwrap();
+        assert_eq!((first.path.as_str(), first.size), (long_name.as_str(), 3));
+        let mut body = Vec::new();
+        entries.data().read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"abc");
+
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.path);
+        }
+        assert_eq!(names, vec!["skipped.bin", "../escape", "last.txt"]);
+        assert!(entries.next_entry().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn corrupt_header_is_an_error() {
+        let mut archive = member("a.txt", b"x");
+        archive[0] ^= 0x55;
+        let mut entries = TarEntries::new(archive.as_slice());
+        assert!(entries.next_entry().await.is_err());
+    }
+
+    #[test]
+    fn unsafe_paths_have_no_filename() {
+        assert_eq!(safe_filename("photos/a.jpg"), Some("a.jpg"));
+        assert_eq!(safe_filename("./b.txt"), Some("b.txt"));
+        assert_eq!(safe_filename("/etc/passwd"), None);
+        assert_eq!(safe_filename("x/../../y"), None);
+        assert_eq!(safe_filename("dir/"), Some("dir"));
+        assert_eq!(safe_filename(""), None);
+    }
+}
@@ -0,0 +1,164 @@
+//! Rolling per-device count of I/O errors hit while reading or writing objects, so a drive
+//! failing quietly shows up before every request against it does.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Errors counted against one device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct IoErrorCounts {
+    /// Since the server started.
+    pub total: u64,
+    /// Within the rolling window.
+    pub recent: u32,
+}
+
+#[derive(Debug, Default)]
+struct DeviceErrors {
+    total: u64,
+    /// When each error inside the window happened, oldest first.
+    recent: VecDeque<Instant>,
+}
+
+impl DeviceErrors {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= window)
+        {
+            self.recent.pop_front();
+        }
+    }
+
+    fn counts(&self) -> IoErrorCounts {
+        IoErrorCounts {
+            total: self.total,
+            recent: self.recent.len() as u32,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct IoErrorTracker {
+    window: Duration,
+    /// Errors within `window` that make a device suspect; never when unset.
+    threshold: Option<u32>,
+    devices: Mutex<HashMap<String, DeviceErrors>>,
+}
+
+impl IoErrorTracker {
+    pub fn new(window: Duration, threshold: Option<u32>) -> Self {
+        Self {
+            window,
+            threshold: threshold.filter(|&n| n > 0),
+            devices: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count one error on `uuid`. True when this error brings the device's recent errors up
+    /// to the threshold, once per crossing: the errors have to age out of the window before
+    /// the device can trip again.
+    pub fn record(&self, uuid: &str) -> bool {
+        self.record_at(uuid, Instant::now())
+    }
+
+    fn record_at(&self, uuid: &str, now: Instant) -> bool {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.entry(uuid.to_string()).or_default();
+        device.expire(now, self.window);
+        device.total += 1;
+        device.recent.push_back(now);
+        self.threshold
+            .is_some_and(|n| device.recent.len() == n as usize)
+    }
+
+    /// Devices whose errors within the window are at or past the threshold, sorted; none
+    /// without a threshold.
+    pub fn failing(&self) -> Vec<String> {
+        self.failing_at(Instant::now())
+    }
+
+    fn failing_at(&self, now: Instant) -> Vec<String> {
+        let Some(threshold) = self.threshold else {
+            return Vec::new();
+        };
+        let mut failing: Vec<String> = self
+            .counts_at(now)
+            .into_iter()
+            .filter(|(_, counts)| counts.recent >= threshold)
+            .map(|(uuid, _)| uuid)
+            .collect();
+        failing.sort();
+        failing
+    }
+
+    /// Counts of every device that has had an error since startup.
+    pub fn counts(&self) -> HashMap<String, IoErrorCounts> {
+        self.counts_at(Instant::now())
+    }
+
+    fn counts_at(&self, now: Instant) -> HashMap<String, IoErrorCounts> {
+        let mut devices = self.devices.lock().unwrap();
+        devices
+            .iter_mut()
+            .map(|(uuid, device)| {
+                device.expire(now, self.window);
+                (uuid.clone(), device.counts())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_trips_once_per_window() {
+        let tracker = IoErrorTracker::new(Duration::from_secs(60), Some(3));
+        let t0 = Instant::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+        assert!(!tracker.record_at("u1", at(0)));
+        assert!(!tracker.record_at("u1", at(10)));
+        assert!(!tracker.record_at("u2", at(10)));
+        assert!(tracker.record_at("u1", at(20)));
+        assert!(!tracker.record_at("u1", at(30)));
+
+        // the first two have aged out by now
+        let counts = tracker.counts_at(at(75));
+        assert_eq!(
+            counts["u1"],
+            IoErrorCounts {
+                total: 4,
+                recent: 2
+            }
+        );
+        assert_eq!(
+            counts["u2"],
+            IoErrorCounts {
+                total: 1,
+                recent: 0
+            }
+        );
+        assert!(tracker.failing_at(at(75)).is_empty());
+        assert!(!tracker.record_at("u1", at(85)));
+        assert!(tracker.record_at("u1", at(86)));
+        assert_eq!(tracker.failing_at(at(86)), vec!["u1"]);
+    }
+
+    #[test]
+    fn without_a_threshold_errors_are_only_counted() {
+        let tracker = IoErrorTracker::new(Duration::from_secs(60), None);
+        for _ in 0..10 {
+            assert!(!tracker.record("u1"));
+        }
+        assert_eq!(tracker.counts()["u1"].total, 10);
+        assert!(tracker.failing().is_empty());
+    }
+}
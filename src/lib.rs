@@ -1,8 +1,27 @@
+pub mod compression;
+pub mod config;
 pub mod db;
+pub mod diagnostics;
 pub mod entity;
+pub mod export;
+pub mod hooks;
+pub mod import;
+pub mod io_errors;
 pub mod logging;
+pub mod maintenance;
 pub mod mounter;
+pub mod progress;
 pub mod repo;
 pub mod schema;
+pub mod selector;
 pub mod server;
+pub mod service;
+pub mod signing;
+pub mod sniff;
 pub mod storage;
+pub mod system;
+#[cfg(test)]
+mod test_support;
+pub mod throttle;
+
+pub use maintenance::migrate_layout;
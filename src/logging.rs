@@ -1,8 +1,65 @@
-use std::sync::OnceLock;
+use std::{
+    cell::RefCell,
+    future::Future,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use log::warn;
 
 static INIT: OnceLock<()> = OnceLock::new();
 
-/// Initialize global logging (idempotent). Includes timestamp, level, file and line.
+tokio::task_local! {
+    static TASK_REQUEST_ID: String;
+}
+
+thread_local! {
+    static THREAD_REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Id of the request being served by the current task or blocking thread, if any.
+pub fn request_id() -> Option<String> {
+    TASK_REQUEST_ID
+        .try_with(|id| id.clone())
+        .ok()
+        .or_else(|| THREAD_REQUEST_ID.with(|id| id.borrow().clone()))
+}
+
+/// Run `fut` with `id` attached to every log line it emits.
+pub async fn scope_request_id<F: Future>(id: String, fut: F) -> F::Output {
+    TASK_REQUEST_ID.scope(id, fut).await
+}
+
+/// Run blocking `f` with `id` attached to its log lines; for work moved off the task,
+/// where the task-local is not visible.
+pub fn with_request_id<R>(id: Option<String>, f: impl FnOnce() -> R) -> R {
+    let prev = THREAD_REQUEST_ID.with(|cell| cell.replace(id));
+    let out = f();
+    THREAD_REQUEST_ID.with(|cell| *cell.borrow_mut() = prev);
+    out
+}
+
+/// Run blocking `f`, warning when it takes `slow_after` or longer (None = never). `what`
+/// names the call in the warning.
+pub fn warn_if_slow<R>(slow_after: Option<Duration>, what: &str, f: impl FnOnce() -> R) -> R {
+    let started = Instant::now();
+    let out = f();
+    let elapsed = started.elapsed();
+    if slow_after.is_some_and(|t| elapsed >= t) {
+        warn!("slow db call took {} ms: {}", elapsed.as_millis(), what);
+    }
+    out
+}
+
+/// `" [req=<id>]"` inside a request, empty otherwise.
+pub fn request_tag() -> String {
+    request_id()
+        .map(|id| format!(" [req={id}]"))
+        .unwrap_or_default()
+}
+
+/// Initialize global logging (idempotent). Includes timestamp, level, file and line, and
+/// the request id when logging on behalf of an API request.
 pub fn init_logging() {
     INIT.get_or_init(|| {
         let _ = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
@@ -16,8 +73,9 @@ pub fn init_logging() {
                     .unwrap_or_else(|| "?".into());
                 writeln!(
                     buf,
-                    "{ts} [{:<5}] {}:{} {}",
+                    "{ts} [{:<5}]{} {}:{} {}",
                     record.level(),
+                    request_tag(),
                     file,
                     line,
                     record.args()
@@ -0,0 +1,357 @@
+//! Offline consistency checks between the metadata database and the stored objects.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::fs;
+
+use crate::entity::file_meta::FileMeta;
+use crate::repo::file_repo::FileRepo;
+use crate::service::blocking;
+use crate::storage::{Layout, Storage, layout_path};
+
+/// Rows fetched per repo round trip while walking the files table.
+const SCAN_BATCH: i64 = 500;
+
+/// An object whose bytes on disk don't match its recorded size.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SizeMismatch {
+    pub key: String,
+    pub device_uuid: Option<String>,
+    pub path: PathBuf,
+    pub recorded: i64,
+    /// Size on disk; None when the object is missing altogether.
+    pub actual: Option<i64>,
+}
+
+/// Where the object's bytes live. `files.path` is authoritative: objects may have been
+/// moved to another layout by `migrate_layout`.
+fn object_path(meta: &FileMeta) -> PathBuf {
+    PathBuf::from(&meta.path)
+}
+
+/// Stat every live object and report those whose size differs from `files.size`
+/// (truncated, overwritten or missing). Errors other than NotFound abort the scan.
+pub async fn size_check(
+    file_repo: Arc<dyn FileRepo>,
+    slow_after: Option<Duration>,
+) -> Result<Vec<SizeMismatch>> {
+    let mut out = Vec::new();
+    let mut after_id = 0;
+    loop {
+        let repo = file_repo.clone();
+        let page = blocking(slow_after, move || {
+            repo.list_live_page(after_id, SCAN_BATCH)
+        })
+        .await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after_id = last.id;
+        for meta in page {
+            let path = object_path(&meta);
+            let actual = match fs::metadata(&path).await {
+                Ok(m) => Some(m.len() as i64),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(anyhow::Error::new(e).context(format!("stat {:?}", path))),
+            };
+            if actual != Some(meta.size) {
+                out.push(SizeMismatch {
+                    key: meta.key,
+                    device_uuid: meta.device_uuid,
+                    path,
+                    recorded: meta.size,
+                    actual,
+                });
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Remove the bytes and soft-delete the rows of objects whose TTL ran out by `now`.
+/// Objects still under a write-once retention window are left alone. Returns how many
+/// objects were expired.
+pub async fn sweep_expired(
+    file_repo: Arc<dyn FileRepo>,
+    now: i64,
+    slow_after: Option<Duration>,
+) -> Result<usize> {
+    let repo = file_repo.clone();
+    let expired = blocking(slow_after, move || repo.list_expired(now)).await?;
+    let mut count = 0;
+    for meta in expired {
+        if meta.is_retained(now) {
+            continue;
+        }
+        let path = object_path(&meta);
+        if let Err(e) = fs::remove_file(&path).await
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            warn!("expire {}: remove {:?} error: {}", meta.key, path, e);
+            continue;
+        }
+        let repo = file_repo.clone();
+        let key = meta.key.clone();
+        blocking(slow_after, move || repo.soft_delete(&key)).await?;
+        info!("expired {}", meta.key);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Outcome of a `migrate_layout` run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LayoutMigration {
+    /// Objects relocated (or whose row caught up with an earlier, interrupted move).
+    pub moved: usize,
+    /// Objects already in the target layout.
+    pub skipped: usize,
+    /// Rows whose bytes were found in neither layout, or without a device.
+    pub missing: usize,
+}
+
+async fn path_exists(path: &Path) -> Result<bool> {
+    match fs::metadata(path).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(anyhow::Error::new(e).context(format!("stat {:?}", path))),
+    }
+}
+
+/// Relocate every live object from layout `from` to `to`, updating `files.path` after
+/// each move. Safe to rerun: objects already in place are skipped, and an object moved
+/// before an interruption has its row updated on the next run.
+pub async fn migrate_layout(
+    file_repo: Arc<dyn FileRepo>,
+    storage: &dyn Storage,
+    from: Layout,
+    to: Layout,
+    slow_after: Option<Duration>,
+) -> Result<LayoutMigration> {
+    let mut report = LayoutMigration::default();
+    let mut after_id = 0;
+    loop {
+        let repo = file_repo.clone();
+        let page = blocking(slow_after, move || {
+            repo.list_live_page(after_id, SCAN_BATCH)
+        })
+        .await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after_id = last.id;
+        for meta in page {
+            let Some(device) = meta.device_uuid.as_deref() else {
+                report.missing += 1;
+                continue;
+            };
+            let tenant = meta.tenant.as_deref();
+            let src = layout_path(storage, from, tenant, device, &meta.key, meta.created_at)?;
+            let dst = layout_path(storage, to, tenant, device, &meta.key, meta.created_at)?;
+            let recorded = Path::new(&meta.path) == dst;
+            if src != dst && path_exists(&src).await? {
+                if let Some(parent) = dst.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                fs::rename(&src, &dst)
+                    .await
+                    .map_err(|e| anyhow::Error::new(e).context(format!("move {:?}", src)))?;
+            } else if !path_exists(&dst).await? {
+                warn!("migrate {}: no object at {:?} or {:?}", meta.key, src, dst);
+                report.missing += 1;
+                continue;
+            } else if recorded {
+                report.skipped += 1;
+                continue;
+            }
+            let repo = file_repo.clone();
+            let key = meta.key.clone();
+            let path = dst.to_string_lossy().into_owned();
+            blocking(slow_after, move || repo.set_path(&key, &path)).await?;
+            info!("migrated {} to {:?}", meta.key, dst);
+            report.moved += 1;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::file_meta::NewFileMeta;
+    use crate::repo::file_repo::new_file_repo;
+    use crate::storage::StorageImpl;
+    use crate::test_support::{temp_dir, temp_pool};
+
+    #[tokio::test]
+    async fn truncated_and_missing_objects_are_reported() {
+        let storage = StorageImpl::new(temp_dir("maint"));
+        let repo: Arc<dyn FileRepo> = Arc::new(new_file_repo(temp_pool()));
+        for key in ["intact", "truncated", "missing"] {
+            let (path, size) = storage
+                .write_stream("dev", key, &mut &b"0123456789"[..])
+                .await
+                .unwrap();
+            repo.insert_file(&NewFileMeta {
+                key,
+                filename: "f.bin",
+                content_type: None,
+                size,
+                path: path.to_string_lossy().as_ref(),
+                created_at: 0,
+                deleted: 0,
+                device_uuid: Some("dev"),
+                sha256: None,
+                retain_until: None,
+                tenant: None,
+                expires_at: None,
+                filename_supplied: 1,
+                compressed: 0,
+                uncompressed_size: None,
+            })
+            .unwrap();
+        }
+        let truncated = storage.resolve_path("dev", "truncated").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&truncated)
+            .unwrap()
+            .set_len(4)
+            .unwrap();
+        storage.delete("dev", "missing").await.unwrap();
+
+        let found = size_check(repo, None).await.unwrap();
+        let summary: Vec<(&str, Option<i64>)> =
+            found.iter().map(|m| (m.key.as_str(), m.actual)).collect();
+        assert_eq!(summary, vec![("truncated", Some(4)), ("missing", None)]);
+        assert_eq!(found[0].recorded, 10);
+        assert_eq!(found[0].path, truncated);
+    }
+
+    #[tokio::test]
+    async fn sweep_removes_only_expired_objects() {
+        let storage = StorageImpl::new(temp_dir("maint"));
+        let repo: Arc<dyn FileRepo> = Arc::new(new_file_repo(temp_pool()));
+        let now = 1_000;
+        for (key, expires_at, retain_until) in [
+            ("old", Some(now - 1), None),
+            ("fresh", Some(now + 60), None),
+            ("forever", None, None),
+            ("retained", Some(now - 1), Some(now + 60)),
+        ] {
+            let (path, size) = storage
+                .write_stream("dev", key, &mut &b"x"[..])
+                .await
+                .unwrap();
+            repo.insert_file(&NewFileMeta {
+                key,
+                filename: "f.bin",
+                content_type: None,
+                size,
+                path: path.to_string_lossy().as_ref(),
+                created_at: 0,
+                deleted: 0,
+                device_uuid: Some("dev"),
+                sha256: None,
+                retain_until,
+                tenant: None,
+                expires_at,
+                filename_supplied: 1,
+                compressed: 0,
+                uncompressed_size: None,
+            })
+            .unwrap();
+        }
+
+        let keys: Vec<String> = repo
+            .list_expired(now)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.key)
+            .collect();
+        assert_eq!(keys, vec!["old", "retained"]);
+        assert_eq!(sweep_expired(repo.clone(), now, None).await.unwrap(), 1);
+        assert!(repo.get_by_key("old").unwrap().is_none());
+        assert!(!storage.exists("dev", "old").await.unwrap());
+        for key in ["fresh", "forever", "retained"] {
+            assert!(repo.get_by_key(key).unwrap().is_some(), "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn layout_migration_moves_objects_and_is_rerunnable() {
+        let storage = StorageImpl::new(temp_dir("maint"));
+        let repo: Arc<dyn FileRepo> = Arc::new(new_file_repo(temp_pool()));
+        // 2024-03-01 and 2024-03-02, UTC
+        for (key, created_at) in [("a", 1_709_251_200), ("b", 1_709_337_600)] {
+            let (path, size) = storage
+                .write_stream("dev", key, &mut key.as_bytes())
+                .await
+                .unwrap();
+            repo.insert_file(&NewFileMeta {
+                key,
+                filename: "f.bin",
+                content_type: None,
+                size,
+                path: path.to_string_lossy().as_ref(),
+                created_at,
+                deleted: 0,
+                device_uuid: Some("dev"),
+                sha256: None,
+                retain_until: None,
+                tenant: None,
+                expires_at: None,
+                filename_supplied: 1,
+                compressed: 0,
+                uncompressed_size: None,
+            })
+            .unwrap();
+        }
+        let dated = |key: &str, day: &str| {
+            let flat = storage.resolve_path("dev", key).unwrap();
+            flat.parent().unwrap().join(format!("2024/03/{day}/{key}"))
+        };
+
+        let report = migrate_layout(repo.clone(), &storage, Layout::Flat, Layout::Dated, None)
+            .await
+            .unwrap();
+        assert_eq!(report.moved, 2);
+        for (key, day) in [("a", "01"), ("b", "02")] {
+            let meta = repo.get_by_key(key).unwrap().unwrap();
+            assert_eq!(PathBuf::from(&meta.path), dated(key, day));
+            assert_eq!(std::fs::read(&meta.path).unwrap(), key.as_bytes());
+            assert!(!storage.exists("dev", key).await.unwrap());
+        }
+        assert!(size_check(repo.clone(), None).await.unwrap().is_empty());
+
+        // a move whose row update was lost is picked up again
+        let flat_b = storage.resolve_path("dev", "b").unwrap();
+        repo.set_path("b", flat_b.to_string_lossy().as_ref())
+            .unwrap();
+        let report = migrate_layout(repo.clone(), &storage, Layout::Flat, Layout::Dated, None)
+            .await
+            .unwrap();
+        assert_eq!((report.moved, report.skipped, report.missing), (1, 1, 0));
+        let meta = repo.get_by_key("b").unwrap().unwrap();
+        assert_eq!(PathBuf::from(&meta.path), dated("b", "02"));
+
+        let report = migrate_layout(repo.clone(), &storage, Layout::Dated, Layout::Flat, None)
+            .await
+            .unwrap();
+        assert_eq!(report.moved, 2);
+        assert_eq!(storage.read_all("dev", "a").await.unwrap(), b"a");
+        let meta = repo.get_by_key("a").unwrap().unwrap();
+        assert_eq!(
+            PathBuf::from(&meta.path),
+            storage.resolve_path("dev", "a").unwrap()
+        );
+    }
+}
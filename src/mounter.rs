@@ -1,25 +1,314 @@
 use std::{
-    fs,
+    collections::HashMap,
+    fmt, fs, io,
     os::fd::AsFd,
-    path::{Path, PathBuf},
-    process::Command,
-    sync::Arc,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
     thread,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Result;
+use anyhow::{Result, bail};
 use log::{debug, error, info, warn};
 use nix::poll::{PollFd, PollFlags, poll};
-use udev::{EventType, MonitorBuilder};
+use regex::Regex;
+use serde::Serialize;
+use udev::{EventType, MonitorBuilder, MonitorSocket};
 
+use crate::entity::device::Device;
 use crate::repo::device_repo::DeviceRepo;
+use crate::system::{CommandOutput, CommandTimeout, HostSystem, System, parse_mount_table};
+
+/// Device name prefixes tracked by default: SCSI/SATA/USB disks, NVMe SSDs, SD/eMMC cards.
+pub const DEFAULT_DEVICE_PREFIXES: &[&str] = &["sd", "nvme", "mmcblk"];
+
+/// A block device name split into its parent disk and optional partition number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockName {
+    pub disk: String,
+    pub partition: Option<u32>,
+}
+
+/// Classify a device node (`/dev/sda1`, `/dev/nvme0n1p2`, `/dev/mmcblk0p1`) against the
+/// allowed name prefixes. Returns None if the name matches none of them.
+///
+/// Follows the kernel convention: when the disk name ends in a digit (`nvme0n1`, `mmcblk0`)
+/// partitions carry a `p` separator, otherwise the partition number is appended directly.
+pub fn classify_devnode(devnode: &str, prefixes: &[String]) -> Option<BlockName> {
+    let name = devnode.strip_prefix("/dev/").unwrap_or(devnode);
+    let prefix = prefixes.iter().find(|p| name.starts_with(p.as_str()))?;
+    let rest = &name[prefix.len()..];
+    if rest.is_empty() {
+        return None;
+    }
+    let stem = rest.trim_end_matches(|c: char| c.is_ascii_digit());
+    let digits = &rest[stem.len()..];
+    let digit_named = stem.is_empty() || stem.chars().any(|c| c.is_ascii_digit());
+    let whole = || BlockName {
+        disk: name.to_string(),
+        partition: None,
+    };
+    if digits.is_empty() {
+        return Some(whole());
+    }
+    if digit_named {
+        // nvme0n1p1 / mmcblk0p1: partition only when preceded by `p` after a digit
+        if let Some(disk) = stem.strip_suffix('p')
+            && disk.ends_with(|c: char| c.is_ascii_digit())
+        {
+            return Some(BlockName {
+                disk: format!("{prefix}{disk}"),
+                partition: digits.parse().ok(),
+            });
+        }
+        return Some(whole());
+    }
+    Some(BlockName {
+        disk: format!("{prefix}{stem}"),
+        partition: digits.parse().ok(),
+    })
+}
+
+/// First delay after a udev monitor error; doubles on each consecutive failure.
+const MONITOR_BACKOFF_BASE: Duration = Duration::from_millis(100);
+/// Upper bound for the udev monitor backoff delay.
+const MONITOR_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// Consecutive monitor errors after which the monitor socket is torn down and rebuilt.
+const MONITOR_REBUILD_AFTER: u32 = 5;
+
+/// Commands that can wedge on a flaky controller and so run under `command_timeout`.
+const TIMED_COMMANDS: &[&str] = &["mount", "umount"];
+/// Longest a device whose mount keeps timing out is left alone between attempts.
+const MOUNT_HOLDOFF_MAX: Duration = Duration::from_secs(600);
+/// Pause between `blkid` polls while waiting for a new device's filesystem UUID.
+const SETTLE_POLL: Duration = Duration::from_millis(250);
+
+/// Block device hotplug event the mounter reacts to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Add(String),
+    Remove(String),
+}
+
+/// Source of block device hotplug events (the udev monitor in production).
+pub trait EventSource {
+    /// Block until events arrive and return them. Errors are retried with backoff.
+    fn next_events(&mut self) -> Result<Vec<DeviceEvent>>;
+}
+
+/// `EventSource` over a udev monitor socket for the `block` subsystem.
+pub struct UdevEvents {
+    monitor: MonitorSocket,
+}
+
+impl UdevEvents {
+    pub fn connect() -> Result<Self> {
+        let monitor = MonitorBuilder::new()?.match_subsystem("block")?.listen()?;
+        Ok(Self { monitor })
+    }
+}
+
+impl EventSource for UdevEvents {
+    fn next_events(&mut self) -> Result<Vec<DeviceEvent>> {
+        let borrowed = self.monitor.as_fd();
+        let mut fds = [PollFd::new(&borrowed, PollFlags::POLLIN)];
+        poll(&mut fds, -1)?;
+        let events = self
+            .monitor
+            .iter()
+            .filter_map(|ev| {
+                let devnode = ev.devnode()?.to_string_lossy().to_string();
+                match ev.event_type() {
+                    EventType::Add => Some(DeviceEvent::Add(devnode)),
+                    EventType::Remove => Some(DeviceEvent::Remove(devnode)),
+                    _ => None,
+                }
+            })
+            .collect();
+        Ok(events)
+    }
+}
+
+/// Exponential backoff between `MONITOR_BACKOFF_BASE` and `MONITOR_BACKOFF_MAX`.
+#[derive(Debug)]
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self {
+            next: MONITOR_BACKOFF_BASE,
+        }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let d = self.next;
+        self.next = (d * 2).min(MONITOR_BACKOFF_MAX);
+        d
+    }
+
+    fn reset(&mut self) {
+        self.next = MONITOR_BACKOFF_BASE;
+    }
+}
+
+/// Overall SMART verdict reported by `smartctl -H`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmartHealth {
+    Passed,
+    Failed,
+    /// No verdict in the output (e.g. a USB bridge that doesn't pass SMART through).
+    Unknown,
+}
+
+impl SmartHealth {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SmartHealth::Passed => "passed",
+            SmartHealth::Failed => "failed",
+            SmartHealth::Unknown => "unknown",
+        }
+    }
+}
+
+/// Parse `smartctl -H` output. ATA drives report `...self-assessment test result: PASSED`,
+/// SCSI/SAS ones `SMART Health Status: OK`. The exit status is a bitmask that is often
+/// non-zero for healthy drives, so only the verdict line is trusted.
+pub fn parse_smart_health(output: &str) -> SmartHealth {
+    for line in output.lines() {
+        let line = line.trim();
+        let Some((label, verdict)) = line.split_once(':') else {
+            continue;
+        };
+        let label = label.to_ascii_lowercase();
+        if !(label.contains("self-assessment test result") || label.contains("health status")) {
+            continue;
+        }
+        let verdict = verdict.trim().to_ascii_uppercase();
+        return if verdict.starts_with("PASSED") || verdict.starts_with("OK") {
+            SmartHealth::Passed
+        } else {
+            SmartHealth::Failed
+        };
+    }
+    SmartHealth::Unknown
+}
+
+/// Directory name for a filesystem label: characters outside `[A-Za-z0-9._-]` become `_`.
+/// None for labels that are empty or would name `.` or `..`.
+fn label_dir_name(label: &str) -> Option<String> {
+    let name: String = label
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    (!name.is_empty() && name != "." && name != "..").then_some(name)
+}
+
+/// Whether something other than an empty directory is at `path`.
+fn is_occupied(path: &Path) -> io::Result<bool> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => Ok(fs::read_dir(path)?.next().is_some()),
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Filesystem UUID from `blkid -s UUID -o value` output: the first non-empty line, if it
+/// has a UUID's shape (hex digit groups separated by dashes, e.g. ext4's 8-4-4-4-12,
+/// vfat's `ABCD-1234` or ntfs's 16 bare digits). Anything else is treated as no UUID.
+pub fn parse_blkid_uuid(output: &str) -> Option<&str> {
+    let line = output.lines().map(str::trim).find(|l| !l.is_empty())?;
+    let well_formed = line.len() <= 64
+        && line
+            .split('-')
+            .all(|group| !group.is_empty() && group.bytes().all(|b| b.is_ascii_hexdigit()));
+    well_formed.then_some(line)
+}
+
+/// Which newly detected devices are joined without a manual join.
+#[derive(Debug, Clone, Default)]
+pub enum AutoJoin {
+    #[default]
+    Manual,
+    AllNew,
+    /// New devices whose filesystem label (`blkid -s LABEL`) matches.
+    MatchLabel(Regex),
+}
+
+/// Point-in-time view of mounter state, served by the diagnostics endpoint.
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+    pub devices: Vec<Device>,
+    /// Epoch seconds of the last completed reconciliation pass.
+    pub last_scan_at: Option<i64>,
+    /// Failed mount attempts per devnode since startup.
+    pub mount_failures: HashMap<String, u32>,
+}
+
+/// A refresh found the device's filesystem UUID changed in place. Returned (inside
+/// `anyhow::Error`) by `Mounter::refresh_device`: the mount path and the objects recorded
+/// against the device are keyed by the old UUID, so the device has to be removed and
+/// joined again instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UuidChanged {
+    pub devnode: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl fmt::Display for UuidChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} changed UUID from {} to {}; remove and re-join it",
+            self.devnode, self.old, self.new
+        )
+    }
+}
+
+impl std::error::Error for UuidChanged {}
 
 /// Orchestrates device tracking, mounting, and periodic reconciliation.
 pub struct Mounter {
     repo: Arc<dyn DeviceRepo>,
+    system: Arc<dyn System>,
     storage_root: PathBuf,
     scan_interval: Duration,
+    device_prefixes: Vec<String>,
+    smart: bool,
+    blacklist: Vec<String>,
+    min_free_bytes: u64,
+    never_format: bool,
+    auto_join: AutoJoin,
+    command_timeout: Option<Duration>,
+    removed_retention_secs: u64,
+    /// Wait before the first reconciliation pass, so boot-time probing can finish.
+    boot_grace: Duration,
+    /// How long a newly added device is re-polled for a filesystem UUID.
+    uuid_settle_timeout: Duration,
+    /// How long a removed device that is still busy is retried before a lazy unmount.
+    unmount_grace: Duration,
+    /// Mount devices at `{root}/{label}` rather than `{root}/{uuid}`.
+    label_mount_dirs: bool,
+    /// Write a probe file after each mount; a filesystem that refuses is flagged read-only.
+    write_probe: bool,
+    /// Least time between two capacity readings of a mounted device.
+    capacity_interval: Duration,
+    last_scan_at: Mutex<Option<i64>>,
+    mount_failures: Mutex<HashMap<String, u32>>,
+    /// Devices whose mount timed out, with the earliest time to try again.
+    mount_holdoff: Mutex<HashMap<String, Instant>>,
+    /// When each device's capacity was last read.
+    last_capacity: Mutex<HashMap<String, Instant>>,
 }
 
 impl Mounter {
@@ -29,11 +318,130 @@ impl Mounter {
     {
         Self {
             repo: Arc::new(repo),
+            system: Arc::new(HostSystem),
             storage_root,
             scan_interval: Duration::from_secs(scan_interval_secs),
+            device_prefixes: DEFAULT_DEVICE_PREFIXES
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+            smart: false,
+            blacklist: Vec::new(),
+            min_free_bytes: 0,
+            never_format: false,
+            auto_join: AutoJoin::default(),
+            command_timeout: None,
+            removed_retention_secs: 0,
+            boot_grace: Duration::ZERO,
+            uuid_settle_timeout: Duration::ZERO,
+            unmount_grace: Duration::ZERO,
+            label_mount_dirs: false,
+            write_probe: false,
+            capacity_interval: Duration::ZERO,
+            last_scan_at: Mutex::new(None),
+            mount_failures: Mutex::new(HashMap::new()),
+            mount_holdoff: Mutex::new(HashMap::new()),
+            last_capacity: Mutex::new(HashMap::new()),
         }
     }
 
+    /// Restrict tracking to device names starting with one of `prefixes`.
+    pub fn with_device_prefixes(mut self, prefixes: Vec<String>) -> Self {
+        self.device_prefixes = prefixes;
+        self
+    }
+
+    /// Check SMART health of every joined device on each reconciliation pass.
+    pub fn with_smart(mut self, enabled: bool) -> Self {
+        self.smart = enabled;
+        self
+    }
+
+    /// Never mount devices whose filesystem UUID or drive serial is in `entries`.
+    /// They are still recorded in the DB so operators can see them.
+    pub fn with_blacklist(mut self, entries: Vec<String>) -> Self {
+        self.blacklist = entries;
+        self
+    }
+
+    /// Keep devices with less than `bytes` free out of upload selection (0 = off).
+    pub fn with_min_free_bytes(mut self, bytes: u64) -> Self {
+        self.min_free_bytes = bytes;
+        self
+    }
+
+    /// Join newly detected devices according to `policy` instead of waiting for a manual join.
+    pub fn with_auto_join(mut self, policy: AutoJoin) -> Self {
+        self.auto_join = policy;
+        self
+    }
+
+    /// Safe mode: refuse to run any `mkfs*` program, whatever else is configured.
+    pub fn with_never_format(mut self, enabled: bool) -> Self {
+        self.never_format = enabled;
+        self
+    }
+
+    /// Kill `mount`, `umount` and `mkfs*` if they run longer than `timeout` (None = wait forever).
+    /// A device whose mount times out is retried with exponential backoff.
+    pub fn with_command_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    /// Delete rows of devices that have been removed for longer than `secs` (0 = keep forever).
+    pub fn with_removed_retention(mut self, secs: u64) -> Self {
+        self.removed_retention_secs = secs;
+        self
+    }
+
+    /// Hold off the first mount pass for `grace` after start, and give devices that show
+    /// up without a filesystem UUID up to `uuid_timeout` for `blkid` to report one.
+    /// Both are for boot, when udev replays every device before the kernel has finished
+    /// probing them; zero turns either off.
+    pub fn with_boot_settle(mut self, grace: Duration, uuid_timeout: Duration) -> Self {
+        self.boot_grace = grace;
+        self.uuid_settle_timeout = uuid_timeout;
+        self
+    }
+
+    /// Give a removed device that is still busy up to `grace` for open files (downloads
+    /// in flight) to close, retrying `umount`, then detach it lazily. Zero tries once.
+    pub fn with_unmount_grace(mut self, grace: Duration) -> Self {
+        self.unmount_grace = grace;
+        self
+    }
+
+    /// Name the mount directory of a device without a recorded mount path after its
+    /// filesystem label, suffixed `-2`, `-3`, ... if another device has the name, falling
+    /// back to its UUID when it has no label. `{root}/{uuid}` is kept as a symlink to the
+    /// label directory, since that is where the server looks for a device's objects.
+    pub fn with_label_mount_dirs(mut self, enabled: bool) -> Self {
+        self.label_mount_dirs = enabled;
+        self
+    }
+
+    /// Check each fresh mount accepts writes. A filesystem that mounted read-only (`mount`
+    /// still exits 0, e.g. after errors) is recorded as mounted but flagged read-only, so it
+    /// keeps serving downloads without being picked for uploads.
+    pub fn with_write_probe(mut self, enabled: bool) -> Self {
+        self.write_probe = enabled;
+        self
+    }
+
+    /// Re-read the capacity of an already mounted device at most every `interval` rather
+    /// than on every scan (zero = every scan). A fresh mount is always measured.
+    pub fn with_capacity_interval(mut self, interval: Duration) -> Self {
+        self.capacity_interval = interval;
+        self
+    }
+
+    /// Replace the host abstraction used for commands and the mount table.
+    pub fn with_system(mut self, system: Arc<dyn System>) -> Self {
+        self.system = system;
+        self
+    }
+
     fn now_epoch() -> i64 {
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -41,34 +449,147 @@ impl Mounter {
             .as_secs() as i64
     }
 
+    /// Every external command goes through here so safe mode can't be bypassed.
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let name = program.rsplit('/').next().unwrap_or(program);
+        if self.never_format && name.starts_with("mkfs") {
+            error!(
+                "never-format is set, refusing to run {} {}",
+                program,
+                args.join(" ")
+            );
+            bail!("formatting is disabled (never-format)");
+        }
+        match self.command_timeout {
+            Some(timeout) if TIMED_COMMANDS.contains(&name) || name.starts_with("mkfs") => {
+                self.system.run_with_timeout(program, args, timeout)
+            }
+            _ => self.system.run(program, args),
+        }
+    }
+
     fn fetch_uuid(&self, devnode: &str) -> Option<String> {
-        let out = Command::new("blkid")
-            .arg("-s")
-            .arg("UUID")
-            .arg("-o")
-            .arg("value")
-            .arg(devnode)
-            .output()
+        let out = self
+            .run("blkid", &["-s", "UUID", "-o", "value", devnode])
             .ok()?;
-        if out.status.success() {
-            let s = String::from_utf8_lossy(&out.stdout).trim().to_string();
-            if !s.is_empty() {
-                return Some(s);
+        if !out.success || out.stdout.trim().is_empty() {
+            return None;
+        }
+        let uuid = parse_blkid_uuid(&out.stdout);
+        if uuid.is_none() {
+            warn!(
+                "ignoring malformed blkid UUID output for {}: {:?}",
+                devnode, out.stdout
+            );
+        }
+        uuid.map(str::to_string)
+    }
+
+    /// `fetch_uuid`, re-polled until a UUID appears or `uuid_settle_timeout` passes.
+    fn settle_uuid(&self, devnode: &str) -> Option<String> {
+        let deadline = Instant::now() + self.uuid_settle_timeout;
+        let mut polls = 1;
+        loop {
+            if let Some(uuid) = self.fetch_uuid(devnode) {
+                if polls > 1 {
+                    debug!("{} reported UUID {} after {} polls", devnode, uuid, polls);
+                }
+                return Some(uuid);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return None;
             }
+            thread::sleep(SETTLE_POLL.min(left));
+            polls += 1;
         }
-        None
     }
 
-    fn is_mounted(&self, devnode: &str) -> bool {
-        if let Ok(mounts) = fs::read_to_string("/proc/mounts") {
-            mounts.lines().any(|l| l.starts_with(devnode))
-        } else {
-            false
+    fn fetch_fstype(&self, devnode: &str) -> Option<String> {
+        let out = self
+            .run("blkid", &["-s", "TYPE", "-o", "value", devnode])
+            .ok()?;
+        let fstype = out.stdout.trim();
+        (out.success && !fstype.is_empty()).then(|| fstype.to_string())
+    }
+
+    fn fetch_label(&self, devnode: &str) -> Option<String> {
+        let out = self
+            .run("blkid", &["-s", "LABEL", "-o", "value", devnode])
+            .ok()?;
+        let label = out.stdout.trim();
+        (out.success && !label.is_empty()).then(|| label.to_string())
+    }
+
+    fn should_auto_join(&self, label: Option<&str>) -> bool {
+        match &self.auto_join {
+            AutoJoin::Manual => false,
+            AutoJoin::AllNew => true,
+            AutoJoin::MatchLabel(re) => label.is_some_and(|label| re.is_match(label)),
         }
     }
 
+    /// Drive serial from udev's `ID_SERIAL_SHORT` (falling back to `ID_SERIAL`).
+    fn fetch_serial(&self, devnode: &str) -> Option<String> {
+        let out = self
+            .run(
+                "udevadm",
+                &["info", "--query=property", &format!("--name={devnode}")],
+            )
+            .ok()?;
+        if !out.success {
+            return None;
+        }
+        let prop = |name: &str| {
+            out.stdout.lines().find_map(|l| {
+                l.strip_prefix(name)
+                    .and_then(|v| v.strip_prefix('='))
+                    .map(str::to_string)
+            })
+        };
+        prop("ID_SERIAL_SHORT").or_else(|| prop("ID_SERIAL"))
+    }
+
+    fn is_blacklisted(&self, devnode: &str, uuid: &str) -> bool {
+        if self.blacklist.is_empty() {
+            return false;
+        }
+        if self.blacklist.iter().any(|b| b == uuid) {
+            return true;
+        }
+        self.fetch_serial(devnode)
+            .is_some_and(|serial| self.blacklist.contains(&serial))
+    }
+
+    fn mounts(&self) -> Vec<(String, String)> {
+        match self.system.mount_table() {
+            Ok(table) => parse_mount_table(&table),
+            Err(e) => {
+                warn!("reading mount table failed: {e}");
+                Vec::new()
+            }
+        }
+    }
+
+    fn is_mounted(&self, devnode: &str) -> bool {
+        self.mounts().iter().any(|(src, _)| src == devnode)
+    }
+
+    /// True if `mount_path` is currently an active mount point in the kernel mount table.
+    fn is_live_mount_point(&self, mount_path: &str) -> bool {
+        let wanted = Path::new(mount_path);
+        self.mounts()
+            .iter()
+            .any(|(_, target)| Path::new(target) == wanted)
+    }
+
     fn pick_mount_path(&self, uuid: Option<String>) -> Result<PathBuf> {
         if let Some(u) = uuid {
+            if self.label_mount_dirs
+                && let Some(path) = self.label_mount_path(&u)?
+            {
+                return Ok(path);
+            }
             return Ok(self.storage_root.join(u));
         }
         fs::create_dir_all(&self.storage_root)?;
@@ -82,34 +603,250 @@ impl Mounter {
         }
     }
 
-    fn mount_device(&self, devnode: &str, target: &Path) -> Result<bool> {
+    /// `{root}/{label}` for the device known as `uuid`, or the first of `{label}-2`,
+    /// `{label}-3`, ... that no other device uses as its mount path or UUID and that isn't
+    /// a non-empty directory. None if the filesystem has no usable label.
+    fn label_mount_path(&self, uuid: &str) -> Result<Option<PathBuf>> {
+        let devices = self.repo.list_all()?;
+        let Some(base) = devices
+            .iter()
+            .find(|d| d.uuid.as_deref() == Some(uuid))
+            .and_then(|d| d.label.as_deref())
+            .and_then(label_dir_name)
+        else {
+            return Ok(None);
+        };
+        let others: Vec<&Device> = devices
+            .iter()
+            .filter(|d| d.uuid.as_deref() != Some(uuid))
+            .collect();
+        let mut n = 1u32;
+        loop {
+            let name = match n {
+                1 => base.clone(),
+                n => format!("{base}-{n}"),
+            };
+            let path = self.storage_root.join(&name);
+            let claimed = others.iter().any(|d| {
+                d.uuid.as_deref() == Some(name.as_str())
+                    || d.mount_path
+                        .as_deref()
+                        .is_some_and(|mp| Path::new(mp) == path)
+            });
+            if !claimed && !is_occupied(&path)? {
+                return Ok(Some(path));
+            }
+            n += 1;
+        }
+    }
+
+    /// Point `{root}/{uuid}` at a label-named mount point directly below the root. An empty
+    /// directory there (an earlier UUID mount point) is replaced; anything else is an error.
+    fn link_uuid_dir(&self, uuid: &str, target: &Path) -> Result<()> {
+        let link = self.storage_root.join(uuid);
+        if !self.label_mount_dirs
+            || link == target
+            || target.parent() != Some(self.storage_root.as_path())
+        {
+            return Ok(());
+        }
+        let Some(name) = target.file_name() else {
+            return Ok(());
+        };
+        match fs::symlink_metadata(&link) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                if fs::read_link(&link)? == Path::new(name) {
+                    return Ok(());
+                }
+                fs::remove_file(&link)?;
+            }
+            Ok(meta) if meta.is_dir() && fs::read_dir(&link)?.next().is_none() => {
+                fs::remove_dir(&link)?;
+            }
+            Ok(_) => bail!("{:?} is in the way of the link to {:?}", link, target),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        std::os::unix::fs::symlink(name, &link)?;
+        Ok(())
+    }
+
+    /// Refuse mount targets outside `storage_root`, checked lexically before any directory
+    /// is created and again once symlinks are resolved, so a stale `mount_path` row or a
+    /// planted symlink can't get a device mounted over `/` or `/home`.
+    fn check_mount_target(&self, target: &Path) -> Result<()> {
+        let lexically_inside = target.starts_with(&self.storage_root)
+            && target != self.storage_root
+            && !target
+                .components()
+                .any(|c| matches!(c, Component::ParentDir | Component::CurDir));
+        if !lexically_inside {
+            bail!(
+                "mount target {:?} is not below storage root {:?}",
+                target,
+                self.storage_root
+            );
+        }
         fs::create_dir_all(target)?;
-        Ok(Command::new("mount")
-            .arg(devnode)
-            .arg(target)
-            .status()?
-            .success())
+        let root = fs::canonicalize(&self.storage_root)?;
+        let resolved = fs::canonicalize(target)?;
+        if !resolved.starts_with(&root) || resolved == root {
+            bail!(
+                "mount target {:?} resolves to {:?}, outside storage root {:?}",
+                target,
+                resolved,
+                root
+            );
+        }
+        Ok(())
+    }
+
+    fn mount_device(&self, devnode: &str, target: &Path) -> Result<bool> {
+        self.check_mount_target(target)?;
+        Ok(self
+            .run("mount", &[devnode, &target.to_string_lossy()])?
+            .success)
     }
 
     fn upsert_device(&self, devnode: &str) -> Result<()> {
-        if let Some(uuid) = self.fetch_uuid(devnode) {
-            self.repo.upsert_device(devnode, &uuid, Self::now_epoch())?;
+        let Some(name) = classify_devnode(devnode, &self.device_prefixes) else {
+            debug!("ignoring untracked device {}", devnode);
+            return Ok(());
+        };
+        debug!(
+            "{} classified as disk {} partition {:?}",
+            devnode, name.disk, name.partition
+        );
+        if let Some(uuid) = self.settle_uuid(devnode) {
+            let new = self.repo.upsert_device(devnode, &uuid, Self::now_epoch())?;
+            let label = self.fetch_label(devnode);
+            self.repo.set_label(devnode, label.as_deref())?;
+            if self.is_blacklisted(devnode, &uuid) {
+                info!("{} ({}) is blacklisted, recorded only", devnode, uuid);
+            } else if new
+                && self.should_auto_join(label.as_deref())
+                && self.repo.join_device(&uuid)?
+            {
+                info!("auto-joined new device {} ({})", devnode, uuid);
+            }
+        } else if self.never_format {
+            warn!(
+                "{} has no filesystem and would need formatting; never-format is set, leaving it untouched",
+                devnode
+            );
         }
         Ok(())
     }
 
     fn mark_removed(&self, devnode: &str) -> Result<()> {
+        // recorded first, so the server stops choosing the device while reads drain
+        let recorded = self.repo.mark_removed(devnode, Self::now_epoch());
         if self.is_mounted(devnode) {
-            match Command::new("umount").arg(devnode).status() {
-                Ok(st) if st.success() => info!("unmounted {}", devnode),
-                Ok(_) => warn!("umount command failed for {}", devnode),
-                Err(e) => error!("umount error for {}: {}", devnode, e),
+            self.unmount_removed(devnode);
+        }
+        Ok(recorded?)
+    }
+
+    /// Unmount a removed device. The kernel refuses while files on it are open, so a busy
+    /// device is retried for `unmount_grace`, then detached lazily: downloads already
+    /// reading from it finish, and nothing new can open it.
+    fn unmount_removed(&self, devnode: &str) {
+        let deadline = Instant::now() + self.unmount_grace;
+        let mut attempts = 1;
+        loop {
+            match self.run("umount", &[devnode]) {
+                Ok(out) if out.success => {
+                    info!("unmounted {}", devnode);
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("umount error for {}: {}", devnode, e);
+                    return;
+                }
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            if attempts == 1 {
+                info!(
+                    "{} is busy, waiting up to {:?} for open files to close",
+                    devnode, self.unmount_grace
+                );
+            }
+            thread::sleep(SETTLE_POLL.min(left));
+            attempts += 1;
+        }
+        if self.unmount_grace.is_zero() {
+            warn!("umount command failed for {}", devnode);
+            return;
+        }
+        warn!(
+            "{} still busy after {:?}, unmounting lazily",
+            devnode, self.unmount_grace
+        );
+        match self.run("umount", &["-l", devnode]) {
+            Ok(out) if out.success => info!("lazily unmounted {}", devnode),
+            Ok(_) => warn!("lazy umount failed for {}", devnode),
+            Err(e) => error!("lazy umount error for {}: {}", devnode, e),
+        }
+    }
+
+    /// Run `smartctl -H` on `devnode`, record the verdict and flag a failing drive
+    /// read-only so it keeps serving reads but takes no new writes.
+    fn check_health(&self, devnode: &str, uuid: &str, read_only: bool) -> Result<()> {
+        let out = self.run("smartctl", &["-H", devnode])?;
+        let health = parse_smart_health(&out.stdout);
+        self.repo.set_health(devnode, health.as_str())?;
+        if health == SmartHealth::Failed && !read_only {
+            warn!(
+                "SMART reports {} ({}) failing, flagging it read-only",
+                devnode, uuid
+            );
+            self.repo.set_read_only(uuid, true)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `devnode`'s capacity hasn't been read within `capacity_interval`.
+    fn capacity_due(&self, devnode: &str) -> bool {
+        self.last_capacity
+            .lock()
+            .unwrap()
+            .get(devnode)
+            .is_none_or(|at| at.elapsed() >= self.capacity_interval)
+    }
+
+    /// Record capacity of a mounted device and (re)evaluate its low-space flag against the
+    /// free-space floor. Runs every `capacity_interval`, so a device that frees up space rejoins
+    /// upload selection; the operator's `read_only` flag is never touched.
+    fn check_capacity(&self, devnode: &str, uuid: &str, target: &Path) -> Result<()> {
+        self.last_capacity
+            .lock()
+            .unwrap()
+            .insert(devnode.to_string(), Instant::now());
+        let stats = self.system.fs_stats(target)?;
+        self.repo
+            .set_capacity(devnode, stats.total_bytes as i64, stats.free_bytes as i64)?;
+        let low = self.min_free_bytes > 0 && stats.free_bytes < self.min_free_bytes;
+        if self.repo.set_low_space(devnode, low)? {
+            if low {
+                warn!(
+                    "{} ({}) has only {} bytes free (floor {}), no longer taking uploads",
+                    devnode, uuid, stats.free_bytes, self.min_free_bytes
+                );
+            } else {
+                info!(
+                    "{} ({}) is back above the free-space floor with {} bytes free",
+                    devnode, uuid, stats.free_bytes
+                );
             }
         }
-        self.repo.mark_removed(devnode, Self::now_epoch())
+        Ok(())
     }
 
-    fn process_pending(&self) -> Result<()> {
+    pub(crate) fn process_pending(&self) -> Result<()> {
         let rows = self.repo.list_joined_active()?;
         for row in rows {
             let uuid_val = match row.uuid {
@@ -119,14 +856,57 @@ impl Mounter {
                     continue;
                 }
             };
-            if row.mount_success == 1 && self.is_mounted(&row.devnode) {
-                info!(
-                    "{} already mounted {}",
-                    row.devnode,
-                    row.mount_path.as_deref().unwrap_or("?")
+            if self.is_blacklisted(&row.devnode, &uuid_val) {
+                debug!(
+                    "{} ({}) is blacklisted, not mounting",
+                    row.devnode, uuid_val
                 );
                 continue;
             }
+            if self.smart
+                && let Err(e) = self.check_health(&row.devnode, &uuid_val, row.read_only == 1)
+            {
+                warn!("SMART check failed for {}: {}", row.devnode, e);
+            }
+            if row.mount_success == 1 {
+                // The flag survives reboots while the real mount does not; trust the kernel.
+                let live = row
+                    .mount_path
+                    .as_deref()
+                    .is_some_and(|mp| self.is_live_mount_point(mp));
+                if live && self.is_mounted(&row.devnode) {
+                    info!(
+                        "{} already mounted {}",
+                        row.devnode,
+                        row.mount_path.as_deref().unwrap_or("?")
+                    );
+                    let target = PathBuf::from(row.mount_path.as_deref().unwrap_or_default());
+                    if self.capacity_due(&row.devnode)
+                        && let Err(e) = self.check_capacity(&row.devnode, &uuid_val, &target)
+                    {
+                        warn!("capacity check failed for {}: {}", row.devnode, e);
+                    }
+                    continue;
+                }
+                if !live {
+                    warn!(
+                        "{} flagged mounted but {} is not a live mount, clearing flag",
+                        row.devnode,
+                        row.mount_path.as_deref().unwrap_or("?")
+                    );
+                    self.repo.mark_unmounted(&row.devnode)?;
+                }
+            }
+            if self
+                .mount_holdoff
+                .lock()
+                .unwrap()
+                .get(&row.devnode)
+                .is_some_and(|until| Instant::now() < *until)
+            {
+                debug!("{} mount timed out recently, backing off", row.devnode);
+                continue;
+            }
             let target = if let Some(mp) = row.mount_path.clone().map(PathBuf::from) {
                 mp
             } else {
@@ -138,28 +918,157 @@ impl Mounter {
                     &target.to_string_lossy(),
                     &uuid_val,
                 )?;
+                if let Err(e) = self.link_uuid_dir(&uuid_val, &target) {
+                    warn!("linking {} to {:?} failed: {}", uuid_val, target, e);
+                }
+                if let Err(e) = self.check_capacity(&row.devnode, &uuid_val, &target) {
+                    warn!("capacity check failed for {}: {}", row.devnode, e);
+                }
                 continue;
             }
             match self.mount_device(&row.devnode, &target) {
                 Ok(true) => {
                     info!("mounted {} at {:?}", row.devnode, target);
+                    self.mount_holdoff.lock().unwrap().remove(&row.devnode);
                     self.repo.update_mount_result(
                         &row.devnode,
                         &target.to_string_lossy(),
                         &uuid_val,
                     )?;
+                    if self.write_probe
+                        && let Err(e) = self.system.probe_writable(&target)
+                    {
+                        warn!(
+                            "{} mounted at {:?} but is not writable ({}), flagging it read-only",
+                            row.devnode, target, e
+                        );
+                        self.repo.set_read_only(&uuid_val, true)?;
+                    }
+                    if let Err(e) = self.link_uuid_dir(&uuid_val, &target) {
+                        warn!("linking {} to {:?} failed: {}", uuid_val, target, e);
+                    }
+                    if let Err(e) = self.check_capacity(&row.devnode, &uuid_val, &target) {
+                        warn!("capacity check failed for {}: {}", row.devnode, e);
+                    }
+                }
+                Ok(false) => {
+                    error!("mount command failed for {}", row.devnode);
+                    self.record_mount_failure(&row.devnode);
+                }
+                Err(e) if e.downcast_ref::<CommandTimeout>().is_some() => {
+                    let failures = self.record_mount_failure(&row.devnode);
+                    let holdoff = self
+                        .scan_interval
+                        .saturating_mul(1 << failures.min(16))
+                        .min(MOUNT_HOLDOFF_MAX);
+                    error!(
+                        "mounting {} hung ({}), retrying in {:?}",
+                        row.devnode, e, holdoff
+                    );
+                    self.mount_holdoff
+                        .lock()
+                        .unwrap()
+                        .insert(row.devnode.clone(), Instant::now() + holdoff);
+                }
+                Err(e) => {
+                    error!("error mounting {}: {}", row.devnode, e);
+                    self.record_mount_failure(&row.devnode);
                 }
-                Ok(false) => error!("mount command failed for {}", row.devnode),
-                Err(e) => error!("error mounting {}: {}", row.devnode, e),
             }
         }
+        if let Err(e) = self.reap_removed() {
+            warn!("purging removed devices failed: {e}");
+        }
+        *self.last_scan_at.lock().unwrap() = Some(Self::now_epoch());
         Ok(())
     }
 
+    /// Drop long-removed devices so the table doesn't grow with every stick ever plugged in.
+    fn reap_removed(&self) -> Result<()> {
+        if self.removed_retention_secs == 0 {
+            return Ok(());
+        }
+        let cutoff = Self::now_epoch().saturating_sub(self.removed_retention_secs as i64);
+        let purged = self.repo.purge_removed(cutoff)?;
+        if purged > 0 {
+            info!("purged {} devices removed before {}", purged, cutoff);
+        }
+        Ok(())
+    }
+
+    /// Count a failed mount; returns the device's failure total.
+    fn record_mount_failure(&self, devnode: &str) -> u32 {
+        let mut failures = self.mount_failures.lock().unwrap();
+        let count = failures.entry(devnode.to_string()).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    /// Re-read UUID and filesystem type with `blkid` and, if mounted, capacity with
+    /// statvfs for the present device currently known as `uuid`, e.g. after it was
+    /// resized in place. None if no such device is present; `UuidChanged` (nothing
+    /// updated) if it was reformatted with a new UUID.
+    pub fn refresh_device(&self, uuid: &str) -> Result<Option<Device>> {
+        let Some(dev) = self
+            .repo
+            .list_all()?
+            .into_iter()
+            .find(|d| d.removed == 0 && d.uuid.as_deref() == Some(uuid))
+        else {
+            return Ok(None);
+        };
+        let Some(new_uuid) = self.fetch_uuid(&dev.devnode) else {
+            bail!("blkid reports no filesystem UUID for {}", dev.devnode);
+        };
+        if new_uuid != uuid {
+            warn!("{} changed UUID from {} to {}", dev.devnode, uuid, new_uuid);
+            return Err(UuidChanged {
+                devnode: dev.devnode,
+                old: uuid.to_string(),
+                new: new_uuid,
+            }
+            .into());
+        }
+        let fstype = self.fetch_fstype(&dev.devnode);
+        self.repo
+            .set_identity(&dev.devnode, &new_uuid, fstype.as_deref())?;
+        let label = self.fetch_label(&dev.devnode);
+        self.repo.set_label(&dev.devnode, label.as_deref())?;
+        if dev.mount_success == 1
+            && let Some(mp) = dev.mount_path.as_deref()
+        {
+            let stats = self.system.fs_stats(Path::new(mp))?;
+            self.repo.set_capacity(
+                &dev.devnode,
+                stats.total_bytes as i64,
+                stats.free_bytes as i64,
+            )?;
+        }
+        info!("refreshed {} ({})", dev.devnode, new_uuid);
+        Ok(self
+            .repo
+            .list_all()?
+            .into_iter()
+            .find(|d| d.devnode == dev.devnode))
+    }
+
+    /// Snapshot of tracked devices and reconciliation health.
+    pub fn diagnostics(&self) -> Result<Diagnostics> {
+        Ok(Diagnostics {
+            devices: self.repo.list_all()?,
+            last_scan_at: *self.last_scan_at.lock().unwrap(),
+            mount_failures: self.mount_failures.lock().unwrap().clone(),
+        })
+    }
+
     /// Spawn background thread for periodic reconciliation.
     pub fn start_scheduler(self: &Arc<Self>) {
         let this = Arc::clone(self);
         thread::spawn(move || {
+            if !this.boot_grace.is_zero() {
+                info!("waiting {:?} for devices to settle", this.boot_grace);
+                thread::sleep(this.boot_grace);
+            }
             loop {
                 if let Err(e) = this.process_pending() {
                     error!("process_pending error: {e}");
@@ -171,38 +1080,769 @@ impl Mounter {
         });
     }
 
-    /// Blocking udev event loop. Never returns unless there is a fatal error.
+    /// Blocking udev event loop. Never returns: monitor errors are retried with
+    /// exponential backoff and a persistently failing monitor is rebuilt.
     pub fn run_udev_loop(self: &Arc<Self>) -> Result<()> {
-        let monitor = MonitorBuilder::new()?.match_subsystem("block")?.listen()?;
-        let borrowed = monitor.as_fd();
-        let mut fds = [PollFd::new(&borrowed, PollFlags::POLLIN)];
+        self.run_events(
+            || Ok(Box::new(UdevEvents::connect()?) as Box<dyn EventSource>),
+            |d| {
+                thread::sleep(d);
+                true
+            },
+        )
+    }
+
+    /// Event loop over sources built by `connect`. `pause` sleeps for the given backoff
+    /// and returns false to stop the loop (only tests do that).
+    pub(crate) fn run_events<C, P>(&self, mut connect: C, mut pause: P) -> Result<()>
+    where
+        C: FnMut() -> Result<Box<dyn EventSource>>,
+        P: FnMut(Duration) -> bool,
+    {
+        let mut backoff = Backoff::new();
+        let mut source: Option<Box<dyn EventSource>> = None;
+        let mut failures = 0u32;
         loop {
-            if let Err(e) = poll(&mut fds, -1) {
-                error!("poll error: {:?}", e);
-                continue;
-            }
-            for ev in monitor.iter() {
-                if let Some(devnode) = ev.devnode() {
-                    let devpath = devnode.to_string_lossy().to_string();
-                    match ev.event_type() {
-                        EventType::Add => {
-                            if let Err(e) = self.upsert_device(&devpath) {
-                                error!("db upsert error {}: {}", devpath, e);
-                            } else {
-                                info!("device add {} recorded", devpath);
-                            }
-                        }
-                        EventType::Remove => {
-                            if let Err(e) = self.mark_removed(&devpath) {
-                                error!("db remove mark error {}: {}", devpath, e);
-                            } else {
-                                info!("device removed {}", devpath);
-                            }
+            let src = match source.as_mut() {
+                Some(src) => src,
+                None => match connect() {
+                    Ok(src) => {
+                        info!("udev monitor connected");
+                        source.insert(src)
+                    }
+                    Err(e) => {
+                        let d = backoff.next_delay();
+                        error!("udev monitor connect failed: {e}; retrying in {d:?}");
+                        if !pause(d) {
+                            return Ok(());
                         }
-                        _ => {}
+                        continue;
+                    }
+                },
+            };
+            match src.next_events() {
+                Ok(events) => {
+                    backoff.reset();
+                    failures = 0;
+                    for ev in events {
+                        self.handle_event(ev);
                     }
                 }
+                Err(e) => {
+                    failures += 1;
+                    let d = backoff.next_delay();
+                    if failures >= MONITOR_REBUILD_AFTER {
+                        warn!(
+                            "udev monitor failed {failures} times in a row ({e}); reconnecting in {d:?}"
+                        );
+                        source = None;
+                        failures = 0;
+                    } else {
+                        error!("udev monitor error: {e}; retrying in {d:?}");
+                    }
+                    if !pause(d) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_event(&self, ev: DeviceEvent) {
+        let (DeviceEvent::Add(devpath) | DeviceEvent::Remove(devpath)) = &ev;
+        if classify_devnode(devpath, &self.device_prefixes).is_none() {
+            return;
+        }
+        match ev {
+            DeviceEvent::Add(devpath) => {
+                if let Err(e) = self.upsert_device(&devpath) {
+                    error!("db upsert error {}: {}", devpath, e);
+                } else {
+                    info!("device add {} recorded", devpath);
+                }
+            }
+            DeviceEvent::Remove(devpath) => {
+                if let Err(e) = self.mark_removed(&devpath) {
+                    error!("db remove mark error {}: {}", devpath, e);
+                } else {
+                    info!("device removed {}", devpath);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Pool;
+    use crate::repo::device_repo::new_device_repo;
+    use crate::schema::devices;
+    use crate::test_support::{FakeSystem, temp_dir, temp_pool};
+    use diesel::prelude::*;
+
+    fn seed_mounted(pool: &Pool, devnode: &str, uuid: &str, mount_path: &Path) {
+        new_device_repo(pool.clone())
+            .upsert_device(devnode, uuid, 1)
+            .unwrap();
+        let mut conn = pool.get().unwrap();
+        diesel::update(devices::table.filter(devices::uuid.eq(uuid)))
+            .set((
+                devices::joined.eq(1),
+                devices::mount_success.eq(1),
+                devices::mount_path.eq(Some(mount_path.to_string_lossy().to_string())),
+            ))
+            .execute(&mut conn)
+            .unwrap();
+    }
+
+    fn mount_success(pool: &Pool, uuid: &str) -> i32 {
+        let mut conn = pool.get().unwrap();
+        devices::table
+            .filter(devices::uuid.eq(uuid))
+            .select(devices::mount_success)
+            .first(&mut conn)
+            .unwrap()
+    }
+
+    fn defaults() -> Vec<String> {
+        DEFAULT_DEVICE_PREFIXES
+            .iter()
+            .map(|p| p.to_string())
+            .collect()
+    }
+
+    fn part(disk: &str, partition: Option<u32>) -> Option<BlockName> {
+        Some(BlockName {
+            disk: disk.to_string(),
+            partition,
+        })
+    }
+
+    #[test]
+    fn classifies_device_naming_schemes() {
+        let p = defaults();
+        assert_eq!(classify_devnode("/dev/sda", &p), part("sda", None));
+        assert_eq!(classify_devnode("/dev/sdb12", &p), part("sdb", Some(12)));
+        assert_eq!(classify_devnode("/dev/nvme0n1", &p), part("nvme0n1", None));
+        assert_eq!(
+            classify_devnode("/dev/nvme0n1p1", &p),
+            part("nvme0n1", Some(1))
+        );
+        assert_eq!(classify_devnode("/dev/mmcblk0", &p), part("mmcblk0", None));
+        assert_eq!(
+            classify_devnode("/dev/mmcblk0p2", &p),
+            part("mmcblk0", Some(2))
+        );
+        assert_eq!(classify_devnode("/dev/loop0", &p), None);
+        assert_eq!(classify_devnode("/dev/sd", &p), None);
+    }
+
+    #[test]
+    fn uuid_is_polled_until_the_device_settles() {
+        let pool = temp_pool();
+        let sys = Arc::new(FakeSystem::default());
+        sys.queue_output("blkid -s UUID -o value /dev/sdb1", true, "");
+        sys.queue_output("blkid -s UUID -o value /dev/sdb1", true, "");
+        sys.set_output("blkid -s UUID -o value /dev/sdb1", true, "4e3f-a1b2\n");
+        let mounter = Mounter::new(new_device_repo(pool.clone()), temp_dir("mnt"), 5)
+            .with_system(sys.clone())
+            .with_boot_settle(Duration::ZERO, Duration::from_secs(5));
+
+        mounter.upsert_device("/dev/sdb1").unwrap();
+        let polls = sys
+            .calls()
+            .iter()
+            .filter(|c| c.starts_with("blkid -s UUID"))
+            .count();
+        assert_eq!(polls, 3);
+        let devices = mounter.repo.list_all().unwrap();
+        assert_eq!(devices[0].uuid.as_deref(), Some("4e3f-a1b2"));
+
+        // without a settle timeout the first empty answer is final
+        let pool = temp_pool();
+        sys.queue_output("blkid -s UUID -o value /dev/sdc1", true, "");
+        sys.set_output("blkid -s UUID -o value /dev/sdc1", true, "5f40-b2c3\n");
+        let mounter =
+            Mounter::new(new_device_repo(pool), temp_dir("mnt"), 5).with_system(sys.clone());
+        mounter.upsert_device("/dev/sdc1").unwrap();
+        assert!(mounter.repo.list_all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn removal_waits_for_in_flight_downloads_before_unmounting() {
+        let pool = temp_pool();
+        let sys = Arc::new(FakeSystem::default());
+        let mp = temp_dir("mnt").join("u1");
+        seed_mounted(&pool, "/dev/sda1", "u1", &mp);
+        sys.set_mounts(&format!("/dev/sda1 {} ext4 rw 0 0\n", mp.display()));
+        // a download holds a file open: the kernel refuses a plain umount until it closes
+        sys.set_output("umount /dev/sda1", false, "");
+        let mounter = Mounter::new(new_device_repo(pool.clone()), temp_dir("mnt"), 5)
+            .with_system(sys.clone())
+            .with_unmount_grace(Duration::from_secs(10));
+        let download = {
+            let sys = sys.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(600));
+                sys.set_output("umount /dev/sda1", true, "");
+            })
+        };
+
+        let started = Instant::now();
+        mounter.handle_event(DeviceEvent::Remove("/dev/sda1".into()));
+        download.join().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(600));
+        let calls = sys.calls();
+        assert!(calls.iter().filter(|c| *c == "umount /dev/sda1").count() > 1);
+        assert!(!calls.contains(&"umount -l /dev/sda1".to_string()));
+        assert_eq!(mounter.repo.list_all().unwrap()[0].removed, 1);
+
+        // a download outlasting the grace doesn't hold the removal up: detach lazily
+        let pool = temp_pool();
+        seed_mounted(&pool, "/dev/sda1", "u1", &mp);
+        sys.set_output("umount /dev/sda1", false, "");
+        sys.set_output("umount -l /dev/sda1", true, "");
+        let mounter = Mounter::new(new_device_repo(pool), temp_dir("mnt"), 5)
+            .with_system(sys.clone())
+            .with_unmount_grace(Duration::from_secs(1));
+        mounter.handle_event(DeviceEvent::Remove("/dev/sda1".into()));
+        assert_eq!(sys.calls().last().unwrap(), "umount -l /dev/sda1");
+    }
+
+    #[test]
+    fn untracked_prefix_is_ignored() {
+        let pool = temp_pool();
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("blkid", true, "u1\n");
+        let mounter = Mounter::new(new_device_repo(pool.clone()), temp_dir("mnt"), 5)
+            .with_system(sys.clone())
+            .with_device_prefixes(vec!["nvme".into()]);
+
+        mounter.upsert_device("/dev/sda1").unwrap();
+        assert!(sys.calls().is_empty());
+        mounter.upsert_device("/dev/nvme0n1p1").unwrap();
+        assert_eq!(sys.calls().len(), 1);
+    }
+
+    /// Source whose every poll fails, like a monitor fd stuck in an error state.
+    struct Broken;
+
+    impl EventSource for Broken {
+        fn next_events(&mut self) -> Result<Vec<DeviceEvent>> {
+            anyhow::bail!("poll: EBADF")
+        }
+    }
+
+    #[test]
+    fn monitor_errors_back_off_and_rebuild() {
+        let mounter = Mounter::new(new_device_repo(temp_pool()), temp_dir("mnt"), 5);
+        let mut connects = 0;
+        let mut delays = Vec::new();
+        mounter
+            .run_events(
+                || {
+                    connects += 1;
+                    Ok(Box::new(Broken) as Box<dyn EventSource>)
+                },
+                |d| {
+                    delays.push(d);
+                    delays.len() < 20
+                },
+            )
+            .unwrap();
+
+        assert_eq!(connects, 4);
+        assert_eq!(delays[0], MONITOR_BACKOFF_BASE);
+        assert!(delays.windows(2).all(|w| w[1] >= w[0]));
+        assert_eq!(*delays.last().unwrap(), MONITOR_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn parses_smart_verdicts() {
+        let ata = "smartctl 7.3 2022-02-28 r5338\n\n=== START OF READ SMART DATA SECTION ===\n\
+                   SMART overall-health self-assessment test result: PASSED\n";
+        assert_eq!(parse_smart_health(ata), SmartHealth::Passed);
+        let scsi = "=== START OF READ SMART DATA SECTION ===\nSMART Health Status: OK\n";
+        assert_eq!(parse_smart_health(scsi), SmartHealth::Passed);
+        let failing = "SMART overall-health self-assessment test result: FAILED!\n\
+                       Drive failure expected in less than 24 hours. SAVE ALL DATA.\n";
+        assert_eq!(parse_smart_health(failing), SmartHealth::Failed);
+        let usb = "/dev/sdb: Unknown USB bridge [0x1234:0x5678]\n\
+                   Please specify device type with the -d option.\n";
+        assert_eq!(parse_smart_health(usb), SmartHealth::Unknown);
+    }
+
+    #[test]
+    fn mount_targets_must_stay_below_storage_root() {
+        let root = temp_dir("mnt");
+        let outside = temp_dir("elsewhere");
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("mount", true, "");
+        let mounter =
+            Mounter::new(new_device_repo(temp_pool()), root.clone(), 5).with_system(sys.clone());
+
+        assert!(mounter.mount_device("/dev/sdb1", &root.join("u1")).unwrap());
+        for target in [
+            root.join("escape"),
+            root.join("../elsewhere"),
+            root.clone(),
+            PathBuf::from("/home"),
+        ] {
+            assert!(
+                mounter.mount_device("/dev/sdc1", &target).is_err(),
+                "{target:?}"
+            );
+        }
+        let mounts: Vec<String> = sys
+            .calls()
+            .into_iter()
+            .filter(|c| c.starts_with("mount "))
+            .collect();
+        assert_eq!(mounts.len(), 1);
+        assert!(mounts[0].starts_with("mount /dev/sdb1 "));
+    }
+
+    #[test]
+    fn blkid_uuid_output_is_validated() {
+        let ext4 = "0b9c5a1e-3f2d-4c8b-9a7e-6d5f4e3c2b1a";
+        assert_eq!(parse_blkid_uuid(&format!("{ext4}\n")), Some(ext4));
+        assert_eq!(parse_blkid_uuid("\n  \nABCD-1234\n\n"), Some("ABCD-1234"));
+        assert_eq!(
+            parse_blkid_uuid("1234ABCD5678EF90\n0b9c5a1e-3f2d\n"),
+            Some("1234ABCD5678EF90")
+        );
+        assert_eq!(parse_blkid_uuid(""), None);
+        assert_eq!(parse_blkid_uuid("/dev/sdb1: UUID=\"ABCD-1234\"\n"), None);
+        assert_eq!(parse_blkid_uuid("ABCD--1234"), None);
+        assert_eq!(parse_blkid_uuid("not-a-uuid"), None);
+
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("blkid", true, "warning: cache stale\n");
+        let mounter =
+            Mounter::new(new_device_repo(temp_pool()), temp_dir("mnt"), 5).with_system(sys);
+        assert_eq!(mounter.fetch_uuid("/dev/sdb1"), None);
+    }
+
+    #[test]
+    fn failing_drive_is_flagged_read_only() {
+        let pool = temp_pool();
+        let root = temp_dir("mnt");
+        let mp = root.join("u1");
+        seed_mounted(&pool, "/dev/sdz1", "u1", &mp);
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_mounts(&format!("/dev/sdz1 {} ext4 rw 0 0\n", mp.display()));
+        sys.set_output(
+            "smartctl",
+            false,
+            "SMART overall-health self-assessment test result: FAILED!\n",
+        );
+        let mounter = Mounter::new(new_device_repo(pool.clone()), root, 5)
+            .with_system(sys.clone())
+            .with_smart(true);
+
+        mounter.process_pending().unwrap();
+        assert_eq!(sys.calls(), vec!["smartctl -H /dev/sdz1"]);
+        let dev = &mounter.diagnostics().unwrap().devices[0];
+        assert_eq!(dev.health.as_deref(), Some("failed"));
+        assert_eq!(dev.read_only, 1);
+        assert_eq!(mount_success(&pool, "u1"), 1);
+    }
+
+    fn seed_joined(pool: &Pool, devnode: &str, uuid: &str) {
+        let repo = new_device_repo(pool.clone());
+        repo.upsert_device(devnode, uuid, 1).unwrap();
+        repo.join_device(uuid).unwrap();
+    }
+
+    #[test]
+    fn mounts_that_refuse_writes_are_flagged_read_only() {
+        let pool = temp_pool();
+        seed_joined(&pool, "/dev/sda1", "rw");
+        seed_joined(&pool, "/dev/sdb1", "ro");
+        let root = temp_dir("mnt");
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("mount", true, "");
+        sys.set_read_only(&root.join("ro"));
+        let mounter = Mounter::new(new_device_repo(pool.clone()), root, 5)
+            .with_system(sys)
+            .with_write_probe(true);
+
+        mounter.process_pending().unwrap();
+        let devices = mounter.diagnostics().unwrap().devices;
+        let dev = |uuid: &str| {
+            devices
+                .iter()
+                .find(|d| d.uuid.as_deref() == Some(uuid))
+                .unwrap()
+        };
+        assert_eq!((dev("rw").mount_success, dev("rw").read_only), (1, 0));
+        // still mounted for downloads, but never picked for uploads
+        assert_eq!((dev("ro").mount_success, dev("ro").read_only), (1, 1));
+    }
+
+    #[test]
+    fn blacklisted_uuid_is_never_mounted() {
+        let pool = temp_pool();
+        seed_joined(&pool, "/dev/sda1", "system-disk");
+        seed_joined(&pool, "/dev/sdb1", "u2");
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("mount", true, "");
+        let mounter = Mounter::new(new_device_repo(pool.clone()), temp_dir("mnt"), 5)
+            .with_system(sys.clone())
+            .with_blacklist(vec!["system-disk".into()]);
+
+        mounter.process_pending().unwrap();
+        let calls = sys.calls();
+        assert!(!calls.iter().any(|c| c.starts_with("mount /dev/sda1")));
+        assert!(calls.iter().any(|c| c.starts_with("mount /dev/sdb1")));
+        assert_eq!(mount_success(&pool, "system-disk"), 0);
+        assert_eq!(mount_success(&pool, "u2"), 1);
+    }
+
+    #[test]
+    fn blacklisted_serial_is_never_mounted() {
+        let pool = temp_pool();
+        seed_joined(&pool, "/dev/sda1", "u1");
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("mount", true, "");
+        sys.set_output(
+            "udevadm",
+            true,
+            "DEVNAME=/dev/sda1\nID_SERIAL=Samsung_SSD_870_S5XYNS0R123456\nID_SERIAL_SHORT=S5XYNS0R123456\n",
+        );
+        let mounter = Mounter::new(new_device_repo(pool.clone()), temp_dir("mnt"), 5)
+            .with_system(sys.clone())
+            .with_blacklist(vec!["S5XYNS0R123456".into()]);
+
+        mounter.process_pending().unwrap();
+        assert!(!sys.calls().iter().any(|c| c.starts_with("mount ")));
+        assert_eq!(mount_success(&pool, "u1"), 0);
+    }
+
+    fn joined(pool: &Pool, uuid: &str) -> i32 {
+        let mut conn = pool.get().unwrap();
+        devices::table
+            .filter(devices::uuid.eq(uuid))
+            .select(devices::joined)
+            .first(&mut conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn auto_join_policies() {
+        let run = |policy: AutoJoin| {
+            let pool = temp_pool();
+            let sys = Arc::new(FakeSystem::default());
+            for (dev, uuid, label) in [("/dev/sda1", "a1", "backup-1"), ("/dev/sdb1", "a2", "misc")]
+            {
+                sys.set_output(&format!("blkid -s UUID -o value {dev}"), true, uuid);
+                sys.set_output(&format!("blkid -s LABEL -o value {dev}"), true, label);
             }
+            let mounter = Mounter::new(new_device_repo(pool.clone()), temp_dir("mnt"), 5)
+                .with_system(sys)
+                .with_auto_join(policy);
+            mounter.upsert_device("/dev/sda1").unwrap();
+            mounter.upsert_device("/dev/sdb1").unwrap();
+            (joined(&pool, "a1"), joined(&pool, "a2"))
+        };
+
+        assert_eq!(run(AutoJoin::Manual), (0, 0));
+        assert_eq!(run(AutoJoin::AllNew), (1, 1));
+        let backups = Regex::new("^backup-").unwrap();
+        assert_eq!(run(AutoJoin::MatchLabel(backups)), (1, 0));
+    }
+
+    #[test]
+    fn never_format_refuses_mkfs() {
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("blkid", false, "");
+        sys.set_output("mkfs.ext4", true, "");
+        let mounter = Mounter::new(new_device_repo(temp_pool()), temp_dir("mnt"), 5)
+            .with_system(sys.clone())
+            .with_never_format(true);
+
+        // a blank device is left alone rather than formatted
+        mounter.upsert_device("/dev/sdb").unwrap();
+        assert!(mounter.run("mkfs.ext4", &["/dev/sdb"]).is_err());
+        assert!(
+            mounter
+                .run("/sbin/mkfs", &["-t", "ext4", "/dev/sdb"])
+                .is_err()
+        );
+        assert!(sys.calls().iter().all(|c| !c.contains("mkfs")));
+        assert!(mounter.run("blkid", &["/dev/sdb"]).is_ok());
+    }
+
+    #[test]
+    fn hanging_mount_times_out_and_backs_off() {
+        let pool = temp_pool();
+        seed_joined(&pool, "/dev/sda1", "stuck");
+        seed_joined(&pool, "/dev/sdb1", "fine");
+        let root = temp_dir("mnt");
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("mount", true, "");
+        sys.set_hanging(&format!("mount /dev/sda1 {}", root.join("stuck").display()));
+        let mounter = Mounter::new(new_device_repo(pool.clone()), root, 5)
+            .with_system(sys.clone())
+            .with_command_timeout(Some(Duration::from_millis(100)));
+
+        mounter.process_pending().unwrap();
+        let killed: Vec<String> = sys
+            .calls()
+            .into_iter()
+            .filter(|c| c.ends_with("(killed)"))
+            .collect();
+        assert_eq!(killed.len(), 1, "{killed:?}");
+        assert!(killed[0].starts_with("mount /dev/sda1 "));
+        assert_eq!(mount_success(&pool, "fine"), 1);
+        assert_eq!(mount_success(&pool, "stuck"), 0);
+        assert_eq!(
+            mounter.diagnostics().unwrap().mount_failures["/dev/sda1"],
+            1
+        );
+
+        // the hung device is held off, so the next pass doesn't wait on it again
+        let before = sys.calls().len();
+        mounter.process_pending().unwrap();
+        assert!(
+            sys.calls()[before..]
+                .iter()
+                .all(|c| !c.starts_with("mount /dev/sda1"))
+        );
+    }
+
+    #[test]
+    fn capacity_is_refreshed_on_its_own_interval() {
+        let pool = temp_pool();
+        let root = temp_dir("mnt");
+        let mp = root.join("u1");
+        seed_mounted(&pool, "/dev/sda1", "u1", &mp);
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_mounts(&format!("/dev/sda1 {} ext4 rw 0 0\n", mp.display()));
+        sys.set_fs_stats(&mp, 1 << 40, 100);
+        let mounter = Mounter::new(new_device_repo(pool.clone()), root, 5)
+            .with_system(sys.clone())
+            .with_capacity_interval(Duration::from_millis(300));
+        let free = || mounter.diagnostics().unwrap().devices[0].free_bytes;
+
+        mounter.process_pending().unwrap();
+        assert_eq!(free(), Some(100));
+        // reconciliation keeps running, the reading stays until the interval is up
+        sys.set_fs_stats(&mp, 1 << 40, 200);
+        for _ in 0..3 {
+            mounter.process_pending().unwrap();
+            assert_eq!(free(), Some(100));
+        }
+        thread::sleep(Duration::from_millis(350));
+        mounter.process_pending().unwrap();
+        assert_eq!(free(), Some(200));
+    }
+
+    #[test]
+    fn low_free_space_is_flagged_and_rechecked_every_scan() {
+        let pool = temp_pool();
+        let root = temp_dir("mnt");
+        seed_joined(&pool, "/dev/sda1", "roomy");
+        seed_joined(&pool, "/dev/sdb1", "full");
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("mount", true, "");
+        sys.set_fs_stats(&root.join("roomy"), 1 << 40, 1 << 39);
+        sys.set_fs_stats(&root.join("full"), 1 << 40, 1 << 20);
+        let mounter = Mounter::new(new_device_repo(pool.clone()), root.clone(), 5)
+            .with_system(sys.clone())
+            .with_min_free_bytes(1 << 30);
+
+        mounter.process_pending().unwrap();
+        let devices = mounter.diagnostics().unwrap().devices;
+        let dev = |uuid: &str| {
+            devices
+                .iter()
+                .find(|d| d.uuid.as_deref() == Some(uuid))
+                .unwrap()
+        };
+        assert_eq!(dev("roomy").low_space, 0);
+        assert_eq!(dev("roomy").free_bytes, Some(1 << 39));
+        assert_eq!(dev("full").low_space, 1);
+        assert_eq!(dev("full").read_only, 0);
+        assert_eq!(dev("full").total_bytes, Some(1 << 40));
+        assert_eq!(mount_success(&pool, "full"), 1);
+
+        // both stay mounted; the next scan sees the space change hands
+        sys.set_mounts(&format!(
+            "/dev/sda1 {} ext4 rw 0 0\n/dev/sdb1 {} ext4 rw 0 0\n",
+            root.join("roomy").display(),
+            root.join("full").display()
+        ));
+        sys.set_fs_stats(&root.join("roomy"), 1 << 40, 1 << 20);
+        sys.set_fs_stats(&root.join("full"), 1 << 40, 1 << 39);
+        mounter.process_pending().unwrap();
+        let devices = mounter.diagnostics().unwrap().devices;
+        let dev = |uuid: &str| {
+            devices
+                .iter()
+                .find(|d| d.uuid.as_deref() == Some(uuid))
+                .unwrap()
+        };
+        assert_eq!(dev("roomy").low_space, 1);
+        assert_eq!(dev("full").low_space, 0);
+        assert_eq!(dev("full").free_bytes, Some(1 << 39));
+    }
+
+    #[test]
+    fn refresh_picks_up_capacity_and_refuses_a_new_uuid() {
+        let pool = temp_pool();
+        let root = temp_dir("mnt");
+        let mp = root.join("0a1d");
+        seed_mounted(&pool, "/dev/sdb1", "0a1d", &mp);
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("blkid -s UUID -o value /dev/sdb1", true, "0a1d\n");
+        sys.set_output("blkid -s TYPE -o value /dev/sdb1", true, "xfs\n");
+        sys.set_fs_stats(&mp, 2 << 40, 1 << 40);
+        let mounter = Mounter::new(new_device_repo(pool.clone()), root, 5).with_system(sys.clone());
+
+        assert!(mounter.refresh_device("missing").unwrap().is_none());
+        let dev = mounter.refresh_device("0a1d").unwrap().unwrap();
+        assert_eq!(dev.fstype.as_deref(), Some("xfs"));
+        assert_eq!(
+            (dev.total_bytes, dev.free_bytes),
+            (Some(2 << 40), Some(1 << 40))
+        );
+
+        // reformatted in place: the row keeps its UUID and mount path
+        sys.set_output("blkid -s UUID -o value /dev/sdb1", true, "4e3f-a1b2\n");
+        let err = mounter.refresh_device("0a1d").unwrap_err();
+        let changed = err.downcast_ref::<UuidChanged>().unwrap();
+        assert_eq!(changed.new, "4e3f-a1b2");
+        let dev = mounter.diagnostics().unwrap().devices.remove(0);
+        assert_eq!(dev.uuid.as_deref(), Some("0a1d"));
+        assert_eq!(dev.mount_path.as_deref(), Some(mp.to_str().unwrap()));
+
+        sys.set_output("blkid -s UUID -o value /dev/sdb1", false, "");
+        assert!(mounter.refresh_device("0a1d").is_err());
+    }
+
+    #[test]
+    fn stale_mount_flag_is_cleared() {
+        let pool = temp_pool();
+        let root = temp_dir("mnt");
+        seed_mounted(&pool, "/dev/sdz1", "u1", &root.join("u1"));
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_output("mount", false, "");
+        let mounter = Mounter::new(new_device_repo(pool.clone()), root, 5).with_system(sys.clone());
+
+        mounter.process_pending().unwrap();
+        assert_eq!(mount_success(&pool, "u1"), 0);
+        assert!(sys.calls().iter().any(|c| c.starts_with("mount /dev/sdz1")));
+    }
+
+    #[test]
+    fn live_mount_keeps_flag() {
+        let pool = temp_pool();
+        let root = temp_dir("mnt");
+        let mp = root.join("u1");
+        seed_mounted(&pool, "/dev/sdz1", "u1", &mp);
+        let sys = Arc::new(FakeSystem::default());
+        sys.set_mounts(&format!("/dev/sdz1 {} ext4 rw 0 0\n", mp.display()));
+        let mounter = Mounter::new(new_device_repo(pool.clone()), root, 5).with_system(sys.clone());
+
+        mounter.process_pending().unwrap();
+        assert_eq!(mount_success(&pool, "u1"), 1);
+        assert!(sys.calls().is_empty());
+    }
+
+    fn label_mounter(
+        root: &Path,
+        devices: &[(&str, &str, Option<&str>)],
+    ) -> (Arc<FakeSystem>, Mounter) {
+        let sys = Arc::new(FakeSystem::default());
+        for (dev, uuid, label) in devices {
+            sys.set_output(&format!("blkid -s UUID -o value {dev}"), true, uuid);
+            sys.set_output(
+                &format!("blkid -s LABEL -o value {dev}"),
+                label.is_some(),
+                label.unwrap_or_default(),
+            );
         }
+        sys.set_output("mount", true, "");
+        let mounter = Mounter::new(new_device_repo(temp_pool()), root.to_path_buf(), 5)
+            .with_system(sys.clone())
+            .with_auto_join(AutoJoin::AllNew)
+            .with_label_mount_dirs(true);
+        for (dev, _, _) in devices {
+            mounter.upsert_device(dev).unwrap();
+        }
+        mounter.process_pending().unwrap();
+        (sys, mounter)
+    }
+
+    #[test]
+    fn unique_label_names_the_mount_dir() {
+        let root = temp_dir("mnt");
+        let (sys, mounter) = label_mounter(&root, &[("/dev/sda1", "0a1d", Some("photos 2024"))]);
+
+        let target = root.join("photos_2024");
+        assert!(
+            sys.calls()
+                .contains(&format!("mount /dev/sda1 {}", target.display()))
+        );
+        let dev = mounter.diagnostics().unwrap().devices.remove(0);
+        assert_eq!(dev.label.as_deref(), Some("photos 2024"));
+        assert_eq!(dev.mount_path.as_deref(), Some(target.to_str().unwrap()));
+        // the server still finds the device's objects under its UUID
+        assert_eq!(
+            fs::read_link(root.join("0a1d")).unwrap(),
+            Path::new("photos_2024")
+        );
+        assert_eq!(label_dir_name(".."), None);
+        assert_eq!(label_dir_name("  "), None);
+    }
+
+    #[test]
+    fn colliding_labels_get_a_suffix() {
+        let root = temp_dir("mnt");
+        // left behind by something else; not empty, so not reused
+        fs::create_dir_all(root.join("backup-2")).unwrap();
+        fs::write(root.join("backup-2").join("keep"), b"x").unwrap();
+        let (_, mounter) = label_mounter(
+            &root,
+            &[
+                ("/dev/sda1", "a1", Some("backup")),
+                ("/dev/sdb1", "b2", Some("backup")),
+            ],
+        );
+
+        let mut paths: Vec<String> = mounter
+            .diagnostics()
+            .unwrap()
+            .devices
+            .into_iter()
+            .filter_map(|d| d.mount_path)
+            .collect();
+        paths.sort();
+        let expected = |name: &str| root.join(name).to_string_lossy().into_owned();
+        assert_eq!(paths, vec![expected("backup"), expected("backup-3")]);
+        let mut links = [
+            fs::read_link(root.join("a1")).unwrap(),
+            fs::read_link(root.join("b2")).unwrap(),
+        ];
+        links.sort();
+        assert_eq!(links, [PathBuf::from("backup"), PathBuf::from("backup-3")]);
+    }
+
+    #[test]
+    fn unlabelled_device_is_mounted_under_its_uuid() {
+        let root = temp_dir("mnt");
+        let (sys, mounter) = label_mounter(&root, &[("/dev/sda1", "0a1d", None)]);
+
+        let target = root.join("0a1d");
+        assert!(
+            sys.calls()
+                .contains(&format!("mount /dev/sda1 {}", target.display()))
+        );
+        let dev = mounter.diagnostics().unwrap().devices.remove(0);
+        assert_eq!(dev.label, None);
+        assert!(fs::symlink_metadata(&target).unwrap().is_dir());
     }
 }
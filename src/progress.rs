@@ -0,0 +1,281 @@
+//! In-memory registry of uploads tagged with a client-chosen id, so progress can be
+//! followed from another connection while the bytes are still arriving.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Buffered events per subscriber; a slower reader skips ahead to the latest count.
+const EVENT_BUFFER: usize = 64;
+/// How long a finished session stays visible after its last activity.
+pub const RECENT_SESSION_TTL_SECS: i64 = 600;
+
+fn now_epoch() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Something that happened to an upload session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// Total bytes received so far.
+    Received(u64),
+    /// Upload stored under `key`.
+    Complete {
+        received: u64,
+        key: String,
+    },
+    Failed(String),
+}
+
+impl ProgressEvent {
+    pub fn is_terminal(&self) -> bool {
+        !matches!(self, ProgressEvent::Received(_))
+    }
+
+    /// Server-Sent Events frame for this event.
+    pub fn to_sse(&self) -> String {
+        let (name, data) = match self {
+            ProgressEvent::Received(n) => ("progress", serde_json::json!({ "received": n })),
+            ProgressEvent::Complete { received, key } => (
+                "complete",
+                serde_json::json!({ "received": received, "key": key }),
+            ),
+            ProgressEvent::Failed(message) => ("error", serde_json::json!({ "message": message })),
+        };
+        format!("event: {name}\ndata: {data}\n\n")
+    }
+}
+
+/// Lifecycle of an upload session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionState {
+    InFlight,
+    Complete,
+    Failed(String),
+}
+
+/// A session as listed by `GET /uploads`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SessionSummary {
+    pub id: String,
+    pub tenant: Option<String>,
+    /// Object key, once the upload has been given one.
+    pub key: Option<String>,
+    pub received: u64,
+    pub created_at: i64,
+    pub last_activity: i64,
+    /// `in-flight`, `complete` or `failed`.
+    pub state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// One tagged upload.
+#[derive(Debug)]
+pub struct UploadSession {
+    pub id: String,
+    /// Tenant of the uploader; only the same tenant can follow the session.
+    pub tenant: Option<String>,
+    pub created_at: i64,
+    key: Mutex<Option<String>>,
+    received: AtomicU64,
+    last_activity: AtomicI64,
+    state: Mutex<SessionState>,
+    tx: broadcast::Sender<ProgressEvent>,
+}
+
+impl UploadSession {
+    fn new(tenant: Option<&str>, id: &str) -> Self {
+        let now = now_epoch();
+        Self {
+            id: id.to_string(),
+            tenant: tenant.map(str::to_string),
+            created_at: now,
+            key: Mutex::new(None),
+            received: AtomicU64::new(0),
+            last_activity: AtomicI64::new(now),
+            state: Mutex::new(SessionState::InFlight),
+            tx: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+
+    pub fn set_key(&self, key: &str) {
+        *self.key.lock().unwrap() = Some(key.to_string());
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Count `n` more bytes and notify subscribers.
+    pub fn add(&self, n: usize) {
+        let total = self.received.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        self.last_activity.store(now_epoch(), Ordering::Relaxed);
+        let _ = self.tx.send(ProgressEvent::Received(total));
+    }
+
+    pub fn complete(&self) {
+        let key = self.key.lock().unwrap().clone().unwrap_or_default();
+        self.finish(
+            SessionState::Complete,
+            ProgressEvent::Complete {
+                received: self.received(),
+                key,
+            },
+        );
+    }
+
+    pub fn fail(&self, message: &str) {
+        self.finish(
+            SessionState::Failed(message.to_string()),
+            ProgressEvent::Failed(message.to_string()),
+        );
+    }
+
+    fn finish(&self, state: SessionState, event: ProgressEvent) {
+        *self.state.lock().unwrap() = state;
+        self.last_activity.store(now_epoch(), Ordering::Relaxed);
+        let _ = self.tx.send(event);
+    }
+
+    /// Events describing the current state: the byte count, plus the outcome if finished.
+    pub fn snapshot(&self) -> Vec<ProgressEvent> {
+        let mut events = vec![ProgressEvent::Received(self.received())];
+        match self.state.lock().unwrap().clone() {
+            SessionState::InFlight => {}
+            SessionState::Complete => events.push(ProgressEvent::Complete {
+                received: self.received(),
+                key: self.key.lock().unwrap().clone().unwrap_or_default(),
+            }),
+            SessionState::Failed(message) => events.push(ProgressEvent::Failed(message)),
+        }
+        events
+    }
+
+    /// Receiver for events after this call. Subscribe before taking a `snapshot` so
+    /// nothing falls in between.
+    pub fn subscribe(&self) -> broadcast::Receiver<ProgressEvent> {
+        self.tx.subscribe()
+    }
+
+    fn is_in_flight(&self) -> bool {
+        *self.state.lock().unwrap() == SessionState::InFlight
+    }
+
+    fn summary(&self) -> SessionSummary {
+        let (state, error) = match self.state.lock().unwrap().clone() {
+            SessionState::InFlight => ("in-flight", None),
+            SessionState::Complete => ("complete", None),
+            SessionState::Failed(message) => ("failed", Some(message)),
+        };
+        SessionSummary {
+            id: self.id.clone(),
+            tenant: self.tenant.clone(),
+            key: self.key.lock().unwrap().clone(),
+            received: self.received(),
+            created_at: self.created_at,
+            last_activity: self.last_activity.load(Ordering::Relaxed),
+            state,
+            error,
+        }
+    }
+
+    /// Still in flight, or finished within `RECENT_SESSION_TTL_SECS`.
+    fn is_current(&self, now: i64) -> bool {
+        self.is_in_flight()
+            || self.last_activity.load(Ordering::Relaxed) >= now - RECENT_SESSION_TTL_SECS
+    }
+}
+
+/// Fails its session if dropped while the upload is still in flight, e.g. when the
+/// client disconnects and the handler future is dropped mid-body. Subscribers then see
+/// the error and their streams end, and the id becomes free again.
+#[derive(Debug)]
+pub struct SessionGuard(Arc<UploadSession>);
+
+impl SessionGuard {
+    pub fn new(session: Arc<UploadSession>) -> Self {
+        Self(session)
+    }
+}
+
+impl std::ops::Deref for SessionGuard {
+    type Target = UploadSession;
+
+    fn deref(&self) -> &UploadSession {
+        &self.0
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if self.0.is_in_flight() {
+            self.0.fail("upload aborted");
+        }
+    }
+}
+
+/// Registry key: (tenant, upload id).
+type SessionSlot = (Option<String>, String);
+
+/// Sessions by tenant and id, so tenants can't see (or collide with) each other's ids.
+/// Finished sessions linger for `RECENT_SESSION_TTL_SECS` so a late subscriber still
+/// sees the outcome.
+#[derive(Debug, Default)]
+pub struct UploadRegistry {
+    sessions: Mutex<HashMap<SessionSlot, Arc<UploadSession>>>,
+}
+
+impl UploadRegistry {
+    /// Register a new in-flight session. None if `id` is already in flight for `tenant`;
+    /// a finished session with the same id is replaced.
+    pub fn start(&self, tenant: Option<&str>, id: &str) -> Option<Arc<UploadSession>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = now_epoch();
+        sessions.retain(|_, s| s.is_current(now));
+        let slot = (tenant.map(str::to_string), id.to_string());
+        if sessions.get(&slot).is_some_and(|s| s.is_in_flight()) {
+            return None;
+        }
+        let session = Arc::new(UploadSession::new(tenant, id));
+        sessions.insert(slot, session.clone());
+        Some(session)
+    }
+
+    /// `tenant`'s session `id`, if any.
+    pub fn get(&self, tenant: Option<&str>, id: &str) -> Option<Arc<UploadSession>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&(tenant.map(str::to_string), id.to_string()))
+            .cloned()
+    }
+
+    /// Every tenant's in-flight and recently finished sessions, oldest first.
+    pub fn list(&self) -> Vec<SessionSummary> {
+        let now = now_epoch();
+        let mut sessions: Vec<SessionSummary> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|s| s.is_current(now))
+            .map(|s| s.summary())
+            .collect();
+        sessions.sort_by(|a, b| {
+            (a.created_at, &a.tenant, &a.id).cmp(&(b.created_at, &b.tenant, &b.id))
+        });
+        sessions
+    }
+}
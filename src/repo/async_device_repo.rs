@@ -0,0 +1,133 @@
+//! Async front for `DeviceRepo`, so request handlers can await device queries directly.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use tokio::sync::Semaphore;
+
+use crate::entity::device::Device;
+use crate::logging;
+use crate::repo::RepoResult;
+use crate::repo::device_repo::{DeviceCounts, DeviceMountRow, DeviceRepo};
+
+/// Runs each `DeviceRepo` call on tokio's blocking pool. At most `max_in_flight` calls
+/// run at once; the rest wait on the runtime instead of tying up more blocking threads
+/// that would only queue on the connection pool.
+#[derive(Clone)]
+pub struct AsyncDeviceRepo {
+    inner: Arc<dyn DeviceRepo>,
+    permits: Arc<Semaphore>,
+    slow_after: Option<Duration>,
+}
+
+impl AsyncDeviceRepo {
+    pub fn new(inner: Arc<dyn DeviceRepo>, max_in_flight: usize) -> Self {
+        Self {
+            inner,
+            permits: Arc::new(Semaphore::new(max_in_flight.max(1))),
+            slow_after: None,
+        }
+    }
+
+    /// Warn about calls that take at least `threshold` (None = never).
+    pub fn with_slow_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_after = threshold;
+        self
+    }
+
+    /// The wrapped synchronous repo, for code that already runs off the runtime.
+    pub fn inner(&self) -> Arc<dyn DeviceRepo> {
+        self.inner.clone()
+    }
+
+    async fn call<T, F>(&self, name: &'static str, f: F) -> RepoResult<T>
+    where
+        F: FnOnce(&dyn DeviceRepo) -> RepoResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self.permits.acquire().await.map_err(anyhow::Error::from)?;
+        let repo = self.inner.clone();
+        let slow_after = self.slow_after;
+        let id = logging::request_id();
+        tokio::task::spawn_blocking(move || {
+            logging::with_request_id(id, || {
+                logging::warn_if_slow(slow_after, &format!("DeviceRepo::{name}"), || {
+                    f(repo.as_ref())
+                })
+            })
+        })
+        .await
+        .map_err(|e| anyhow!("blocking task failed: {e}"))?
+    }
+
+    pub async fn list_joined_active(&self) -> RepoResult<Vec<DeviceMountRow>> {
+        self.call("list_joined_active", |r| r.list_joined_active())
+            .await
+    }
+
+    pub async fn list_all(&self) -> RepoResult<Vec<Device>> {
+        self.call("list_all", |r| r.list_all()).await
+    }
+
+    pub async fn get_active_uuid(&self) -> RepoResult<Option<String>> {
+        self.call("get_active_uuid", |r| r.get_active_uuid()).await
+    }
+
+    pub async fn counts(&self) -> RepoResult<DeviceCounts> {
+        self.call("counts", |r| r.counts()).await
+    }
+
+    pub async fn join_device(&self, uuid: &str) -> RepoResult<bool> {
+        let uuid = uuid.to_string();
+        self.call("join_device", move |r| r.join_device(&uuid))
+            .await
+    }
+
+    pub async fn set_read_only(&self, uuid: &str, read_only: bool) -> RepoResult<bool> {
+        let uuid = uuid.to_string();
+        self.call("set_read_only", move |r| r.set_read_only(&uuid, read_only))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::device_repo::new_device_repo;
+    use crate::test_support::{seed_device, temp_pool};
+
+    #[tokio::test]
+    async fn async_calls_reach_the_wrapped_repo() {
+        let pool = temp_pool();
+        seed_device(&pool, "/dev/sda1", "u1");
+        let repo = AsyncDeviceRepo::new(Arc::new(new_device_repo(pool)), 2);
+
+        let rows = repo.list_joined_active().await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].uuid.as_deref(), Some("u1"));
+        assert_eq!(repo.get_active_uuid().await.unwrap().as_deref(), Some("u1"));
+
+        assert!(repo.set_read_only("u1", true).await.unwrap());
+        assert!(!repo.set_read_only("nope", true).await.unwrap());
+        assert_eq!(repo.get_active_uuid().await.unwrap(), None);
+        assert_eq!(repo.list_all().await.unwrap()[0].read_only, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_calls_share_the_permits() {
+        let pool = temp_pool();
+        seed_device(&pool, "/dev/sda1", "u1");
+        let repo = AsyncDeviceRepo::new(Arc::new(new_device_repo(pool)), 1);
+
+        let calls: Vec<_> = (0..8)
+            .map(|_| {
+                let repo = repo.clone();
+                tokio::spawn(async move { repo.list_all().await })
+            })
+            .collect();
+        for call in calls {
+            assert_eq!(call.await.unwrap().unwrap().len(), 1);
+        }
+        assert_eq!(repo.permits.available_permits(), 1);
+    }
+}
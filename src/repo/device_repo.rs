@@ -1,6 +1,7 @@
-use crate::{db::Pool, schema::devices};
-use anyhow::Result;
+use crate::{db::Pool, entity::device::Device, repo::RepoResult, schema::devices};
 use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use serde::Serialize;
 
 /// Row subset used during mount scheduling.
 #[derive(Debug, Queryable)]
@@ -9,6 +10,24 @@ pub struct DeviceMountRow {
     pub uuid: Option<String>,
     pub mount_success: i32,
     pub mount_path: Option<String>,
+    pub read_only: i32,
+    pub low_space: i32,
+    pub free_bytes: Option<i64>,
+}
+
+/// Devices per state. Apart from `total` and `removed`, only present devices count.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, QueryableByName, Serialize)]
+pub struct DeviceCounts {
+    #[diesel(sql_type = BigInt)]
+    pub total: i64,
+    #[diesel(sql_type = BigInt)]
+    pub joined: i64,
+    #[diesel(sql_type = BigInt)]
+    pub mounted: i64,
+    #[diesel(sql_type = BigInt)]
+    pub removed: i64,
+    #[diesel(sql_type = BigInt)]
+    pub read_only: i64,
 }
 
 #[derive(Clone)]
@@ -23,13 +42,21 @@ impl DeviceRepoImpl {
 
     fn conn(
         &self,
-    ) -> Result<r2d2::PooledConnection<diesel::r2d2::ConnectionManager<SqliteConnection>>> {
+    ) -> RepoResult<r2d2::PooledConnection<diesel::r2d2::ConnectionManager<SqliteConnection>>> {
         Ok(self.pool.get()?)
     }
 
-    pub fn upsert_device(&self, devnode: &str, uuid: &str, ts: i64) -> Result<()> {
+    pub fn transaction(
+        &self,
+        f: &mut dyn FnMut(&mut SqliteConnection) -> RepoResult<()>,
+    ) -> RepoResult<()> {
+        let mut conn = self.conn()?;
+        conn.immediate_transaction(|c| f(c))
+    }
+
+    pub fn upsert_device(&self, devnode: &str, uuid: &str, ts: i64) -> RepoResult<bool> {
         let mut conn = self.conn()?;
-        conn.immediate_transaction(|c| {
+        let inserted = conn.immediate_transaction(|c| {
             let updated = diesel::update(devices::table.filter(devices::uuid.eq(uuid)))
                 .set((
                     devices::devnode.eq(devnode),
@@ -50,12 +77,12 @@ impl DeviceRepoImpl {
                     ))
                     .execute(c)?;
             }
-            Ok::<(), diesel::result::Error>(())
+            Ok::<bool, diesel::result::Error>(updated == 0)
         })?;
-        Ok(())
+        Ok(inserted)
     }
 
-    pub fn mark_removed(&self, devnode: &str, ts: i64) -> Result<()> {
+    pub fn mark_removed(&self, devnode: &str, ts: i64) -> RepoResult<()> {
         let mut conn = self.conn()?;
         diesel::update(devices::table.filter(devices::devnode.eq(devnode)))
             .set((
@@ -67,7 +94,7 @@ impl DeviceRepoImpl {
         Ok(())
     }
 
-    pub fn list_joined_active(&self) -> Result<Vec<DeviceMountRow>> {
+    pub fn list_joined_active(&self) -> RepoResult<Vec<DeviceMountRow>> {
         let mut conn = self.conn()?;
         let rows = devices::table
             .filter(devices::removed.eq(0))
@@ -77,27 +104,46 @@ impl DeviceRepoImpl {
                 devices::uuid,
                 devices::mount_success,
                 devices::mount_path,
+                devices::read_only,
+                devices::low_space,
+                devices::free_bytes,
             ))
             .load::<DeviceMountRow>(&mut conn)?;
         Ok(rows)
     }
 
+    /// Every tracked device row, including removed ones.
+    pub fn list_all(&self) -> RepoResult<Vec<Device>> {
+        let mut conn = self.conn()?;
+        Ok(devices::table
+            .order(devices::id.asc())
+            .load::<Device>(&mut conn)?)
+    }
+
     /// Returns the UUID of an active device if available.
-    /// Policy: removed=0 AND joined=1 AND mount_success=1 AND uuid IS NOT NULL; pick first.
-    pub fn get_active_uuid(&self) -> Result<Option<String>> {
+    /// Policy: removed=0 AND joined=1 AND mount_success=1 AND read_only=0 AND low_space=0
+    /// AND uuid IS NOT NULL; pick first.
+    pub fn get_active_uuid(&self) -> RepoResult<Option<String>> {
         let mut conn = self.conn()?;
         use crate::schema::devices::dsl as d;
         let res = d::devices
             .filter(d::removed.eq(0))
             .filter(d::joined.eq(1))
             .filter(d::mount_success.eq(1))
+            .filter(d::read_only.eq(0))
+            .filter(d::low_space.eq(0))
             .select(d::uuid)
             .first::<Option<String>>(&mut conn)
             .optional()?;
         Ok(res.flatten())
     }
 
-    pub fn update_mount_result(&self, devnode: &str, mount_path: &str, uuid: &str) -> Result<()> {
+    pub fn update_mount_result(
+        &self,
+        devnode: &str,
+        mount_path: &str,
+        uuid: &str,
+    ) -> RepoResult<()> {
         let mut conn = self.conn()?;
         diesel::update(
             devices::table
@@ -112,48 +158,361 @@ impl DeviceRepoImpl {
         Ok(())
     }
 
-    pub fn mark_mounted_existing(&self, devnode: &str, mount_path: &str, uuid: &str) -> Result<()> {
+    pub fn mark_mounted_existing(
+        &self,
+        devnode: &str,
+        mount_path: &str,
+        uuid: &str,
+    ) -> RepoResult<()> {
         self.update_mount_result(devnode, mount_path, uuid)
     }
+
+    /// Join an active device into the pool and reset its mount state in one transaction, so
+    /// the mounter (re)mounts it from scratch. Returns false if no active device has `uuid`.
+    pub fn join_device(&self, uuid: &str) -> RepoResult<bool> {
+        let mut joined = false;
+        self.transaction(&mut |c| {
+            let active: i64 = devices::table
+                .filter(devices::uuid.eq(uuid))
+                .filter(devices::removed.eq(0))
+                .count()
+                .get_result(c)?;
+            if active == 0 {
+                return Ok(());
+            }
+            diesel::update(devices::table.filter(devices::uuid.eq(uuid)))
+                .set((devices::joined.eq(1), devices::mount_success.eq(0)))
+                .execute(c)?;
+            joined = true;
+            Ok(())
+        })?;
+        Ok(joined)
+    }
+
+    /// Flag a device as read-only (still readable, never selected for writes).
+    /// Returns false if no device has `uuid`.
+    pub fn set_read_only(&self, uuid: &str, read_only: bool) -> RepoResult<bool> {
+        let mut conn = self.conn()?;
+        let updated = diesel::update(devices::table.filter(devices::uuid.eq(uuid)))
+            .set(devices::read_only.eq(read_only as i32))
+            .execute(&mut conn)?;
+        Ok(updated > 0)
+    }
+
+    /// Set or clear the low free space flag of the device at `devnode`. Returns true if
+    /// the flag changed.
+    pub fn set_low_space(&self, devnode: &str, low: bool) -> RepoResult<bool> {
+        let mut conn = self.conn()?;
+        let updated = diesel::update(
+            devices::table
+                .filter(devices::devnode.eq(devnode))
+                .filter(devices::low_space.ne(low as i32)),
+        )
+        .set(devices::low_space.eq(low as i32))
+        .execute(&mut conn)?;
+        Ok(updated > 0)
+    }
+
+    /// Record the latest SMART verdict for the device at `devnode`.
+    pub fn set_health(&self, devnode: &str, health: &str) -> RepoResult<()> {
+        let mut conn = self.conn()?;
+        diesel::update(devices::table.filter(devices::devnode.eq(devnode)))
+            .set(devices::health.eq(Some(health)))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    /// Record the filesystem capacity of the device at `devnode`.
+    pub fn set_capacity(&self, devnode: &str, total_bytes: i64, free_bytes: i64) -> RepoResult<()> {
+        let mut conn = self.conn()?;
+        diesel::update(devices::table.filter(devices::devnode.eq(devnode)))
+            .set((
+                devices::total_bytes.eq(Some(total_bytes)),
+                devices::free_bytes.eq(Some(free_bytes)),
+            ))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    /// Record what `blkid` currently reports for the device at `devnode`.
+    pub fn set_identity(&self, devnode: &str, uuid: &str, fstype: Option<&str>) -> RepoResult<()> {
+        let mut conn = self.conn()?;
+        diesel::update(devices::table.filter(devices::devnode.eq(devnode)))
+            .set((devices::uuid.eq(Some(uuid)), devices::fstype.eq(fstype)))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    /// Record the filesystem label of the device at `devnode` (None if it has none).
+    pub fn set_label(&self, devnode: &str, label: Option<&str>) -> RepoResult<()> {
+        let mut conn = self.conn()?;
+        diesel::update(devices::table.filter(devices::devnode.eq(devnode)))
+            .set(devices::label.eq(label))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    /// Clear a stale `mount_success` flag (e.g. after a reboot dropped the real mount).
+    /// `mount_path` is kept so the next mount reuses the same target.
+    pub fn mark_unmounted(&self, devnode: &str) -> RepoResult<()> {
+        let mut conn = self.conn()?;
+        diesel::update(devices::table.filter(devices::devnode.eq(devnode)))
+            .set(devices::mount_success.eq(0))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    pub fn counts(&self) -> RepoResult<DeviceCounts> {
+        let mut conn = self.conn()?;
+        Ok(diesel::sql_query(
+            "SELECT COUNT(*) AS total, \
+             COALESCE(SUM(removed = 0 AND joined = 1), 0) AS joined, \
+             COALESCE(SUM(removed = 0 AND mount_success = 1), 0) AS mounted, \
+             COALESCE(SUM(removed = 1), 0) AS removed, \
+             COALESCE(SUM(removed = 0 AND read_only = 1), 0) AS read_only \
+             FROM devices",
+        )
+        .get_result::<DeviceCounts>(&mut conn)?)
+    }
+
+    pub fn purge_removed(&self, older_than: i64) -> RepoResult<usize> {
+        let mut conn = self.conn()?;
+        Ok(diesel::delete(
+            devices::table
+                .filter(devices::removed.eq(1))
+                .filter(devices::last_seen.lt(older_than))
+                .filter(devices::read_only.eq(0))
+                .filter(devices::health.is_null().or(devices::health.ne("failed"))),
+        )
+        .execute(&mut conn)?)
+    }
 }
 
 /// Repository interface for device-related queries and mutations.
 pub trait DeviceRepo: Send + Sync + 'static {
-    fn upsert_device(&self, devnode: &str, uuid: &str, ts: i64) -> Result<()>;
-    fn mark_removed(&self, devnode: &str, ts: i64) -> Result<()>;
-    fn list_joined_active(&self) -> Result<Vec<DeviceMountRow>>;
-    fn get_active_uuid(&self) -> Result<Option<String>>;
-    fn update_mount_result(&self, devnode: &str, mount_path: &str, uuid: &str) -> Result<()>;
-    fn mark_mounted_existing(&self, devnode: &str, mount_path: &str, uuid: &str) -> Result<()>;
+    /// Run `f` inside an IMMEDIATE transaction; an error from `f` rolls back all its writes.
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&mut SqliteConnection) -> RepoResult<()>,
+    ) -> RepoResult<()>;
+    /// Record `uuid` as present at `devnode`. True if the device was seen for the first time.
+    fn upsert_device(&self, devnode: &str, uuid: &str, ts: i64) -> RepoResult<bool>;
+    fn mark_removed(&self, devnode: &str, ts: i64) -> RepoResult<()>;
+    fn list_joined_active(&self) -> RepoResult<Vec<DeviceMountRow>>;
+    fn list_all(&self) -> RepoResult<Vec<Device>>;
+    fn get_active_uuid(&self) -> RepoResult<Option<String>>;
+    fn update_mount_result(&self, devnode: &str, mount_path: &str, uuid: &str) -> RepoResult<()>;
+    fn mark_mounted_existing(&self, devnode: &str, mount_path: &str, uuid: &str) -> RepoResult<()>;
+    fn mark_unmounted(&self, devnode: &str) -> RepoResult<()>;
+    fn join_device(&self, uuid: &str) -> RepoResult<bool>;
+    fn set_read_only(&self, uuid: &str, read_only: bool) -> RepoResult<bool>;
+    fn set_health(&self, devnode: &str, health: &str) -> RepoResult<()>;
+    fn set_capacity(&self, devnode: &str, total_bytes: i64, free_bytes: i64) -> RepoResult<()>;
+    /// Flag (or clear) a device as below the free-space floor. True if the flag changed.
+    fn set_low_space(&self, devnode: &str, low: bool) -> RepoResult<bool>;
+    fn set_identity(&self, devnode: &str, uuid: &str, fstype: Option<&str>) -> RepoResult<()>;
+    fn set_label(&self, devnode: &str, label: Option<&str>) -> RepoResult<()>;
+    /// Delete rows of devices removed before `older_than` (epoch seconds). Returns how many.
+    /// Devices flagged read-only or failing SMART are kept, so the flag is still there if
+    /// the drive is plugged back in.
+    fn purge_removed(&self, older_than: i64) -> RepoResult<usize>;
+    /// Devices per state, in one query.
+    fn counts(&self) -> RepoResult<DeviceCounts>;
 }
 
 impl DeviceRepo for DeviceRepoImpl {
-    fn upsert_device(&self, devnode: &str, uuid: &str, ts: i64) -> Result<()> {
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&mut SqliteConnection) -> RepoResult<()>,
+    ) -> RepoResult<()> {
+        DeviceRepoImpl::transaction(self, f)
+    }
+
+    fn upsert_device(&self, devnode: &str, uuid: &str, ts: i64) -> RepoResult<bool> {
         DeviceRepoImpl::upsert_device(self, devnode, uuid, ts)
     }
 
-    fn mark_removed(&self, devnode: &str, ts: i64) -> Result<()> {
+    fn mark_removed(&self, devnode: &str, ts: i64) -> RepoResult<()> {
         DeviceRepoImpl::mark_removed(self, devnode, ts)
     }
 
-    fn list_joined_active(&self) -> Result<Vec<DeviceMountRow>> {
+    fn list_joined_active(&self) -> RepoResult<Vec<DeviceMountRow>> {
         DeviceRepoImpl::list_joined_active(self)
     }
 
-    fn get_active_uuid(&self) -> Result<Option<String>> {
+    fn list_all(&self) -> RepoResult<Vec<Device>> {
+        DeviceRepoImpl::list_all(self)
+    }
+
+    fn get_active_uuid(&self) -> RepoResult<Option<String>> {
         DeviceRepoImpl::get_active_uuid(self)
     }
 
-    fn update_mount_result(&self, devnode: &str, mount_path: &str, uuid: &str) -> Result<()> {
+    fn update_mount_result(&self, devnode: &str, mount_path: &str, uuid: &str) -> RepoResult<()> {
         DeviceRepoImpl::update_mount_result(self, devnode, mount_path, uuid)
     }
 
-    fn mark_mounted_existing(&self, devnode: &str, mount_path: &str, uuid: &str) -> Result<()> {
+    fn mark_mounted_existing(&self, devnode: &str, mount_path: &str, uuid: &str) -> RepoResult<()> {
         DeviceRepoImpl::mark_mounted_existing(self, devnode, mount_path, uuid)
     }
+
+    fn mark_unmounted(&self, devnode: &str) -> RepoResult<()> {
+        DeviceRepoImpl::mark_unmounted(self, devnode)
+    }
+
+    fn join_device(&self, uuid: &str) -> RepoResult<bool> {
+        DeviceRepoImpl::join_device(self, uuid)
+    }
+
+    fn set_read_only(&self, uuid: &str, read_only: bool) -> RepoResult<bool> {
+        DeviceRepoImpl::set_read_only(self, uuid, read_only)
+    }
+
+    fn set_health(&self, devnode: &str, health: &str) -> RepoResult<()> {
+        DeviceRepoImpl::set_health(self, devnode, health)
+    }
+
+    fn set_capacity(&self, devnode: &str, total_bytes: i64, free_bytes: i64) -> RepoResult<()> {
+        DeviceRepoImpl::set_capacity(self, devnode, total_bytes, free_bytes)
+    }
+
+    fn set_low_space(&self, devnode: &str, low: bool) -> RepoResult<bool> {
+        DeviceRepoImpl::set_low_space(self, devnode, low)
+    }
+
+    fn set_identity(&self, devnode: &str, uuid: &str, fstype: Option<&str>) -> RepoResult<()> {
+        DeviceRepoImpl::set_identity(self, devnode, uuid, fstype)
+    }
+
+    fn set_label(&self, devnode: &str, label: Option<&str>) -> RepoResult<()> {
+        DeviceRepoImpl::set_label(self, devnode, label)
+    }
+
+    fn purge_removed(&self, older_than: i64) -> RepoResult<usize> {
+        DeviceRepoImpl::purge_removed(self, older_than)
+    }
+
+    fn counts(&self) -> RepoResult<DeviceCounts> {
+        DeviceRepoImpl::counts(self)
+    }
 }
 
 /// Create a new device repository instance. The concrete type is hidden; callers only see the trait.
 pub fn new_device_repo(pool: Pool) -> impl DeviceRepo {
     DeviceRepoImpl::new(pool)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{seed_device, temp_pool};
+
+    fn joined(pool: &Pool, uuid: &str) -> i32 {
+        let mut conn = pool.get().unwrap();
+        devices::table
+            .filter(devices::uuid.eq(uuid))
+            .select(devices::joined)
+            .first(&mut conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn transaction_rolls_back_on_error() {
+        let pool = temp_pool();
+        let repo = new_device_repo(pool.clone());
+        repo.upsert_device("/dev/sda1", "u1", 1).unwrap();
+
+        let res = repo.transaction(&mut |c| {
+            diesel::update(devices::table.filter(devices::uuid.eq("u1")))
+                .set(devices::joined.eq(1))
+                .execute(c)?;
+            Err(anyhow::anyhow!("boom after first write").into())
+        });
+        assert!(res.is_err());
+        assert_eq!(joined(&pool, "u1"), 0);
+    }
+
+    #[test]
+    fn join_device_requires_active_row() {
+        let pool = temp_pool();
+        let repo = new_device_repo(pool.clone());
+        repo.upsert_device("/dev/sda1", "u1", 1).unwrap();
+        repo.upsert_device("/dev/sdb1", "u2", 1).unwrap();
+        repo.mark_removed("/dev/sdb1", 2).unwrap();
+
+        assert!(repo.join_device("u1").unwrap());
+        assert_eq!(joined(&pool, "u1"), 1);
+        assert!(!repo.join_device("u2").unwrap());
+        assert_eq!(joined(&pool, "u2"), 0);
+        assert!(!repo.join_device("missing").unwrap());
+    }
+
+    #[test]
+    fn read_only_device_is_not_active() {
+        let pool = temp_pool();
+        seed_device(&pool, "/dev/sda1", "u1");
+        let repo = new_device_repo(pool.clone());
+        assert_eq!(repo.get_active_uuid().unwrap().as_deref(), Some("u1"));
+
+        assert!(repo.set_read_only("u1", true).unwrap());
+        assert_eq!(repo.get_active_uuid().unwrap(), None);
+        assert!(repo.set_read_only("u1", false).unwrap());
+        assert_eq!(repo.get_active_uuid().unwrap().as_deref(), Some("u1"));
+        assert!(!repo.set_read_only("missing", true).unwrap());
+    }
+
+    #[test]
+    fn purge_removed_keeps_recent_and_active_rows() {
+        let pool = temp_pool();
+        let repo = new_device_repo(pool.clone());
+        repo.upsert_device("/dev/sda1", "old", 1).unwrap();
+        repo.upsert_device("/dev/sdb1", "recent", 1).unwrap();
+        repo.upsert_device("/dev/sdc1", "active", 1).unwrap();
+        repo.upsert_device("/dev/sdd1", "pinned", 1).unwrap();
+        repo.upsert_device("/dev/sde1", "failing", 1).unwrap();
+        repo.set_read_only("pinned", true).unwrap();
+        repo.set_health("/dev/sde1", "failed").unwrap();
+        repo.mark_removed("/dev/sda1", 100).unwrap();
+        repo.mark_removed("/dev/sdb1", 900).unwrap();
+        repo.mark_removed("/dev/sdd1", 100).unwrap();
+        repo.mark_removed("/dev/sde1", 100).unwrap();
+
+        assert_eq!(repo.purge_removed(500).unwrap(), 1);
+        let mut left: Vec<String> = repo
+            .list_all()
+            .unwrap()
+            .into_iter()
+            .filter_map(|d| d.uuid)
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["active", "failing", "pinned", "recent"]);
+        assert_eq!(repo.purge_removed(500).unwrap(), 0);
+    }
+
+    #[test]
+    fn counts_devices_per_state() {
+        let pool = temp_pool();
+        let repo = new_device_repo(pool.clone());
+        assert_eq!(repo.counts().unwrap(), DeviceCounts::default());
+
+        seed_device(&pool, "/dev/sda1", "mounted");
+        seed_device(&pool, "/dev/sdb1", "full");
+        repo.set_read_only("full", true).unwrap();
+        repo.upsert_device("/dev/sdc1", "new", 1).unwrap();
+        repo.upsert_device("/dev/sdd1", "joined", 1).unwrap();
+        repo.join_device("joined").unwrap();
+        seed_device(&pool, "/dev/sde1", "gone");
+        repo.mark_removed("/dev/sde1", 2).unwrap();
+
+        assert_eq!(
+            repo.counts().unwrap(),
+            DeviceCounts {
+                total: 5,
+                joined: 3,
+                mounted: 2,
+                removed: 1,
+                read_only: 1,
+            }
+        );
+    }
+}
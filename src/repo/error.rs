@@ -0,0 +1,87 @@
+use std::fmt;
+
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+/// Failure of a repository call, classified so callers can tell a missing row or a
+/// constraint violation from a database that is merely busy. Anything wanting an
+/// `anyhow::Error` can still use `?`.
+#[derive(Debug)]
+pub enum RepoError {
+    /// The row the call needs doesn't exist.
+    NotFound,
+    /// A unique or foreign-key constraint refused the write.
+    Conflict(String),
+    /// SQLite stayed locked past the busy timeout, or no pooled connection freed up in time;
+    /// worth retrying.
+    Locked(String),
+    Other(anyhow::Error),
+}
+
+pub type RepoResult<T> = std::result::Result<T, RepoError>;
+
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => f.write_str("row not found"),
+            Self::Conflict(msg) => write!(f, "constraint violation: {msg}"),
+            Self::Locked(msg) => write!(f, "database busy: {msg}"),
+            Self::Other(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for RepoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<DieselError> for RepoError {
+    fn from(e: DieselError) -> Self {
+        match e {
+            DieselError::NotFound => Self::NotFound,
+            DieselError::DatabaseError(
+                DatabaseErrorKind::UniqueViolation | DatabaseErrorKind::ForeignKeyViolation,
+                info,
+            ) => Self::Conflict(info.message().to_string()),
+            // SQLITE_BUSY / SQLITE_LOCKED have no kind of their own
+            DieselError::DatabaseError(_, info) if info.message().contains("locked") => {
+                Self::Locked(info.message().to_string())
+            }
+            e => Self::Other(e.into()),
+        }
+    }
+}
+
+/// Pool checkout timed out: every connection is in use.
+impl From<r2d2::Error> for RepoError {
+    fn from(e: r2d2::Error) -> Self {
+        Self::Locked(e.to_string())
+    }
+}
+
+impl From<anyhow::Error> for RepoError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Other(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diesel_errors_are_classified() {
+        assert!(matches!(
+            RepoError::from(DieselError::NotFound),
+            RepoError::NotFound
+        ));
+        assert!(matches!(
+            RepoError::from(DieselError::RollbackTransaction),
+            RepoError::Other(_)
+        ));
+    }
+}
@@ -1,9 +1,9 @@
-use anyhow::Result;
 use diesel::prelude::*;
 
 use crate::{
     db::Pool,
     entity::file_meta::{FileMeta, NewFileMeta},
+    repo::RepoResult,
     schema::files,
 };
 
@@ -18,35 +18,18 @@ impl FileRepoImpl {
 
     fn conn(
         &self,
-    ) -> Result<r2d2::PooledConnection<diesel::r2d2::ConnectionManager<SqliteConnection>>> {
+    ) -> RepoResult<r2d2::PooledConnection<diesel::r2d2::ConnectionManager<SqliteConnection>>> {
         Ok(self.pool.get()?)
     }
 
-    pub fn insert_file(
-        &self,
-        key: &str,
-        filename: &str,
-        content_type: Option<&str>,
-        size: i64,
-        path: &str,
-        created_at: i64,
-    ) -> Result<usize> {
+    pub fn insert_file(&self, row: &NewFileMeta<'_>) -> RepoResult<usize> {
         let mut conn = self.conn()?;
-        let new_row = NewFileMeta {
-            key,
-            filename,
-            content_type,
-            size,
-            path,
-            created_at,
-            deleted: 0,
-        };
         Ok(diesel::insert_into(files::table)
-            .values(&new_row)
+            .values(row)
             .execute(&mut conn)?)
     }
 
-    pub fn get_by_key(&self, key: &str) -> Result<Option<FileMeta>> {
+    pub fn get_by_key(&self, key: &str) -> RepoResult<Option<FileMeta>> {
         let mut conn = self.conn()?;
         let res = files::table
             .filter(files::key.eq(key))
@@ -56,55 +39,534 @@ impl FileRepoImpl {
         Ok(res)
     }
 
-    pub fn soft_delete(&self, key: &str) -> Result<usize> {
+    /// Like `get_by_key`, but only matches rows owned by `tenant` (None = untenanted rows).
+    pub fn get_by_key_in_tenant(
+        &self,
+        key: &str,
+        tenant: Option<&str>,
+    ) -> RepoResult<Option<FileMeta>> {
+        let mut conn = self.conn()?;
+        let query = files::table
+            .filter(files::key.eq(key))
+            .filter(files::deleted.eq(0))
+            .into_boxed();
+        let query = match tenant {
+            Some(t) => query.filter(files::tenant.eq(t)),
+            None => query.filter(files::tenant.is_null()),
+        };
+        Ok(query.first::<FileMeta>(&mut conn).optional()?)
+    }
+
+    pub fn get_deleted_by_key(&self, key: &str) -> RepoResult<Option<FileMeta>> {
+        let mut conn = self.conn()?;
+        Ok(files::table
+            .filter(files::key.eq(key))
+            .filter(files::deleted.eq(1))
+            .first::<FileMeta>(&mut conn)
+            .optional()?)
+    }
+
+    pub fn get_by_keys(&self, keys: &[&str]) -> RepoResult<Vec<FileMeta>> {
+        let mut conn = self.conn()?;
+        Ok(files::table
+            .filter(files::key.eq_any(keys))
+            .filter(files::deleted.eq(0))
+            .load::<FileMeta>(&mut conn)?)
+    }
+
+    pub fn list_by_device(&self, device_uuid: &str) -> RepoResult<Vec<FileMeta>> {
+        let mut conn = self.conn()?;
+        Ok(files::table
+            .filter(files::device_uuid.eq(device_uuid))
+            .filter(files::deleted.eq(0))
+            .order(files::id.asc())
+            .load::<FileMeta>(&mut conn)?)
+    }
+
+    pub fn list_by_key_prefix(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        tenant: Option<&str>,
+        limit: i64,
+    ) -> RepoResult<Vec<FileMeta>> {
+        let mut conn = self.conn()?;
+        // every key starting with `prefix` sorts (bytewise) between it and prefix + U+10FFFF
+        let mut query = files::table
+            .filter(files::key.ge(prefix))
+            .filter(files::key.lt(format!("{prefix}\u{10FFFF}")))
+            .filter(files::deleted.eq(0))
+            .order(files::key.asc())
+            .limit(limit)
+            .into_boxed();
+        if let Some(after) = after {
+            query = query.filter(files::key.gt(after));
+        }
+        let query = match tenant {
+            Some(t) => query.filter(files::tenant.eq(t)),
+            None => query.filter(files::tenant.is_null()),
+        };
+        Ok(query.load::<FileMeta>(&mut conn)?)
+    }
+
+    pub fn list_live_page(&self, after_id: i32, limit: i64) -> RepoResult<Vec<FileMeta>> {
+        let mut conn = self.conn()?;
+        Ok(files::table
+            .filter(files::id.gt(after_id))
+            .filter(files::deleted.eq(0))
+            .order(files::id.asc())
+            .limit(limit)
+            .load::<FileMeta>(&mut conn)?)
+    }
+
+    pub fn key_exists(&self, key: &str) -> RepoResult<bool> {
+        let mut conn = self.conn()?;
+        Ok(
+            diesel::select(diesel::dsl::exists(files::table.filter(files::key.eq(key))))
+                .get_result(&mut conn)?,
+        )
+    }
+
+    pub fn count_active(&self) -> RepoResult<i64> {
+        let mut conn = self.conn()?;
+        Ok(files::table
+            .filter(files::deleted.eq(0))
+            .count()
+            .get_result(&mut conn)?)
+    }
+
+    pub fn count_by_device(&self, device_uuid: &str) -> RepoResult<i64> {
+        let mut conn = self.conn()?;
+        Ok(files::table
+            .filter(files::device_uuid.eq(device_uuid))
+            .filter(files::deleted.eq(0))
+            .count()
+            .get_result(&mut conn)?)
+    }
+
+    pub fn total_active_bytes(&self) -> RepoResult<i64> {
+        let mut conn = self.conn()?;
+        // diesel types SUM over BIGINT as Numeric, which sqlite can't decode to i64
+        Ok(files::table
+            .filter(files::deleted.eq(0))
+            .select(diesel::dsl::sql::<diesel::sql_types::BigInt>(
+                "COALESCE(SUM(size), 0)",
+            ))
+            .first(&mut conn)?)
+    }
+
+    pub fn applied_migrations(&self) -> RepoResult<Vec<String>> {
+        let mut conn = self.conn()?;
+        Ok(crate::db::applied_migrations(&mut conn)?)
+    }
+
+    pub fn list_expired(&self, now: i64) -> RepoResult<Vec<FileMeta>> {
+        let mut conn = self.conn()?;
+        Ok(files::table
+            .filter(files::expires_at.le(now))
+            .filter(files::deleted.eq(0))
+            .order(files::id.asc())
+            .load::<FileMeta>(&mut conn)?)
+    }
+
+    pub fn touch(&self, key: &str, retain_until: i64) -> RepoResult<bool> {
+        let mut conn = self.conn()?;
+        let updated = diesel::update(
+            files::table
+                .filter(files::key.eq(key))
+                .filter(files::deleted.eq(0)),
+        )
+        .set(files::retain_until.eq(Some(retain_until)))
+        .execute(&mut conn)?;
+        Ok(updated > 0)
+    }
+
+    pub fn set_path(&self, key: &str, path: &str) -> RepoResult<bool> {
+        let mut conn = self.conn()?;
+        let updated = diesel::update(files::table.filter(files::key.eq(key)))
+            .set(files::path.eq(path))
+            .execute(&mut conn)?;
+        Ok(updated > 0)
+    }
+
+    pub fn soft_delete(&self, key: &str) -> RepoResult<usize> {
         let mut conn = self.conn()?;
         Ok(diesel::update(files::table.filter(files::key.eq(key)))
             .set(files::deleted.eq(1))
             .execute(&mut conn)?)
     }
+
+    pub fn prune_versions(
+        &self,
+        tenant: Option<&str>,
+        filename: &str,
+        keep: u32,
+        now: i64,
+    ) -> RepoResult<Vec<FileMeta>> {
+        let mut conn = self.conn()?;
+        conn.immediate_transaction(|conn| {
+            let query = files::table
+                .filter(files::filename.eq(filename))
+                .filter(files::deleted.eq(0))
+                .into_boxed();
+            let query = match tenant {
+                Some(t) => query.filter(files::tenant.eq(t)),
+                None => query.filter(files::tenant.is_null()),
+            };
+            let older: Vec<FileMeta> = query
+                .order(files::id.desc())
+                .offset(keep as i64)
+                .load::<FileMeta>(conn)?
+                .into_iter()
+                .filter(|m| !m.is_retained(now))
+                .collect();
+            let ids: Vec<i32> = older.iter().map(|m| m.id).collect();
+            diesel::update(files::table.filter(files::id.eq_any(&ids)))
+                .set(files::deleted.eq(1))
+                .execute(conn)?;
+            Ok(older)
+        })
+    }
 }
 
 /// Repository interface for file metadata operations.
 /// Public trait; concrete implementation is private to this module.
 pub trait FileRepo: Send + Sync + 'static {
-    fn insert_file(
+    fn insert_file(&self, row: &NewFileMeta<'_>) -> RepoResult<usize>;
+
+    fn get_by_key(&self, key: &str) -> RepoResult<Option<FileMeta>>;
+
+    /// Live object `key` owned by `tenant`; rows of other tenants are invisible.
+    fn get_by_key_in_tenant(&self, key: &str, tenant: Option<&str>)
+    -> RepoResult<Option<FileMeta>>;
+
+    /// The soft-deleted row of `key`, if the object was deleted and not purged yet.
+    fn get_deleted_by_key(&self, key: &str) -> RepoResult<Option<FileMeta>>;
+
+    /// Live objects among `keys` in one query; missing or deleted keys are simply absent.
+    fn get_by_keys(&self, keys: &[&str]) -> RepoResult<Vec<FileMeta>>;
+
+    /// Live (non-deleted) objects stored on `device_uuid`, oldest first.
+    fn list_by_device(&self, device_uuid: &str) -> RepoResult<Vec<FileMeta>>;
+
+    /// Up to `limit` live objects of `tenant` whose key starts with `prefix` (literally and
+    /// case-sensitively), in key order, starting after key `after` when given.
+    fn list_by_key_prefix(
         &self,
-        key: &str,
+        prefix: &str,
+        after: Option<&str>,
+        tenant: Option<&str>,
+        limit: i64,
+    ) -> RepoResult<Vec<FileMeta>>;
+
+    /// Up to `limit` live objects with `id > after_id`, by id; for walking the whole table.
+    fn list_live_page(&self, after_id: i32, limit: i64) -> RepoResult<Vec<FileMeta>>;
+
+    /// Mark `key` deleted; the row stays for auditing but no lookup returns it.
+    /// This is the only delete on the repo, used by every caller.
+    fn soft_delete(&self, key: &str) -> RepoResult<usize>;
+
+    /// Soft-delete all but the newest `keep` live rows named `filename` in `tenant`
+    /// (None = untenanted rows), sparing rows still under retention at `now`. Returns the
+    /// rows deleted; their bytes are the caller's to remove.
+    fn prune_versions(
+        &self,
+        tenant: Option<&str>,
         filename: &str,
-        content_type: Option<&str>,
-        size: i64,
-        path: &str,
-        created_at: i64,
-    ) -> Result<usize>;
+        keep: u32,
+        now: i64,
+    ) -> RepoResult<Vec<FileMeta>>;
 
-    fn get_by_key(&self, key: &str) -> Result<Option<FileMeta>>;
+    /// Live objects whose TTL ran out at or before `now`.
+    fn list_expired(&self, now: i64) -> RepoResult<Vec<FileMeta>>;
 
-    fn soft_delete(&self, key: &str) -> Result<usize>;
+    /// Set the retention deadline of live object `key`. False if there is no such object.
+    fn touch(&self, key: &str, retain_until: i64) -> RepoResult<bool>;
+
+    /// Record that object `key` now lives at `path`. False if there is no such object.
+    fn set_path(&self, key: &str, path: &str) -> RepoResult<bool>;
+
+    /// Whether any row holds `key`. Soft-deleted rows count: `files.key` is unique, so
+    /// a deleted object's key can't be reused.
+    fn key_exists(&self, key: &str) -> RepoResult<bool>;
+
+    /// Number of live (non-deleted) objects.
+    fn count_active(&self) -> RepoResult<i64>;
+
+    /// Number of live objects stored on `device_uuid`; `list_by_device` without the rows.
+    fn count_by_device(&self, device_uuid: &str) -> RepoResult<i64>;
+
+    /// Sum of `size` over live objects.
+    fn total_active_bytes(&self) -> RepoResult<i64>;
+
+    /// Schema migrations applied to the database behind this repo.
+    fn applied_migrations(&self) -> RepoResult<Vec<String>>;
 }
 
 impl FileRepo for FileRepoImpl {
-    fn insert_file(
+    fn insert_file(&self, row: &NewFileMeta<'_>) -> RepoResult<usize> {
+        Self::insert_file(self, row)
+    }
+
+    fn get_by_key(&self, key: &str) -> RepoResult<Option<FileMeta>> {
+        Self::get_by_key(self, key)
+    }
+
+    fn get_by_key_in_tenant(
         &self,
         key: &str,
-        filename: &str,
-        content_type: Option<&str>,
-        size: i64,
-        path: &str,
-        created_at: i64,
-    ) -> Result<usize> {
-        Self::insert_file(self, key, filename, content_type, size, path, created_at)
+        tenant: Option<&str>,
+    ) -> RepoResult<Option<FileMeta>> {
+        Self::get_by_key_in_tenant(self, key, tenant)
     }
 
-    fn get_by_key(&self, key: &str) -> Result<Option<FileMeta>> {
-        Self::get_by_key(self, key)
+    fn get_deleted_by_key(&self, key: &str) -> RepoResult<Option<FileMeta>> {
+        Self::get_deleted_by_key(self, key)
+    }
+
+    fn get_by_keys(&self, keys: &[&str]) -> RepoResult<Vec<FileMeta>> {
+        Self::get_by_keys(self, keys)
+    }
+
+    fn list_by_device(&self, device_uuid: &str) -> RepoResult<Vec<FileMeta>> {
+        Self::list_by_device(self, device_uuid)
+    }
+
+    fn list_by_key_prefix(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        tenant: Option<&str>,
+        limit: i64,
+    ) -> RepoResult<Vec<FileMeta>> {
+        Self::list_by_key_prefix(self, prefix, after, tenant, limit)
     }
 
-    fn soft_delete(&self, key: &str) -> Result<usize> {
+    fn list_live_page(&self, after_id: i32, limit: i64) -> RepoResult<Vec<FileMeta>> {
+        Self::list_live_page(self, after_id, limit)
+    }
+
+    fn soft_delete(&self, key: &str) -> RepoResult<usize> {
         Self::soft_delete(self, key)
     }
+
+    fn prune_versions(
+        &self,
+        tenant: Option<&str>,
+        filename: &str,
+        keep: u32,
+        now: i64,
+    ) -> RepoResult<Vec<FileMeta>> {
+        Self::prune_versions(self, tenant, filename, keep, now)
+    }
+
+    fn list_expired(&self, now: i64) -> RepoResult<Vec<FileMeta>> {
+        Self::list_expired(self, now)
+    }
+
+    fn touch(&self, key: &str, retain_until: i64) -> RepoResult<bool> {
+        Self::touch(self, key, retain_until)
+    }
+
+    fn set_path(&self, key: &str, path: &str) -> RepoResult<bool> {
+        Self::set_path(self, key, path)
+    }
+
+    fn key_exists(&self, key: &str) -> RepoResult<bool> {
+        Self::key_exists(self, key)
+    }
+
+    fn count_active(&self) -> RepoResult<i64> {
+        Self::count_active(self)
+    }
+
+    fn count_by_device(&self, device_uuid: &str) -> RepoResult<i64> {
+        Self::count_by_device(self, device_uuid)
+    }
+
+    fn total_active_bytes(&self) -> RepoResult<i64> {
+        Self::total_active_bytes(self)
+    }
+
+    fn applied_migrations(&self) -> RepoResult<Vec<String>> {
+        Self::applied_migrations(self)
+    }
 }
 
 /// Create a new file repository instance. The concrete type is hidden; callers only see the trait.
 pub fn new_file_repo(pool: Pool) -> impl FileRepo {
     FileRepoImpl::new(pool)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{ConnectionOptions, establish_pool_with_options};
+    use crate::repo::RepoError;
+    use crate::test_support::{temp_dir, temp_pool};
+    use diesel::connection::SimpleConnection;
+
+    #[test]
+    fn count_active_excludes_soft_deleted() {
+        let repo = new_file_repo(temp_pool());
+        assert_eq!(repo.count_active().unwrap(), 0);
+        assert_eq!(repo.total_active_bytes().unwrap(), 0);
+        for (key, size) in [("a", 10), ("b", 20), ("c", 30)] {
+            repo.insert_file(&NewFileMeta {
+                key,
+                filename: "f.bin",
+                content_type: None,
+                size,
+                path: "/tmp/f.bin",
+                created_at: 0,
+                deleted: 0,
+                device_uuid: Some("dev"),
+                sha256: None,
+                retain_until: None,
+                tenant: None,
+                expires_at: None,
+                filename_supplied: 1,
+                compressed: 0,
+                uncompressed_size: None,
+            })
+            .unwrap();
+        }
+        repo.soft_delete("b").unwrap();
+        assert_eq!(repo.count_active().unwrap(), 2);
+        assert_eq!(repo.count_by_device("dev").unwrap(), 2);
+        assert_eq!(repo.count_by_device("other").unwrap(), 0);
+        assert_eq!(repo.total_active_bytes().unwrap(), 40);
+    }
+
+    #[test]
+    fn prefix_listing_matches_literally_and_in_key_order() {
+        let repo = new_file_repo(temp_pool());
+        for (key, tenant) in [
+            ("logs-b", None),
+            ("logs-a", None),
+            ("logsx", None),
+            ("Logs-c", None),
+            ("logs-t", Some("t1")),
+            ("50%_off", None),
+            ("50xxoff", None),
+            ("a_b", None),
+            ("axb", None),
+        ] {
+            repo.insert_file(&NewFileMeta {
+                key,
+                filename: "f.bin",
+                content_type: None,
+                size: 1,
+                path: "/tmp/f.bin",
+                created_at: 0,
+                deleted: 0,
+                device_uuid: Some("dev"),
+                sha256: None,
+                retain_until: None,
+                tenant,
+                expires_at: None,
+                filename_supplied: 1,
+                compressed: 0,
+                uncompressed_size: None,
+            })
+            .unwrap();
+        }
+        repo.soft_delete("logs-b").unwrap();
+        let keys = |prefix: &str, tenant: Option<&str>, limit: i64| -> Vec<String> {
+            repo.list_by_key_prefix(prefix, None, tenant, limit)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.key)
+                .collect()
+        };
+
+        assert_eq!(keys("logs", None, 10), vec!["logs-a", "logsx"]);
+        assert_eq!(keys("logs-", None, 10), vec!["logs-a"]);
+        assert_eq!(keys("logs", Some("t1"), 10), vec!["logs-t"]);
+        assert_eq!(keys("50%", None, 10), vec!["50%_off"]);
+        assert_eq!(keys("a_", None, 10), vec!["a_b"]);
+        assert_eq!(keys("", None, 2), vec!["50%_off", "50xxoff"]);
+        assert_eq!(keys("Logs", None, 10), vec!["Logs-c"]);
+
+        let page = |after: &str| -> Vec<String> {
+            repo.list_by_key_prefix("logs", Some(after), None, 10)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.key)
+                .collect()
+        };
+        assert_eq!(page("logs-a"), vec!["logsx"]);
+        assert_eq!(page("a"), vec!["logs-a", "logsx"]);
+        assert!(page("logsx").is_empty());
+    }
+
+    #[test]
+    fn soft_deleted_rows_are_hidden_from_lookups() {
+        let repo = new_file_repo(temp_pool());
+        repo.insert_file(&NewFileMeta {
+            key: "gone",
+            filename: "f.bin",
+            content_type: None,
+            size: 1,
+            path: "/tmp/f.bin",
+            created_at: 0,
+            deleted: 0,
+            device_uuid: Some("dev"),
+            sha256: None,
+            retain_until: None,
+            tenant: None,
+            expires_at: None,
+            filename_supplied: 1,
+            compressed: 0,
+            uncompressed_size: None,
+        })
+        .unwrap();
+        assert_eq!(repo.soft_delete("gone").unwrap(), 1);
+        assert!(repo.get_by_key("gone").unwrap().is_none());
+        assert!(repo.get_by_key_in_tenant("gone", None).unwrap().is_none());
+        assert!(repo.get_by_keys(&["gone"]).unwrap().is_empty());
+        assert!(repo.list_by_device("dev").unwrap().is_empty());
+    }
+
+    #[test]
+    fn constraint_and_lock_failures_are_classified() {
+        let options = ConnectionOptions {
+            busy_timeout_ms: 0,
+            ..ConnectionOptions::default()
+        };
+        let pool = establish_pool_with_options(&temp_dir("db").join("t.db"), 2, options).unwrap();
+        let repo = new_file_repo(pool.clone());
+        let row = |key| NewFileMeta {
+            key,
+            filename: "f.bin",
+            content_type: None,
+            size: 1,
+            path: "/tmp/f.bin",
+            created_at: 0,
+            deleted: 0,
+            device_uuid: None,
+            sha256: None,
+            retain_until: None,
+            tenant: None,
+            expires_at: None,
+            filename_supplied: 1,
+            compressed: 0,
+            uncompressed_size: None,
+        };
+        repo.insert_file(&row("k")).unwrap();
+        assert!(matches!(
+            repo.insert_file(&row("k")),
+            Err(RepoError::Conflict(_))
+        ));
+
+        // another connection holds the write lock
+        let mut holder = pool.get().unwrap();
+        holder.batch_execute("BEGIN IMMEDIATE").unwrap();
+        assert!(matches!(
+            repo.insert_file(&row("k2")),
+            Err(RepoError::Locked(_))
+        ));
+        holder.batch_execute("ROLLBACK").unwrap();
+        repo.insert_file(&row("k2")).unwrap();
+    }
+}
@@ -1,2 +1,6 @@
+pub mod async_device_repo;
 pub mod device_repo;
+pub mod error;
 pub mod file_repo;
+
+pub use error::{RepoError, RepoResult};
@@ -1,8 +1,6 @@
-// @generated automatically by Diesel CLI (placeholder created by assistant)
-// This file will be overwritten by `diesel print-schema` after running migrations.
-// For now we declare the table manually to allow compilation.
-
-// See migrations in `migrations/` for authoritative schema.
+// Hand-maintained to match `migrations/`, which stay authoritative: a new migration
+// needs its columns added here too. `db::tests::schema_matches_migrations` compares the
+// two and fails on drift. `diesel print-schema` output can replace this file wholesale.
 
 diesel::table! {
     devices (id) {
@@ -14,6 +12,13 @@ diesel::table! {
         mount_success -> Integer,
         mount_path -> Nullable<Text>,
         last_seen -> BigInt,
+        read_only -> Integer,
+        health -> Nullable<Text>,
+        total_bytes -> Nullable<BigInt>,
+        free_bytes -> Nullable<BigInt>,
+        fstype -> Nullable<Text>,
+        low_space -> Integer,
+        label -> Nullable<Text>,
     }
 }
 
@@ -27,5 +32,13 @@ diesel::table! {
         path -> Text,
         created_at -> BigInt,
         deleted -> Integer,
+        device_uuid -> Nullable<Text>,
+        sha256 -> Nullable<Text>,
+        retain_until -> Nullable<BigInt>,
+        tenant -> Nullable<Text>,
+        expires_at -> Nullable<BigInt>,
+        filename_supplied -> Integer,
+        compressed -> Integer,
+        uncompressed_size -> Nullable<BigInt>,
     }
 }
@@ -0,0 +1,176 @@
+//! Upload target policies: which of the usable devices a new object goes to.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A device that can take an upload right now (joined, mounted, writable, not full).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub uuid: String,
+    /// Free space as of the mounter's last scan; None before the first one.
+    pub free_bytes: Option<i64>,
+}
+
+/// Picks the upload target among `candidates`. None only when there are none.
+pub trait DeviceSelector: Send + Sync + fmt::Debug {
+    fn select(&self, candidates: &[DeviceInfo]) -> Option<String>;
+}
+
+/// Built-in policies, chosen with `--device-selection`.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Deserialize,
+    serde::Serialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceSelection {
+    /// Any candidate, uniformly.
+    #[default]
+    Random,
+    /// Each candidate in turn.
+    RoundRobin,
+    /// The candidate with the most free space.
+    MostFree,
+}
+
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl DeviceSelector for Random {
+    fn select(&self, candidates: &[DeviceInfo]) -> Option<String> {
+        if candidates.is_empty() {
+            return None;
+        }
+        // Pseudo-random selection using current time nanos to avoid extra deps
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos() as usize;
+        Some(candidates[nanos % candidates.len()].uuid.clone())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl DeviceSelector for RoundRobin {
+    fn select(&self, candidates: &[DeviceInfo]) -> Option<String> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let turn = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(candidates[turn % candidates.len()].uuid.clone())
+    }
+}
+
+/// Devices without a capacity reading yet count as having no free space, so a device
+/// is only favoured once it has been measured.
+#[derive(Debug, Default)]
+pub struct MostFreeSpace;
+
+impl DeviceSelector for MostFreeSpace {
+    fn select(&self, candidates: &[DeviceInfo]) -> Option<String> {
+        candidates
+            .iter()
+            // first of equals, so the choice is stable between scans
+            .rev()
+            .max_by_key(|d| d.free_bytes.unwrap_or(0))
+            .map(|d| d.uuid.clone())
+    }
+}
+
+/// Always `primary` while it is a candidate; `fallback` decides otherwise.
+#[derive(Debug)]
+pub struct PrimaryPreferred {
+    pub primary: String,
+    pub fallback: Box<dyn DeviceSelector>,
+}
+
+impl DeviceSelector for PrimaryPreferred {
+    fn select(&self, candidates: &[DeviceInfo]) -> Option<String> {
+        if candidates.iter().any(|d| d.uuid == self.primary) {
+            return Some(self.primary.clone());
+        }
+        self.fallback.select(candidates)
+    }
+}
+
+/// The selector for `policy`, preferring `primary` when one is configured.
+pub fn selector(policy: DeviceSelection, primary: Option<String>) -> Box<dyn DeviceSelector> {
+    let base: Box<dyn DeviceSelector> = match policy {
+        DeviceSelection::Random => Box::new(Random),
+        DeviceSelection::RoundRobin => Box::new(RoundRobin::default()),
+        DeviceSelection::MostFree => Box::new(MostFreeSpace),
+    };
+    match primary {
+        Some(primary) => Box::new(PrimaryPreferred {
+            primary,
+            fallback: base,
+        }),
+        None => base,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates() -> Vec<DeviceInfo> {
+        [("u1", Some(10)), ("u2", Some(30)), ("u3", None)]
+            .into_iter()
+            .map(|(uuid, free_bytes)| DeviceInfo {
+                uuid: uuid.to_string(),
+                free_bytes,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_picks_a_candidate() {
+        let devices = candidates();
+        for _ in 0..20 {
+            let uuid = Random.select(&devices).unwrap();
+            assert!(devices.iter().any(|d| d.uuid == uuid));
+        }
+        assert_eq!(Random.select(&[]), None);
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let rr = RoundRobin::default();
+        let picks: Vec<String> = (0..4).map(|_| rr.select(&candidates()).unwrap()).collect();
+        assert_eq!(picks, vec!["u1", "u2", "u3", "u1"]);
+        assert_eq!(rr.select(&[]), None);
+    }
+
+    #[test]
+    fn most_free_prefers_the_emptiest_measured_device() {
+        assert_eq!(MostFreeSpace.select(&candidates()).as_deref(), Some("u2"));
+        let mut tied = candidates();
+        tied[0].free_bytes = Some(30);
+        assert_eq!(MostFreeSpace.select(&tied).as_deref(), Some("u1"));
+        assert_eq!(MostFreeSpace.select(&[]), None);
+    }
+
+    #[test]
+    fn primary_is_preferred_while_it_is_a_candidate() {
+        let sel = selector(DeviceSelection::MostFree, Some("u3".into()));
+        assert_eq!(sel.select(&candidates()).as_deref(), Some("u3"));
+        let without: Vec<DeviceInfo> = candidates()
+            .into_iter()
+            .filter(|d| d.uuid != "u3")
+            .collect();
+        assert_eq!(sel.select(&without).as_deref(), Some("u2"));
+    }
+}
@@ -5,32 +5,149 @@ use std::{
 };
 
 use actix_files::NamedFile;
-use actix_multipart::Multipart;
-use actix_web::{App, HttpResponse, HttpServer, Responder, delete, get, post, web};
+use actix_multipart::{Field, Multipart};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{
+    self, Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue, Header,
+};
+use actix_web::middleware::{Condition, Logger, Next, from_fn};
+use actix_web::{
+    App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder, delete, get, mime, post,
+    put, web,
+};
 use anyhow::Result;
-use futures_util::StreamExt;
-use log::{error, info};
+use futures_util::{Stream, StreamExt};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::sync::Mutex as StdMutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Instant;
-use tokio::sync::RwLock;
-use tokio::{fs as tokio_fs, io::AsyncWriteExt};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard, RwLock, broadcast};
+use tokio::{
+    fs as tokio_fs,
+    io::{AsyncWrite, AsyncWriteExt},
+};
+use tokio_util::io::{ReaderStream, StreamReader};
 use uuid::Uuid;
 
+use crate::compression;
+use crate::config::{self, FilenamePolicy};
+use crate::entity::device::Device;
+use crate::entity::file_meta::{FileMeta, NewFileMeta};
+use crate::export::{self, DuplicateNames};
+use crate::hooks::{HookDecision, NoopHook, PostUploadHook, UploadMeta};
+use crate::import::{self, TarEntries};
+use crate::io_errors::{IoErrorCounts, IoErrorTracker};
+use crate::logging;
+use crate::maintenance;
+use crate::progress::{ProgressEvent, SessionGuard, UploadRegistry, UploadSession};
+use crate::repo::RepoError;
+use crate::repo::async_device_repo::AsyncDeviceRepo;
 use crate::repo::device_repo::DeviceRepo;
 use crate::repo::file_repo::FileRepo;
-use crate::storage::{Storage, StorageImpl};
+use crate::selector::{self, DeviceInfo, DeviceSelection, DeviceSelector};
+use crate::service::{DeleteOutcome, Service};
+use crate::signing::{self, SignatureError};
+use crate::sniff;
+use crate::storage::{
+    Layout, Storage, StorageError, StorageImpl, TempNames, create_temp, drop_page_cache,
+    layout_path, publish,
+};
+use crate::system::{HostSystem, System, parse_mount_table};
+use crate::throttle;
+
+/// Lifetime of a signed download URL when the caller doesn't ask for one.
+const DEFAULT_SIGNED_URL_TTL_SECS: i64 = 3600;
+/// Longest lifetime a signed download URL may be issued for.
+const MAX_SIGNED_URL_TTL_SECS: i64 = 7 * 24 * 3600;
+
+/// Most keys accepted by one metadata batch request.
+const MAX_METADATA_BATCH: usize = 500;
+/// Most keys accepted by one zip download request.
+const MAX_ZIP_KEYS: usize = 1000;
+
+/// Objects returned by a key listing when the caller doesn't ask for a limit.
+const DEFAULT_LIST_LIMIT: i64 = 100;
+/// Most objects returned by one key listing.
+const MAX_LIST_LIMIT: i64 = 1000;
+
+/// Request header naming the tenant that owns uploaded objects; absent = no tenant.
+const TENANT_HEADER: &str = "X-Tenant";
+
+/// Correlation id header; taken from the request when valid, generated otherwise, and
+/// echoed on the response.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longest client-supplied request id that is trusted.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Text form field that overrides the file part's own filename.
+const FILENAME_FIELD: &str = "filename";
+/// Text fields kept per upload, and the longest value kept; anything beyond is ignored.
+const MAX_FORM_FIELDS: usize = 16;
+const MAX_FORM_FIELD_LEN: usize = 4096;
+
+/// Optional client-chosen id for an upload; its progress can then be followed at
+/// `GET /uploads/{id}/progress`.
+const UPLOAD_ID_HEADER: &str = "X-Upload-Id";
+
+/// Upload header carrying the object's original creation time (epoch seconds), for
+/// clients migrating a library that want to keep it.
+const ORIGINAL_CREATED_AT_HEADER: &str = "X-Original-Created-At";
+/// How far in the future an original creation time may lie (client clock skew).
+const MAX_CREATED_AT_SKEW_SECS: i64 = 24 * 3600;
+
+/// Upload header with the hex SHA-256 the client computed over the body; the upload is
+/// refused with 422 if the received bytes hash differently.
+const CONTENT_SHA256_HEADER: &str = "X-Content-SHA256";
+
+/// Download response header naming the device the bytes came from, when enabled.
+const DEVICE_HEADER: &str = "x-storage-device";
 
 #[derive(Clone)]
 struct AppState {
     storage: Arc<dyn Storage>,
     file_repo: Arc<dyn FileRepo>,
-    device_repo: Arc<dyn DeviceRepo>,
+    device_repo: AsyncDeviceRepo,
     device_cache: Arc<DeviceUuidCache>,
+    hook: Arc<dyn PostUploadHook>,
+    uploads: Arc<UploadRegistry>,
+    /// Kernel mount table, for `verify_mounts`.
+    system: Arc<dyn System>,
+    /// Last result of the storage root existence check; stays true when the check is off.
+    root_present: Arc<AtomicBool>,
+    io_errors: Arc<IoErrorTracker>,
+    write_locks: Arc<DeviceWriteLocks>,
+    config: Arc<ServerConfig>,
 }
 
+/// Device queries allowed on the blocking pool at once; more would only wait for a
+/// pooled connection there.
+const DEVICE_QUERY_CONCURRENCY: usize = 4;
+
+/// How long a device that hit ENOSPC stays deselected before it is tried again
+/// (deletes may have freed space in the meantime).
+const EXHAUSTED_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// `Retry-After` sent with 503s for a locked database: the lock is usually a single
+/// writer transaction, gone within a second.
+const DB_RETRY_AFTER_SECS: u64 = 1;
+
+/// `Retry-After` sent with 503s for objects on an unplugged device: a person has to plug
+/// it back in, so there is no point in hammering the server sooner.
+const DEVICE_OFFLINE_RETRY_AFTER_SECS: u64 = 60;
+
 #[derive(Debug)]
 struct DeviceUuidCache {
-    inner: RwLock<Option<(Vec<String>, Instant)>>,
+    inner: RwLock<Option<(Vec<DeviceInfo>, Instant)>>,
     ttl: Duration,
+    exhausted: StdMutex<HashMap<String, Instant>>,
+    /// Picks among the devices that are mounted, writable and not exhausted.
+    selector: Box<dyn DeviceSelector>,
 }
 
 impl DeviceUuidCache {
@@ -38,55 +155,457 @@ impl DeviceUuidCache {
         Self {
             inner: RwLock::new(None),
             ttl,
+            exhausted: StdMutex::new(HashMap::new()),
+            selector: Box::new(selector::Random),
         }
     }
 
-    // Get cached device UUID if fresh; otherwise fetch via repo and update cache
-    async fn get_or_fetch(&self, repo: Arc<dyn DeviceRepo>) -> actix_web::Result<String> {
-        let nanos = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .subsec_nanos() as usize;
-        // Read cache snapshot
-        if let Some((uuids, ts)) = { self.inner.read().await.clone() } {
-            if ts.elapsed() < self.ttl {
-                if uuids.is_empty() {
-                    return Err(actix_web::error::ErrorServiceUnavailable(
-                        "no active device uuid",
-                    ));
-                }
+    fn with_selector(mut self, selector: Box<dyn DeviceSelector>) -> Self {
+        self.selector = selector;
+        self
+    }
 
-                let idx = nanos % uuids.len();
-                return Ok(uuids[idx].clone());
-            }
+    /// Deselect `uuid` for uploads after its filesystem reported no space left.
+    fn mark_exhausted(&self, uuid: &str) {
+        self.exhausted
+            .lock()
+            .unwrap()
+            .insert(uuid.to_string(), Instant::now());
+    }
+
+    /// Drop the cached device list so the next upload re-reads it from the DB.
+    async fn invalidate(&self) {
+        *self.inner.write().await = None;
+    }
+
+    // Let the selector pick one of `devices` that is not marked exhausted
+    fn pick(&self, devices: &[DeviceInfo]) -> actix_web::Result<String> {
+        let usable: Vec<DeviceInfo> = {
+            let mut exhausted = self.exhausted.lock().unwrap();
+            exhausted.retain(|_, since| since.elapsed() < EXHAUSTED_RETRY_AFTER);
+            devices
+                .iter()
+                .filter(|d| !exhausted.contains_key(d.uuid.as_str()))
+                .cloned()
+                .collect()
+        };
+        self.selector
+            .select(&usable)
+            .ok_or_else(|| actix_web::error::ErrorServiceUnavailable("no active device uuid"))
+    }
+
+    // Get cached device UUID if fresh; otherwise fetch via repo and update cache.
+    // A zero TTL never caches, so every call queries the repo.
+    async fn get_or_fetch(&self, repo: &AsyncDeviceRepo) -> actix_web::Result<String> {
+        // Read cache snapshot
+        if let Some((devices, ts)) = { self.inner.read().await.clone() }
+            && ts.elapsed() < self.ttl
+        {
+            return self.pick(&devices);
         }
 
-        // Fetch from DB (blocking). Consider multiple devices: pick one at random among mounted.
-        let rows = web::block(move || repo.list_joined_active())
-            .await
-            .map_err(|e| {
-                actix_web::error::ErrorServiceUnavailable(format!("device uuid error: {e}"))
-            })?
-            .map_err(|e| {
-                actix_web::error::ErrorServiceUnavailable(format!("device uuid error: {e}"))
-            })?;
-        let candidates: Vec<String> = rows
+        // Fetch from DB. Consider multiple devices: the selector picks among mounted.
+        let rows = repo.list_joined_active().await.map_err(|e| {
+            actix_web::error::ErrorServiceUnavailable(format!("device uuid error: {e}"))
+        })?;
+        let candidates: Vec<DeviceInfo> = rows
             .into_iter()
-            .filter(|r| r.mount_success == 1)
-            .filter_map(|r| r.uuid)
+            .filter(|r| r.mount_success == 1 && r.read_only == 0 && r.low_space == 0)
+            .filter_map(|r| {
+                Some(DeviceInfo {
+                    uuid: r.uuid?,
+                    free_bytes: r.free_bytes,
+                })
+            })
             .collect();
-        {
+        if !self.ttl.is_zero() {
             let mut w = self.inner.write().await;
             *w = Some((candidates.clone(), Instant::now()));
         }
-        if candidates.is_empty() {
-            return Err(actix_web::error::ErrorServiceUnavailable(
-                "no active device uuid",
+        self.pick(&candidates)
+    }
+}
+
+/// One mutex per device, held while an upload's bytes are written when
+/// `serialize_device_writes` is on.
+#[derive(Debug, Default)]
+struct DeviceWriteLocks {
+    locks: StdMutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+impl DeviceWriteLocks {
+    /// Wait until no other upload is writing to `uuid`.
+    async fn lock(&self, uuid: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(uuid.to_string())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+}
+
+/// Upload target from the device cache, skipping devices that already hold
+/// `max_objects_per_device` objects. A device at the cap is deselected like one that ran
+/// out of space, so it is only recounted once `EXHAUSTED_RETRY_AFTER` has passed.
+async fn select_device(data: &AppState) -> actix_web::Result<String> {
+    let Some(cap) = data.config.max_objects_per_device else {
+        return data.device_cache.get_or_fetch(&data.device_repo).await;
+    };
+    let mut skipped = false;
+    loop {
+        let uuid = match data.device_cache.get_or_fetch(&data.device_repo).await {
+            Ok(uuid) => uuid,
+            Err(_) if skipped => {
+                return Err(actix_web::error::InternalError::new(
+                    "every device is at its object limit",
+                    StatusCode::INSUFFICIENT_STORAGE,
+                )
+                .into());
+            }
+            Err(e) => return Err(e),
+        };
+        let repo = data.file_repo.clone();
+        let device = uuid.clone();
+        let count = data
+            .block(move || repo.count_by_device(&device))
+            .await
+            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+            .map_err(db_error("count_by_device"))?;
+        if (count as u64) < cap {
+            return Ok(uuid);
+        }
+        warn!("device {uuid} holds {count} objects (limit {cap}), deselecting it");
+        data.device_cache.mark_exhausted(&uuid);
+        skipped = true;
+    }
+}
+
+/// Failure while streaming an upload body into its temp file.
+#[derive(Debug)]
+enum StreamWriteError {
+    /// The client stream failed (disconnect, malformed multipart).
+    Payload(String),
+    /// The target filesystem is out of space (ENOSPC).
+    StorageFull,
+    /// No chunk arrived within the idle timeout.
+    Stalled,
+    Io(std::io::Error),
+}
+
+impl From<StorageError> for actix_web::Error {
+    fn from(e: StorageError) -> Self {
+        let status = match &e {
+            StorageError::NotFound { .. } => StatusCode::NOT_FOUND,
+            StorageError::PermissionDenied { .. } => StatusCode::FORBIDDEN,
+            StorageError::StorageFull { .. } => StatusCode::INSUFFICIENT_STORAGE,
+            StorageError::AlreadyExists { .. } => StatusCode::CONFLICT,
+            StorageError::InvalidKey(_) => StatusCode::BAD_REQUEST,
+            StorageError::Io { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        if status.is_server_error() {
+            error!("storage error: {e}");
+        }
+        actix_web::error::InternalError::new(e.to_string(), status).into()
+    }
+}
+
+impl From<RepoError> for actix_web::Error {
+    fn from(e: RepoError) -> Self {
+        let (status, msg) = match &e {
+            RepoError::NotFound => (StatusCode::NOT_FOUND, "not found"),
+            RepoError::Conflict(_) => (StatusCode::CONFLICT, "conflict"),
+            RepoError::Locked(_) => (StatusCode::SERVICE_UNAVAILABLE, "database busy"),
+            RepoError::Other(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error"),
+        };
+        let mut resp = HttpResponse::build(status);
+        if matches!(e, RepoError::Locked(_)) {
+            resp.insert_header((header::RETRY_AFTER, DB_RETRY_AFTER_SECS.to_string()));
+        }
+        // the details stay in the log
+        actix_web::error::InternalError::from_response(e, resp.body(msg)).into()
+    }
+}
+
+/// `map_err` adapter for a failed repo call `op`: logs it unless it is the client's doing
+/// and answers with the status the error calls for.
+fn db_error(op: &'static str) -> impl FnOnce(RepoError) -> actix_web::Error {
+    move |e| {
+        match &e {
+            RepoError::Locked(_) => warn!("{op}: {e}"),
+            RepoError::Other(_) => error!("{op} error: {e}"),
+            RepoError::NotFound | RepoError::Conflict(_) => {}
+        }
+        e.into()
+    }
+}
+
+/// `map_err` adapter for a failed `Service` call `op`. Repo failures get their status
+/// from `db_error`; anything else is a 500 whose details stay in the log.
+fn service_error(op: &'static str) -> impl FnOnce(anyhow::Error) -> actix_web::Error {
+    move |e| match e.downcast::<RepoError>() {
+        Ok(e) => db_error(op)(e),
+        Err(e) => {
+            error!("{op} error: {e:#}");
+            actix_web::error::ErrorInternalServerError(format!("{op} failed"))
+        }
+    }
+}
+
+impl From<StreamWriteError> for actix_web::Error {
+    fn from(e: StreamWriteError) -> Self {
+        match e {
+            StreamWriteError::Payload(msg) => actix_web::error::ErrorBadRequest(msg),
+            StreamWriteError::StorageFull => actix_web::error::InternalError::new(
+                "insufficient storage on target device",
+                StatusCode::INSUFFICIENT_STORAGE,
+            )
+            .into(),
+            StreamWriteError::Stalled => {
+                actix_web::error::InternalError::new("upload stalled", StatusCode::REQUEST_TIMEOUT)
+                    .into()
+            }
+            StreamWriteError::Io(e) => actix_web::error::ErrorInternalServerError(e.to_string()),
+        }
+    }
+}
+
+fn is_storage_full(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::StorageFull || e.raw_os_error() == Some(nix::libc::ENOSPC)
+}
+
+fn write_error(e: std::io::Error) -> StreamWriteError {
+    if is_storage_full(&e) {
+        StreamWriteError::StorageFull
+    } else {
+        StreamWriteError::Io(e)
+    }
+}
+
+/// Copy `chunks` into `writer`, returning the byte count and hex SHA-256 of the content.
+/// Waiting longer than `idle_timeout` for the next chunk aborts with `Stalled`. On any
+/// failure the partially written temp file at `temp_path` is removed so none is left
+/// behind.
+async fn stream_to_temp<S, E, W>(
+    chunks: &mut S,
+    writer: &mut W,
+    temp_path: &Path,
+    idle_timeout: Option<Duration>,
+) -> Result<(i64, String), StreamWriteError>
+where
+    S: Stream<Item = Result<web::Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+    W: AsyncWrite + Unpin,
+{
+    let res = async {
+        let mut total: i64 = 0;
+        let mut hasher = Sha256::new();
+        loop {
+            let next = match idle_timeout {
+                Some(idle) => tokio::time::timeout(idle, chunks.next())
+                    .await
+                    .map_err(|_| StreamWriteError::Stalled)?,
+                None => chunks.next().await,
+            };
+            let Some(chunk) = next else {
+                break;
+            };
+            let bytes = chunk.map_err(|e| StreamWriteError::Payload(e.to_string()))?;
+            total += bytes.len() as i64;
+            hasher.update(&bytes);
+            writer.write_all(&bytes).await.map_err(write_error)?;
+        }
+        // tokio's File reports a failed background write on the next call, so a full
+        // disk during the last chunk only surfaces here
+        writer.flush().await.map_err(write_error)?;
+        Ok((total, format!("{:x}", hasher.finalize())))
+    }
+    .await;
+    if res.is_err() {
+        remove_temp(temp_path).await;
+    }
+    res
+}
+
+async fn remove_temp(temp_path: &Path) {
+    if let Err(e) = tokio_fs::remove_file(temp_path).await
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        error!("remove temp file {:?} error: {}", temp_path, e);
+    }
+}
+
+/// Gzip the upload at `temp_path` in place if that makes it smaller. Returns the
+/// compressed length, or None when the upload is left as is (including on failure).
+async fn compress_temp(data: &AppState, temp_path: &Path, total: i64) -> Option<i64> {
+    let gz_path = data.config.temp_names.derived_path(temp_path, "gz");
+    let (src, dst) = (temp_path.to_path_buf(), gz_path.clone());
+    match data.block(move || compression::gzip_file(&src, &dst)).await {
+        Ok(Ok(len)) if len < total as u64 => match tokio_fs::rename(&gz_path, temp_path).await {
+            Ok(()) => return Some(len as i64),
+            Err(e) => error!("replace {:?} with its gzip error: {}", temp_path, e),
+        },
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => error!("gzip {:?} error: {}", temp_path, e),
+        Err(e) => error!("gzip {:?} error: {}", temp_path, e),
+    }
+    remove_temp(&gz_path).await;
+    None
+}
+
+/// Byte count and hex SHA-256 of the file at `path`.
+async fn hash_file(path: &Path) -> std::io::Result<(i64, String)> {
+    let mut chunks = ReaderStream::new(tokio_fs::File::open(path).await?);
+    let mut total: i64 = 0;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        total += chunk.len() as i64;
+        hasher.update(&chunk);
+    }
+    Ok((total, format!("{:x}", hasher.finalize())))
+}
+
+/// Leading chunks of `chunks`, read until they hold at least `want` bytes or the body
+/// ends, so an upload's type can be sniffed before anything touches the disk. Returns
+/// the chunks, kept whole for the caller to write out ahead of the rest of the stream,
+/// and a copy of their first `want` bytes (fewer for a shorter body).
+async fn read_head<S, E>(
+    chunks: &mut S,
+    want: usize,
+    idle_timeout: Option<Duration>,
+) -> Result<(Vec<web::Bytes>, Vec<u8>), StreamWriteError>
+where
+    S: Stream<Item = Result<web::Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut head = Vec::new();
+    let mut sniffed = Vec::with_capacity(want);
+    while sniffed.len() < want {
+        let next = match idle_timeout {
+            Some(idle) => tokio::time::timeout(idle, chunks.next())
+                .await
+                .map_err(|_| StreamWriteError::Stalled)?,
+            None => chunks.next().await,
+        };
+        let Some(chunk) = next else {
+            break;
+        };
+        let bytes = chunk.map_err(|e| StreamWriteError::Payload(e.to_string()))?;
+        let take = bytes.len().min(want - sniffed.len());
+        sniffed.extend_from_slice(&bytes[..take]);
+        head.push(bytes);
+    }
+    Ok((head, sniffed))
+}
+
+/// Enforce the upload content-type allowlist. The type is sniffed from the upload's
+/// leading bytes; unrecognised bytes count as `application/octet-stream` whatever the
+/// client declared, so neither a renamed executable nor arbitrary data can pass as
+/// `image/jpeg`. Rejected uploads get a 415.
+fn check_content_type(
+    allowed: &[String],
+    head: &[u8],
+    declared: Option<&str>,
+) -> actix_web::Result<()> {
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    let effective = sniff::sniff(head).unwrap_or("application/octet-stream");
+    if allowed.iter().any(|p| effective.starts_with(p.as_str())) {
+        return Ok(());
+    }
+    info!(
+        "rejecting upload of type {} (declared {:?})",
+        effective, declared
+    );
+    Err(actix_web::error::ErrorUnsupportedMediaType(format!(
+        "content type {effective} is not allowed"
+    )))
+}
+
+/// Filename of an upload part, preferring an RFC 5987 `filename*` over plain `filename`.
+/// A `filename*` in a charset other than UTF-8, ISO-8859-1 or US-ASCII, or whose bytes
+/// aren't valid in it, is ignored in favour of `filename`.
+fn disposition_filename(disposition: &ContentDisposition) -> Option<String> {
+    let decoded = disposition
+        .get_filename_ext()
+        .and_then(|ext| match &ext.charset {
+            Charset::Ext(name) if name.eq_ignore_ascii_case("utf-8") => {
+                String::from_utf8(ext.value.clone()).ok()
+            }
+            Charset::Iso_8859_1 => Some(ext.value.iter().map(|&b| b as char).collect()),
+            Charset::Us_Ascii => ext
+                .value
+                .is_ascii()
+                .then(|| String::from_utf8_lossy(&ext.value).into_owned()),
+            _ => None,
+        });
+    decoded.or_else(|| disposition.get_filename().map(str::to_string))
+}
+
+/// Apply the filename length limit (in bytes). Over-long names are refused with 400 under
+/// `Reject`; under `Truncate` the stem is shortened so the extension survives.
+fn limit_filename(name: &str, max: usize, policy: FilenamePolicy) -> actix_web::Result<String> {
+    if name.len() <= max {
+        return Ok(name.to_string());
+    }
+    if policy == FilenamePolicy::Reject {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "filename longer than {max} bytes"
+        )));
+    }
+    let ext = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot < max => &name[dot..],
+        _ => "",
+    };
+    let mut cut = max - ext.len();
+    while !name.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    Ok(format!("{}{}", &name[..cut], ext))
+}
+
+/// Run the post-upload hook on the finished temp file and return the (size, sha256) to
+/// record; these change when the hook swaps in a different file. The temp file is removed
+/// whenever the upload does not go ahead.
+async fn run_post_upload_hook(
+    hook: &dyn PostUploadHook,
+    temp_path: &Path,
+    meta: &UploadMeta,
+) -> actix_web::Result<(i64, String)> {
+    let decision = match hook.process(temp_path, meta).await {
+        Ok(d) => d,
+        Err(e) => {
+            error!("post-upload hook failed for {}: {}", meta.key, e);
+            remove_temp(temp_path).await;
+            return Err(actix_web::error::ErrorInternalServerError(
+                "post-upload hook failed",
             ));
         }
-        // Pseudo-random selection using current time nanos to avoid extra deps
-        let idx = nanos % candidates.len();
-        Ok(candidates[idx].clone())
+    };
+    match decision {
+        HookDecision::Accept => Ok((meta.size, meta.sha256.clone())),
+        HookDecision::Reject(reason) => {
+            info!("upload {} rejected by hook: {}", meta.key, reason);
+            remove_temp(temp_path).await;
+            Err(
+                actix_web::error::InternalError::new(reason, StatusCode::UNPROCESSABLE_ENTITY)
+                    .into(),
+            )
+        }
+        HookDecision::Replace(path) => {
+            if let Err(e) = tokio_fs::rename(&path, temp_path).await {
+                error!("swap in hook output {:?} error: {}", path, e);
+                remove_temp(temp_path).await;
+                return Err(actix_web::error::ErrorInternalServerError(e.to_string()));
+            }
+            hash_file(temp_path)
+                .await
+                .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
+        }
     }
 }
 
@@ -97,183 +616,5121 @@ fn now_epoch() -> i64 {
         .as_secs() as i64
 }
 
-#[post("/upload")]
-async fn upload(
-    mut payload: Multipart,
-    data: web::Data<AppState>,
-) -> actix_web::Result<impl Responder> {
+/// Creation time from `X-Original-Created-At`, if it is a plausible epoch second: not
+/// negative and no further ahead than clock skew explains. Anything else is ignored with
+/// a warning, and the object gets the server time as usual.
+fn original_created_at(req: &HttpRequest) -> Option<i64> {
+    let value = req.headers().get(ORIGINAL_CREATED_AT_HEADER)?;
+    let parsed = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse::<i64>().ok())
+        .filter(|t| (0..=now_epoch() + MAX_CREATED_AT_SKEW_SECS).contains(t));
+    if parsed.is_none() {
+        warn!("ignoring invalid {ORIGINAL_CREATED_AT_HEADER} header: {value:?}");
+    }
+    parsed
+}
+
+/// The `X-Content-SHA256` in `headers`, lowercased; a value that isn't 64 hex digits is
+/// a 400 rather than a mismatch later.
+fn declared_sha256(headers: &header::HeaderMap) -> actix_web::Result<Option<String>> {
+    let Some(value) = headers.get(CONTENT_SHA256_HEADER) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|v| v.len() == 64 && v.bytes().all(|b| b.is_ascii_hexdigit()))
+        .map(|v| Some(v.to_ascii_lowercase()))
+        .ok_or_else(|| {
+            actix_web::error::ErrorBadRequest(format!(
+                "{CONTENT_SHA256_HEADER} must be 64 hex digits"
+            ))
+        })
+}
+
+/// Tenant a request authenticated with a tenant token is pinned to, stored in the request
+/// extensions by `require_token`.
+#[derive(Debug, Clone)]
+struct TokenTenant(String);
+
+/// Tenant the request acts for. A tenant token pins it: `X-Tenant` may only repeat the
+/// token's tenant (403 otherwise). Without one, the `X-Tenant` header names it; it becomes
+/// a path segment, so it must be a single safe segment, anything else is a 400.
+fn request_tenant(req: &HttpRequest) -> actix_web::Result<Option<String>> {
+    let header = req
+        .headers()
+        .get(TENANT_HEADER)
+        .map(|value| {
+            value
+                .to_str()
+                .map_err(|_| actix_web::error::ErrorBadRequest("invalid tenant header"))
+        })
+        .transpose()?;
+    if let Some(TokenTenant(pinned)) = req.extensions().get::<TokenTenant>() {
+        if header.is_some_and(|h| h != pinned) {
+            return Err(actix_web::error::ErrorForbidden(
+                "token is bound to another tenant",
+            ));
+        }
+        return Ok(Some(pinned.clone()));
+    }
+    let Some(tenant) = header else {
+        return Ok(None);
+    };
+    StorageImpl::ensure_segment(tenant, "tenant")?;
+    Ok(Some(tenant.to_string()))
+}
+
+/// Device-wide admin routes see every tenant's objects, so tenant callers are refused.
+fn require_untenanted(req: &HttpRequest) -> actix_web::Result<()> {
+    match request_tenant(req)? {
+        Some(_) => Err(actix_web::error::ErrorForbidden(
+            "not available to tenant callers",
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Register the upload's progress session when the client tagged it with an upload id.
+/// The guard fails the session if the upload is abandoned before it finishes.
+fn start_upload_session(
+    req: &HttpRequest,
+    uploads: &UploadRegistry,
+) -> actix_web::Result<Option<SessionGuard>> {
+    let Some(value) = req.headers().get(UPLOAD_ID_HEADER) else {
+        return Ok(None);
+    };
+    let id = value
+        .to_str()
+        .ok()
+        .filter(|id| is_safe_id(id))
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("invalid upload id"))?;
+    let tenant = request_tenant(req)?;
+    uploads
+        .start(tenant.as_deref(), id)
+        .map(|session| Some(SessionGuard::new(session)))
+        .ok_or_else(|| actix_web::error::ErrorConflict("upload id already in flight"))
+}
+
+/// Name for a part sent without a filename: the object key, plus an extension guessed
+/// from the declared content type.
+fn generated_filename(key: &str, content_type: Option<&str>) -> String {
+    match content_type.and_then(sniff::extension_for) {
+        Some(ext) => format!("{key}.{ext}"),
+        None => key.to_string(),
+    }
+}
+
+/// Text fields sent alongside the file part.
+type FormFields = HashMap<String, String>;
+
+/// Skip ahead to the part named `file_field`, collecting the text fields sent before it.
+/// Surplus, oversized or non-UTF-8 fields are ignored, and so is anything after the file,
+/// since the file is streamed to disk before later parts can be read.
+async fn find_file_field(
+    payload: &mut Multipart,
+    file_field: &str,
+) -> actix_web::Result<Option<(Field, FormFields)>> {
+    let mut form = FormFields::new();
     while let Some(item) = payload.next().await {
         let mut field = item.map_err(|e| actix_web::error::ErrorBadRequest(e.to_string()))?;
-        let orig_name = field
-            .content_disposition()
-            .get_filename()
-            .unwrap_or("file")
-            .to_string();
-        let content_type = field.content_type().map(|ct| ct.to_string());
-        let key = Uuid::new_v4().to_string();
-        // device uuid: prefer cached value; if absent, query once and cache
-        info!("uploading file: {}", orig_name);
-        let device_uuid = data
-            .device_cache
-            .get_or_fetch(data.device_repo.clone())
-            .await?;
-        // log the device uuid being used
-        info!("Using device UUID: {}", device_uuid);
-
-        // stream write using storage
-        let mut total: i64 = 0;
-        // write via temp file using StorageImpl by feeding chunks
-        let temp_path = data
-            .storage
-            .resolve_path(&device_uuid, &format!("{}.part", key))
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
-        if let Some(parent) = temp_path.parent() {
-            tokio_fs::create_dir_all(parent)
-                .await
-                .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
+        let name = field.name().to_string();
+        if name == file_field {
+            return Ok(Some((field, form)));
         }
-        let mut f = tokio_fs::File::create(&temp_path)
-            .await
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
+        let mut value = Vec::new();
+        let mut oversized = false;
         while let Some(chunk) = field.next().await {
-            let bytes = chunk.map_err(|e| actix_web::error::ErrorBadRequest(e.to_string()))?;
-            total += bytes.len() as i64;
-            f.write_all(&bytes)
-                .await
-                .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
+            let chunk = chunk.map_err(|e| actix_web::error::ErrorBadRequest(e.to_string()))?;
+            oversized |= value.len() + chunk.len() > MAX_FORM_FIELD_LEN;
+            if !oversized {
+                value.extend_from_slice(&chunk);
+            }
         }
-        f.flush().await.ok();
-        drop(f);
-        let final_path = data
-            .storage
-            .resolve_path(&device_uuid, &key)
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
-        tokio_fs::rename(&temp_path, &final_path)
+        match String::from_utf8(value) {
+            Ok(v) if !oversized && form.len() < MAX_FORM_FIELDS => {
+                form.insert(name, v);
+            }
+            _ => warn!("ignoring form field {:?}", name),
+        }
+    }
+    Ok(None)
+}
+
+#[post("/upload")]
+async fn upload(
+    req: HttpRequest,
+    payload: Multipart,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let session = start_upload_session(&req, &data.uploads)?;
+    let res = store_multipart(&req, payload, &data, session.as_deref()).await;
+    if let Some(session) = session {
+        match &res {
+            Ok(resp) if resp.status().is_success() => session.complete(),
+            Ok(resp) => session.fail(resp.status().canonical_reason().unwrap_or("failed")),
+            Err(e) => session.fail(&e.to_string()),
+        }
+    }
+    res
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct UploadQuery {
+    /// Seconds until the object expires and is swept away.
+    ttl: Option<u64>,
+    /// Media type to record instead of the part's declared `Content-Type`.
+    content_type: Option<String>,
+    /// Name to record for a raw `PUT`; multipart uploads use the `filename` form field.
+    filename: Option<String>,
+}
+
+/// Parse a `?content_type=` override, normalised; 400 unless it's a well-formed media type.
+fn parse_content_type(value: &str) -> actix_web::Result<String> {
+    value
+        .trim()
+        .parse::<mime::Mime>()
+        .map(|m| m.to_string())
+        .map_err(|_| actix_web::error::ErrorBadRequest(format!("invalid content_type: {value}")))
+}
+
+/// Everything recorded about a new object besides its bytes.
+struct NewObject {
+    key: String,
+    filename: String,
+    filename_supplied: bool,
+    content_type: Option<String>,
+    tenant: Option<String>,
+    /// Seconds until the object expires.
+    ttl: Option<u64>,
+    /// Never replace bytes already at the final path, even outside write-once mode; for
+    /// caller-chosen keys, where two requests can race for the same name.
+    no_clobber: bool,
+    /// Creation time the client asked to keep; the server time when None.
+    created_at: Option<i64>,
+    /// SHA-256 the client declared for the body; checked before anything is published.
+    expected_sha256: Option<String>,
+}
+
+/// Write `chunks` to a temp file on the selected device, run the content checks and the
+/// upload hook, publish the result under `obj.key` and record its row. Returns the
+/// upload response body.
+async fn store_object<S, E>(
+    data: &AppState,
+    obj: NewObject,
+    chunks: &mut S,
+) -> actix_web::Result<serde_json::Value>
+where
+    S: Stream<Item = Result<web::Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let NewObject {
+        key,
+        filename: orig_name,
+        filename_supplied,
+        content_type,
+        tenant,
+        ttl,
+        no_clobber,
+        created_at,
+        expected_sha256,
+    } = obj;
+    // with the root gone, device directories would be recreated wherever it used to be
+    if !data.root_present.load(Ordering::Relaxed) {
+        return Err(actix_web::error::ErrorServiceUnavailable(
+            "storage root is missing",
+        ));
+    }
+    // sniffed from bytes still in memory, so a disallowed upload never reaches a device;
+    // an empty body is left to the clearer `reject_empty_uploads` error further down
+    let allowed = &data.config.allowed_content_types;
+    let head = if allowed.is_empty() {
+        Vec::new()
+    } else {
+        let (head, sniffed) = read_head(
+            chunks,
+            data.config.sniff_buffer_bytes,
+            data.config.upload_idle_timeout,
+        )
+        .await?;
+        if !(sniffed.is_empty() && data.config.reject_empty_uploads) {
+            check_content_type(allowed, &sniffed, content_type.as_deref())?;
+        }
+        head
+    };
+    // the buffered chunks go to the temp file first, ahead of the rest of the body
+    let mut chunks = futures_util::stream::iter(head.into_iter().map(Ok)).chain(chunks);
+    // device uuid: prefer cached value; if absent, query once and cache
+    info!("uploading file: {}", orig_name);
+    let device_uuid = select_device(data).await?;
+    // log the device uuid being used
+    info!("Using device UUID: {}", device_uuid);
+
+    // one writer per device at a time: on a spinning disk, interleaved uploads mostly seek.
+    // Taken before the mount check, since waiting for it can outlast another upload.
+    let write_guard = if data.config.serialize_device_writes {
+        Some(data.write_locks.lock(&device_uuid).await)
+    } else {
+        None
+    };
+
+    // The mounter may have unmounted the device since it was selected; writing now would
+    // land on the bare mount point on the root filesystem. Once the temp file is open the
+    // mount is busy and a plain umount fails, so only this window needs guarding.
+    if data.config.verify_mounts {
+        let mounted = data.device_is_mounted(&device_uuid).await.map_err(|e| {
+            error!("reading mount table failed: {e:#}");
+            actix_web::error::ErrorServiceUnavailable("cannot verify device mount")
+        })?;
+        if !mounted {
+            warn!("device {device_uuid} is no longer mounted, refusing the write");
+            data.device_cache.invalidate().await;
+            return Err(actix_web::error::ErrorServiceUnavailable(
+                "selected device is not mounted",
+            ));
+        }
+    }
+
+    // the temp file sits next to its final path, so publishing is a same-directory rename.
+    // A dated layout goes by `created_at` like `migrate_layout` does; retention and the
+    // TTL run from the time the bytes actually arrived.
+    let stored_at = now_epoch();
+    let created_at = created_at.unwrap_or(stored_at);
+    let final_path = layout_path(
+        data.storage.as_ref(),
+        data.config.layout,
+        tenant.as_deref(),
+        &device_uuid,
+        &key,
+        created_at,
+    )?;
+    // unique per request: concurrent uploads of one caller-chosen key must not share a temp
+    let temp_path = data.config.temp_names.temp_path(&final_path, &key);
+    if let Some(parent) = temp_path.parent() {
+        tokio_fs::create_dir_all(parent)
             .await
             .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
-        let size = total;
-        let repo = data.file_repo.clone();
-        let fp = final_path.clone();
-        let fkey = key.clone();
-        let fname = orig_name.clone();
-        let _inserted: usize = web::block(move || {
-            repo.insert_file(
-                &fkey,
-                &fname,
-                content_type.as_deref(),
+    }
+    // a collision is a 409 with `exclusive_temp_files`; the client can simply retry
+    let mut f = create_temp(&temp_path, data.config.exclusive_temp_files)
+        .await
+        .map_err(|e| StorageError::io(&temp_path, e))?;
+    let (total, digest) = match stream_to_temp(
+        &mut chunks,
+        &mut f,
+        &temp_path,
+        data.config.upload_idle_timeout,
+    )
+    .await
+    {
+        Ok(res) => res,
+        Err(StreamWriteError::StorageFull) => {
+            error!("device {} is out of space, deselecting it", device_uuid);
+            data.device_cache.mark_exhausted(&device_uuid);
+            return Err(StreamWriteError::StorageFull.into());
+        }
+        Err(StreamWriteError::Stalled) => {
+            warn!("upload of {} stalled, aborting", key);
+            return Err(StreamWriteError::Stalled.into());
+        }
+        Err(StreamWriteError::Io(e)) => {
+            data.note_io_error(Some(&device_uuid), &e);
+            return Err(StreamWriteError::Io(e).into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    drop(f);
+    drop(write_guard);
+    // a truncated or corrupted body never gets published
+    if let Some(expected) = expected_sha256
+        && expected != digest
+    {
+        warn!("upload of {key} hashes to {digest}, client declared {expected}");
+        remove_temp(&temp_path).await;
+        return Err(actix_web::error::InternalError::new(
+            format!("body does not match {CONTENT_SHA256_HEADER}"),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .into());
+    }
+    // only known once the body has been read: multipart parts carry no length
+    if total == 0 && data.config.reject_empty_uploads {
+        remove_temp(&temp_path).await;
+        return Err(actix_web::error::ErrorBadRequest("empty upload"));
+    }
+    let (total, digest) = run_post_upload_hook(
+        data.hook.as_ref(),
+        &temp_path,
+        &UploadMeta {
+            key: key.clone(),
+            filename: orig_name.clone(),
+            content_type: content_type.clone(),
+            device_uuid: device_uuid.clone(),
+            size: total,
+            sha256: digest,
+        },
+    )
+    .await?;
+    // `total` and `digest` stay those of the upload itself; `size` is what sits on disk
+    let compressed_size = if data.config.compress_uploads {
+        compress_temp(data, &temp_path, total).await
+    } else {
+        None
+    };
+    publish(
+        &temp_path,
+        &final_path,
+        no_clobber || data.config.write_once,
+        data.config.publish_retries,
+    )
+    .await
+    .map_err(|e| {
+        if e.kind() == std::io::ErrorKind::AlreadyExists {
+            actix_web::error::ErrorConflict("object already exists")
+        } else {
+            actix_web::error::ErrorInternalServerError(e.to_string())
+        }
+    })?;
+    if data.config.drop_cache_after_write
+        && let Err(e) = drop_page_cache(&final_path).await
+    {
+        error!("drop page cache for {:?} error: {}", final_path, e);
+    }
+    let size = compressed_size.unwrap_or(total);
+    let repo = data.file_repo.clone();
+    let fp = final_path.clone();
+    let fkey = key.clone();
+    let fname = orig_name.clone();
+    let fdevice = device_uuid.clone();
+    let owner = tenant.clone();
+    let retain_until = crate::service::retain_until(
+        data.config.write_once,
+        data.config.retention_secs,
+        stored_at,
+    );
+    let expires_at = ttl.map(|t| stored_at.saturating_add(t.min(i64::MAX as u64) as i64));
+    let inserted = data
+        .block(move || {
+            repo.insert_file(&NewFileMeta {
+                key: &fkey,
+                filename: &fname,
+                content_type: content_type.as_deref(),
                 size,
-                fp.to_string_lossy().as_ref(),
-                now_epoch(),
-            )
+                path: fp.to_string_lossy().as_ref(),
+                created_at,
+                deleted: 0,
+                device_uuid: Some(&fdevice),
+                sha256: Some(&digest),
+                retain_until,
+                tenant: tenant.as_deref(),
+                expires_at,
+                filename_supplied: filename_supplied as i32,
+                compressed: compressed_size.is_some() as i32,
+                uncompressed_size: compressed_size.map(|_| total),
+            })
         })
         .await
         .map_err(|e| {
             error!("insert_file error: {e:?}");
             actix_web::error::ErrorInternalServerError("db error")
-        })?
-        .map_err(|e| {
-            error!("insert_file inner error: {e}");
-            actix_web::error::ErrorInternalServerError("db error")
         })?;
-        let resp = serde_json::json!({"key": key, "filename": orig_name, "size": size, "device_uuid": device_uuid});
-        return Ok(HttpResponse::Ok().json(resp));
+    // `files.key` is unique across tenants, so a race for one key can lose here, after
+    // the bytes were published
+    if let Err(e) = inserted {
+        discard_unrecorded(data, &key, &final_path).await;
+        return Err(db_error("insert_file")(e));
+    }
+    if let Some(keep) = data.config.keep_versions {
+        prune_versions(data, owner, orig_name.clone(), keep).await;
+    }
+    let resp = serde_json::json!({
+        "key": key,
+        "filename": orig_name,
+        "filename_supplied": filename_supplied,
+        "size": total,
+        "device_uuid": device_uuid,
+    });
+    Ok(resp)
+}
+
+/// Remove an object published under `key` whose row could not be inserted, so no bytes
+/// are left without a row. Kept when the row that won a race for `key` records the very
+/// same path, or when that can't be checked.
+async fn discard_unrecorded(data: &AppState, key: &str, path: &Path) {
+    let repo = data.file_repo.clone();
+    let k = key.to_string();
+    match data.block(move || repo.get_by_key(&k)).await {
+        Ok(Ok(Some(row))) if Path::new(&row.path) == path => {}
+        Ok(Ok(_)) => {
+            if let Err(e) = tokio_fs::remove_file(path).await {
+                error!("remove unrecorded {:?} error: {}", path, e);
+            }
+        }
+        Ok(Err(e)) => warn!("keeping {path:?}, looking up {key} failed: {e}"),
+        Err(e) => warn!("keeping {path:?}, looking up {key} failed: {e:#}"),
+    }
+}
+
+/// Versioned-by-filename mode: soft-delete the copies of `filename` beyond the newest
+/// `keep` and remove their bytes in the background. The new object is already stored,
+/// so failures are only logged.
+async fn prune_versions(data: &AppState, tenant: Option<String>, filename: String, keep: u32) {
+    let repo = data.file_repo.clone();
+    let name = filename.clone();
+    let pruned = match data
+        .block(move || repo.prune_versions(tenant.as_deref(), &name, keep, now_epoch()))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|r| r.map_err(|e| e.to_string()))
+    {
+        Ok(pruned) => pruned,
+        Err(e) => {
+            error!("pruning old versions of {filename}: {e}");
+            return;
+        }
+    };
+    if pruned.is_empty() {
+        return;
+    }
+    info!("{filename}: {} old versions deleted", pruned.len());
+    actix_web::rt::spawn(async move {
+        for meta in pruned {
+            if let Err(e) = tokio_fs::remove_file(&meta.path).await
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                warn!("remove old version {} at {:?}: {}", meta.key, meta.path, e);
+            }
+        }
+    });
+}
+
+async fn store_multipart(
+    req: &HttpRequest,
+    mut payload: Multipart,
+    data: &AppState,
+    session: Option<&UploadSession>,
+) -> actix_web::Result<HttpResponse> {
+    let tenant = request_tenant(req)?;
+    let query = web::Query::<UploadQuery>::from_query(req.query_string())
+        .map_err(|e| actix_web::error::ErrorBadRequest(e.to_string()))?
+        .into_inner();
+    let ttl = query.ttl;
+    if ttl == Some(0) {
+        return Err(actix_web::error::ErrorBadRequest("ttl must be positive"));
+    }
+    let content_type_override = query
+        .content_type
+        .as_deref()
+        .map(parse_content_type)
+        .transpose()?;
+    if let Some((mut field, form)) =
+        find_file_field(&mut payload, &data.config.upload_field).await?
+    {
+        let content_type =
+            content_type_override.or_else(|| field.content_type().map(|ct| ct.to_string()));
+        // on the file part itself or, for the whole request, on the request
+        let expected_sha256 = match declared_sha256(field.headers())? {
+            Some(digest) => Some(digest),
+            None => declared_sha256(req.headers())?,
+        };
+        let key = Uuid::new_v4().to_string();
+        let part_name = disposition_filename(field.content_disposition());
+        let supplied = form
+            .get(FILENAME_FIELD)
+            .map(String::as_str)
+            .or(part_name.as_deref())
+            .filter(|name| !name.trim().is_empty());
+        let filename_supplied = supplied.is_some();
+        let orig_name = match supplied {
+            Some(name) => limit_filename(
+                name,
+                data.config.max_filename_len,
+                data.config.filename_policy,
+            )?,
+            None => generated_filename(&key, content_type.as_deref()),
+        };
+        if let Some(session) = session {
+            session.set_key(&key);
+        }
+        let mut chunks = (&mut field).inspect(|chunk| {
+            if let (Some(session), Ok(bytes)) = (session, chunk) {
+                session.add(bytes.len());
+            }
+        });
+        let obj = NewObject {
+            key,
+            filename: orig_name,
+            filename_supplied,
+            content_type,
+            tenant,
+            ttl,
+            no_clobber: false,
+            created_at: original_created_at(req),
+            expected_sha256,
+        };
+        let resp = store_object(data, obj, &mut chunks).await?;
+        return Ok(stored_response(data, StatusCode::OK, resp));
     }
     // add some logging here
     error!("upload called but no file part found in the request");
     Ok(HttpResponse::BadRequest().body("no file part"))
 }
 
-#[get("/files/{key}")]
-async fn download(
+/// Caller-chosen keys become file names, so keep them short and to `[A-Za-z0-9._-]`.
+fn validate_key(key: &str, max_len: usize, temp_names: &TempNames) -> actix_web::Result<()> {
+    if key.len() > max_len {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "key is longer than {max_len} bytes"
+        )));
+    }
+    if let Some(c) = key
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')))
+    {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "key contains {c:?}; allowed are A-Z, a-z, 0-9, '.', '_' and '-'"
+        )));
+    }
+    // reserved for in-progress temp files next to the objects
+    if temp_names.matches(key) {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "key may not match the temp file pattern {}*{}",
+            temp_names.prefix(),
+            temp_names.suffix()
+        )));
+    }
+    Ok(())
+}
+
+/// Store the request body as-is under a caller-chosen key. Keys are never overwritten:
+/// one that is live or was used before gets 409.
+#[put("/files/{key}")]
+async fn put_object(
+    req: HttpRequest,
     path: web::Path<String>,
+    mut payload: web::Payload,
     data: web::Data<AppState>,
-) -> actix_web::Result<NamedFile> {
+) -> actix_web::Result<HttpResponse> {
     let key = path.into_inner();
+    validate_key(&key, data.config.max_key_len, &data.config.temp_names)?;
+    StorageImpl::ensure_segment(&key, "key")?;
+    let tenant = request_tenant(&req)?;
+    let query = web::Query::<UploadQuery>::from_query(req.query_string())
+        .map_err(|e| actix_web::error::ErrorBadRequest(e.to_string()))?
+        .into_inner();
+    if query.ttl == Some(0) {
+        return Err(actix_web::error::ErrorBadRequest("ttl must be positive"));
+    }
+    let header_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    let content_type = query
+        .content_type
+        .as_deref()
+        .or(header_type)
+        .map(parse_content_type)
+        .transpose()?;
     let repo = data.file_repo.clone();
-    let meta_res = web::block(move || repo.get_by_key(&key))
+    let key_db = key.clone();
+    let exists = data
+        .block(move || repo.key_exists(&key_db))
         .await
-        .map_err(|e| {
-            error!("get_by_key error: {e:?}");
-            actix_web::error::ErrorInternalServerError("db error")
-        })?;
-    let meta = meta_res.map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
-    let meta = meta.ok_or_else(|| actix_web::error::ErrorNotFound("not found"))?;
-    let file = NamedFile::open(Path::new(&meta.path))?;
-    Ok(
-        file.set_content_disposition(actix_web::http::header::ContentDisposition {
-            disposition: actix_web::http::header::DispositionType::Attachment,
-            parameters: vec![actix_web::http::header::DispositionParam::Filename(
-                String::from(meta.filename.clone()),
-            )],
-        }),
-    )
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .map_err(db_error("key_exists"))?;
+    if exists {
+        return Err(actix_web::error::ErrorConflict("key already exists"));
+    }
+    let supplied = query.filename.filter(|name| !name.trim().is_empty());
+    let filename_supplied = supplied.is_some();
+    let filename = match supplied {
+        Some(name) => limit_filename(
+            &name,
+            data.config.max_filename_len,
+            data.config.filename_policy,
+        )?,
+        None => generated_filename(&key, content_type.as_deref()),
+    };
+    let obj = NewObject {
+        key,
+        filename,
+        filename_supplied,
+        content_type,
+        tenant,
+        ttl: query.ttl,
+        no_clobber: true,
+        created_at: original_created_at(&req),
+        expected_sha256: declared_sha256(req.headers())?,
+    };
+    let resp = store_object(&data, obj, &mut payload).await?;
+    Ok(stored_response(&data, StatusCode::CREATED, resp))
 }
 
-#[delete("/files/{key}")]
-async fn delete_file(
+/// Response for a newly stored object: `status` by default, or 201 with a `Location` of
+/// the object's download URL under `created_responses`.
+fn stored_response(data: &AppState, status: StatusCode, stored: serde_json::Value) -> HttpResponse {
+    if !data.config.created_responses {
+        return HttpResponse::build(status).json(stored);
+    }
+    let location = format!("/files/{}", stored["key"].as_str().unwrap_or_default());
+    HttpResponse::Created()
+        .insert_header((header::LOCATION, location))
+        .json(stored)
+}
+
+/// Progress of a tagged upload as Server-Sent Events: the current byte count first, then
+/// an event per received chunk, closing after the `complete` or `error` event.
+#[get("/uploads/{id}/progress")]
+async fn upload_progress(
+    req: HttpRequest,
     path: web::Path<String>,
     data: web::Data<AppState>,
-) -> actix_web::Result<impl Responder> {
+) -> actix_web::Result<HttpResponse> {
+    let tenant = request_tenant(&req)?;
+    let session = data
+        .uploads
+        .get(tenant.as_deref(), &path.into_inner())
+        .ok_or_else(|| actix_web::error::ErrorNotFound("unknown upload id"))?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(progress_stream(session)))
+}
+
+/// Tagged uploads still in flight or finished within the last few minutes, with their
+/// byte counts and last activity, so a stalled or abandoned upload can be spotted.
+#[get("/uploads")]
+async fn list_uploads(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    require_untenanted(&req)?;
+    Ok(HttpResponse::Ok().json(data.uploads.list()))
+}
+
+fn progress_stream(
+    session: Arc<UploadSession>,
+) -> impl Stream<Item = Result<web::Bytes, std::convert::Infallible>> {
+    let rx = session.subscribe();
+    let snapshot = session.snapshot();
+    let finished = snapshot.last().is_some_and(ProgressEvent::is_terminal);
+    let live = futures_util::stream::unfold(
+        (rx, session, finished),
+        |(mut rx, session, done)| async move {
+            if done {
+                return None;
+            }
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                // fell behind; the running total is all a client needs
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    ProgressEvent::Received(session.received())
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            let done = event.is_terminal();
+            Some((event, (rx, session, done)))
+        },
+    );
+    futures_util::stream::iter(snapshot)
+        .chain(live)
+        .map(|event| Ok(web::Bytes::from(event.to_sse())))
+}
+
+/// Pass `body` through while hashing it; at EOF compare against `expected` (hex SHA-256).
+/// On mismatch the error is logged and the stream ends with an error so the connection is
+/// aborted and the client sees a failed transfer (headers are already sent by then).
+fn verified_stream<S>(
+    body: S,
+    expected: String,
+    key: String,
+) -> impl Stream<Item = std::io::Result<web::Bytes>>
+where
+    S: Stream<Item = std::io::Result<web::Bytes>> + Unpin,
+{
+    futures_util::stream::unfold((body, Some(Sha256::new())), move |(mut inner, hasher)| {
+        let expected = expected.clone();
+        let key = key.clone();
+        async move {
+            let mut hasher = hasher?;
+            match inner.next().await {
+                Some(Ok(chunk)) => {
+                    hasher.update(&chunk);
+                    Some((Ok(chunk), (inner, Some(hasher))))
+                }
+                Some(Err(e)) => Some((Err(e), (inner, None))),
+                None => {
+                    let actual = format!("{:x}", hasher.finalize());
+                    if actual == expected {
+                        return None;
+                    }
+                    error!(
+                        "checksum mismatch serving {}: expected {} got {}",
+                        key, expected, actual
+                    );
+                    Some((
+                        Err(std::io::Error::other("checksum mismatch")),
+                        (inner, None),
+                    ))
+                }
+            }
+        }
+    })
+}
+
+/// `attachment` disposition for `filename`. Names that aren't plain ASCII also get a
+/// `filename*` (RFC 5987, UTF-8) parameter, and `filename` becomes an ASCII fallback
+/// with `_` for every other character, for clients that don't read `filename*`.
+fn attachment(filename: &str) -> ContentDisposition {
+    let mut parameters = Vec::with_capacity(2);
+    if filename
+        .chars()
+        .all(|c| c.is_ascii() && !c.is_ascii_control())
+    {
+        parameters.push(DispositionParam::Filename(filename.to_string()));
+    } else {
+        let fallback: String = filename
+            .chars()
+            .map(|c| {
+                if c.is_ascii() && !c.is_ascii_control() {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        parameters.push(DispositionParam::Filename(fallback));
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".into()),
+            language_tag: None,
+            value: filename.as_bytes().to_vec(),
+        }));
+    }
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters,
+    }
+}
+
+/// Query parameters of a signed download link.
+#[derive(Debug, Default, Deserialize)]
+struct SignedQuery {
+    expires: Option<i64>,
+    sig: Option<String>,
+}
+
+/// Short and made of `[A-Za-z0-9._-]` only, so a client-chosen id is safe to log verbatim.
+fn is_safe_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Client-supplied request id, if it is safe to use.
+fn incoming_request_id(req: &ServiceRequest) -> Option<String> {
+    let id = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    is_safe_id(id).then(|| id.to_string())
+}
+
+/// Tag everything logged while serving the request with its id and echo the id back.
+async fn assign_request_id<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> actix_web::Result<ServiceResponse<B>> {
+    let id = incoming_request_id(&req).unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+    let mut res = logging::scope_request_id(id.clone(), next.call(req)).await?;
+    if let Ok(value) = header::HeaderValue::from_str(&id) {
+        res.headers_mut()
+            .insert(header::HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(res)
+}
+
+/// Whether `req` is a `GET /files/{key}` carrying a link signature. Only that route
+/// checks signatures, so only it may skip the token.
+fn is_signed_download(req: &ServiceRequest) -> bool {
+    req.method() == actix_web::http::Method::GET
+        && req
+            .path()
+            .strip_prefix("/files/")
+            .is_some_and(|key| !key.is_empty() && !key.contains('/'))
+        && web::Query::<SignedQuery>::from_query(req.query_string()).is_ok_and(|q| q.sig.is_some())
+}
+
+/// Bearer-token check applied to every route when `api_token` or `tenant_tokens` is
+/// configured. A tenant token pins the request to its tenant (see `request_tenant`).
+/// Downloads carrying a link signature skip it; the download handler verifies the
+/// signature instead.
+async fn require_token<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> actix_web::Result<ServiceResponse<EitherBody<B>>> {
+    let Some(config) = req
+        .app_data::<web::Data<AppState>>()
+        .map(|d| d.config.clone())
+    else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+    if config.api_token.is_some() || !config.tenant_tokens.is_empty() {
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let tenant = bearer.and_then(|b| {
+            config
+                .tenant_tokens
+                .iter()
+                .find(|(_, token)| signing::token_eq(b, token))
+                .map(|(tenant, _)| tenant.clone())
+        });
+        let admin = bearer
+            .zip(config.api_token.as_deref())
+            .is_some_and(|(b, expected)| signing::token_eq(b, expected));
+        let signed = is_signed_download(&req);
+        match tenant {
+            Some(tenant) => {
+                req.extensions_mut().insert(TokenTenant(tenant));
+            }
+            None if admin || signed => {}
+            None => {
+                return Ok(req
+                    .into_response(HttpResponse::Unauthorized().body("missing or invalid token"))
+                    .map_into_right_body());
+            }
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[derive(Debug, Deserialize)]
+struct DownloadUrlQuery {
+    ttl_secs: Option<i64>,
+}
+
+/// Issue a time-limited link to `GET /files/{key}` that works without the auth header.
+#[get("/files/{key}/download-url")]
+async fn download_url(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<DownloadUrlQuery>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(secret) = data.config.signing_secret.clone() else {
+        return Err(actix_web::error::ErrorNotImplemented(
+            "signed URLs are not configured",
+        ));
+    };
     let key = path.into_inner();
+    let tenant = request_tenant(&req)?;
     let repo = data.file_repo.clone();
-    let key_db = key.clone();
-    let meta_res = web::block(move || repo.get_by_key(&key_db))
+    let lookup = key.clone();
+    data.block(move || repo.get_by_key_in_tenant(&lookup, tenant.as_deref()))
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .map_err(db_error("get_by_key"))?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("not found"))?;
+    let ttl = query
+        .ttl_secs
+        .unwrap_or(DEFAULT_SIGNED_URL_TTL_SECS)
+        .clamp(1, MAX_SIGNED_URL_TTL_SECS);
+    let expires = now_epoch() + ttl;
+    let sig = signing::sign(secret.as_bytes(), &key, expires);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "url": format!("/files/{key}?expires={expires}&sig={sig}"),
+        "expires": expires,
+    })))
+}
+
+/// `Cache-Control` for downloads, None when caching isn't configured. Bytes never change
+/// under a key, hence `immutable`; responses are kept out of shared caches whenever access
+/// is restricted by a token, a signed link or a tenant.
+fn download_cache_control(config: &ServerConfig, tenanted: bool) -> Option<String> {
+    let max_age = config.download_cache_max_age_secs?;
+    let scope = if tenanted
+        || config.api_token.is_some()
+        || !config.tenant_tokens.is_empty()
+        || config.signing_secret.is_some()
+    {
+        "private"
+    } else {
+        "public"
+    };
+    Some(format!("{scope}, max-age={max_age}, immutable"))
+}
+
+fn set_cache_headers(
+    resp: &mut HttpResponse,
+    config: &ServerConfig,
+    etag: Option<&header::EntityTag>,
+    tenanted: bool,
+) {
+    let headers = resp.headers_mut();
+    if let Some(tag) = etag
+        && let Ok(value) = header::HeaderValue::from_str(&tag.to_string())
+    {
+        headers.insert(header::ETAG, value);
+    }
+    if let Some(cc) = download_cache_control(config, tenanted)
+        && let Ok(value) = header::HeaderValue::from_str(&cc)
+    {
+        headers.insert(header::CACHE_CONTROL, value);
+        // the same URL resolves differently per tenant
+        headers.append(
+            header::VARY,
+            header::HeaderValue::from_static(TENANT_HEADER),
+        );
+    }
+}
+
+/// Whether the client's `If-None-Match` already covers `tag`.
+fn etag_matches(req: &HttpRequest, tag: &header::EntityTag) -> bool {
+    match header::IfNoneMatch::parse(req) {
+        Ok(header::IfNoneMatch::Any) => true,
+        Ok(header::IfNoneMatch::Items(items)) => items.iter().any(|t| t.weak_eq(tag)),
+        Err(_) => false,
+    }
+}
+
+#[get("/files/{key}")]
+async fn download(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let key = path.into_inner();
+    let signed = web::Query::<SignedQuery>::from_query(req.query_string())
+        .map(web::Query::into_inner)
+        .unwrap_or_default();
+    // a valid signature was issued for a key the signer could see, so skip tenant scoping
+    let tenant = match signed.sig.as_deref() {
+        Some(sig) => {
+            let secret = data.config.signing_secret.as_deref().ok_or_else(|| {
+                actix_web::error::ErrorForbidden("signed URLs are not configured")
+            })?;
+            let expires = signed.expires.unwrap_or_default();
+            signing::verify(secret.as_bytes(), &key, expires, sig, now_epoch()).map_err(
+                |e| match e {
+                    SignatureError::Expired => actix_web::error::ErrorForbidden("link expired"),
+                    SignatureError::Invalid => {
+                        actix_web::error::ErrorForbidden("invalid signature")
+                    }
+                },
+            )?;
+            None
+        }
+        None => Some(request_tenant(&req)?),
+    };
+    let repo = data.file_repo.clone();
+    let (lookup_key, lookup_tenant) = (key.clone(), tenant.clone());
+    let meta_res = data
+        .block(move || match lookup_tenant {
+            Some(tenant) => repo.get_by_key_in_tenant(&lookup_key, tenant.as_deref()),
+            None => repo.get_by_key(&lookup_key),
+        })
         .await
         .map_err(|e| {
             error!("get_by_key error: {e:?}");
             actix_web::error::ErrorInternalServerError("db error")
         })?;
-    let meta = meta_res.map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
-    if let Some(m) = meta {
-        // delete by path directly
-        if let Err(e) = tokio_fs::remove_file(&m.path).await {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                error!("remove_file error: {}", e);
-            }
+    let meta = meta_res.map_err(db_error("get_by_key"))?;
+    let Some(meta) = meta else {
+        if data.config.outage_aware_downloads && data.was_deleted(key, tenant).await? {
+            return Err(actix_web::error::InternalError::new("deleted", StatusCode::GONE).into());
+        }
+        return Err(actix_web::error::ErrorNotFound("not found"));
+    };
+    // expired but not swept yet
+    if meta.is_expired(now_epoch()) {
+        return Err(actix_web::error::InternalError::new("expired", StatusCode::GONE).into());
+    }
+    if data.config.outage_aware_downloads
+        && let Some(uuid) = meta.device_uuid.as_deref()
+        && !data.device_is_online(uuid).await?
+    {
+        info!("{} is on device {uuid}, which is offline", meta.key);
+        let resp = HttpResponse::ServiceUnavailable()
+            .insert_header((
+                header::RETRY_AFTER,
+                DEVICE_OFFLINE_RETRY_AFTER_SECS.to_string(),
+            ))
+            .body("device offline");
+        return Err(actix_web::error::InternalError::from_response("device offline", resp).into());
+    }
+    let compressed = meta.compressed != 0;
+    // gzipped objects go out as stored to clients that take gzip, inflated otherwise
+    let inflate = compressed
+        && !req
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(compression::accepts_gzip);
+    // the content hash is a strong validator; the gzipped representation is other bytes,
+    // so it gets a tag of its own. Rows without a hash fall back to NamedFile's
+    let etag = meta
+        .sha256
+        .as_ref()
+        .map(|sha| match compressed && !inflate {
+            true => header::EntityTag::new_strong(format!("{sha}-gzip")),
+            false => header::EntityTag::new_strong(sha.clone()),
+        });
+    if let Some(tag) = &etag
+        && etag_matches(&req, tag)
+    {
+        let mut resp = HttpResponse::NotModified().finish();
+        if compressed {
+            resp.headers_mut().insert(
+                header::VARY,
+                header::HeaderValue::from_static("Accept-Encoding"),
+            );
         }
-        let repo2 = data.file_repo.clone();
-        let key_del = key.clone();
-        let _affected: usize = web::block(move || repo2.soft_delete(&key_del))
+        set_cache_headers(&mut resp, &data.config, Some(tag), meta.tenant.is_some());
+        return Ok(resp);
+    }
+    // the hash covers the inflated content; gzip passed through carries its own CRC
+    let verify = meta
+        .sha256
+        .clone()
+        .filter(|_| data.config.verify_downloads && (inflate || !compressed));
+    let rate_limit = data.config.download_rate_limit;
+    let mut resp = if verify.is_some() || rate_limit.is_some() || compressed {
+        let file = tokio_fs::File::open(&meta.path)
             .await
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
-        Ok(HttpResponse::Ok().finish())
+            .inspect_err(|e| data.note_io_error(meta.device_uuid.as_deref(), e))?;
+        let mut resp = HttpResponse::Ok();
+        resp.insert_header(attachment(&meta.filename));
+        // streamed bodies are always whole; say so rather than silently ignoring Range
+        resp.insert_header((header::ACCEPT_RANGES, "none"));
+        if compressed {
+            resp.insert_header((header::VARY, "Accept-Encoding"));
+        }
+        if compressed && !inflate {
+            resp.insert_header((header::CONTENT_ENCODING, "gzip"));
+        }
+        // rows from before uncompressed sizes were recorded inflate to an unknown
+        // length; those go out chunked
+        if !inflate {
+            resp.no_chunking(meta.size as u64);
+        } else if let Some(len) = meta.uncompressed_size {
+            resp.no_chunking(len as u64);
+        }
+        if let Some(ct) = &meta.content_type {
+            resp.content_type(ct.as_str());
+        }
+        let state = data.clone();
+        let device = meta.device_uuid.clone();
+        let body = ReaderStream::new(file)
+            .inspect(move |chunk| {
+                if let Err(e) = chunk {
+                    state.note_io_error(device.as_deref(), e);
+                }
+            })
+            .boxed();
+        let body = if inflate {
+            compression::gunzip(body).boxed()
+        } else {
+            body
+        };
+        let body = match verify {
+            Some(expected) => verified_stream(body, expected, meta.key).boxed(),
+            None => body,
+        };
+        match rate_limit {
+            Some(rate) => resp.streaming(throttle::throttle(body, rate)),
+            None => resp.streaming(body),
+        }
     } else {
-        Ok(HttpResponse::NotFound().finish())
+        // the cheapest path actix offers, though not zero-copy: every body goes through
+        // its write buffers and handlers never get the socket, so sendfile(2) can't be
+        // used. NamedFile reads 64 KiB chunks on the blocking pool and handles ranges.
+        NamedFile::open(Path::new(&meta.path))
+            .inspect_err(|e| data.note_io_error(meta.device_uuid.as_deref(), e))?
+            .use_etag(etag.is_none())
+            .set_content_disposition(attachment(&meta.filename))
+            .into_response(&req)
+    };
+    set_cache_headers(
+        &mut resp,
+        &data.config,
+        etag.as_ref(),
+        meta.tenant.is_some(),
+    );
+    if data.config.expose_device_header
+        && let Some(value) = meta
+            .device_uuid
+            .as_deref()
+            .and_then(|uuid| header::HeaderValue::from_str(uuid).ok())
+    {
+        resp.headers_mut()
+            .insert(header::HeaderName::from_static(DEVICE_HEADER), value);
+    }
+    Ok(resp)
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataBatchBody {
+    keys: Vec<String>,
+}
+
+/// Public view of a file row (no server paths or bookkeeping columns).
+#[derive(Debug, Serialize)]
+struct FileMetadata {
+    key: String,
+    filename: String,
+    content_type: Option<String>,
+    size: i64,
+    created_at: i64,
+    sha256: Option<String>,
+}
+
+impl From<FileMeta> for FileMetadata {
+    fn from(m: FileMeta) -> Self {
+        Self {
+            size: m.content_size(),
+            key: m.key,
+            filename: m.filename,
+            content_type: m.content_type,
+            created_at: m.created_at,
+            sha256: m.sha256,
+        }
+    }
+}
+
+/// Metadata for many keys in one round trip: `{key: metadata | null}`, null for keys
+/// that are unknown, deleted or owned by another tenant.
+#[post("/files/metadata-batch")]
+async fn metadata_batch(
+    req: HttpRequest,
+    body: web::Json<MetadataBatchBody>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let tenant = request_tenant(&req)?;
+    let keys = body.into_inner().keys;
+    if keys.len() > MAX_METADATA_BATCH {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "at most {MAX_METADATA_BATCH} keys per batch"
+        )));
+    }
+    let repo = data.file_repo.clone();
+    let lookup = keys.clone();
+    let rows = data
+        .block(move || {
+            let refs: Vec<&str> = lookup.iter().map(String::as_str).collect();
+            repo.get_by_keys(&refs)
+        })
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .map_err(db_error("get_by_keys"))?;
+    let mut found: HashMap<String, FileMetadata> = rows
+        .into_iter()
+        .filter(|m| m.tenant == tenant)
+        .map(|m| (m.key.clone(), m.into()))
+        .collect();
+    let out: HashMap<String, Option<FileMetadata>> = keys
+        .into_iter()
+        .map(|k| {
+            let meta = found.remove(&k);
+            (k, meta)
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(out))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ListQuery {
+    prefix: Option<String>,
+    /// Resume after this key: the last one of the previous page.
+    after: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Browse the caller's objects by key prefix, in key order: `GET /files?prefix=logs-`.
+/// Pages past the first are fetched with `after=<last key seen>`.
+#[get("/files")]
+async fn list_files(
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let tenant = request_tenant(&req)?;
+    let ListQuery {
+        prefix,
+        after,
+        limit,
+    } = query.into_inner();
+    let prefix = prefix.unwrap_or_default();
+    let limit = limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+    let repo = data.file_repo.clone();
+    let rows = data
+        .block(move || repo.list_by_key_prefix(&prefix, after.as_deref(), tenant.as_deref(), limit))
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .map_err(db_error("list_by_key_prefix"))?;
+    let out: Vec<FileMetadata> = rows.into_iter().map(FileMetadata::from).collect();
+    Ok(HttpResponse::Ok().json(out))
+}
+
+/// Build and schema version, for checking what an upgraded deployment is running.
+#[get("/version")]
+async fn version(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let repo = data.file_repo.clone();
+    let migrations = data
+        .block(move || repo.applied_migrations())
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .map_err(db_error("applied_migrations"))?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "migrations": migrations,
+    })))
+}
+
+/// Device counts per state plus object totals, for dashboards and health checks.
+#[get("/stats")]
+async fn stats(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let devices = data
+        .device_repo
+        .counts()
+        .await
+        .map_err(db_error("device counts"))?;
+    let repo = data.file_repo.clone();
+    let (objects, bytes) = data
+        .block(move || Ok::<_, RepoError>((repo.count_active()?, repo.total_active_bytes()?)))
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .map_err(db_error("object totals"))?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "devices": devices,
+        "objects": objects,
+        "bytes": bytes,
+        "io_errors": data.io_errors.counts(),
+    })))
+}
+
+/// Liveness plus the devices whose recent I/O errors crossed `io_error_threshold`. Always
+/// 200: one failing drive is `degraded`, not a reason to take the whole server out.
+#[get("/healthz")]
+async fn healthz(data: web::Data<AppState>) -> HttpResponse {
+    let failing = data.io_errors.failing();
+    let status = if failing.is_empty() { "ok" } else { "degraded" };
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": status,
+        "failing_devices": failing,
+    }))
+}
+
+/// The configuration this server runs with, for checking which flags and config-file
+/// values took effect. Secrets are redacted; without an API token only local callers
+/// (loopback or the Unix socket) may read it.
+#[get("/config")]
+async fn effective_config(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    require_untenanted(&req)?;
+    let local = req.peer_addr().is_none_or(|peer| peer.ip().is_loopback());
+    if data.config.api_token.is_none() && !local {
+        return Err(actix_web::error::ErrorForbidden(
+            "configuration is only served locally without an API token",
+        ));
+    }
+    Ok(HttpResponse::Ok().json(data.config.as_ref()))
+}
+
+/// A device row with the read/write errors counted against it since startup.
+#[derive(Debug, Serialize)]
+struct DeviceStatus {
+    #[serde(flatten)]
+    device: Device,
+    io_errors: IoErrorCounts,
+}
+
+/// Every tracked device, including removed ones, with its I/O error counts.
+#[get("/devices")]
+async fn list_devices(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    require_untenanted(&req)?;
+    let devices = data
+        .device_repo
+        .list_all()
+        .await
+        .map_err(db_error("list devices"))?;
+    let mut counts = data.io_errors.counts();
+    let devices: Vec<DeviceStatus> = devices
+        .into_iter()
+        .map(|device| DeviceStatus {
+            io_errors: device
+                .uuid
+                .as_ref()
+                .and_then(|uuid| counts.remove(uuid))
+                .unwrap_or_default(),
+            device,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(devices))
+}
+
+/// Drop the cached device list, so a change made behind the server's back (a join by the
+/// mounter, a flag set in the DB) applies to the next upload instead of after the TTL.
+#[post("/devices/cache/invalidate")]
+async fn invalidate_device_cache(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    require_untenanted(&req)?;
+    data.device_cache.invalidate().await;
+    info!("device cache invalidated");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Live objects whose size on disk differs from the recorded size (truncation, corruption)
+/// or that are missing. Walks every row, so meant for occasional admin use.
+#[get("/maintenance/size-check")]
+async fn size_check(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    require_untenanted(&req)?;
+    let mismatches = maintenance::size_check(data.file_repo.clone(), data.slow_after())
+        .await
+        .map_err(|e| {
+            error!("size check error: {e:#}");
+            actix_web::error::ErrorInternalServerError("size check failed")
+        })?;
+    if !mismatches.is_empty() {
+        info!("size check found {} mismatched objects", mismatches.len());
+    }
+    Ok(HttpResponse::Ok().json(mismatches))
+}
+
+/// Options shared by the archive downloads.
+#[derive(Debug, Default, Deserialize)]
+struct ExportQuery {
+    /// `numbered` (default) or `key`: how entries sharing a filename are renamed.
+    #[serde(default)]
+    duplicates: DuplicateNames,
+}
+
+#[get("/devices/{uuid}/export.tar")]
+async fn export_device(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    require_untenanted(&req)?;
+    let uuid = path.into_inner();
+    let repo = data.file_repo.clone();
+    let device = uuid.clone();
+    let files = data
+        .block(move || repo.list_by_device(&device))
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .map_err(db_error("list_by_device"))?;
+    info!("exporting {} objects from device {}", files.len(), uuid);
+    // no Content-Length: the archive goes out chunked as it is built, never buffered
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-tar")
+        .insert_header(attachment(&format!("{uuid}.tar")))
+        .streaming(export::tar_stream(export::entries(files, query.duplicates))))
+}
+
+/// Download a selection of objects as one zip: `{"keys": [...]}`. Entries are named after
+/// the objects' filenames (renamed per `?duplicates=` when they repeat) in request order.
+/// Keys that are unknown, deleted or owned by another tenant are left out with a warning.
+#[post("/files/zip")]
+async fn zip_files(
+    req: HttpRequest,
+    query: web::Query<ExportQuery>,
+    body: web::Json<MetadataBatchBody>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let tenant = request_tenant(&req)?;
+    let keys = body.into_inner().keys;
+    if keys.len() > MAX_ZIP_KEYS {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "at most {MAX_ZIP_KEYS} keys per zip"
+        )));
+    }
+    let repo = data.file_repo.clone();
+    let lookup = keys.clone();
+    let rows = data
+        .block(move || {
+            let refs: Vec<&str> = lookup.iter().map(String::as_str).collect();
+            repo.get_by_keys(&refs)
+        })
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .map_err(db_error("get_by_keys"))?;
+    let mut found: HashMap<String, FileMeta> = rows
+        .into_iter()
+        .filter(|m| m.tenant == tenant)
+        .map(|m| (m.key.clone(), m))
+        .collect();
+    let mut files = Vec::with_capacity(found.len());
+    for key in keys {
+        match found.remove(&key) {
+            Some(meta) => files.push(meta),
+            // also a key listed twice: it is archived once
+            None => warn!("zip: skipping unknown key {key}"),
+        }
+    }
+    info!("zipping {} objects", files.len());
+    // streamed chunked, like export.tar
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(attachment("files.zip"))
+        .streaming(export::zip_stream(export::entries(files, query.duplicates))))
+}
+
+/// Store every regular file of an uploaded tar as a new object named after its entry,
+/// the inverse of `export.tar`. Entries are stored as they stream in. Directories,
+/// links, paths that are absolute or contain `..`, and files refused on their own
+/// (name too long, empty, disallowed content type) are skipped and listed as such.
+/// A failure part-way keeps the objects already imported: the response carries the
+/// failure's status with the partial report and an `error` message.
+#[post("/import.tar")]
+async fn import_tar(
+    req: HttpRequest,
+    payload: web::Payload,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let tenant = request_tenant(&req)?;
+    let body = StreamReader::new(payload.map(|chunk| chunk.map_err(std::io::Error::other)));
+    let mut entries = TarEntries::new(body);
+    let mut imported = Vec::new();
+    let mut skipped = Vec::new();
+    let failure = loop {
+        let entry = match entries.next_entry().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break None,
+            Err(e) => break Some(actix_web::error::ErrorBadRequest(format!("tar: {e}"))),
+        };
+        // tar headers carry the size, so empty members are refused before reading them
+        let empty_refused = entry.size == 0 && data.config.reject_empty_uploads;
+        let filename = import::safe_filename(&entry.path)
+            .filter(|_| entry.regular && !empty_refused)
+            .and_then(|name| {
+                limit_filename(
+                    name,
+                    data.config.max_filename_len,
+                    data.config.filename_policy,
+                )
+                .ok()
+            });
+        let Some(filename) = filename else {
+            warn!("import: skipping {:?}", entry.path);
+            skipped.push(entry.path);
+            continue;
+        };
+        let obj = NewObject {
+            key: Uuid::new_v4().to_string(),
+            filename,
+            filename_supplied: true,
+            content_type: None,
+            tenant: tenant.clone(),
+            ttl: None,
+            no_clobber: false,
+            created_at: None,
+            expected_sha256: None,
+        };
+        let mut chunks = ReaderStream::new(entries.data());
+        match store_object(&data, obj, &mut chunks).await {
+            Ok(stored) => imported.push(stored),
+            Err(e) if e.as_response_error().status_code() == StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+                warn!("import: skipping {:?}: {e}", entry.path);
+                skipped.push(entry.path);
+            }
+            Err(e) => break Some(e),
+        }
+    };
+    let mut report = serde_json::json!({
+        "imported": imported,
+        "skipped": skipped,
+    });
+    let Some(e) = failure else {
+        info!(
+            "imported {} objects from tar, skipped {}",
+            imported.len(),
+            skipped.len()
+        );
+        return Ok(HttpResponse::Ok().json(report));
+    };
+    error!(
+        "tar import failed after {} objects, skipped {}: {e}",
+        imported.len(),
+        skipped.len()
+    );
+    report["error"] = e.to_string().into();
+    Ok(HttpResponse::build(e.as_response_error().status_code()).json(report))
+}
+
+#[derive(Debug, Deserialize)]
+struct ReadOnlyBody {
+    read_only: bool,
+}
+
+/// Toggle a device's read-only flag. Read-only devices keep serving downloads but are
+/// no longer selected for uploads.
+#[put("/devices/{uuid}/read-only")]
+async fn set_device_read_only(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<ReadOnlyBody>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    require_untenanted(&req)?;
+    let uuid = path.into_inner();
+    let read_only = body.read_only;
+    let found = data
+        .device_repo
+        .set_read_only(&uuid, read_only)
+        .await
+        .map_err(db_error("set_read_only"))?;
+    if !found {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    info!("device {} read_only={}", uuid, read_only);
+    data.device_cache.invalidate().await;
+    Ok(HttpResponse::Ok().json(serde_json::json!({"uuid": uuid, "read_only": read_only})))
+}
+
+#[derive(Debug, Deserialize)]
+struct RetentionBody {
+    retain_until: i64,
+}
+
+/// Extend how long an object is kept without re-uploading it. The new deadline must lie
+/// in the future and may not cut an active retention window short.
+#[put("/files/{key}/retention")]
+async fn set_retention(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<RetentionBody>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let key = path.into_inner();
+    let retain_until = body.retain_until;
+    let now = now_epoch();
+    if retain_until <= now {
+        return Err(actix_web::error::ErrorBadRequest(
+            "retain_until must be in the future",
+        ));
+    }
+    let tenant = request_tenant(&req)?;
+    let repo = data.file_repo.clone();
+    let lookup = key.clone();
+    let meta = data
+        .block(move || repo.get_by_key_in_tenant(&lookup, tenant.as_deref()))
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .map_err(db_error("get_by_key"))?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("not found"))?;
+    if meta.is_retained(now) && meta.retain_until.unwrap_or_default() > retain_until {
+        return Err(actix_web::error::ErrorConflict(format!(
+            "object is already retained until {}",
+            meta.retain_until.unwrap_or_default()
+        )));
     }
+    let repo = data.file_repo.clone();
+    let target = key.clone();
+    let found = data
+        .block(move || repo.touch(&target, retain_until))
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+        .map_err(db_error("touch"))?;
+    if !found {
+        return Err(actix_web::error::ErrorNotFound("not found"));
+    }
+    info!("{} retained until {}", key, retain_until);
+    Ok(HttpResponse::Ok().json(serde_json::json!({"key": key, "retain_until": retain_until})))
+}
+
+#[delete("/files/{key}")]
+async fn delete_file(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    let key = path.into_inner();
+    let tenant = request_tenant(&req)?;
+    let outcome = data
+        .service()
+        .delete_object_in_tenant(&key, tenant.as_deref())
+        .await
+        .map_err(service_error("delete"))?;
+    Ok(match outcome {
+        DeleteOutcome::Deleted => HttpResponse::Ok().finish(),
+        DeleteOutcome::NotFound => HttpResponse::NotFound().finish(),
+        DeleteOutcome::Retained(until) => HttpResponse::Forbidden()
+            .body(format!("object is write-once and retained until {until}")),
+    })
 }
 
-#[derive(Clone, Debug)]
+/// Served by `GET /config` with secrets redacted and durations in seconds.
+#[derive(Clone, Debug, Serialize)]
 pub struct ServerConfig {
     pub storage_root: PathBuf,
     pub addr: String,
+    /// Bind this Unix domain socket instead of `addr`.
+    pub unix_socket: Option<PathBuf>,
+    /// Permission bits applied to the socket file after binding.
+    pub unix_socket_mode: Option<u32>,
+    /// How long the list of writable devices is reused between uploads. 0 disables the
+    /// cache: every upload selects from a fresh (single, indexed) query.
     pub device_cache_ttl_secs: u64,
+    /// Hash downloads while streaming and abort the transfer on digest mismatch.
+    /// Verified downloads are always sent whole: `Range` requests get a full 200
+    /// response (advertised with `Accept-Ranges: none`).
+    pub verify_downloads: bool,
+    /// `max-age` advertised on downloads; no `Cache-Control` is sent when unset.
+    pub download_cache_max_age_secs: Option<u64>,
+    /// Pace each download to this many bytes per second; unlimited when unset.
+    /// Like `verify_downloads`, this turns off `Range` support for downloads.
+    pub download_rate_limit: Option<u64>,
+    /// Never overwrite an existing object; refuse deletes during `retention_secs`.
+    pub write_once: bool,
+    /// Retention window applied to uploads in write-once mode (0 = no window).
+    pub retention_secs: u64,
+    /// `posix_fadvise(DONTNEED)` each stored object so uploads don't flood the page cache.
+    pub drop_cache_after_write: bool,
+    /// Store uploads gzipped when that makes them smaller (`files.compressed`). Clients
+    /// that don't accept gzip get them inflated.
+    pub compress_uploads: bool,
+    /// Versioned-by-filename mode: after each upload, older live objects with the same
+    /// tenant and filename beyond the newest N are deleted (retained ones are spared).
+    pub keep_versions: Option<u32>,
+    /// Preferred upload target; other devices are used only when it is unavailable or full.
+    pub primary_device_uuid: Option<String>,
+    /// Bearer token required on every request when set.
+    #[serde(serialize_with = "redact")]
+    pub api_token: Option<String>,
+    /// Per-tenant bearer tokens (tenant → token), accepted alongside `api_token`. A
+    /// request carrying one acts for that tenant only, whatever its `X-Tenant` says.
+    #[serde(serialize_with = "redact_values")]
+    pub tenant_tokens: HashMap<String, String>,
+    /// HMAC secret for signed download URLs; the feature is off when unset.
+    #[serde(serialize_with = "redact")]
+    pub signing_secret: Option<String>,
+    /// Content-type prefixes accepted on upload (checked against sniffed bytes); empty = all.
+    pub allowed_content_types: Vec<String>,
+    /// Longest accepted upload filename in bytes, enforced per `filename_policy`.
+    pub max_filename_len: usize,
+    pub filename_policy: FilenamePolicy,
+    /// Multipart field holding the file; other fields are read as small text values.
+    pub upload_field: String,
+    /// Period of the background task that removes objects whose upload TTL ran out.
+    pub expiry_sweep_interval_secs: u64,
+    /// Check the selected device is still mounted just before writing to it (503 if not).
+    /// Off by default here since embedders and tests often use a plain directory.
+    pub verify_mounts: bool,
+    /// Longest caller-chosen object key accepted by raw `PUT`, in bytes.
+    pub max_key_len: usize,
+    /// Warn about repo calls slower than this many milliseconds; off when unset.
+    pub slow_query_ms: Option<u64>,
+    /// Threads in each HTTP worker's blocking pool, where DB calls and upload finalization
+    /// run; tokio's default (512 over all workers) when unset. See
+    /// `Config::blocking_threads` for sizing against the DB pool.
+    pub blocking_threads: Option<usize>,
+    /// Period of the check that `storage_root` still exists as a directory; uploads get
+    /// 503 while it doesn't. Off when unset.
+    pub root_mount_check_secs: Option<u64>,
+    /// Answer uploads whose body turned out empty with 400 instead of storing them.
+    pub reject_empty_uploads: bool,
+    /// Abort an upload with 408 when no body chunk arrives for this long; unset waits
+    /// forever.
+    #[serde(serialize_with = "opt_secs")]
+    pub upload_idle_timeout: Option<Duration>,
+    /// Name the serving device in an `X-Storage-Device` header on downloads. Meant for
+    /// debugging multi-drive setups; it reveals the drive layout to clients.
+    pub expose_device_header: bool,
+    /// actix `Logger` format for HTTP access lines (see `config::ACCESS_LOG_COMMON`);
+    /// no access log when unset.
+    pub access_log_format: Option<String>,
+    /// Most live objects a device may hold before uploads skip it (guards against inode
+    /// exhaustion with many small files); unlimited when unset.
+    pub max_objects_per_device: Option<u64>,
+    /// Directory layout new uploads are stored under. Reads go through `files.path`, so
+    /// changing it doesn't strand existing objects (`--migrate-layout` can move them).
+    pub layout: Layout,
+    /// How uploads are spread over usable devices; `primary_device_uuid` still wins
+    /// while it is usable.
+    pub device_selection: DeviceSelection,
+    /// Flag a device read-only once this many object reads or writes on it failed with an
+    /// I/O error within `io_error_window`; errors are only counted when unset.
+    pub io_error_threshold: Option<u32>,
+    #[serde(serialize_with = "secs")]
+    pub io_error_window: Duration,
+    /// Naming of in-progress uploads next to their final path.
+    pub temp_names: TempNames,
+    /// Refuse an upload whose temp file already exists rather than truncating it, so
+    /// concurrent writers on one path show up as errors.
+    pub exclusive_temp_files: bool,
+    /// Let only one upload at a time write its bytes to a given device, so a spinning disk
+    /// sees one sequential write instead of several interleaved ones. Leave off for SSDs.
+    pub serialize_device_writes: bool,
+    /// Answer uploads (multipart and `PUT`) with 201 and a `Location: /files/{key}` header
+    /// instead of the historical 200 for multipart.
+    pub created_responses: bool,
+    /// Tell downloads of objects on an unplugged or unmounted device (503 with
+    /// `Retry-After`) and of deleted objects (410) apart from unknown keys (404).
+    pub outage_aware_downloads: bool,
+    /// Leading bytes of an upload read into memory to sniff its type against
+    /// `allowed_content_types` before anything is written to disk.
+    pub sniff_buffer_bytes: usize,
+    /// Extra tries, with backoff, for the rename that publishes an upload when it fails
+    /// transiently (EBUSY on a network-backed mount, say). 0 gives up at once.
+    pub publish_retries: u32,
 }
 
-pub async fn run<R, D>(config: ServerConfig, repo: R, device_repo: D) -> Result<()>
-where
-    R: FileRepo + 'static,
-    D: DeviceRepo + 'static,
-{
-    let state = AppState {
-        storage: Arc::new(StorageImpl::new(config.storage_root.clone())) as Arc<dyn Storage>,
-        file_repo: Arc::new(repo) as Arc<dyn FileRepo>,
-        device_repo: Arc::new(device_repo) as Arc<dyn DeviceRepo>,
-        device_cache: Arc::new(DeviceUuidCache::new(Duration::from_secs(
-            config.device_cache_ttl_secs.max(1),
-        ))),
-    };
-    let bind_addr = config.addr.clone();
-    info!("Starting api-server at http://{}", &bind_addr);
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(state.clone()))
-            .service(upload)
-            .service(download)
-            .service(delete_file)
-    })
-    .bind(&bind_addr)?
-    .run()
-    .await?;
-    Ok(())
+/// Stand-in for secrets in `GET /config`.
+const REDACTED: &str = "<redacted>";
+
+fn redact<S: serde::Serializer>(
+    secret: &Option<String>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    secret.as_ref().map(|_| REDACTED).serialize(s)
+}
+
+fn redact_values<S: serde::Serializer>(
+    secrets: &HashMap<String, String>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    s.collect_map(secrets.keys().map(|name| (name, REDACTED)))
+}
+
+fn secs<S: serde::Serializer>(d: &Duration, s: S) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_u64(d.as_secs())
+}
+
+fn opt_secs<S: serde::Serializer>(
+    d: &Option<Duration>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    d.map(|d| d.as_secs()).serialize(s)
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            storage_root: PathBuf::from(config::DEFAULT_STORAGE_ROOT),
+            addr: config::DEFAULT_ADDR.to_string(),
+            unix_socket: None,
+            unix_socket_mode: None,
+            device_cache_ttl_secs: config::DEFAULT_DEVICE_CACHE_TTL_SECS,
+            verify_downloads: false,
+            download_cache_max_age_secs: None,
+            download_rate_limit: None,
+            write_once: false,
+            retention_secs: 0,
+            drop_cache_after_write: false,
+            compress_uploads: false,
+            keep_versions: None,
+            primary_device_uuid: None,
+            api_token: None,
+            tenant_tokens: HashMap::new(),
+            signing_secret: None,
+            allowed_content_types: Vec::new(),
+            max_filename_len: config::DEFAULT_MAX_FILENAME_LEN,
+            filename_policy: FilenamePolicy::Reject,
+            upload_field: config::DEFAULT_UPLOAD_FIELD.to_string(),
+            expiry_sweep_interval_secs: config::DEFAULT_EXPIRY_SWEEP_INTERVAL_SECS,
+            verify_mounts: false,
+            max_key_len: config::DEFAULT_MAX_KEY_LEN,
+            slow_query_ms: None,
+            blocking_threads: None,
+            root_mount_check_secs: None,
+            reject_empty_uploads: false,
+            upload_idle_timeout: None,
+            expose_device_header: false,
+            access_log_format: None,
+            max_objects_per_device: None,
+            layout: Layout::Flat,
+            device_selection: DeviceSelection::Random,
+            io_error_threshold: None,
+            io_error_window: Duration::from_secs(config::DEFAULT_IO_ERROR_WINDOW_SECS),
+            temp_names: TempNames::default(),
+            exclusive_temp_files: false,
+            serialize_device_writes: false,
+            created_responses: false,
+            outage_aware_downloads: false,
+            sniff_buffer_bytes: sniff::SNIFF_LEN,
+            publish_retries: 0,
+        }
+    }
+}
+
+impl AppState {
+    fn new<R, D>(config: ServerConfig, repo: R, device_repo: D) -> Self
+    where
+        R: FileRepo + 'static,
+        D: DeviceRepo + 'static,
+    {
+        Self {
+            storage: Arc::new(
+                StorageImpl::new(config.storage_root.clone())
+                    .with_write_once(config.write_once)
+                    .with_drop_cache(config.drop_cache_after_write)
+                    .with_temp_names(config.temp_names.clone())
+                    .with_exclusive_temp(config.exclusive_temp_files)
+                    .with_publish_retries(config.publish_retries),
+            ) as Arc<dyn Storage>,
+            file_repo: Arc::new(repo) as Arc<dyn FileRepo>,
+            device_repo: AsyncDeviceRepo::new(Arc::new(device_repo), DEVICE_QUERY_CONCURRENCY)
+                .with_slow_threshold(config.slow_query_ms.map(Duration::from_millis)),
+            device_cache: Arc::new(
+                DeviceUuidCache::new(Duration::from_secs(config.device_cache_ttl_secs))
+                    .with_selector(selector::selector(
+                        config.device_selection,
+                        config.primary_device_uuid.clone(),
+                    )),
+            ),
+            hook: Arc::new(NoopHook),
+            uploads: Arc::new(UploadRegistry::default()),
+            system: Arc::new(HostSystem),
+            root_present: Arc::new(AtomicBool::new(true)),
+            io_errors: Arc::new(IoErrorTracker::new(
+                config.io_error_window,
+                config.io_error_threshold,
+            )),
+            write_locks: Arc::new(DeviceWriteLocks::default()),
+            config: Arc::new(config),
+        }
+    }
+
+    fn with_hook(mut self, hook: Arc<dyn PostUploadHook>) -> Self {
+        self.hook = hook;
+        self
+    }
+
+    #[cfg(test)]
+    fn with_system(mut self, system: Arc<dyn System>) -> Self {
+        self.system = system;
+        self
+    }
+
+    /// Count a failed read or write of an object on `device_uuid`. Missing files, permissions
+    /// and a full disk say nothing about the drive itself, so only other errors count; the
+    /// device is flagged read-only when they cross the threshold. Reads served by `NamedFile`
+    /// are only seen failing to open: its body errors never reach the handler.
+    fn note_io_error(&self, device_uuid: Option<&str>, e: &std::io::Error) {
+        let Some(uuid) = device_uuid else {
+            return;
+        };
+        if !StorageError::is_device_fault(e) || !self.io_errors.record(uuid) {
+            return;
+        }
+        warn!(
+            "device {uuid} hit {} I/O errors within {:?}, flagging it read-only",
+            self.config.io_error_threshold.unwrap_or_default(),
+            self.config.io_error_window
+        );
+        let state = self.clone();
+        let uuid = uuid.to_string();
+        actix_web::rt::spawn(async move {
+            match state.device_repo.set_read_only(&uuid, true).await {
+                Ok(_) => state.device_cache.invalidate().await,
+                Err(e) => error!("flagging {uuid} read-only failed: {e}"),
+            }
+        });
+    }
+
+    /// Whether a soft-deleted row holds `key` (and, for a scoped lookup, belongs to `tenant`).
+    async fn was_deleted(
+        &self,
+        key: String,
+        tenant: Option<Option<String>>,
+    ) -> actix_web::Result<bool> {
+        let repo = self.file_repo.clone();
+        let row = self
+            .block(move || repo.get_deleted_by_key(&key))
+            .await
+            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+            .map_err(db_error("get_deleted_by_key"))?;
+        Ok(row.is_some_and(|row| tenant.is_none_or(|t| row.tenant == t)))
+    }
+
+    /// Whether `device_uuid` is plugged in and mounted, going by the mounter's records.
+    async fn device_is_online(&self, device_uuid: &str) -> actix_web::Result<bool> {
+        let devices = self
+            .device_repo
+            .list_all()
+            .await
+            .map_err(db_error("list devices"))?;
+        Ok(devices.iter().any(|d| {
+            d.uuid.as_deref() == Some(device_uuid) && d.removed == 0 && d.mount_success == 1
+        }))
+    }
+
+    /// Whether `device_uuid`'s mount point (its row's `mount_path`, else the default under
+    /// the storage root) is an active mount right now. The kernel lists canonical paths,
+    /// so the mount point is canonicalized before comparing.
+    async fn device_is_mounted(&self, device_uuid: &str) -> Result<bool> {
+        let mount_path = self
+            .device_repo
+            .list_joined_active()
+            .await?
+            .into_iter()
+            .find(|row| row.uuid.as_deref() == Some(device_uuid))
+            .and_then(|row| row.mount_path)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| self.config.storage_root.join(device_uuid));
+        let system = self.system.clone();
+        self.block(move || {
+            let mount_point = std::fs::canonicalize(&mount_path).unwrap_or(mount_path);
+            Ok(parse_mount_table(&system.mount_table()?)
+                .iter()
+                .any(|(_, target)| Path::new(target) == mount_point))
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("mount check failed: {e}"))?
+    }
+
+    /// `web::block` that keeps the current request id on the blocking task's log lines and
+    /// warns when the closure (in practice a repo call) runs longer than `slow_query_ms`.
+    async fn block<F, R>(&self, f: F) -> Result<R, actix_web::error::BlockingError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let slow_after = self.slow_after();
+        let id = logging::request_id();
+        web::block(move || {
+            logging::with_request_id(id, || {
+                logging::warn_if_slow(slow_after, std::any::type_name::<F>(), f)
+            })
+        })
+        .await
+    }
+
+    fn slow_after(&self) -> Option<Duration> {
+        self.config.slow_query_ms.map(Duration::from_millis)
+    }
+
+    fn service(&self) -> Service {
+        Service::from_parts(
+            self.storage.clone(),
+            self.file_repo.clone(),
+            self.device_repo.inner(),
+        )
+        .with_retention(self.config.write_once, self.config.retention_secs)
+        .with_slow_threshold(self.slow_after())
+    }
+}
+
+/// Register all HTTP routes.
+fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(upload)
+        .service(put_object)
+        .service(upload_progress)
+        .service(list_uploads)
+        .service(download_url)
+        .service(download)
+        .service(metadata_batch)
+        .service(zip_files)
+        .service(list_files)
+        .service(version)
+        .service(stats)
+        .service(healthz)
+        .service(list_devices)
+        .service(invalidate_device_cache)
+        .service(effective_config)
+        .service(size_check)
+        .service(export_device)
+        .service(import_tar)
+        .service(set_device_read_only)
+        .service(set_retention)
+        .service(delete_file);
+}
+
+/// Background task removing objects whose upload TTL ran out.
+async fn sweep_expired_loop(state: AppState) {
+    let mut tick =
+        actix_web::rt::time::interval(Duration::from_secs(state.config.expiry_sweep_interval_secs));
+    loop {
+        tick.tick().await;
+        if let Err(e) =
+            maintenance::sweep_expired(state.file_repo.clone(), now_epoch(), state.slow_after())
+                .await
+        {
+            error!("expiry sweep error: {e:#}");
+        }
+    }
+}
+
+/// Stat `storage_root` and record whether it still exists as a directory, logging changes.
+/// Device mounts live below it, so the root itself is a plain directory, not a mount point.
+fn check_root_present(state: &AppState) {
+    let root = &state.config.storage_root;
+    let present = match std::fs::metadata(root) {
+        Ok(m) => m.is_dir(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => {
+            error!("stat storage root {:?} error: {}", root, e);
+            false
+        }
+    };
+    let was = state.root_present.swap(present, Ordering::Relaxed);
+    if was && !present {
+        error!("storage root {:?} is missing, refusing uploads", root);
+    } else if !was && present {
+        info!("storage root {:?} is back, accepting uploads", root);
+    }
+}
+
+/// Background task keeping `AppState::root_present` current.
+async fn watch_root_loop(state: AppState, period: Duration) {
+    let mut tick = actix_web::rt::time::interval(period);
+    loop {
+        tick.tick().await;
+        let s = state.clone();
+        if let Err(e) = state.block(move || check_root_present(&s)).await {
+            error!("storage root check error: {e}");
+        }
+    }
+}
+
+/// HTTP access logging, separate from the application log lines; a no-op without a
+/// configured format.
+fn access_logger(config: &ServerConfig) -> Condition<Logger> {
+    match &config.access_log_format {
+        Some(format) => Condition::new(true, Logger::new(format)),
+        None => Condition::new(false, Logger::default()),
+    }
+}
+
+pub async fn run<R, D>(config: ServerConfig, repo: R, device_repo: D) -> Result<()>
+where
+    R: FileRepo + 'static,
+    D: DeviceRepo + 'static,
+{
+    run_with_hook(config, repo, device_repo, Arc::new(NoopHook)).await
+}
+
+/// Like `run`, with `hook` invoked on every upload before it is published.
+pub async fn run_with_hook<R, D>(
+    config: ServerConfig,
+    repo: R,
+    device_repo: D,
+    hook: Arc<dyn PostUploadHook>,
+) -> Result<()>
+where
+    R: FileRepo + 'static,
+    D: DeviceRepo + 'static,
+{
+    let bind_addr = config.addr.clone();
+    let unix_socket = config.unix_socket.clone();
+    let socket_mode = config.unix_socket_mode;
+    match (repo.count_active(), repo.total_active_bytes()) {
+        (Ok(count), Ok(bytes)) => info!("managing {count} objects, {bytes} bytes"),
+        (Err(e), _) | (_, Err(e)) => warn!("counting stored objects failed: {e:#}"),
+    }
+    let state = AppState::new(config, repo, device_repo).with_hook(hook);
+    actix_web::rt::spawn(sweep_expired_loop(state.clone()));
+    if let Some(secs) = state.config.root_mount_check_secs {
+        check_root_present(&state);
+        actix_web::rt::spawn(watch_root_loop(
+            state.clone(),
+            Duration::from_secs(secs.max(1)),
+        ));
+    }
+    let blocking_threads = state.config.blocking_threads;
+    // Nothing is held back for a flush at shutdown: every metadata write goes to the
+    // database within its request, and the counters kept in memory (device cache, I/O
+    // errors, upload progress) are rebuilt or meant to restart. On SIGINT/SIGTERM actix
+    // stops accepting and lets in-flight requests finish, which is all there is to drain.
+    let mut server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            .wrap(from_fn(require_token))
+            .wrap(from_fn(assign_request_id))
+            .wrap(access_logger(&state.config))
+            .configure(configure)
+    });
+    if let Some(n) = blocking_threads {
+        server = server.worker_max_blocking_threads(n);
+    }
+    let Some(socket) = unix_socket else {
+        info!("Starting api-server at http://{}", &bind_addr);
+        server.bind(&bind_addr)?.run().await?;
+        return Ok(());
+    };
+    remove_stale_socket(&socket)?;
+    let server = server.bind_uds(&socket)?;
+    if let Some(mode) = socket_mode {
+        std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(mode))?;
+    }
+    info!("Starting api-server on unix socket {:?}", socket);
+    let res = server.run().await;
+    if let Err(e) = std::fs::remove_file(&socket)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        error!("remove socket {:?} error: {}", socket, e);
+    }
+    Ok(res?)
+}
+
+/// Remove a socket file left behind by an unclean shutdown so binding doesn't fail.
+/// Anything that isn't a socket is left alone and the bind reports the conflict.
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::{device_repo::new_device_repo, file_repo::new_file_repo};
+    use crate::test_support::{FakeSystem, captured_logs, seed_device, temp_dir, temp_pool};
+    use actix_web::test;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::AsyncReadExt;
+
+    /// Writer that always fails like a full disk.
+    struct FullDisk;
+
+    impl AsyncWrite for FullDisk {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Err(std::io::Error::from_raw_os_error(nix::libc::ENOSPC)))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Accepts every write, then reports ENOSPC on flush, like tokio's `File` does for a
+    /// background write that failed.
+    struct FullOnFlush;
+
+    impl AsyncWrite for FullOnFlush {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(std::io::Error::from_raw_os_error(nix::libc::ENOSPC)))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn seed_mounted(pool: &crate::db::Pool, uuids: &[&str]) -> AsyncDeviceRepo {
+        for (i, uuid) in uuids.iter().enumerate() {
+            seed_device(pool, &format!("/dev/sd{}1", i), uuid);
+        }
+        AsyncDeviceRepo::new(Arc::new(new_device_repo(pool.clone())), 1)
+    }
+
+    #[actix_web::test]
+    async fn repo_errors_map_to_statuses() {
+        let response = |e: RepoError| actix_web::Error::from(e).error_response();
+        assert_eq!(
+            response(RepoError::NotFound).status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            response(RepoError::Conflict("UNIQUE".into())).status(),
+            StatusCode::CONFLICT
+        );
+        let busy = response(RepoError::Locked("database is locked".into()));
+        assert_eq!(busy.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(busy.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        assert_eq!(
+            response(RepoError::Other(anyhow::anyhow!("boom"))).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[actix_web::test]
+    async fn service_errors_keep_the_repo_status() {
+        let locked = crate::service::blocking(None, || {
+            Err::<(), _>(RepoError::Locked("database is locked".into()))
+        })
+        .await
+        .unwrap_err();
+        let busy = service_error("delete")(locked).error_response();
+        assert_eq!(busy.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(busy.headers().get(header::RETRY_AFTER).unwrap(), "1");
+
+        let io = anyhow::Error::new(std::io::Error::other("/mnt/u1/k: EIO"));
+        let res = service_error("delete")(io).error_response();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "delete failed");
+    }
+
+    #[actix_web::test]
+    async fn storage_errors_map_to_statuses() {
+        let status = |e: StorageError| {
+            let e: actix_web::Error = e.into();
+            e.as_response_error().status_code()
+        };
+        let io = |kind| std::io::Error::from(kind);
+        assert_eq!(
+            status(StorageError::io("/p", io(std::io::ErrorKind::NotFound))),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(StorageError::io(
+                "/p",
+                io(std::io::ErrorKind::PermissionDenied)
+            )),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(StorageError::io(
+                "/p",
+                std::io::Error::from_raw_os_error(nix::libc::ENOSPC)
+            )),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        assert_eq!(
+            status(StorageError::InvalidKey("bad".into())),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn enospc_maps_to_507_and_removes_temp() {
+        let temp_path = temp_dir("upload").join("k.part");
+        tokio_fs::write(&temp_path, b"partial").await.unwrap();
+        let mut chunks = futures_util::stream::iter(vec![Ok::<_, std::io::Error>(
+            web::Bytes::from_static(b"abc"),
+        )]);
+
+        let err = stream_to_temp(&mut chunks, &mut FullDisk, &temp_path, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StreamWriteError::StorageFull));
+        assert!(!temp_path.exists());
+        let err: actix_web::Error = err.into();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+    }
+
+    #[tokio::test]
+    async fn enospc_on_final_flush_is_not_lost() {
+        let temp_path = temp_dir("upload").join("k.part");
+        tokio_fs::write(&temp_path, b"partial").await.unwrap();
+        let mut chunks = futures_util::stream::iter(vec![Ok::<_, std::io::Error>(
+            web::Bytes::from_static(b"abc"),
+        )]);
+
+        let err = stream_to_temp(&mut chunks, &mut FullOnFlush, &temp_path, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StreamWriteError::StorageFull));
+        assert!(!temp_path.exists());
+    }
+
+    #[tokio::test]
+    async fn stalled_upload_maps_to_408_and_removes_temp() {
+        let temp_path = temp_dir("upload").join("k.part");
+        let mut f = tokio_fs::File::create(&temp_path).await.unwrap();
+        let first = futures_util::stream::iter(vec![Ok::<_, std::io::Error>(
+            web::Bytes::from_static(b"abc"),
+        )]);
+        let mut chunks = first.chain(futures_util::stream::pending());
+
+        let err = stream_to_temp(
+            &mut chunks,
+            &mut f,
+            &temp_path,
+            Some(Duration::from_millis(50)),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, StreamWriteError::Stalled));
+        assert!(!temp_path.exists());
+        let err: actix_web::Error = err.into();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::REQUEST_TIMEOUT
+        );
+    }
+
+    async fn collect_verified(content: &[u8], stored: &[u8]) -> Vec<std::io::Result<web::Bytes>> {
+        let path = temp_dir("verify").join("obj");
+        tokio_fs::write(&path, stored).await.unwrap();
+        let expected = format!("{:x}", Sha256::digest(content));
+        let file = tokio_fs::File::open(&path).await.unwrap();
+        verified_stream(ReaderStream::new(file), expected, "obj".into())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn verified_stream_passes_intact_bytes() {
+        let items = collect_verified(b"intact bytes", b"intact bytes").await;
+        assert!(items.iter().all(|i| i.is_ok()));
+        let body: Vec<u8> = items
+            .into_iter()
+            .flat_map(|i| i.unwrap().to_vec())
+            .collect();
+        assert_eq!(body, b"intact bytes");
+    }
+
+    #[tokio::test]
+    async fn verified_stream_errors_on_corruption() {
+        let items = collect_verified(b"intact bytes", b"intact bytez").await;
+        let last = items.last().unwrap();
+        assert_eq!(last.as_ref().unwrap_err().to_string(), "checksum mismatch");
+    }
+
+    struct RejectAll;
+
+    #[async_trait::async_trait]
+    impl PostUploadHook for RejectAll {
+        async fn process(&self, _temp: &Path, _meta: &UploadMeta) -> Result<HookDecision> {
+            Ok(HookDecision::Reject("infected".into()))
+        }
+    }
+
+    /// Replaces every upload with an uppercased copy written next to the temp file.
+    struct Uppercase;
+
+    #[async_trait::async_trait]
+    impl PostUploadHook for Uppercase {
+        async fn process(&self, temp: &Path, _meta: &UploadMeta) -> Result<HookDecision> {
+            let out = temp.with_extension("out");
+            let body = tokio_fs::read(temp).await?;
+            tokio_fs::write(&out, body.to_ascii_uppercase()).await?;
+            Ok(HookDecision::Replace(out))
+        }
+    }
+
+    async fn hook_fixture(body: &[u8]) -> (PathBuf, UploadMeta) {
+        let temp = temp_dir("hook").join("k.part");
+        tokio_fs::write(&temp, body).await.unwrap();
+        let meta = UploadMeta {
+            key: "k".into(),
+            filename: "f.txt".into(),
+            content_type: None,
+            device_uuid: "u1".into(),
+            size: body.len() as i64,
+            sha256: format!("{:x}", Sha256::digest(body)),
+        };
+        (temp, meta)
+    }
+
+    #[actix_web::test]
+    async fn filename_limit_rejects_or_truncates() {
+        assert_eq!(
+            limit_filename("short.jpg", 16, FilenamePolicy::Reject).unwrap(),
+            "short.jpg"
+        );
+        let long = "a-very-long-holiday-photo.jpeg";
+        let err = limit_filename(long, 16, FilenamePolicy::Reject).unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::BAD_REQUEST
+        );
+        let cut = limit_filename(long, 16, FilenamePolicy::Truncate).unwrap();
+        assert_eq!(cut, "a-very-long.jpeg");
+        // multi-byte characters are never split
+        let cut = limit_filename("ééééé.png", 8, FilenamePolicy::Truncate).unwrap();
+        assert_eq!(cut, "éé.png");
+    }
+
+    #[actix_web::test]
+    async fn content_type_allowlist_uses_sniffed_type() {
+        let allowed = vec!["image/".to_string(), "video/".to_string()];
+        let status = |head: &[u8], declared: &str| {
+            check_content_type(&allowed, head, Some(declared))
+                .err()
+                .map(|e| e.as_response_error().status_code())
+        };
+
+        assert_eq!(status(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", "image/png"), None);
+        let rejected = Some(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(status(b"%PDF-1.7\n", "application/pdf"), rejected);
+        // an executable renamed to .jpg and declared as a JPEG
+        assert_eq!(status(b"\x7FELF\x02\x01\x01\0", "image/jpeg"), rejected);
+        // bytes the sniffer doesn't know don't get to borrow the declared type
+        assert_eq!(status(b"just some text", "image/png"), rejected);
+    }
+
+    #[tokio::test]
+    async fn head_is_read_up_to_the_buffer_and_kept_whole() {
+        let stream = |parts: &[&'static [u8]]| {
+            futures_util::stream::iter(
+                parts
+                    .iter()
+                    .map(|p| Ok::<_, std::io::Error>(web::Bytes::from_static(p)))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        // the chunk crossing the boundary is kept whole; the one after it stays unread
+        let mut chunks = stream(&[b"abc", b"defg", b"hij"]);
+        let (head, sniffed) = read_head(&mut chunks, 5, None).await.unwrap();
+        assert_eq!(head, vec![&b"abc"[..], &b"defg"[..]]);
+        assert_eq!(sniffed, b"abcde");
+        assert_eq!(chunks.next().await.unwrap().unwrap(), &b"hij"[..]);
+
+        let (head, sniffed) = read_head(&mut stream(&[b"ab"]), 5, None).await.unwrap();
+        assert_eq!((head.len(), sniffed.as_slice()), (1, &b"ab"[..]));
+        let (head, sniffed) = read_head(&mut stream(&[]), 5, None).await.unwrap();
+        assert!(head.is_empty() && sniffed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejecting_hook_returns_422_and_removes_temp() {
+        let (temp, meta) = hook_fixture(b"payload").await;
+        let err = run_post_upload_hook(&RejectAll, &temp, &meta)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert!(!temp.exists());
+    }
+
+    #[tokio::test]
+    async fn replacing_hook_swaps_content_and_digest() {
+        let (temp, meta) = hook_fixture(b"payload!").await;
+        let (size, digest) = run_post_upload_hook(&Uppercase, &temp, &meta)
+            .await
+            .unwrap();
+        assert_eq!(tokio_fs::read(&temp).await.unwrap(), b"PAYLOAD!");
+        assert_eq!(size, 8);
+        assert_eq!(digest, format!("{:x}", Sha256::digest(b"PAYLOAD!")));
+
+        let (temp, meta) = hook_fixture(b"as is").await;
+        let res = run_post_upload_hook(&NoopHook, &temp, &meta).await.unwrap();
+        assert_eq!(res, (meta.size, meta.sha256.clone()));
+    }
+
+    fn test_state(config: ServerConfig) -> (AppState, crate::db::Pool) {
+        let pool = temp_pool();
+        let state = AppState::new(
+            ServerConfig {
+                storage_root: temp_dir("server"),
+                ..config
+            },
+            new_file_repo(pool.clone()),
+            new_device_repo(pool.clone()),
+        );
+        (state, pool)
+    }
+
+    fn insert_row(state: &AppState, key: &str, retain_until: Option<i64>) {
+        state
+            .file_repo
+            .insert_file(&NewFileMeta {
+                key,
+                filename: "f.txt",
+                content_type: None,
+                size: 0,
+                path: "/nonexistent",
+                created_at: now_epoch(),
+                deleted: 0,
+                device_uuid: None,
+                sha256: None,
+                retain_until,
+                tenant: None,
+                expires_at: None,
+                filename_supplied: 1,
+                compressed: 0,
+                uncompressed_size: None,
+            })
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn retained_object_cannot_be_deleted() {
+        let (state, _pool) = test_state(ServerConfig::default());
+        insert_row(&state, "kept", Some(now_epoch() + 3600));
+        insert_row(&state, "expired", Some(now_epoch() - 1));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::delete().uri("/files/kept").to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        assert!(state.file_repo.get_by_key("kept").unwrap().is_some());
+
+        let req = test::TestRequest::delete()
+            .uri("/files/expired")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert!(state.file_repo.get_by_key("expired").unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn retention_can_be_extended_but_not_backdated() {
+        let (state, _pool) = test_state(ServerConfig::default());
+        let now = now_epoch();
+        insert_row(&state, "k", Some(now + 60));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let put = |retain_until: i64| {
+            test::TestRequest::put()
+                .uri("/files/k/retention")
+                .set_json(serde_json::json!({ "retain_until": retain_until }))
+                .to_request()
+        };
+
+        let res = test::call_service(&app, put(now + 7200)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let meta = state.file_repo.get_by_key("k").unwrap().unwrap();
+        assert_eq!(meta.retain_until, Some(now + 7200));
+
+        let res = test::call_service(&app, put(now - 10)).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = test::call_service(&app, put(now + 600)).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let meta = state.file_repo.get_by_key("k").unwrap().unwrap();
+        assert_eq!(meta.retain_until, Some(now + 7200));
+
+        let req = test::TestRequest::put()
+            .uri("/files/missing/retention")
+            .set_json(serde_json::json!({ "retain_until": now + 60 }))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        // another tenant can't pin someone else's object
+        let req = test::TestRequest::put()
+            .uri("/files/k/retention")
+            .insert_header((TENANT_HEADER, "t2"))
+            .set_json(serde_json::json!({ "retain_until": now + 86_400 }))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let meta = state.file_repo.get_by_key("k").unwrap().unwrap();
+        assert_eq!(meta.retain_until, Some(now + 7200));
+    }
+
+    /// (content-type header, body) of a single-file multipart upload.
+    fn multipart_body(filename: &str, body: &str) -> (String, String) {
+        let boundary = "XBOUNDARYX";
+        let payload = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\
+             Content-Type: text/plain\r\n\r\n{body}\r\n--{boundary}--\r\n"
+        );
+        (format!("multipart/form-data; boundary={boundary}"), payload)
+    }
+
+    fn multipart_upload(tenant: Option<&str>, filename: &str, body: &str) -> test::TestRequest {
+        let (content_type, payload) = multipart_body(filename, body);
+        let mut req = test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("content-type", content_type))
+            .set_payload(payload);
+        if let Some(t) = tenant {
+            req = req.insert_header((TENANT_HEADER, t));
+        }
+        req
+    }
+
+    #[actix_web::test]
+    async fn slow_blocking_calls_are_logged() {
+        captured_logs();
+        let (state, _pool) = test_state(ServerConfig {
+            slow_query_ms: Some(20),
+            ..Default::default()
+        });
+        let id = Uuid::new_v4().to_string();
+        logging::scope_request_id(
+            id.clone(),
+            state.block(|| std::thread::sleep(Duration::from_millis(50))),
+        )
+        .await
+        .unwrap();
+        logging::scope_request_id(id.clone(), state.block(|| ()))
+            .await
+            .unwrap();
+        let slow: Vec<String> = captured_logs()
+            .into_iter()
+            .filter(|l| l.contains(&id))
+            .collect();
+        assert_eq!(slow.len(), 1, "{slow:?}");
+        assert!(slow[0].contains("slow db call"));
+    }
+
+    #[actix_web::test]
+    async fn request_id_tags_log_lines_and_response() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .wrap(from_fn(assign_request_id))
+                .configure(configure),
+        )
+        .await;
+        captured_logs();
+
+        let req = multipart_upload(None, "traced.txt", "hello")
+            .insert_header((REQUEST_ID_HEADER, "trace-abc-123"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(REQUEST_ID_HEADER).unwrap(),
+            "trace-abc-123"
+        );
+        let tagged = captured_logs()
+            .into_iter()
+            .filter(|l| l.contains("[req=trace-abc-123]"))
+            .count();
+        assert!(tagged >= 2, "expected several tagged lines, got {tagged}");
+
+        // blocking tasks inherit the id; unsafe client ids are replaced
+        let id = logging::scope_request_id("blk".into(), state.block(logging::request_id)).await;
+        assert_eq!(id.unwrap(), Some("blk".to_string()));
+        let req = test::TestRequest::get()
+            .uri("/version")
+            .insert_header((REQUEST_ID_HEADER, "bad id; drop"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let echoed = res
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert_eq!(echoed.len(), 32);
+    }
+
+    #[actix_web::test]
+    async fn downloads_carry_cache_headers() {
+        let (state, pool) = test_state(ServerConfig {
+            download_cache_max_age_secs: Some(86_400),
+            ..Default::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+        let req = multipart_upload(None, "a.txt", "cache me").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let uri = format!("/files/{}", body["key"].as_str().unwrap());
+
+        let res = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=86400, immutable"
+        );
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        assert_eq!(
+            etag.to_str().unwrap(),
+            format!("\"{:x}\"", Sha256::digest(b"cache me"))
+        );
+
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert!(res.headers().contains_key(header::CACHE_CONTROL));
+
+        let restricted = ServerConfig {
+            download_cache_max_age_secs: Some(60),
+            api_token: Some("t".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            download_cache_control(&restricted, false).unwrap(),
+            "private, max-age=60, immutable"
+        );
+        let open = ServerConfig {
+            download_cache_max_age_secs: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(
+            download_cache_control(&open, true).unwrap(),
+            "private, max-age=60, immutable"
+        );
+        assert_eq!(
+            download_cache_control(&ServerConfig::default(), false),
+            None
+        );
+    }
+
+    #[actix_web::test]
+    async fn uploads_follow_the_configured_layout() {
+        let (state, pool) = test_state(ServerConfig {
+            layout: Layout::Dated,
+            ..Default::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let req = multipart_upload(None, "a.txt", "dated").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let key = body["key"].as_str().unwrap();
+
+        let meta = state.file_repo.get_by_key(key).unwrap().unwrap();
+        let expected = layout_path(
+            state.storage.as_ref(),
+            Layout::Dated,
+            None,
+            "u1",
+            key,
+            meta.created_at,
+        )
+        .unwrap();
+        assert_eq!(Path::new(&meta.path), expected);
+        assert_eq!(std::fs::read(&expected).unwrap(), b"dated");
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/files/{key}"))
+            .to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "dated");
+        let (_, mut reader) = state.service().get_object(key).await.unwrap().unwrap();
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(bytes, b"dated");
+    }
+
+    #[actix_web::test]
+    async fn device_export_streams_a_tar_of_its_objects() {
+        use std::io::Read;
+
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+        for (name, body) in [("a.txt", "alpha"), ("a.txt", "again")] {
+            let req = multipart_upload(None, name, body).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/devices/u1/export.tar")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/x-tar"
+        );
+        let archive = test::read_body(res).await;
+        let mut got = Vec::new();
+        for entry in tar::Archive::new(archive.as_ref()).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut body = String::new();
+            entry.read_to_string(&mut body).unwrap();
+            got.push((name, body));
+        }
+        let mut names: Vec<&str> = got.iter().map(|(n, _)| n.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["a (2).txt", "a.txt"]);
+        let mut bodies: Vec<&str> = got.iter().map(|(_, b)| b.as_str()).collect();
+        bodies.sort();
+        assert_eq!(bodies, vec!["again", "alpha"]);
+
+        let req = test::TestRequest::get()
+            .uri("/devices/u1/export.tar?duplicates=key")
+            .to_request();
+        let archive = test::read_body(test::call_service(&app, req).await).await;
+        let names: Vec<String> = tar::Archive::new(archive.as_ref())
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.iter().all(|n| n.ends_with("_a.txt")), "{names:?}");
+    }
+
+    #[actix_web::test]
+    async fn archives_stream_chunked_without_buffering() {
+        use actix_web::body::BodySize;
+
+        const SIZE: u64 = 48 * 1024 * 1024;
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        // sparse, so the test costs no disk; the archive is far bigger than any chunk
+        let path = state.config.storage_root.join("big.bin");
+        std::fs::File::create(&path).unwrap().set_len(SIZE).unwrap();
+        state
+            .file_repo
+            .insert_file(&NewFileMeta {
+                key: "big",
+                filename: "big.bin",
+                content_type: None,
+                size: SIZE as i64,
+                path: path.to_str().unwrap(),
+                created_at: now_epoch(),
+                deleted: 0,
+                device_uuid: Some("u1"),
+                sha256: None,
+                retain_until: None,
+                tenant: None,
+                expires_at: None,
+                filename_supplied: 1,
+                compressed: 0,
+                uncompressed_size: None,
+            })
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+
+        let tar = test::TestRequest::get()
+            .uri("/devices/u1/export.tar")
+            .to_request();
+        let zip = test::TestRequest::post()
+            .uri("/files/zip")
+            .set_json(serde_json::json!({ "keys": ["big"] }))
+            .to_request();
+        for req in [tar, zip] {
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers().get(header::CONTENT_LENGTH).is_none());
+            assert_eq!(res.response().body().size(), BodySize::Stream);
+            let mut body = Box::pin(res.into_body());
+            let (mut total, mut largest) = (0u64, 0usize);
+            while let Some(chunk) = std::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+                let Ok(chunk) = chunk else {
+                    panic!("archive stream failed")
+                };
+                largest = largest.max(chunk.len());
+                total += chunk.len() as u64;
+            }
+            assert!(total > SIZE, "{total}");
+            assert!(largest <= 64 * 1024, "{largest}-byte chunk");
+        }
+    }
+
+    #[actix_web::test]
+    async fn read_errors_are_counted_and_flag_the_device_read_only() {
+        let (state, pool) = test_state(ServerConfig {
+            verify_downloads: true,
+            io_error_threshold: Some(2),
+            ..ServerConfig::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        // a directory opens fine but fails every read (EISDIR), like a bad sector would
+        let bad = state.config.storage_root.join("u1").join("bad");
+        std::fs::create_dir_all(&bad).unwrap();
+        for (key, path) in [("bad", bad.to_str().unwrap()), ("gone", "/nonexistent")] {
+            state
+                .file_repo
+                .insert_file(&NewFileMeta {
+                    key,
+                    filename: "f.bin",
+                    content_type: None,
+                    size: 10,
+                    path,
+                    created_at: now_epoch(),
+                    deleted: 0,
+                    device_uuid: Some("u1"),
+                    sha256: Some("00"),
+                    retain_until: None,
+                    tenant: None,
+                    expires_at: None,
+                    filename_supplied: 1,
+                    compressed: 0,
+                    uncompressed_size: None,
+                })
+                .unwrap();
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        // a missing file says nothing about the drive
+        let req = test::TestRequest::get().uri("/files/gone").to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let health: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(health["status"], "ok");
+        for _ in 0..2 {
+            let req = test::TestRequest::get().uri("/files/bad").to_request();
+            assert!(
+                test::try_read_body(test::call_service(&app, req).await)
+                    .await
+                    .is_err()
+            );
+        }
+        let mut read_only = 0;
+        for _ in 0..50 {
+            read_only = state.device_repo.list_all().await.unwrap()[0].read_only;
+            if read_only == 1 {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(read_only, 1);
+
+        let req = test::TestRequest::get().uri("/devices").to_request();
+        let devices: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(devices[0]["uuid"], "u1");
+        assert_eq!(devices[0]["read_only"], 1);
+        assert_eq!(devices[0]["io_errors"]["total"], 2);
+        let req = test::TestRequest::get().uri("/stats").to_request();
+        let totals: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(totals["io_errors"]["u1"]["recent"], 2);
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let health: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            health,
+            serde_json::json!({"status": "degraded", "failing_devices": ["u1"]})
+        );
+    }
+
+    #[actix_web::test]
+    async fn non_ascii_filenames_get_an_extended_parameter() {
+        let header = |name: &str| attachment(name).to_string();
+        assert_eq!(header("a.txt"), "attachment; filename=\"a.txt\"");
+        assert_eq!(
+            header("写真 📷.jpg"),
+            "attachment; filename=\"__ _.jpg\"; \
+             filename*=UTF-8''%E5%86%99%E7%9C%9F%20%F0%9F%93%B7.jpg"
+        );
+    }
+
+    #[actix_web::test]
+    async fn download_of_a_non_ascii_filename_carries_both_parameters() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+        let req = multipart_upload(None, "日本語🎉.txt", "hello").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/files/{}", body["key"].as_str().unwrap()))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let value = res.headers().get(header::CONTENT_DISPOSITION).unwrap();
+        let disposition = ContentDisposition::from_raw(value).unwrap();
+        assert_eq!(disposition.get_filename(), Some("____.txt"));
+        let ext = disposition.get_filename_ext().unwrap();
+        assert_eq!(ext.value, "日本語🎉.txt".as_bytes());
+    }
+
+    #[actix_web::test]
+    async fn extended_upload_filenames_are_decoded() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+        let part_upload = |params: &str| {
+            let boundary = "XBOUNDARYX";
+            test::TestRequest::post()
+                .uri("/upload")
+                .insert_header((
+                    "content-type",
+                    format!("multipart/form-data; boundary={boundary}"),
+                ))
+                .set_payload(format!(
+                    "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; {params}\r\n\
+                     Content-Type: text/plain\r\n\r\nhello\r\n--{boundary}--\r\n"
+                ))
+                .to_request()
+        };
+
+        for (params, stored) in [
+            (
+                "filename=\"__.txt\"; filename*=UTF-8''%E5%86%99%E7%9C%9F.txt",
+                "写真.txt",
+            ),
+            ("filename*=UTF-8''caf%C3%A9%20menu.pdf", "café menu.pdf"),
+            ("filename*=ISO-8859-1''na%EFve.txt", "naïve.txt"),
+            // not valid UTF-8: the plain parameter is used instead
+            (
+                "filename=\"plain.txt\"; filename*=UTF-8''%FF.txt",
+                "plain.txt",
+            ),
+        ] {
+            let body: serde_json::Value =
+                test::call_and_read_body_json(&app, part_upload(params)).await;
+            assert_eq!(body["filename"], stored, "{params}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn original_creation_time_is_kept_when_plausible() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let far_future = (now_epoch() + 365 * 24 * 3600).to_string();
+        for (header, kept) in [
+            (Some("1500000000"), true),
+            (Some("-5"), false),
+            (Some("yesterday"), false),
+            (Some(far_future.as_str()), false),
+            (None, false),
+        ] {
+            let mut req = multipart_upload(None, "a.txt", "x");
+            if let Some(value) = header {
+                req = req.insert_header((ORIGINAL_CREATED_AT_HEADER, value));
+            }
+            let before = now_epoch();
+            let body: serde_json::Value =
+                test::call_and_read_body_json(&app, req.to_request()).await;
+            let meta = state
+                .file_repo
+                .get_by_key(body["key"].as_str().unwrap())
+                .unwrap()
+                .unwrap();
+            if kept {
+                assert_eq!(meta.created_at, 1_500_000_000);
+            } else {
+                assert!(meta.created_at >= before, "{header:?}");
+            }
+        }
+
+        // the PUT path honours it too
+        let req = test::TestRequest::put()
+            .uri("/files/old-photo")
+            .insert_header((ORIGINAL_CREATED_AT_HEADER, "1500000000"))
+            .set_payload("x")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CREATED
+        );
+        let meta = state.file_repo.get_by_key("old-photo").unwrap().unwrap();
+        assert_eq!(meta.created_at, 1_500_000_000);
+    }
+
+    #[actix_web::test]
+    async fn uploads_can_answer_201_with_a_location() {
+        for created_responses in [false, true] {
+            let (state, pool) = test_state(ServerConfig {
+                created_responses,
+                ..ServerConfig::default()
+            });
+            seed_device(&pool, "/dev/sda1", "u1");
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(state))
+                    .configure(configure),
+            )
+            .await;
+
+            let res =
+                test::call_service(&app, multipart_upload(None, "a.txt", "hi").to_request()).await;
+            let location = res.headers().get(header::LOCATION).cloned();
+            if created_responses {
+                assert_eq!(res.status(), StatusCode::CREATED);
+            } else {
+                assert_eq!(res.status(), StatusCode::OK);
+                assert!(location.is_none());
+            }
+            let body: serde_json::Value = test::read_body_json(res).await;
+            if let Some(location) = location {
+                let location = location.to_str().unwrap().to_string();
+                assert_eq!(
+                    location,
+                    format!("/files/{}", body["key"].as_str().unwrap())
+                );
+                let req = test::TestRequest::get().uri(&location).to_request();
+                assert_eq!(test::call_and_read_body(&app, req).await, "hi");
+            }
+
+            let req = test::TestRequest::put()
+                .uri("/files/named")
+                .set_payload("x")
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            assert_eq!(
+                res.headers()
+                    .get(header::LOCATION)
+                    .map(|v| v.to_str().unwrap()),
+                created_responses.then_some("/files/named")
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn downloads_tell_an_offline_device_from_a_deleted_object() {
+        let (state, pool) = test_state(ServerConfig {
+            outage_aware_downloads: true,
+            ..ServerConfig::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let mut keys = Vec::new();
+        for name in ["kept.txt", "gone.txt"] {
+            let req = multipart_upload(None, name, "bytes").to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            keys.push(body["key"].as_str().unwrap().to_string());
+        }
+        let req = test::TestRequest::delete()
+            .uri(&format!("/files/{}", keys[1]))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let get = |key: &str, tenant: Option<&str>| {
+            let mut req = test::TestRequest::get().uri(&format!("/files/{key}"));
+            if let Some(t) = tenant {
+                req = req.insert_header((TENANT_HEADER, t));
+            }
+            req.to_request()
+        };
+        let status = |res: ServiceResponse| res.status();
+
+        assert_eq!(
+            status(test::call_service(&app, get(&keys[0], None)).await),
+            StatusCode::OK
+        );
+        state
+            .device_repo
+            .inner()
+            .mark_removed("/dev/sda1", now_epoch())
+            .unwrap();
+        let res = test::call_service(&app, get(&keys[0], None)).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "60");
+
+        assert_eq!(
+            status(test::call_service(&app, get(&keys[1], None)).await),
+            StatusCode::GONE
+        );
+        // another tenant never learns the key existed
+        assert_eq!(
+            status(test::call_service(&app, get(&keys[1], Some("t2"))).await),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(test::call_service(&app, get("never-stored", None)).await),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn declared_checksums_are_verified_before_publishing() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let sha = |body: &str| format!("{:x}", Sha256::digest(body.as_bytes()));
+        let put = |key: &str, declared: &str| {
+            test::TestRequest::put()
+                .uri(&format!("/files/{key}"))
+                .insert_header((CONTENT_SHA256_HEADER, declared.to_string()))
+                .set_payload("full body")
+                .to_request()
+        };
+
+        let res = test::call_service(&app, put("good", &sha("full body").to_uppercase())).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = test::call_service(&app, put("cut", &sha("full bo"))).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(state.file_repo.get_by_key("cut").unwrap().is_none());
+        let left: Vec<_> = std::fs::read_dir(state.config.storage_root.join("u1"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(left, vec!["good"]);
+        let res = test::call_service(&app, put("bad", "not-a-digest")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // multipart: the request header covers the file part
+        for (declared, expected) in [
+            (sha("hello"), StatusCode::OK),
+            (sha("hell"), StatusCode::UNPROCESSABLE_ENTITY),
+        ] {
+            let req = multipart_upload(None, "a.txt", "hello")
+                .insert_header((CONTENT_SHA256_HEADER, declared))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn sniffed_uploads_are_written_whole() {
+        let (state, pool) = test_state(ServerConfig {
+            allowed_content_types: vec!["image/".into(), "application/octet-stream".into()],
+            sniff_buffer_bytes: 32,
+            ..Default::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let png = |len: usize| {
+            let mut body = b"\x89PNG\r\n\x1a\n".to_vec();
+            body.resize(len, b'x');
+            body
+        };
+        let mut jpeg = b"\xFF\xD8\xFF\xE0".to_vec();
+        jpeg.resize(10, b'j');
+
+        // shorter than the buffer, empty, exactly the buffer, one byte past it
+        for (key, body) in [
+            ("ten", jpeg),
+            ("empty", Vec::new()),
+            ("exact", png(32)),
+            ("over", png(33)),
+        ] {
+            let req = test::TestRequest::put()
+                .uri(&format!("/files/{key}"))
+                .set_payload(body.clone())
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::CREATED
+            );
+            let path = state.storage.resolve_path("u1", key).unwrap();
+            assert_eq!(std::fs::read(path).unwrap(), body, "{key}");
+        }
+
+        let req = test::TestRequest::put()
+            .uri("/files/elf")
+            .set_payload(&b"\x7FELF\x02\x01\x01\0"[..])
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert!(state.file_repo.get_by_key("elf").unwrap().is_none());
+        assert!(!state.storage.resolve_path("u1", "elf").unwrap().exists());
+    }
+
+    #[actix_web::test]
+    async fn versioned_uploads_keep_the_newest_copies_of_a_filename() {
+        let (state, pool) = test_state(ServerConfig {
+            keep_versions: Some(2),
+            ..Default::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let mut keys = Vec::new();
+        for (tenant, name) in [
+            (None, "notes.txt"),
+            (Some("acme"), "notes.txt"),
+            (None, "notes.txt"),
+            (None, "other.txt"),
+            (None, "notes.txt"),
+        ] {
+            let req = multipart_upload(tenant, name, "v").to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            keys.push(body["key"].as_str().unwrap().to_string());
+        }
+        let oldest = state.storage.resolve_path("u1", &keys[0]).unwrap();
+        for _ in 0..50 {
+            if !oldest.exists() {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!oldest.exists(), "bytes of the pruned version remain");
+
+        let live = |key: &str| state.file_repo.get_by_key(key).unwrap().is_some();
+        assert!(!live(&keys[0]));
+        // other tenants and other names have versions of their own
+        assert!(keys[1..].iter().all(|k| live(k)));
+    }
+
+    #[actix_web::test]
+    async fn zip_download_archives_the_requested_keys() {
+        use std::io::Read;
+
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+        let mut keys = Vec::new();
+        for (name, body) in [("a.txt", "alpha"), ("b.txt", "bravo")] {
+            let req = multipart_upload(None, name, body).to_request();
+            let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            keys.push(res["key"].as_str().unwrap().to_string());
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/files/zip")
+            .set_json(serde_json::json!({ "keys": [keys[1], "missing", keys[0]] }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/zip"
+        );
+        let archive = test::read_body(res).await;
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(archive.to_vec())).unwrap();
+        let mut got = Vec::new();
+        for i in 0..zip.len() {
+            let mut entry = zip.by_index(i).unwrap();
+            let mut body = String::new();
+            entry.read_to_string(&mut body).unwrap();
+            got.push((entry.name().to_string(), body));
+        }
+        assert_eq!(
+            got,
+            vec![
+                ("b.txt".to_string(), "bravo".to_string()),
+                ("a.txt".to_string(), "alpha".to_string()),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn streamed_downloads_ignore_range_and_say_so() {
+        let (state, pool) = test_state(ServerConfig {
+            download_rate_limit: Some(1 << 20),
+            ..Default::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+        let req = multipart_upload(None, "a.txt", "0123456789").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/files/{}", body["key"].as_str().unwrap()))
+            .insert_header((header::RANGE, "bytes=2-4"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::ACCEPT_RANGES).unwrap(), "none");
+        assert_eq!(test::read_body(res).await, "0123456789");
+    }
+
+    #[actix_web::test]
+    async fn compressed_objects_are_inflated_unless_client_takes_gzip() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let (state, pool) = test_state(ServerConfig {
+            compress_uploads: true,
+            verify_downloads: true,
+            ..ServerConfig::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let plain = b"squeeze me ".repeat(100);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let req = test::TestRequest::put()
+            .uri("/files/packed")
+            .set_payload(plain.clone())
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["size"], plain.len());
+        let row = state.file_repo.get_by_key("packed").unwrap().unwrap();
+        assert_eq!(row.compressed, 1);
+        assert!(row.size < plain.len() as i64);
+        assert_eq!(row.uncompressed_size, Some(plain.len() as i64));
+        let sha = format!("{:x}", Sha256::digest(&plain));
+        assert_eq!(row.sha256.as_deref(), Some(sha.as_str()));
+
+        // already-dense bytes are stored as they came
+        let req = test::TestRequest::put()
+            .uri("/files/dense")
+            .set_payload(&b"xyz"[..])
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let dense = state.file_repo.get_by_key("dense").unwrap().unwrap();
+        assert_eq!((dense.compressed, dense.size), (0, 3));
+
+        let req = test::TestRequest::get().uri("/files/packed").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept-Encoding");
+        assert_eq!(
+            res.headers().get(header::ETAG).unwrap(),
+            &format!("\"{sha}\"")
+        );
+        assert_eq!(test::read_body(res).await, plain);
+
+        let req = test::TestRequest::get()
+            .uri("/files/packed")
+            .insert_header((header::ACCEPT_ENCODING, "br, gzip"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        let gzip_tag = res.headers().get(header::ETAG).unwrap().clone();
+        assert_eq!(gzip_tag, format!("\"{sha}-gzip\"").as_str());
+        let gz = test::read_body(res).await;
+        assert_eq!(gz.len() as i64, row.size);
+        let mut inflated = Vec::new();
+        GzDecoder::new(&gz[..]).read_to_end(&mut inflated).unwrap();
+        assert_eq!(inflated, plain);
+
+        // a cached gzip body doesn't validate the identity representation
+        let req = test::TestRequest::get()
+            .uri("/files/packed")
+            .insert_header((header::IF_NONE_MATCH, gzip_tag))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/files/metadata-batch")
+            .set_json(serde_json::json!({ "keys": ["packed"] }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["packed"]["size"], plain.len());
+
+        let (_, mut reader) = state.service().get_object("packed").await.unwrap().unwrap();
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(bytes, plain);
+    }
+
+    #[actix_web::test]
+    async fn files_can_be_browsed_by_key_prefix() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+        for key in ["logs-2", "logs-1", "other"] {
+            let req = test::TestRequest::put()
+                .uri(&format!("/files/{key}"))
+                .set_payload(key)
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::CREATED
+            );
+        }
+        let req = test::TestRequest::put()
+            .uri("/files/logs-t")
+            .insert_header((TENANT_HEADER, "t1"))
+            .set_payload("t")
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get()
+            .uri("/files?prefix=logs-")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let keys: Vec<&str> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["key"].as_str().unwrap())
+            .collect();
+        assert_eq!(keys, vec!["logs-1", "logs-2"]);
+
+        let req = test::TestRequest::get().uri("/files?limit=1").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body[0]["key"], "logs-1");
+        let req = test::TestRequest::get()
+            .uri("/files?limit=1&after=logs-1")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body[0]["key"], "logs-2");
+
+        let req = test::TestRequest::get()
+            .uri("/files?prefix=LOGS-")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(body.as_array().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn access_log_uses_common_log_format() {
+        captured_logs();
+        let config = ServerConfig {
+            access_log_format: Some(config::ACCESS_LOG_COMMON.to_string()),
+            ..ServerConfig::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(access_logger(&config))
+                .route("/clf-probe", web::get().to(|| async { "hello" })),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/clf-probe?x=1")
+            .peer_addr("10.0.0.7:4000".parse().unwrap())
+            .to_request();
+        test::call_and_read_body(&app, req).await;
+
+        let clf = regex::Regex::new(
+            r#"10\.0\.0\.7 - - \[[^\]]+\] "GET /clf-probe\?x=1 HTTP/1\.1" 200 5$"#,
+        )
+        .unwrap();
+        let logs = captured_logs();
+        assert!(logs.iter().any(|l| clf.is_match(l)), "{logs:?}");
+    }
+
+    #[actix_web::test]
+    async fn devices_at_object_cap_are_skipped() {
+        let (state, pool) = test_state(ServerConfig {
+            max_objects_per_device: Some(1),
+            ..ServerConfig::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        seed_device(&pool, "/dev/sdb1", "u2");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        for name in ["a.txt", "b.txt"] {
+            let req = multipart_upload(None, name, "x").to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+        assert_eq!(state.file_repo.count_by_device("u1").unwrap(), 1);
+        assert_eq!(state.file_repo.count_by_device("u2").unwrap(), 1);
+
+        let req = multipart_upload(None, "c.txt", "x").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(state.file_repo.count_active().unwrap(), 2);
+    }
+
+    #[actix_web::test]
+    async fn tar_import_stores_each_file() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, body) in [("photos/a.jpg", &b"first"[..]), ("b.txt", &[9u8; 2000][..])] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(body.len() as u64);
+            builder.append_data(&mut header, name, body).unwrap();
+        }
+        let mut dir = tar::Header::new_gnu();
+        dir.set_entry_type(tar::EntryType::Directory);
+        dir.set_size(0);
+        builder.append_data(&mut dir, "photos/", &[][..]).unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/import.tar")
+            .insert_header((header::CONTENT_TYPE, "application/x-tar"))
+            .set_payload(archive)
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["skipped"], serde_json::json!(["photos/"]));
+        let imported = body["imported"].as_array().unwrap();
+        assert_eq!(imported.len(), 2);
+        for (resp, (filename, size)) in imported.iter().zip([("a.jpg", 5), ("b.txt", 2000)]) {
+            let key = resp["key"].as_str().unwrap();
+            let meta = state.file_repo.get_by_key(key).unwrap().unwrap();
+            assert_eq!((meta.filename.as_str(), meta.size), (filename, size));
+            assert_eq!(std::fs::metadata(&meta.path).unwrap().len(), size as u64);
+        }
+    }
+
+    #[actix_web::test]
+    async fn tar_import_skips_refused_entries_and_reports_partial_failure() {
+        let (state, pool) = test_state(ServerConfig {
+            max_filename_len: 12,
+            filename_policy: FilenamePolicy::Reject,
+            reject_empty_uploads: true,
+            ..ServerConfig::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, body) in [
+            ("empty.txt", &b""[..]),
+            ("a-rather-long-name.txt", &b"long"[..]),
+            ("kept.txt", &b"kept"[..]),
+            ("cut.bin", &[7u8; 2000][..]),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(body.len() as u64);
+            builder.append_data(&mut header, name, body).unwrap();
+        }
+        let mut archive = builder.into_inner().unwrap();
+        // the connection drops in the middle of the last member
+        archive.truncate(archive.len() - 2048);
+
+        let req = test::TestRequest::post()
+            .uri("/import.tar")
+            .set_payload(archive)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(
+            body["skipped"],
+            serde_json::json!(["empty.txt", "a-rather-long-name.txt"])
+        );
+        assert!(body["error"].is_string());
+        let imported = body["imported"].as_array().unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0]["filename"], "kept.txt");
+        assert_eq!(state.file_repo.count_active().unwrap(), 1);
+    }
+
+    #[actix_web::test]
+    async fn device_header_only_when_enabled() {
+        for expose in [false, true] {
+            let (state, pool) = test_state(ServerConfig {
+                expose_device_header: expose,
+                ..ServerConfig::default()
+            });
+            seed_device(&pool, "/dev/sda1", "u1");
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(state))
+                    .configure(configure),
+            )
+            .await;
+            let req = multipart_upload(None, "a.txt", "where am i").to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            let uri = format!("/files/{}", body["key"].as_str().unwrap());
+
+            let res =
+                test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let device = res.headers().get(DEVICE_HEADER);
+            if expose {
+                assert_eq!(device.unwrap(), "u1");
+            } else {
+                assert!(device.is_none());
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn empty_uploads_are_stored_unless_rejected() {
+        for reject in [false, true] {
+            let (state, pool) = test_state(ServerConfig {
+                reject_empty_uploads: reject,
+                ..ServerConfig::default()
+            });
+            seed_device(&pool, "/dev/sda1", "u1");
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(state.clone()))
+                    .configure(configure),
+            )
+            .await;
+            let req = multipart_upload(None, "empty.txt", "").to_request();
+            let res = test::call_service(&app, req).await;
+            let stored = std::fs::read_dir(state.config.storage_root.join("u1"))
+                .unwrap()
+                .count();
+            if reject {
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+                assert_eq!(stored, 0, "temp file removed");
+                assert_eq!(state.file_repo.count_active().unwrap(), 0);
+            } else {
+                assert_eq!(res.status(), StatusCode::OK);
+                let body: serde_json::Value = test::read_body_json(res).await;
+                assert_eq!(body["size"], 0);
+                assert_eq!(stored, 1);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn tagged_upload_reports_progress() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let req = multipart_upload(None, "a.txt", "hello")
+            .insert_header((UPLOAD_ID_HEADER, "up-1"))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let req = test::TestRequest::get()
+            .uri("/uploads/up-1/progress")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+        let events = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        assert!(events.contains("\"received\":5"), "{events}");
+        assert!(events.contains("event: complete"), "{events}");
+        assert!(events.contains(body["key"].as_str().unwrap()));
+
+        // events published while a subscriber is attached are streamed until completion
+        let session = state.uploads.start(None, "live").unwrap();
+        let req = test::TestRequest::get()
+            .uri("/uploads/live/progress")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        session.add(3);
+        session.add(4);
+        session.fail("client went away");
+        let events = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        let received: Vec<&str> = events
+            .lines()
+            .filter_map(|l| l.strip_prefix("data: {\"received\":"))
+            .collect();
+        assert_eq!(received, vec!["0}", "3}", "7}"]);
+        assert!(events.ends_with("event: error\ndata: {\"message\":\"client went away\"}\n\n"));
+
+        state.uploads.start(None, "busy").unwrap();
+        let req = multipart_upload(None, "b.txt", "x")
+            .insert_header((UPLOAD_ID_HEADER, "busy"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CONFLICT
+        );
+        let req = test::TestRequest::get()
+            .uri("/uploads/nope/progress")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        // another tenant neither sees the session nor collides with its id
+        let req = test::TestRequest::get()
+            .uri("/uploads/up-1/progress")
+            .insert_header((TENANT_HEADER, "t2"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let req = multipart_upload(Some("t2"), "b.txt", "x")
+            .insert_header((UPLOAD_ID_HEADER, "busy"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn upload_sessions_are_listed_with_their_state() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let stuck = state.uploads.start(None, "stuck").unwrap();
+        stuck.set_key("big-video");
+        stuck.add(7);
+        state
+            .uploads
+            .start(Some("t2"), "lost")
+            .unwrap()
+            .fail("upload aborted");
+        let req = multipart_upload(None, "a.txt", "hello")
+            .insert_header((UPLOAD_ID_HEADER, "done"))
+            .to_request();
+        let stored: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/uploads").to_request();
+        let listed: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let by_id = |id: &str| listed.iter().find(|s| s["id"] == id).unwrap().clone();
+        assert_eq!(listed.len(), 3);
+        let s = by_id("stuck");
+        assert_eq!(
+            (&s["state"], &s["key"], &s["received"]),
+            (&"in-flight".into(), &"big-video".into(), &7.into())
+        );
+        assert!(s["created_at"].as_i64().unwrap() <= s["last_activity"].as_i64().unwrap());
+        let s = by_id("lost");
+        assert_eq!(
+            (&s["state"], &s["tenant"], &s["error"]),
+            (&"failed".into(), &"t2".into(), &"upload aborted".into())
+        );
+        let s = by_id("done");
+        assert_eq!(
+            (&s["state"], &s["key"], &s["received"]),
+            (&"complete".into(), &stored["key"], &5.into())
+        );
+        assert!(s.get("error").is_none());
+
+        let req = test::TestRequest::get()
+            .uri("/uploads")
+            .insert_header((TENANT_HEADER, "t2"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_web::test]
+    async fn abandoned_upload_fails_its_session() {
+        let registry = UploadRegistry::default();
+        let session = registry.start(None, "gone").unwrap();
+        let guard = SessionGuard::new(session);
+        guard.add(2);
+        // what actix does to the handler future when the client disconnects
+        drop(guard);
+
+        let session = registry.get(None, "gone").unwrap();
+        assert_eq!(
+            session.snapshot().last(),
+            Some(&ProgressEvent::Failed("upload aborted".into()))
+        );
+        let events: Vec<String> = progress_stream(session)
+            .map(|e| String::from_utf8(e.unwrap().to_vec()).unwrap())
+            .collect()
+            .await;
+        assert!(events.last().unwrap().starts_with("event: error"));
+        assert!(registry.start(None, "gone").is_some());
+    }
+
+    #[actix_web::test]
+    async fn content_type_query_overrides_part_type() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let stored_type = |body: serde_json::Value| {
+            let meta = state
+                .file_repo
+                .get_by_key(body["key"].as_str().unwrap())
+                .unwrap()
+                .unwrap();
+            meta.content_type
+        };
+
+        let req = multipart_upload(None, "a.txt", "a").to_request();
+        let body = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(stored_type(body).as_deref(), Some("text/plain"));
+
+        let req = multipart_upload(None, "b.json", "{}")
+            .uri("/upload?content_type=application%2Fjson")
+            .to_request();
+        let body = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(stored_type(body).as_deref(), Some("application/json"));
+
+        let req = multipart_upload(None, "c.txt", "c")
+            .uri("/upload?content_type=not%20a%20type")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn raw_put_refuses_existing_keys() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let put = |key: &str, body: &'static str| {
+            test::TestRequest::put()
+                .uri(&format!("/files/{key}?filename=notes.txt"))
+                .insert_header(("content-type", "text/plain"))
+                .set_payload(body)
+                .to_request()
+        };
+
+        let res = test::call_service(&app, put("my-key", "first")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["key"], "my-key");
+        assert_eq!(body["filename"], "notes.txt");
+        let meta = state.file_repo.get_by_key("my-key").unwrap().unwrap();
+        assert_eq!(meta.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(std::fs::read(&meta.path).unwrap(), b"first");
+
+        let res = test::call_service(&app, put("my-key", "second")).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let meta = state.file_repo.get_by_key("my-key").unwrap().unwrap();
+        assert_eq!(std::fs::read(&meta.path).unwrap(), b"first");
+
+        // bytes already on disk without a row (a racing PUT that hasn't recorded its row
+        // yet) are never replaced either
+        let stray = state.storage.resolve_path("u1", "racing").unwrap();
+        std::fs::write(&stray, b"winner").unwrap();
+        let res = test::call_service(&app, put("racing", "loser")).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(std::fs::read(&stray).unwrap(), b"winner");
+        let leftovers: Vec<_> = std::fs::read_dir(stray.parent().unwrap())
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".part"))
+            .collect();
+        assert!(leftovers.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn raw_put_validates_key_length_and_charset() {
+        let (state, pool) = test_state(ServerConfig {
+            max_key_len: 16,
+            ..ServerConfig::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let put = |key: &str| {
+            test::TestRequest::put()
+                .uri(&format!("/files/{key}"))
+                .set_payload("x")
+                .to_request()
+        };
+
+        let res = test::call_service(&app, put("a-valid_key.1")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = test::call_service(&app, put(&"k".repeat(17))).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        for bad in [
+            "sp%20ace",
+            "caf%C3%A9",
+            "semi%3Bcolon",
+            "back%5Cslash",
+            "x.part",
+        ] {
+            let res = test::call_service(&app, put(bad)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{bad}");
+        }
+    }
+
+    /// Accepts uploads, remembering their temp paths, until told to reject them.
+    #[derive(Default)]
+    struct RecordTemps {
+        seen: StdMutex<Vec<PathBuf>>,
+        reject: AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl PostUploadHook for RecordTemps {
+        async fn process(&self, temp: &Path, _meta: &UploadMeta) -> Result<HookDecision> {
+            self.seen.lock().unwrap().push(temp.to_path_buf());
+            Ok(match self.reject.load(Ordering::Relaxed) {
+                true => HookDecision::Reject("no".into()),
+                false => HookDecision::Accept,
+            })
+        }
+    }
+
+    #[actix_web::test]
+    async fn custom_temp_names_are_used_end_to_end() {
+        let names = TempNames::new("tmp-", ".upload");
+        let (state, pool) = test_state(ServerConfig {
+            temp_names: names.clone(),
+            ..ServerConfig::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let hook = Arc::new(RecordTemps::default());
+        let state = state.with_hook(hook.clone());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let req = multipart_upload(None, "a.txt", "alpha").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let key = body["key"].as_str().unwrap();
+        let temp = hook.seen.lock().unwrap()[0].clone();
+        let name = temp.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with(&format!("tmp-{key}.")), "{name}");
+        assert!(name.ends_with(".upload"), "{name}");
+        assert!(!temp.exists());
+        let meta = state.file_repo.get_by_key(key).unwrap().unwrap();
+        assert_eq!(std::fs::read(&meta.path).unwrap(), b"alpha");
+
+        // the configured pattern is reserved, the default one is an ordinary key now
+        let put = |key: &str| {
+            test::TestRequest::put()
+                .uri(&format!("/files/{key}"))
+                .set_payload("x")
+                .to_request()
+        };
+        for (key, status) in [
+            ("tmp-x.upload", StatusCode::BAD_REQUEST),
+            ("x.part", StatusCode::CREATED),
+            ("x.upload", StatusCode::CREATED),
+        ] {
+            let res = test::call_service(&app, put(key)).await;
+            assert_eq!(res.status(), status, "{key}");
+        }
+
+        // a failed upload leaves nothing matching the pattern behind
+        hook.reject.store(true, Ordering::Relaxed);
+        let req = multipart_upload(None, "b.txt", "beta").to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        let dir = Path::new(&meta.path).parent().unwrap();
+        let leftovers: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| names.matches(&e.file_name().to_string_lossy()))
+            .collect();
+        assert!(leftovers.is_empty(), "{leftovers:?}");
+    }
+
+    #[actix_web::test]
+    async fn write_to_unmounted_device_is_refused() {
+        let (state, pool) = test_state(ServerConfig {
+            verify_mounts: true,
+            ..ServerConfig::default()
+        });
+        let sys = Arc::new(FakeSystem::default());
+        let mount_point = state.config.storage_root.join("u1");
+        std::fs::create_dir_all(&mount_point).unwrap();
+        // the row records the mount point through a symlink; the kernel lists it resolved
+        let alias = state.config.storage_root.join("alias");
+        std::os::unix::fs::symlink(&mount_point, &alias).unwrap();
+        sys.set_mounts(&format!(
+            "/dev/sda1 {} ext4 rw 0 0\n",
+            mount_point.canonicalize().unwrap().display()
+        ));
+        let state = state.with_system(sys.clone());
+        seed_device(&pool, "/dev/sda1", "u1");
+        state
+            .device_repo
+            .inner()
+            .update_mount_result("/dev/sda1", &alias.to_string_lossy(), "u1")
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        // selected while mounted
+        let req = multipart_upload(None, "a.txt", "mounted").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        // the mounter unmounts it before the next upload's first write
+        sys.set_mounts("");
+        let req = multipart_upload(None, "b.txt", "stray").to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        let stray: Vec<_> = std::fs::read_dir(&mount_point).unwrap().collect();
+        assert_eq!(stray.len(), 1, "only the first object on the mount point");
+    }
+
+    #[actix_web::test]
+    async fn uploads_are_refused_while_storage_root_is_missing() {
+        let (state, pool) = test_state(ServerConfig {
+            root_mount_check_secs: Some(1),
+            ..ServerConfig::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        // a plain directory holding the device mounts is what a healthy root looks like
+        check_root_present(&state);
+        let req = multipart_upload(None, "a.txt", "before").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let root = state.config.storage_root.clone();
+        let moved = root.with_extension("gone");
+        std::fs::rename(&root, &moved).unwrap();
+        check_root_present(&state);
+        assert!(!state.root_present.load(Ordering::Relaxed));
+        let req = multipart_upload(None, "b.txt", "after").to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert!(!root.exists());
+
+        std::fs::rename(&moved, &root).unwrap();
+        check_root_present(&state);
+        let req = multipart_upload(None, "c.txt", "again").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let stored = std::fs::read_dir(root.join("u1")).unwrap();
+        assert_eq!(stored.count(), 2);
+    }
+
+    #[actix_web::test]
+    async fn expired_uploads_are_gone() {
+        use crate::schema::files;
+        use diesel::prelude::*;
+
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let req = multipart_upload(None, "tmp.txt", "short-lived")
+            .uri("/upload?ttl=3600")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let key = body["key"].as_str().unwrap().to_string();
+        let meta = state.file_repo.get_by_key(&key).unwrap().unwrap();
+        assert!(meta.expires_at.unwrap() > now_epoch());
+        let uri = format!("/files/{key}");
+        let res = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // pretend the TTL ran out before the sweeper got to it
+        let mut conn = pool.get().unwrap();
+        diesel::update(files::table.filter(files::key.eq(&key)))
+            .set(files::expires_at.eq(Some(now_epoch() - 1)))
+            .execute(&mut conn)
+            .unwrap();
+        let res = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::GONE);
+
+        let req = multipart_upload(None, "x.txt", "x")
+            .uri("/upload?ttl=0")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn unnamed_part_gets_key_based_filename() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let request = |disposition: &str| {
+            let payload = format!(
+                "--XB\r\nContent-Disposition: form-data; {disposition}\r\n\
+                 Content-Type: image/jpeg\r\n\r\njpeg-bytes\r\n--XB--\r\n"
+            );
+            test::TestRequest::post()
+                .uri("/upload")
+                .insert_header(("content-type", "multipart/form-data; boundary=XB"))
+                .set_payload(payload)
+                .to_request()
+        };
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, request("name=\"file\"")).await;
+        let key = body["key"].as_str().unwrap();
+        assert_eq!(body["filename"], format!("{key}.jpg"));
+        assert_eq!(body["filename_supplied"], false);
+        let meta = state.file_repo.get_by_key(key).unwrap().unwrap();
+        assert_eq!(meta.filename_supplied, 0);
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, request("name=\"file\"; filename=\"cat.jpeg\""))
+                .await;
+        assert_eq!(body["filename"], "cat.jpeg");
+        assert_eq!(body["filename_supplied"], true);
+        let meta = state
+            .file_repo
+            .get_by_key(body["key"].as_str().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(meta.filename_supplied, 1);
+    }
+
+    #[actix_web::test]
+    async fn file_part_is_selected_by_field_name() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let part = |disposition: &str, body: &str| {
+            format!("--XB\r\nContent-Disposition: form-data; {disposition}\r\n\r\n{body}\r\n")
+        };
+        // metadata and a decoy file before the real one, and a trailing field
+        let payload = [
+            part("name=\"note\"", "for the album"),
+            part("name=\"thumb\"; filename=\"t.jpg\"", "not me"),
+            part("name=\"filename\"", "renamed.txt"),
+            part("name=\"file\"; filename=\"orig.txt\"", "the real file"),
+            part("name=\"after\"", "ignored"),
+            "--XB--\r\n".to_string(),
+        ]
+        .concat();
+        let req = test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("content-type", "multipart/form-data; boundary=XB"))
+            .set_payload(payload)
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["filename"], "renamed.txt");
+        let meta = state
+            .file_repo
+            .get_by_key(body["key"].as_str().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read(&meta.path).unwrap(), b"the real file");
+
+        let payload = [part("name=\"note\"", "no file"), "--XB--\r\n".to_string()].concat();
+        let req = test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("content-type", "multipart/form-data; boundary=XB"))
+            .set_payload(payload)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn serves_uploads_over_unix_socket() {
+        use tokio::io::AsyncReadExt;
+        use tokio::net::UnixStream;
+
+        let pool = temp_pool();
+        seed_device(&pool, "/dev/sda1", "u1");
+        let dir = temp_dir("uds");
+        let socket = dir.join("api.sock");
+        // a stale socket from a previous run must not block binding
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        let config = ServerConfig {
+            storage_root: dir.join("pool"),
+            unix_socket: Some(socket.clone()),
+            unix_socket_mode: Some(0o660),
+            ..Default::default()
+        };
+        actix_web::rt::spawn(run(
+            config,
+            new_file_repo(pool.clone()),
+            new_device_repo(pool),
+        ));
+
+        let mut stream = None;
+        for _ in 0..100 {
+            if let Ok(s) = UnixStream::connect(&socket).await {
+                stream = Some(s);
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut stream = stream.expect("server listening on socket");
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let (content_type, body) = multipart_body("a.txt", "over uds");
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: {content_type}\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.contains("\"device_uuid\":\"u1\""));
+    }
+
+    #[actix_web::test]
+    async fn tenants_cannot_see_each_others_keys() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_mounted(&pool, &["u1"]);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            multipart_upload(Some("acme"), "a.txt", "secret").to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let uploaded: serde_json::Value = test::read_body_json(resp).await;
+        let key = uploaded["key"].as_str().unwrap().to_string();
+        let meta = state.file_repo.get_by_key(&key).unwrap().unwrap();
+        assert_eq!(meta.tenant.as_deref(), Some("acme"));
+        assert_eq!(
+            PathBuf::from(&meta.path),
+            state.config.storage_root.join("u1").join("acme").join(&key)
+        );
+
+        let get = |tenant: Option<&str>| {
+            let mut req = test::TestRequest::get().uri(&format!("/files/{key}"));
+            if let Some(t) = tenant {
+                req = req.insert_header((TENANT_HEADER, t));
+            }
+            req.to_request()
+        };
+        let resp = test::call_service(&app, get(Some("acme"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "secret");
+        for other in [Some("globex"), None] {
+            assert_eq!(
+                test::call_service(&app, get(other)).await.status(),
+                StatusCode::NOT_FOUND
+            );
+        }
+
+        let resp = test::call_service(
+            &app,
+            multipart_upload(Some(".."), "b.txt", "x").to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn metadata_batch_maps_missing_keys_to_null() {
+        let (state, _pool) = test_state(ServerConfig::default());
+        insert_row(&state, "k1", None);
+        insert_row(&state, "k2", None);
+        state.file_repo.soft_delete("k2").unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/files/metadata-batch")
+            .set_json(serde_json::json!({"keys": ["k1", "k2", "nope"]}))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["k1"]["filename"], "f.txt");
+        assert!(body["k1"].get("path").is_none());
+        assert!(body["k2"].is_null());
+        assert!(body["nope"].is_null());
+        assert_eq!(body.as_object().unwrap().len(), 3);
+
+        let too_many: Vec<String> = (0..=MAX_METADATA_BATCH).map(|i| i.to_string()).collect();
+        let req = test::TestRequest::post()
+            .uri("/files/metadata-batch")
+            .set_json(serde_json::json!({ "keys": too_many }))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn exhausted_device_is_deselected() {
+        let pool = temp_pool();
+        let repo = seed_mounted(&pool, &["u1", "u2"]);
+        let cache = DeviceUuidCache::new(Duration::from_secs(30));
+
+        cache.mark_exhausted("u1");
+        for _ in 0..10 {
+            assert_eq!(cache.get_or_fetch(&repo).await.unwrap(), "u2");
+        }
+        cache.mark_exhausted("u2");
+        assert!(cache.get_or_fetch(&repo).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn config_endpoint_shows_effective_values_with_secrets_redacted() {
+        let (state, _pool) = test_state(ServerConfig {
+            device_cache_ttl_secs: 7,
+            max_key_len: 64,
+            upload_idle_timeout: Some(Duration::from_secs(30)),
+            api_token: Some("admin-secret".into()),
+            tenant_tokens: HashMap::from([("t1".to_string(), "tok-1".to_string())]),
+            signing_secret: Some("hmac-secret".into()),
+            ..Default::default()
+        });
+        let root = state.config.storage_root.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .wrap(from_fn(require_token))
+                .configure(configure),
+        )
+        .await;
+        let get = |token: &str| {
+            test::TestRequest::get()
+                .uri("/config")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+                .to_request()
+        };
+
+        let res = test::call_service(&app, get("admin-secret")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let raw = test::read_body(res).await;
+        let text = std::str::from_utf8(&raw).unwrap();
+        for secret in ["admin-secret", "tok-1", "hmac-secret"] {
+            assert!(!text.contains(secret), "{secret} leaked: {text}");
+        }
+        let cfg: serde_json::Value = serde_json::from_slice(&raw).unwrap();
+        assert_eq!(cfg["storage_root"], root.to_str().unwrap());
+        assert_eq!(cfg["addr"], config::DEFAULT_ADDR);
+        assert_eq!(cfg["device_cache_ttl_secs"], 7);
+        assert_eq!(cfg["max_key_len"], 64);
+        assert_eq!(cfg["upload_idle_timeout"], 30);
+        assert_eq!(cfg["layout"], "flat");
+        assert_eq!(cfg["api_token"], REDACTED);
+        assert_eq!(cfg["signing_secret"], REDACTED);
+        assert_eq!(cfg["tenant_tokens"]["t1"], REDACTED);
+        assert!(cfg["download_rate_limit"].is_null());
+
+        // not for tenants
+        let res = test::call_service(&app, get("tok-1")).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn config_endpoint_is_local_only_without_a_token() {
+        let (state, _pool) = test_state(ServerConfig::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+        for (peer, status) in [
+            ("127.0.0.1:40000", StatusCode::OK),
+            ("192.0.2.7:40000", StatusCode::FORBIDDEN),
+        ] {
+            let req = test::TestRequest::get()
+                .uri("/config")
+                .peer_addr(peer.parse().unwrap())
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                status,
+                "{peer}"
+            );
+        }
+        // no peer address: the Unix socket
+        let req = test::TestRequest::get().uri("/config").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn tenant_tokens_pin_the_tenant() {
+        let (state, pool) = test_state(ServerConfig {
+            api_token: Some("admin".into()),
+            tenant_tokens: HashMap::from([
+                ("t1".to_string(), "tok-1".to_string()),
+                ("t2".to_string(), "tok-2".to_string()),
+            ]),
+            ..Default::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .wrap(from_fn(require_token))
+                .configure(configure),
+        )
+        .await;
+        let bearer = |token: &str| (header::AUTHORIZATION, format!("Bearer {token}"));
+
+        // t1's token stores under t1 whatever header it sends along
+        let req = multipart_upload(None, "a.txt", "mine")
+            .insert_header(bearer("tok-1"))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let uri = format!("/files/{}", body["key"].as_str().unwrap());
+        let req = multipart_upload(Some("t2"), "a.txt", "sneaky")
+            .insert_header(bearer("tok-1"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        let get = |token: &str, tenant: Option<&str>| {
+            let mut req = test::TestRequest::get()
+                .uri(&uri)
+                .insert_header(bearer(token));
+            if let Some(t) = tenant {
+                req = req.insert_header((TENANT_HEADER, t));
+            }
+            req.to_request()
+        };
+        let res = test::call_service(&app, get("tok-1", None)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = test::call_service(&app, get("tok-2", None)).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = test::call_service(&app, get("tok-2", Some("t1"))).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        // the admin token may still name any tenant
+        let res = test::call_service(&app, get("admin", Some("t1"))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // device-wide listings would show other tenants' objects
+        for uri in ["/devices/u1/export.tar", "/maintenance/size-check"] {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(bearer("tok-2"))
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::FORBIDDEN
+            );
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(bearer("admin"))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+    }
+
+    #[actix_web::test]
+    async fn signed_url_downloads_without_token() {
+        let (state, _pool) = test_state(ServerConfig {
+            api_token: Some("tok".into()),
+            signing_secret: Some("s3cret".into()),
+            ..Default::default()
+        });
+        let obj = state.config.storage_root.join("obj");
+        std::fs::write(&obj, b"shared").unwrap();
+        state
+            .file_repo
+            .insert_file(&NewFileMeta {
+                key: "k1",
+                filename: "f.txt",
+                content_type: None,
+                size: 6,
+                path: obj.to_string_lossy().as_ref(),
+                created_at: now_epoch(),
+                deleted: 0,
+                device_uuid: None,
+                sha256: None,
+                retain_until: None,
+                tenant: None,
+                expires_at: None,
+                filename_supplied: 1,
+                compressed: 0,
+                uncompressed_size: None,
+            })
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .wrap(from_fn(require_token))
+                .configure(configure),
+        )
+        .await;
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+
+        let resp = test::call_service(&app, get("/files/k1")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/files/k1/download-url")
+            .insert_header((header::AUTHORIZATION, "Bearer tok"))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let url = body["url"].as_str().unwrap().to_string();
+        let resp = test::call_service(&app, get(&url)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "shared");
+
+        let tampered = url.replace("/files/k1", "/files/k2");
+        let resp = test::call_service(&app, get(&tampered)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let expired = format!(
+            "/files/k1?expires=1&sig={}",
+            signing::sign(b"s3cret", "k1", 1)
+        );
+        let resp = test::call_service(&app, get(&expired)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // a signature only stands in for the token on the download route itself
+        for uri in [
+            "/stats?sig=00",
+            "/files?prefix=k&sig=00",
+            "/files/k1/download-url?sig=00",
+        ] {
+            let resp = test::call_service(&app, get(uri)).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{uri}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn stats_report_devices_and_objects() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_mounted(&pool, &["u1", "u2"]);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        state.device_repo.set_read_only("u2", true).await.unwrap();
+        let req = multipart_upload(None, "a.txt", "12345").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/stats").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["devices"]["total"], 2);
+        assert_eq!(body["devices"]["mounted"], 2);
+        assert_eq!(body["devices"]["read_only"], 1);
+        assert_eq!(body["devices"]["removed"], 0);
+        assert_eq!(body["objects"], 1);
+        assert_eq!(body["bytes"], 5);
+    }
+
+    #[actix_web::test]
+    async fn version_lists_embedded_migrations() {
+        use diesel::migration::MigrationSource;
+
+        let (state, _pool) = test_state(ServerConfig::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/version").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+
+        let mut embedded: Vec<String> =
+            MigrationSource::<diesel::sqlite::Sqlite>::migrations(&crate::db::MIGRATIONS)
+                .unwrap()
+                .iter()
+                .map(|m| m.name().version().to_string())
+                .collect();
+        embedded.sort();
+        assert_eq!(body["migrations"], serde_json::json!(embedded));
+    }
+
+    #[tokio::test]
+    async fn primary_device_is_preferred_until_unavailable() {
+        let pool = temp_pool();
+        let repo = seed_mounted(&pool, &["u1", "u2", "u3"]);
+        let cache = DeviceUuidCache::new(Duration::from_secs(30)).with_selector(
+            selector::selector(DeviceSelection::Random, Some("u2".into())),
+        );
+        for _ in 0..10 {
+            assert_eq!(cache.get_or_fetch(&repo).await.unwrap(), "u2");
+        }
+
+        // primary full: fall back to the others
+        cache.mark_exhausted("u2");
+        for _ in 0..10 {
+            assert_ne!(cache.get_or_fetch(&repo).await.unwrap(), "u2");
+        }
+
+        // primary unplugged: fall back once the cached list is refreshed
+        let cache = DeviceUuidCache::new(Duration::from_secs(30)).with_selector(
+            selector::selector(DeviceSelection::Random, Some("u2".into())),
+        );
+        repo.inner().mark_removed("/dev/sd11", now_epoch()).unwrap();
+        for _ in 0..10 {
+            assert_ne!(cache.get_or_fetch(&repo).await.unwrap(), "u2");
+        }
+    }
+
+    #[actix_web::test]
+    async fn writes_are_serialized_per_device() {
+        let (state, pool) = test_state(ServerConfig {
+            serialize_device_writes: true,
+            device_selection: DeviceSelection::RoundRobin,
+            ..ServerConfig::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        seed_device(&pool, "/dev/sdb1", "u2");
+        let object = |key: &str| NewObject {
+            key: key.to_string(),
+            filename: format!("{key}.bin"),
+            filename_supplied: true,
+            content_type: None,
+            tenant: None,
+            ttl: None,
+            no_clobber: false,
+            created_at: None,
+            expected_sha256: None,
+        };
+        let body = || futures_util::stream::iter([Ok::<_, std::io::Error>(web::Bytes::from("x"))]);
+        // the first upload's body only arrives once `release` fires
+        let (release, held) = tokio::sync::oneshot::channel::<&'static str>();
+        let mut slow_body = Box::pin(futures_util::stream::once(async move {
+            held.await
+                .map(web::Bytes::from)
+                .map_err(|_| std::io::Error::other("sender dropped"))
+        }));
+        let mut first_body = body();
+        let mut third_body = body();
+        let mut first = std::pin::pin!(store_object(&state, object("a"), &mut slow_body));
+        let mut other_device = std::pin::pin!(store_object(&state, object("b"), &mut first_body));
+        let mut same_device = std::pin::pin!(store_object(&state, object("c"), &mut third_body));
+
+        let wait = Duration::from_millis(300);
+        assert!(tokio::time::timeout(wait, &mut first).await.is_err());
+        let b = tokio::time::timeout(Duration::from_secs(5), &mut other_device)
+            .await
+            .expect("a write to another device goes ahead")
+            .unwrap();
+        assert!(
+            tokio::time::timeout(wait, &mut same_device).await.is_err(),
+            "a write to the busy device waits"
+        );
+
+        release.send("slow").unwrap();
+        let (a, c) = futures_util::future::join(first, same_device).await;
+        let (a, c) = (a.unwrap(), c.unwrap());
+        assert_eq!(a["device_uuid"], c["device_uuid"]);
+        assert_ne!(a["device_uuid"], b["device_uuid"]);
+    }
+
+    #[actix_web::test]
+    async fn mount_is_checked_after_waiting_for_the_device_lock() {
+        let (state, pool) = test_state(ServerConfig {
+            serialize_device_writes: true,
+            verify_mounts: true,
+            ..ServerConfig::default()
+        });
+        let sys = Arc::new(FakeSystem::default());
+        let mount_point = state.config.storage_root.join("u1");
+        std::fs::create_dir_all(&mount_point).unwrap();
+        let mounts = format!(
+            "/dev/sda1 {} ext4 rw 0 0\n",
+            mount_point.canonicalize().unwrap().display()
+        );
+        sys.set_mounts(&mounts);
+        let state = state.with_system(sys.clone());
+        seed_device(&pool, "/dev/sda1", "u1");
+        state
+            .device_repo
+            .inner()
+            .update_mount_result("/dev/sda1", &mount_point.to_string_lossy(), "u1")
+            .unwrap();
+        let object = |key: &str| NewObject {
+            key: key.to_string(),
+            filename: format!("{key}.bin"),
+            filename_supplied: true,
+            content_type: None,
+            tenant: None,
+            ttl: None,
+            no_clobber: false,
+            created_at: None,
+            expected_sha256: None,
+        };
+        let (release, held) = tokio::sync::oneshot::channel::<&'static str>();
+        let mut slow_body = Box::pin(futures_util::stream::once(async move {
+            held.await
+                .map(web::Bytes::from)
+                .map_err(|_| std::io::Error::other("sender dropped"))
+        }));
+        let mut queued_body =
+            futures_util::stream::iter([Ok::<_, std::io::Error>(web::Bytes::from("x"))]);
+        let mut first = std::pin::pin!(store_object(&state, object("a"), &mut slow_body));
+        let mut queued = std::pin::pin!(store_object(&state, object("b"), &mut queued_body));
+        let wait = Duration::from_millis(300);
+        assert!(tokio::time::timeout(wait, &mut first).await.is_err());
+        assert!(tokio::time::timeout(wait, &mut queued).await.is_err());
+
+        // unmounted while the second upload waits behind the first
+        sys.set_mounts("");
+        release.send("slow").unwrap();
+        let (a, b) = futures_util::future::join(first, queued).await;
+        assert!(a.is_ok());
+        let status = b.unwrap_err().as_response_error().status_code();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let names: Vec<_> = std::fs::read_dir(&mount_point)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["a"]);
+    }
+
+    #[actix_web::test]
+    async fn losing_a_key_race_leaves_no_orphaned_object() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        // both tenants got past PUT's `key_exists` before either row was inserted
+        let object = |tenant: &str| NewObject {
+            key: "shared".to_string(),
+            filename: "shared.bin".to_string(),
+            filename_supplied: true,
+            content_type: None,
+            tenant: Some(tenant.to_string()),
+            ttl: None,
+            no_clobber: true,
+            created_at: None,
+            expected_sha256: None,
+        };
+        let body = || futures_util::stream::iter([Ok::<_, std::io::Error>(web::Bytes::from("x"))]);
+        let (mut a_body, mut b_body) = (body(), body());
+        let (a, b) = futures_util::future::join(
+            store_object(&state, object("a"), &mut a_body),
+            store_object(&state, object("b"), &mut b_body),
+        )
+        .await;
+        let (won, lost) = match (a, b) {
+            (Ok(_), Err(e)) => ("a", e),
+            (Err(e), Ok(_)) => ("b", e),
+            other => panic!("exactly one upload should win: {other:?}"),
+        };
+        assert_eq!(lost.as_response_error().status_code(), StatusCode::CONFLICT);
+        let row = state.file_repo.get_by_key("shared").unwrap().unwrap();
+        assert_eq!(row.tenant.as_deref(), Some(won));
+        let device = state.config.storage_root.join("u1");
+        for tenant in ["a", "b"] {
+            let path = device.join(tenant).join("shared");
+            assert_eq!(path.exists(), tenant == won, "{path:?}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn invalidating_the_device_cache_refetches_on_the_next_selection() {
+        let (state, pool) = test_state(ServerConfig {
+            device_cache_ttl_secs: 3600,
+            ..ServerConfig::default()
+        });
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        assert_eq!(select_device(&state).await.unwrap(), "u1");
+
+        // changed in the DB directly, as the mounter would: the cached list still has u1
+        state.device_repo.inner().set_read_only("u1", true).unwrap();
+        seed_device(&pool, "/dev/sdb1", "u2");
+        assert_eq!(select_device(&state).await.unwrap(), "u1");
+
+        let req = test::TestRequest::post()
+            .uri("/devices/cache/invalidate")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(select_device(&state).await.unwrap(), "u2");
+    }
+
+    #[tokio::test]
+    async fn zero_ttl_queries_devices_on_every_call() {
+        let pool = temp_pool();
+        let repo = seed_mounted(&pool, &["u1"]);
+        let fresh = DeviceUuidCache::new(Duration::ZERO);
+        let cached = DeviceUuidCache::new(Duration::from_secs(30));
+        assert_eq!(fresh.get_or_fetch(&repo).await.unwrap(), "u1");
+        assert_eq!(cached.get_or_fetch(&repo).await.unwrap(), "u1");
+
+        repo.inner().set_read_only("u1", true).unwrap();
+        seed_device(&pool, "/dev/sdb1", "u2");
+        for _ in 0..3 {
+            assert_eq!(fresh.get_or_fetch(&repo).await.unwrap(), "u2");
+            assert_eq!(cached.get_or_fetch(&repo).await.unwrap(), "u1");
+        }
+        assert!(fresh.inner.read().await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn read_only_device_is_skipped_for_uploads_but_serves_downloads() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_mounted(&pool, &["u1", "u2"]);
+        let obj = state.config.storage_root.join("u1").join("old");
+        std::fs::create_dir_all(obj.parent().unwrap()).unwrap();
+        std::fs::write(&obj, b"still here").unwrap();
+        state
+            .file_repo
+            .insert_file(&NewFileMeta {
+                key: "old",
+                filename: "old.txt",
+                content_type: None,
+                size: 10,
+                path: obj.to_string_lossy().as_ref(),
+                created_at: now_epoch(),
+                deleted: 0,
+                device_uuid: Some("u1"),
+                sha256: None,
+                retain_until: None,
+                tenant: None,
+                expires_at: None,
+                filename_supplied: 1,
+                compressed: 0,
+                uncompressed_size: None,
+            })
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/devices/u1/read-only")
+            .set_json(serde_json::json!({"read_only": true}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        for _ in 0..10 {
+            let picked = state
+                .device_cache
+                .get_or_fetch(&state.device_repo)
+                .await
+                .unwrap();
+            assert_eq!(picked, "u2");
+        }
+
+        let req = test::TestRequest::get().uri("/files/old").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "still here");
+
+        let req = test::TestRequest::put()
+            .uri("/devices/missing/read-only")
+            .set_json(serde_json::json!({"read_only": true}))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn tenants_cannot_toggle_device_read_only() {
+        let (state, pool) = test_state(ServerConfig::default());
+        seed_device(&pool, "/dev/sda1", "u1");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .configure(configure),
+        )
+        .await;
+        let req = test::TestRequest::put()
+            .uri("/devices/u1/read-only")
+            .insert_header((TENANT_HEADER, "acme"))
+            .set_json(serde_json::json!({"read_only": true}))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let devices = state.device_repo.inner().list_all().unwrap();
+        assert_eq!(devices[0].read_only, 0);
+    }
 }
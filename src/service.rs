@@ -0,0 +1,455 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use futures_util::{StreamExt, stream};
+use log::{info, warn};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use uuid::Uuid;
+
+use crate::compression;
+use crate::entity::file_meta::{FileMeta, NewFileMeta};
+use crate::logging;
+use crate::repo::device_repo::DeviceRepo;
+use crate::repo::file_repo::FileRepo;
+use crate::sniff;
+use crate::storage::Storage;
+
+/// Outcome of `Service::import_dir`.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    /// Files stored successfully.
+    pub count: usize,
+    /// Total bytes stored.
+    pub bytes: i64,
+    /// Files that could not be imported, with the reason.
+    pub failures: Vec<(PathBuf, String)>,
+}
+
+/// An object's bytes as returned by `Service::get_object`: always the stored content,
+/// whether or not it sits gzipped on disk.
+pub type ObjectReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Outcome of `Service::delete_object`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteOutcome {
+    Deleted,
+    /// No live object under that key.
+    NotFound,
+    /// Write-once retention forbids deleting it before this epoch second.
+    Retained(i64),
+}
+
+/// Library-level facade over the repositories and object storage, for embedding the
+/// crate without the HTTP server.
+#[derive(Clone)]
+pub struct Service {
+    storage: Arc<dyn Storage>,
+    file_repo: Arc<dyn FileRepo>,
+    device_repo: Arc<dyn DeviceRepo>,
+    write_once: bool,
+    retention_secs: u64,
+    /// Repo calls at least this slow are logged (None = never).
+    slow_after: Option<Duration>,
+}
+
+/// `retain_until` for an object created at `created_at`: write-once mode with a
+/// non-zero window holds it for `retention_secs`, otherwise it is unprotected.
+pub(crate) fn retain_until(write_once: bool, retention_secs: u64, created_at: i64) -> Option<i64> {
+    (write_once && retention_secs > 0)
+        .then(|| created_at.saturating_add(retention_secs.min(i64::MAX as u64) as i64))
+}
+
+/// Run a synchronous repo call on the blocking pool, keeping the request id on its log
+/// lines and warning when it takes `slow_after` or longer.
+pub(crate) async fn blocking<T, E, F>(slow_after: Option<Duration>, f: F) -> Result<T>
+where
+    F: FnOnce() -> std::result::Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: Into<anyhow::Error> + Send + 'static,
+{
+    let id = logging::request_id();
+    tokio::task::spawn_blocking(move || {
+        logging::with_request_id(id, || {
+            logging::warn_if_slow(slow_after, std::any::type_name::<F>(), f)
+        })
+    })
+    .await
+    .map_err(|e| anyhow!("blocking task failed: {e}"))?
+    .map_err(Into::into)
+}
+
+/// Regular files under `dir`, recursively, in a stable order. Symlinks are not followed.
+fn walk_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut out = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(d) = pending.pop() {
+        for entry in std::fs::read_dir(&d).with_context(|| format!("read_dir {:?}", d))? {
+            let entry = entry?;
+            let ft = entry.file_type()?;
+            if ft.is_dir() {
+                pending.push(entry.path());
+            } else if ft.is_file() {
+                out.push(entry.path());
+            }
+        }
+    }
+    out.sort();
+    Ok(out)
+}
+
+fn now_epoch() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+impl Service {
+    pub fn new<S, F, D>(storage: S, file_repo: F, device_repo: D) -> Self
+    where
+        S: Storage + 'static,
+        F: FileRepo + 'static,
+        D: DeviceRepo + 'static,
+    {
+        Self {
+            storage: Arc::new(storage),
+            file_repo: Arc::new(file_repo),
+            device_repo: Arc::new(device_repo),
+            write_once: false,
+            retention_secs: 0,
+            slow_after: None,
+        }
+    }
+
+    /// Stamp objects stored through `put_object` with a retention window, as the server
+    /// does for uploads in write-once mode (a window of 0 = none).
+    pub fn with_retention(mut self, write_once: bool, retention_secs: u64) -> Self {
+        self.write_once = write_once;
+        self.retention_secs = retention_secs;
+        self
+    }
+
+    /// Warn about repo calls that take at least `threshold` (None = never).
+    pub fn with_slow_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_after = threshold;
+        self
+    }
+
+    async fn blocking<T, E, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> std::result::Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: Into<anyhow::Error> + Send + 'static,
+    {
+        blocking(self.slow_after, f).await
+    }
+
+    /// Facade over already shared handles (the HTTP server's state).
+    pub(crate) fn from_parts(
+        storage: Arc<dyn Storage>,
+        file_repo: Arc<dyn FileRepo>,
+        device_repo: Arc<dyn DeviceRepo>,
+    ) -> Self {
+        Self {
+            storage,
+            file_repo,
+            device_repo,
+            write_once: false,
+            retention_secs: 0,
+            slow_after: None,
+        }
+    }
+
+    /// Look up a live object by key and open its bytes, inflated if it is stored gzipped.
+    /// Ok(None) if the key is unknown or deleted.
+    pub async fn get_object(&self, key: &str) -> Result<Option<(FileMeta, ObjectReader)>> {
+        let repo = self.file_repo.clone();
+        let k = key.to_string();
+        let Some(meta) = self.blocking(move || repo.get_by_key(&k)).await? else {
+            return Ok(None);
+        };
+        // the row's path covers tenant subdirectories and rows from before device tracking
+        let file = File::open(&meta.path)
+            .await
+            .with_context(|| format!("open {:?}", meta.path))?;
+        let reader: ObjectReader = if meta.compressed != 0 {
+            Box::new(compression::inflate(file))
+        } else {
+            Box::new(file)
+        };
+        Ok(Some((meta, reader)))
+    }
+
+    /// Delete a live object: remove its bytes, then soft-delete the row. Bytes that are
+    /// already gone are not an error; any other failure to remove them leaves the row in
+    /// place so the delete can be retried.
+    pub async fn delete_object(&self, key: &str) -> Result<DeleteOutcome> {
+        let repo = self.file_repo.clone();
+        let k = key.to_string();
+        let meta = self.blocking(move || repo.get_by_key(&k)).await?;
+        self.remove(meta).await
+    }
+
+    /// Like `delete_object`, but only sees objects owned by `tenant` (None = untenanted).
+    pub async fn delete_object_in_tenant(
+        &self,
+        key: &str,
+        tenant: Option<&str>,
+    ) -> Result<DeleteOutcome> {
+        let repo = self.file_repo.clone();
+        let k = key.to_string();
+        let tenant = tenant.map(str::to_string);
+        let meta = self
+            .blocking(move || repo.get_by_key_in_tenant(&k, tenant.as_deref()))
+            .await?;
+        self.remove(meta).await
+    }
+
+    async fn remove(&self, meta: Option<FileMeta>) -> Result<DeleteOutcome> {
+        let Some(meta) = meta else {
+            return Ok(DeleteOutcome::NotFound);
+        };
+        if meta.is_retained(now_epoch()) {
+            return Ok(DeleteOutcome::Retained(
+                meta.retain_until.unwrap_or_default(),
+            ));
+        }
+        match tokio::fs::remove_file(&meta.path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("delete {}: bytes at {:?} already gone", meta.key, meta.path);
+            }
+            Err(e) => {
+                return Err(anyhow::Error::new(e).context(format!("remove {:?}", meta.path)));
+            }
+        }
+        let repo = self.file_repo.clone();
+        let key = meta.key.clone();
+        self.blocking(move || repo.soft_delete(&key)).await?;
+        info!("deleted {}", meta.key);
+        Ok(DeleteOutcome::Deleted)
+    }
+
+    /// Store `reader` as a new object on the active device and record its metadata.
+    pub async fn put_object(
+        &self,
+        filename: &str,
+        content_type: Option<&str>,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<FileMeta> {
+        let devices = self.device_repo.clone();
+        let device_uuid = self
+            .blocking(move || devices.get_active_uuid())
+            .await?
+            .ok_or_else(|| anyhow!("no active device uuid"))?;
+        let key = Uuid::new_v4().to_string();
+        let (path, size, sha256) = self
+            .storage
+            .write_stream_with_digest(&device_uuid, &key, reader)
+            .await?;
+
+        let repo = self.file_repo.clone();
+        let filename = filename.to_string();
+        let content_type = content_type.map(str::to_string);
+        let created_at = now_epoch();
+        let retain_until = retain_until(self.write_once, self.retention_secs, created_at);
+        self.blocking(move || {
+            repo.insert_file(&NewFileMeta {
+                key: &key,
+                filename: &filename,
+                content_type: content_type.as_deref(),
+                size,
+                path: path.to_string_lossy().as_ref(),
+                created_at,
+                deleted: 0,
+                device_uuid: Some(&device_uuid),
+                sha256: Some(&sha256),
+                retain_until,
+                tenant: None,
+                expires_at: None,
+                filename_supplied: 1,
+                compressed: 0,
+                uncompressed_size: None,
+            })?;
+            repo.get_by_key(&key)?
+                .ok_or_else(|| anyhow!("inserted row {key} not found"))
+        })
+        .await
+    }
+
+    async fn import_file(&self, path: &Path) -> Result<FileMeta> {
+        let mut file = File::open(path)
+            .await
+            .with_context(|| format!("open {:?}", path))?;
+        let mut head = Vec::with_capacity(sniff::SNIFF_LEN);
+        (&mut file)
+            .take(sniff::SNIFF_LEN as u64)
+            .read_to_end(&mut head)
+            .await?;
+        file.rewind().await?;
+        let filename = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "file".to_string());
+        self.put_object(&filename, sniff::sniff(&head), &mut file)
+            .await
+    }
+
+    /// Bulk-load every regular file under `dir` through `put_object`, at most `concurrency`
+    /// at a time. Per-file errors are collected in the summary rather than aborting the run.
+    pub async fn import_dir(&self, dir: &Path, concurrency: usize) -> Result<ImportSummary> {
+        let root = dir.to_path_buf();
+        let files = self.blocking(move || walk_files(&root)).await?;
+        let results: Vec<(PathBuf, Result<FileMeta>)> = stream::iter(files)
+            .map(|path| async move {
+                let res = self.import_file(&path).await;
+                (path, res)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+        let mut summary = ImportSummary::default();
+        for (path, res) in results {
+            match res {
+                Ok(meta) => {
+                    summary.count += 1;
+                    summary.bytes += meta.size;
+                }
+                Err(e) => {
+                    warn!("import {:?} failed: {:#}", path, e);
+                    summary.failures.push((path, format!("{e:#}")));
+                }
+            }
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::{device_repo::new_device_repo, file_repo::new_file_repo};
+    use crate::storage::StorageImpl;
+    use crate::test_support::{seed_device, temp_dir, temp_pool};
+    use sha2::{Digest, Sha256};
+    use tokio::io::AsyncReadExt;
+
+    fn service_with_device() -> Service {
+        let pool = temp_pool();
+        seed_device(&pool, "/dev/sda1", "dev-1");
+        Service::new(
+            StorageImpl::new(temp_dir("service")),
+            new_file_repo(pool.clone()),
+            new_device_repo(pool),
+        )
+    }
+
+    #[tokio::test]
+    async fn put_then_get_roundtrip() -> Result<()> {
+        let svc = service_with_device();
+        let mut body: &[u8] = b"hello facade";
+        let meta = svc
+            .put_object("greeting.txt", Some("text/plain"), &mut body)
+            .await?;
+        assert_eq!(meta.size, 12);
+        assert_eq!(meta.device_uuid.as_deref(), Some("dev-1"));
+        assert_eq!(
+            meta.sha256,
+            Some(format!("{:x}", Sha256::digest(b"hello facade")))
+        );
+
+        let (got, mut reader) = svc.get_object(&meta.key).await?.expect("object");
+        assert_eq!(got.filename, "greeting.txt");
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        assert_eq!(bytes, b"hello facade");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_dir_stores_every_file() -> Result<()> {
+        let pool = temp_pool();
+        seed_device(&pool, "/dev/sda1", "dev-1");
+        let svc = Service::new(
+            StorageImpl::new(temp_dir("service")),
+            new_file_repo(pool.clone()),
+            new_device_repo(pool.clone()),
+        );
+        let src = temp_dir("import");
+        std::fs::create_dir_all(src.join("2024/summer"))?;
+        std::fs::write(src.join("a.txt"), b"alpha")?;
+        std::fs::write(src.join("2024/b.png"), b"\x89PNG\r\n\x1a\n....")?;
+        std::fs::write(src.join("2024/summer/c.txt"), b"charlie!")?;
+
+        let summary = svc.import_dir(&src, 2).await?;
+        assert_eq!(summary.count, 3, "{:?}", summary.failures);
+        assert_eq!(summary.bytes, 5 + 12 + 8);
+        assert!(summary.failures.is_empty());
+
+        let rows = new_file_repo(pool).list_by_device("dev-1")?;
+        assert_eq!(rows.len(), 3);
+        for row in rows {
+            let (_, mut reader) = svc.get_object(&row.key).await?.expect("object");
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            assert_eq!(bytes.len() as i64, row.size);
+            if row.filename == "b.png" {
+                assert_eq!(row.content_type.as_deref(), Some("image/png"));
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_missing_is_none() -> Result<()> {
+        let svc = service_with_device();
+        assert!(svc.get_object("nope").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_removes_bytes_and_row() -> Result<()> {
+        let svc = service_with_device();
+        let meta = svc.put_object("a.txt", None, &mut &b"doomed"[..]).await?;
+        assert_eq!(svc.delete_object(&meta.key).await?, DeleteOutcome::Deleted);
+        assert!(svc.get_object(&meta.key).await?.is_none());
+        assert!(!Path::new(&meta.path).exists());
+        // a second delete finds nothing
+        assert_eq!(svc.delete_object(&meta.key).await?, DeleteOutcome::NotFound);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_once_puts_are_retained() -> Result<()> {
+        let svc = service_with_device().with_retention(true, 3600);
+        let meta = svc.put_object("a.txt", None, &mut &b"kept"[..]).await?;
+        let until = meta.retain_until.expect("retain_until");
+        assert_eq!(until, meta.created_at + 3600);
+        assert_eq!(
+            svc.delete_object(&meta.key).await?,
+            DeleteOutcome::Retained(until)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_missing_is_not_found() -> Result<()> {
+        let svc = service_with_device();
+        assert_eq!(svc.delete_object("nope").await?, DeleteOutcome::NotFound);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_tolerates_bytes_already_gone() -> Result<()> {
+        let svc = service_with_device();
+        let meta = svc.put_object("a.txt", None, &mut &b"lost"[..]).await?;
+        std::fs::remove_file(&meta.path)?;
+        assert_eq!(svc.delete_object(&meta.key).await?, DeleteOutcome::Deleted);
+        assert!(svc.get_object(&meta.key).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! Content-type detection from leading magic bytes, so uploads can't pass as something
+//! else just by lying about their extension or `Content-Type`.
+
+/// Bytes of an upload needed to recognise every type below.
+pub const SNIFF_LEN: usize = 16;
+
+/// MIME type identified from the first bytes of a file, or None if unrecognised.
+pub fn sniff(head: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+    if at(0, b"\xFF\xD8\xFF") {
+        return Some("image/jpeg");
+    }
+    if at(0, b"\x89PNG\r\n\x1a\n") {
+        return Some("image/png");
+    }
+    if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        return Some("image/gif");
+    }
+    if at(0, b"RIFF") && at(8, b"WEBP") {
+        return Some("image/webp");
+    }
+    if at(0, b"RIFF") && at(8, b"WAVE") {
+        return Some("audio/wav");
+    }
+    if at(4, b"ftyp") {
+        return Some(match head.get(8..12) {
+            Some(b"heic") | Some(b"heix") | Some(b"mif1") => "image/heic",
+            Some(b"qt  ") => "video/quicktime",
+            _ => "video/mp4",
+        });
+    }
+    if at(0, b"\x1A\x45\xDF\xA3") {
+        return Some("video/webm");
+    }
+    if at(0, b"ID3") {
+        return Some("audio/mpeg");
+    }
+    if at(0, b"%PDF-") {
+        return Some("application/pdf");
+    }
+    if at(0, b"PK\x03\x04") {
+        return Some("application/zip");
+    }
+    if at(0, b"\x7FELF") {
+        return Some("application/x-executable");
+    }
+    None
+}
+
+/// Conventional file extension for a MIME type, for naming files that arrived without one.
+pub fn extension_for(content_type: &str) -> Option<&'static str> {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    Some(match essence.to_ascii_lowercase().as_str() {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/heic" => "heic",
+        "video/mp4" => "mp4",
+        "video/quicktime" => "mov",
+        "video/webm" => "webm",
+        "audio/mpeg" => "mp3",
+        "audio/wav" | "audio/x-wav" => "wav",
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        "application/json" => "json",
+        "text/plain" => "txt",
+        "text/csv" => "csv",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_common_types() {
+        assert_eq!(sniff(b"\xFF\xD8\xFF\xE0\0\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"\0\0\0\x18ftypheic"), Some("image/heic"));
+        assert_eq!(sniff(b"\0\0\0\x20ftypisom"), Some("video/mp4"));
+        assert_eq!(
+            sniff(b"\x7FELF\x02\x01\x01"),
+            Some("application/x-executable")
+        );
+        assert_eq!(sniff(b"plain text"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn extensions_follow_content_type() {
+        assert_eq!(extension_for("image/jpeg"), Some("jpg"));
+        assert_eq!(extension_for("Text/Plain; charset=utf-8"), Some("txt"));
+        assert_eq!(extension_for("application/octet-stream"), None);
+    }
+}
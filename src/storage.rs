@@ -1,155 +1,606 @@
-use anyhow::{Context, Result, bail};
 use async_trait::async_trait;
-use log::{debug, error};
-use std::path::PathBuf;
+use log::{debug, error, warn};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fmt, io};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::{fs, fs::File};
 use uuid::Uuid;
 
-/// Storage layout helper: {root}/{device_uuid}/{object_key}
+/// Failure of a `Storage` operation, classified so callers can answer with the right
+/// status. Filesystem errors keep the underlying `io::Error` as their source; anything
+/// wanting an `anyhow::Error` can still use `?`.
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound {
+        path: PathBuf,
+        source: io::Error,
+    },
+    PermissionDenied {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The device has no space left (ENOSPC).
+    StorageFull {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// Write-once storage refused to replace an existing object.
+    AlreadyExists {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// A device uuid, tenant or object key that isn't a single safe path segment.
+    InvalidKey(String),
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+}
+
+pub type StorageResult<T> = std::result::Result<T, StorageError>;
+
+impl StorageError {
+    /// Classify an I/O failure on `path` by its kind.
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        let path = path.into();
+        match source.kind() {
+            io::ErrorKind::NotFound => Self::NotFound { path, source },
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied { path, source },
+            io::ErrorKind::AlreadyExists => Self::AlreadyExists { path, source },
+            io::ErrorKind::StorageFull => Self::StorageFull { path, source },
+            _ if source.raw_os_error() == Some(nix::libc::ENOSPC) => {
+                Self::StorageFull { path, source }
+            }
+            _ => Self::Io { path, source },
+        }
+    }
+
+    /// Whether `e` would classify as `Io`: a fault of the drive or filesystem rather than a
+    /// missing file, permissions, a full disk or an existing target.
+    pub fn is_device_fault(e: &io::Error) -> bool {
+        !matches!(
+            e.kind(),
+            io::ErrorKind::NotFound
+                | io::ErrorKind::PermissionDenied
+                | io::ErrorKind::AlreadyExists
+                | io::ErrorKind::StorageFull
+        ) && e.raw_os_error() != Some(nix::libc::ENOSPC)
+    }
+}
+
+/// `map_err` adapter tagging an I/O error with the path it happened on.
+fn at(path: &Path) -> impl FnOnce(io::Error) -> StorageError + '_ {
+    move |e| StorageError::io(path, e)
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound { path, .. } => write!(f, "{:?} not found", path),
+            Self::PermissionDenied { path, .. } => write!(f, "permission denied on {:?}", path),
+            Self::StorageFull { path, .. } => write!(f, "no space left writing {:?}", path),
+            Self::AlreadyExists { path, .. } => write!(f, "{:?} already exists", path),
+            Self::InvalidKey(msg) => f.write_str(msg),
+            Self::Io { path, source } => write!(f, "{:?}: {}", path, source),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::NotFound { source, .. }
+            | Self::PermissionDenied { source, .. }
+            | Self::StorageFull { source, .. }
+            | Self::AlreadyExists { source, .. }
+            | Self::Io { source, .. } => Some(source),
+            Self::InvalidKey(_) => None,
+        }
+    }
+}
+
+/// Storage layout helper: {root}/{device_uuid}/{object_key}, or
+/// {root}/{device_uuid}/{tenant}/{object_key} for objects owned by a tenant.
 #[async_trait]
 pub trait Storage: Send + Sync {
     /// Resolve the absolute path for a (device_uuid, object_key)
-    fn resolve_path(&self, device_uuid: &str, object_key: &str) -> Result<PathBuf>;
+    fn resolve_path(&self, device_uuid: &str, object_key: &str) -> StorageResult<PathBuf>;
+
+    /// Resolve the absolute path for an object owned by `tenant` (None = no tenant).
+    /// The tenant segment sits below the device so the object stays on that device's mount.
+    fn resolve_tenant_path(
+        &self,
+        tenant: Option<&str>,
+        device_uuid: &str,
+        object_key: &str,
+    ) -> StorageResult<PathBuf>;
 
     /// Write from a stream into the resolved path. Returns (final_path, total_bytes)
-    async fn write_stream<R>(
+    async fn write_stream(
         &self,
         device_uuid: &str,
         object_key: &str,
-        reader: &mut R,
-    ) -> Result<(PathBuf, i64)>
-    where
-        R: AsyncRead + Unpin + Send,
-        Self: Sized;
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> StorageResult<(PathBuf, i64)>;
+
+    /// Like `write_stream`, also hashing the bytes as they pass so callers get the object's
+    /// hex SHA-256 without reading it back. Returns (final_path, total_bytes, sha256).
+    async fn write_stream_with_digest(
+        &self,
+        device_uuid: &str,
+        object_key: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> StorageResult<(PathBuf, i64, String)>;
 
     /// Read entire file to bytes
-    async fn read_all(&self, device_uuid: &str, object_key: &str) -> Result<Vec<u8>>;
+    async fn read_all(&self, device_uuid: &str, object_key: &str) -> StorageResult<Vec<u8>>;
 
     /// Open a reader for the file (seek to start) returning tokio File
-    async fn open_reader(&self, device_uuid: &str, object_key: &str) -> Result<File>;
+    async fn open_reader(&self, device_uuid: &str, object_key: &str) -> StorageResult<File>;
 
     /// Delete the resolved path if it exists; Ok if missing
-    async fn delete(&self, device_uuid: &str, object_key: &str) -> Result<()>;
+    async fn delete(&self, device_uuid: &str, object_key: &str) -> StorageResult<()>;
+
+    /// Whether the object's bytes exist, without opening it. Only NotFound maps to
+    /// `false`; any other error (permissions, a file where a directory should be) is returned.
+    async fn exists(&self, device_uuid: &str, object_key: &str) -> StorageResult<bool>;
+}
+
+/// How objects are arranged below their device (and tenant) directory.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Deserialize,
+    serde::Serialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// `{device_uuid}/{object_key}`
+    #[default]
+    Flat,
+    /// `{device_uuid}/{yyyy}/{mm}/{dd}/{object_key}`, by upload date (UTC).
+    Dated,
+}
+
+/// Proleptic Gregorian (year, month, day) of an epoch second, in UTC.
+pub(crate) fn civil_date(epoch_secs: i64) -> (i64, u32, u32) {
+    let days = epoch_secs.div_euclid(86_400);
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Path of an object under `layout`. `created_at` only matters for `Layout::Dated`.
+pub fn layout_path(
+    storage: &dyn Storage,
+    layout: Layout,
+    tenant: Option<&str>,
+    device_uuid: &str,
+    object_key: &str,
+    created_at: i64,
+) -> StorageResult<PathBuf> {
+    let flat = storage.resolve_tenant_path(tenant, device_uuid, object_key)?;
+    Ok(match layout {
+        Layout::Flat => flat,
+        Layout::Dated => {
+            let (year, month, day) = civil_date(created_at);
+            let dir = flat.parent().unwrap_or(Path::new(""));
+            dir.join(format!("{year:04}"))
+                .join(format!("{month:02}"))
+                .join(format!("{day:02}"))
+                .join(object_key)
+        }
+    })
+}
+
+/// Pause before the first publish retry; doubled for each one after that.
+const PUBLISH_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Errors of a filesystem that is only busy for now (EBUSY on a network-backed mount
+/// under load, say), so another try may well succeed.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ResourceBusy
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// Run `attempt`, and up to `retries` more times while it fails transiently, backing off
+/// between tries. A first-time success costs nothing extra.
+async fn retry_transient<F, Fut>(target: &Path, retries: u32, mut attempt: F) -> io::Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<()>>,
+{
+    let mut backoff = PUBLISH_RETRY_BACKOFF;
+    for tried in 1..=retries {
+        match attempt().await {
+            Err(e) if is_transient(&e) => {
+                warn!("publish to {target:?} failed ({e}, try {tried}), retrying in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            res => return res,
+        }
+    }
+    attempt().await.map_err(|e| match retries {
+        0 => e,
+        _ if !is_transient(&e) => e,
+        n => io::Error::new(e.kind(), format!("{e} (gave up after {} attempts)", n + 1)),
+    })
+}
+
+/// Move a finished temp file to its final path. With `no_clobber` an existing target is
+/// never replaced: the temp is hard-linked into place (which fails atomically with
+/// `AlreadyExists`) and then unlinked. Transient failures are retried up to `retries`
+/// times. The temp file is removed on failure either way.
+pub async fn publish(
+    tmp_path: &Path,
+    final_path: &Path,
+    no_clobber: bool,
+    retries: u32,
+) -> io::Result<()> {
+    let res = retry_transient(final_path, retries, || async {
+        if no_clobber {
+            fs::hard_link(tmp_path, final_path).await
+        } else {
+            fs::rename(tmp_path, final_path).await
+        }
+    })
+    .await;
+    if no_clobber || res.is_err() {
+        let _ = fs::remove_file(tmp_path).await;
+    }
+    res
+}
+
+/// Create the temp file of a write. Temp names carry a random nonce, so a file already
+/// there is a crash leftover and is truncated; with `exclusive` it is refused instead
+/// (`AlreadyExists`), which makes two writers on one temp path visible.
+pub async fn create_temp(path: &Path, exclusive: bool) -> io::Result<File> {
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true);
+    if exclusive {
+        opts.create_new(true);
+    } else {
+        opts.create(true).truncate(true);
+    }
+    opts.open(path).await
+}
+
+/// Flush a freshly written file and advise the kernel to drop its cached pages
+/// (`posix_fadvise(DONTNEED)`), so large sequential writes don't evict useful page cache on
+/// low-memory hosts. DONTNEED only discards clean pages, hence the `fdatasync` first.
+pub async fn drop_page_cache(path: &Path) -> io::Result<()> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        use nix::fcntl::{PosixFadviseAdvice, posix_fadvise};
+        use std::os::fd::AsRawFd;
+
+        let file = std::fs::File::open(&path)?;
+        file.sync_data()?;
+        posix_fadvise(
+            file.as_raw_fd(),
+            0,
+            0,
+            PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+        )
+        .map_err(io::Error::from)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Suffix of in-progress writes unless configured otherwise.
+pub const DEFAULT_TEMP_SUFFIX: &str = ".part";
+
+/// How in-progress writes are named next to their final path:
+/// `{prefix}{object_key}.{uuid}{suffix}`. External cleanup tooling can match on the
+/// pattern, and object keys that would match it are refused.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct TempNames {
+    prefix: String,
+    suffix: String,
+}
+
+impl Default for TempNames {
+    fn default() -> Self {
+        Self::new("", DEFAULT_TEMP_SUFFIX)
+    }
+}
+
+impl TempNames {
+    /// `suffix` should be non-empty, or every key would count as a temp name
+    /// (`Config::temp_names` checks).
+    pub fn new(prefix: impl Into<String>, suffix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            suffix: suffix.into(),
+        }
+    }
+
+    /// A fresh temp path for `object_key` in the directory of `final_path`, so publishing
+    /// is a same-directory rename.
+    pub fn temp_path(&self, final_path: &Path, object_key: &str) -> PathBuf {
+        final_path.with_file_name(format!(
+            "{}{}.{}{}",
+            self.prefix,
+            object_key,
+            Uuid::new_v4(),
+            self.suffix
+        ))
+    }
+
+    /// Temp path for a file derived from `temp_path` (e.g. its gzip), tagged `tag` and
+    /// still matching the pattern.
+    pub fn derived_path(&self, temp_path: &Path, tag: &str) -> PathBuf {
+        let name = temp_path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let stem = name.strip_suffix(self.suffix.as_str()).unwrap_or(&name);
+        temp_path.with_file_name(format!("{stem}.{tag}{}", self.suffix))
+    }
+
+    /// Whether `file_name` has the shape of a temp file.
+    pub fn matches(&self, file_name: &str) -> bool {
+        file_name.len() >= self.prefix.len() + self.suffix.len()
+            && file_name.starts_with(self.prefix.as_str())
+            && file_name.ends_with(self.suffix.as_str())
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn suffix(&self) -> &str {
+        &self.suffix
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct StorageImpl {
+    /// Canonical once the root exists at construction, so every path handed out is the
+    /// resolved one even when the configured root is a symlink (to `/media/...`, say).
     root: PathBuf,
+    write_once: bool,
+    drop_cache: bool,
+    temp_names: TempNames,
+    exclusive_temp: bool,
+    publish_retries: u32,
 }
 
 impl StorageImpl {
+    /// Resolves `root` if it exists; re-pointing a symlinked root takes effect on restart.
+    /// A root that doesn't exist yet is kept as given.
     pub fn new(root: impl Into<PathBuf>) -> Self {
-        Self { root: root.into() }
+        let root = root.into();
+        Self {
+            root: std::fs::canonicalize(&root).unwrap_or(root),
+            write_once: false,
+            drop_cache: false,
+            temp_names: TempNames::default(),
+            exclusive_temp: false,
+            publish_retries: 0,
+        }
     }
 
-    fn ensure_segment(segment: &str, label: &str) -> Result<()> {
-        if segment.is_empty() {
-            bail!("{} must not be empty", label);
-        }
-        if segment.contains('/') || segment.contains('\\') {
-            bail!(
-                "{} must be a single path segment without separators: {}",
-                label,
-                segment
-            );
-        }
-        if segment == "." || segment == ".." {
-            bail!("{} must not be '.' or '..'", label);
-        }
-        Ok(())
+    /// Fail a write whose temp file already exists instead of truncating it (see `create_temp`).
+    pub fn with_exclusive_temp(mut self, exclusive: bool) -> Self {
+        self.exclusive_temp = exclusive;
+        self
     }
-}
 
-#[async_trait]
-impl Storage for StorageImpl {
-    fn resolve_path(&self, device_uuid: &str, object_key: &str) -> Result<PathBuf> {
-        Self::ensure_segment(device_uuid, "device_uuid")?;
-        Self::ensure_segment(object_key, "object_key")?;
-        Ok(self.root.join(device_uuid).join(object_key))
+    /// Name in-progress writes after `names` instead of `{key}.{uuid}.part`.
+    pub fn with_temp_names(mut self, names: TempNames) -> Self {
+        self.temp_names = names;
+        self
+    }
+
+    /// Drop written objects from the page cache (see `drop_page_cache`).
+    pub fn with_drop_cache(mut self, drop_cache: bool) -> Self {
+        self.drop_cache = drop_cache;
+        self
+    }
+
+    /// Retry a transiently failing publish of a written object up to `retries` times.
+    pub fn with_publish_retries(mut self, retries: u32) -> Self {
+        self.publish_retries = retries;
+        self
+    }
+
+    /// Refuse to overwrite objects that already exist.
+    pub fn with_write_once(mut self, write_once: bool) -> Self {
+        self.write_once = write_once;
+        self
+    }
+
+    pub(crate) fn ensure_segment(segment: &str, label: &str) -> StorageResult<()> {
+        let problem = if segment.is_empty() {
+            format!("{} must not be empty", label)
+        } else if segment.contains('/') || segment.contains('\\') {
+            format!(
+                "{} must be a single path segment without separators: {}",
+                label, segment
+            )
+        } else if segment == "." || segment == ".." {
+            format!("{} must not be '.' or '..'", label)
+        } else {
+            return Ok(());
+        };
+        Err(StorageError::InvalidKey(problem))
     }
 
-    async fn write_stream<R>(
+    /// Stream `reader` into a temp file next to the object and publish it, feeding every
+    /// byte to `digest` on the way.
+    async fn write_object(
         &self,
         device_uuid: &str,
         object_key: &str,
-        reader: &mut R,
-    ) -> Result<(PathBuf, i64)>
-    where
-        R: AsyncRead + Unpin + Send,
-    {
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+        mut digest: Option<&mut Sha256>,
+    ) -> StorageResult<(PathBuf, i64)> {
         let final_path = self.resolve_path(device_uuid, object_key)?;
         if let Some(parent) = final_path.parent() {
-            fs::create_dir_all(parent)
-                .await
-                .with_context(|| format!("create_dir_all {:?}", parent))?;
+            fs::create_dir_all(parent).await.map_err(at(parent))?;
         }
         // write to a temp file under the same directory, then atomic rename
-        let tmp_name = format!("{}.{}.part", object_key, Uuid::new_v4());
-        let tmp_path = final_path
-            .parent()
-            .map(|p| p.join(tmp_name))
-            .unwrap_or_else(|| PathBuf::from(format!("{}.{}.part", object_key, Uuid::new_v4())));
+        let tmp_path = self.temp_names.temp_path(&final_path, object_key);
 
-        let mut file = File::create(&tmp_path)
+        let mut file = create_temp(&tmp_path, self.exclusive_temp)
             .await
-            .with_context(|| format!("create temp file {:?}", tmp_path))?;
-        let mut total: i64 = 0;
-        let mut buf = [0u8; 64 * 1024];
-        loop {
-            let n = reader.read(&mut buf).await?;
-            if n == 0 {
-                break;
+            .map_err(at(&tmp_path))?;
+        let copied = async {
+            let mut total: i64 = 0;
+            let mut buf = [0u8; 64 * 1024];
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                if let Some(hasher) = digest.as_deref_mut() {
+                    hasher.update(&buf[..n]);
+                }
+                file.write_all(&buf[..n]).await?;
+                total += n as i64;
             }
-            file.write_all(&buf[..n]).await?;
-            total += n as i64;
+            // a failed background write (e.g. ENOSPC on the last chunk) surfaces here
+            file.flush().await?;
+            Ok::<i64, std::io::Error>(total)
         }
-        file.flush().await.ok();
+        .await;
         drop(file);
+        let total = match copied {
+            Ok(total) => total,
+            Err(e) => {
+                // don't leave a partial temp file behind (e.g. ENOSPC mid-write)
+                let _ = fs::remove_file(&tmp_path).await;
+                return Err(StorageError::io(tmp_path, e));
+            }
+        };
 
         // Atomic rename to final target
-        fs::rename(&tmp_path, &final_path)
-            .await
-            .with_context(|| format!("rename {:?} -> {:?}", tmp_path, final_path))?;
+        publish(
+            &tmp_path,
+            &final_path,
+            self.write_once,
+            self.publish_retries,
+        )
+        .await
+        .map_err(at(&final_path))?;
+        if self.drop_cache
+            && let Err(e) = drop_page_cache(&final_path).await
+        {
+            error!("drop page cache for {:?} error: {}", final_path, e);
+        }
         debug!("wrote {} bytes to {:?}", total, final_path);
         Ok((final_path, total))
     }
+}
 
-    async fn read_all(&self, device_uuid: &str, object_key: &str) -> Result<Vec<u8>> {
-        let path = self.resolve_path(device_uuid, object_key)?;
-        let mut f = File::open(&path)
+#[async_trait]
+impl Storage for StorageImpl {
+    fn resolve_path(&self, device_uuid: &str, object_key: &str) -> StorageResult<PathBuf> {
+        self.resolve_tenant_path(None, device_uuid, object_key)
+    }
+
+    fn resolve_tenant_path(
+        &self,
+        tenant: Option<&str>,
+        device_uuid: &str,
+        object_key: &str,
+    ) -> StorageResult<PathBuf> {
+        Self::ensure_segment(device_uuid, "device_uuid")?;
+        Self::ensure_segment(object_key, "object_key")?;
+        let mut path = self.root.join(device_uuid);
+        if let Some(tenant) = tenant {
+            Self::ensure_segment(tenant, "tenant")?;
+            path.push(tenant);
+        }
+        Ok(path.join(object_key))
+    }
+
+    async fn write_stream(
+        &self,
+        device_uuid: &str,
+        object_key: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> StorageResult<(PathBuf, i64)> {
+        self.write_object(device_uuid, object_key, reader, None)
             .await
-            .with_context(|| format!("open {:?}", path))?;
+    }
+
+    async fn write_stream_with_digest(
+        &self,
+        device_uuid: &str,
+        object_key: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> StorageResult<(PathBuf, i64, String)> {
+        let mut hasher = Sha256::new();
+        let (path, total) = self
+            .write_object(device_uuid, object_key, reader, Some(&mut hasher))
+            .await?;
+        Ok((path, total, format!("{:x}", hasher.finalize())))
+    }
+
+    async fn read_all(&self, device_uuid: &str, object_key: &str) -> StorageResult<Vec<u8>> {
+        let path = self.resolve_path(device_uuid, object_key)?;
+        let mut f = File::open(&path).await.map_err(at(&path))?;
         let mut buf = Vec::new();
-        f.read_to_end(&mut buf).await?;
+        f.read_to_end(&mut buf).await.map_err(at(&path))?;
         Ok(buf)
     }
 
-    async fn open_reader(&self, device_uuid: &str, object_key: &str) -> Result<File> {
+    async fn open_reader(&self, device_uuid: &str, object_key: &str) -> StorageResult<File> {
         let path = self.resolve_path(device_uuid, object_key)?;
-        let mut f = File::open(&path)
-            .await
-            .with_context(|| format!("open {:?}", path))?;
+        let mut f = File::open(&path).await.map_err(at(&path))?;
         f.rewind().await.ok();
         Ok(f)
     }
 
-    async fn delete(&self, device_uuid: &str, object_key: &str) -> Result<()> {
+    async fn delete(&self, device_uuid: &str, object_key: &str) -> StorageResult<()> {
         let path = self.resolve_path(device_uuid, object_key)?;
         match fs::remove_file(&path).await {
             Ok(_) => Ok(()),
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
             Err(e) => {
                 error!("remove_file {:?} error: {}", path, e);
-                Err(e.into())
+                Err(StorageError::io(path, e))
             }
         }
     }
+
+    async fn exists(&self, device_uuid: &str, object_key: &str) -> StorageResult<bool> {
+        let path = self.resolve_path(device_uuid, object_key)?;
+        match fs::metadata(&path).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(StorageError::io(path, e)),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use anyhow::Result;
     use tokio::io::{AsyncWriteExt, duplex};
 
     #[tokio::test]
@@ -172,7 +623,7 @@ mod tests {
             .await?;
         assert_eq!(sz, data.len() as i64);
         assert!(path.exists());
-        assert!(path.starts_with(&tmp_dir.join(device_uuid)));
+        assert!(path.starts_with(tmp_dir.join(device_uuid)));
 
         // read back
         let bytes = storage.read_all(device_uuid, object_key).await?;
@@ -183,4 +634,263 @@ mod tests {
         assert!(!path.exists());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn symlinked_root_is_resolved() -> Result<()> {
+        let dir = crate::test_support::temp_dir("linked-root");
+        let real = dir.join("media");
+        std::fs::create_dir(&real)?;
+        let link = dir.join("storage");
+        std::os::unix::fs::symlink(&real, &link)?;
+        let storage = StorageImpl::new(&link);
+
+        let (path, _) = storage
+            .write_stream("u1", "obj", &mut &b"bytes"[..])
+            .await?;
+        assert_eq!(path, real.canonicalize()?.join("u1").join("obj"));
+        assert_eq!(std::fs::read(link.join("u1").join("obj"))?, b"bytes");
+        for (device, key) in [("..", "obj"), ("u1", "../../escape"), ("u1", "..")] {
+            assert!(matches!(
+                storage.resolve_path(device, key),
+                Err(StorageError::InvalidKey(_))
+            ));
+        }
+        assert!(!dir.join("escape").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn custom_temp_names_are_used_for_writes() -> Result<()> {
+        let names = TempNames::new("~", ".inflight");
+        let final_path = Path::new("/pool/u1/obj");
+        let temp = names.temp_path(final_path, "obj");
+        let name = temp.file_name().unwrap().to_str().unwrap();
+        assert!(
+            name.starts_with("~obj.") && name.ends_with(".inflight"),
+            "{name}"
+        );
+        assert_eq!(temp.parent(), final_path.parent());
+        let gz = names.derived_path(&temp, "gz");
+        assert_eq!(
+            gz.file_name().unwrap().to_str().unwrap(),
+            format!("{}.gz.inflight", name.strip_suffix(".inflight").unwrap())
+        );
+        assert!(names.matches(name));
+        assert!(!names.matches("obj.part"));
+        assert!(!names.matches("obj.inflight"));
+
+        let tmp_dir = crate::test_support::temp_dir("temp-names");
+        let storage = StorageImpl::new(&tmp_dir).with_temp_names(names.clone());
+        let (path, _) = storage
+            .write_stream("u1", "obj", &mut &b"bytes"[..])
+            .await?;
+        let names_in_dir: Vec<String> = std::fs::read_dir(path.parent().unwrap())?
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names_in_dir, vec!["obj"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn existing_temp_is_truncated_unless_exclusive() -> Result<()> {
+        let path = crate::test_support::temp_dir("create-temp").join("k.1234.part");
+        fs::write(&path, b"leftover").await?;
+        let err = create_temp(&path, true).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).await?, b"leftover");
+        assert!(matches!(
+            StorageError::io(&path, err),
+            StorageError::AlreadyExists { .. }
+        ));
+
+        let mut file = create_temp(&path, false).await?;
+        file.write_all(b"new").await?;
+        file.flush().await?;
+        assert_eq!(fs::read(&path).await?, b"new");
+
+        fs::remove_file(&path).await?;
+        create_temp(&path, true).await?;
+        assert!(path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn digest_write_matches_independent_hash() -> Result<()> {
+        let storage = StorageImpl::new(crate::test_support::temp_dir("storage"));
+        // larger than one copy buffer, so the digest spans several reads
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let (path, size, digest) = storage
+            .write_stream_with_digest("dev", "obj", &mut data.as_slice())
+            .await?;
+        assert_eq!(size, data.len() as i64);
+        assert_eq!(digest, format!("{:x}", Sha256::digest(&data)));
+        assert_eq!(fs::read(&path).await?, data);
+
+        let (_, _, empty) = storage
+            .write_stream_with_digest("dev", "empty", &mut &b""[..])
+            .await?;
+        assert_eq!(empty, format!("{:x}", Sha256::digest(b"")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_once_refuses_overwrite() -> Result<()> {
+        let tmp_dir = std::env::temp_dir().join(format!("storage-plus-test-{}", Uuid::new_v4()));
+        let storage = StorageImpl::new(&tmp_dir).with_write_once(true);
+        let mut first: &[u8] = b"original";
+        storage.write_stream("dev", "obj", &mut first).await?;
+
+        let mut second: &[u8] = b"replacement";
+        let err = storage
+            .write_stream("dev", "obj", &mut second)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StorageError::AlreadyExists { .. }), "{err}");
+        assert_eq!(storage.read_all("dev", "obj").await?, b"original");
+        // no temp file left behind
+        let mut entries = fs::read_dir(tmp_dir.join("dev")).await?;
+        let mut names = Vec::new();
+        while let Some(e) = entries.next_entry().await? {
+            names.push(e.file_name());
+        }
+        assert_eq!(names, vec!["obj"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drop_cache_after_write() -> Result<()> {
+        let tmp_dir = std::env::temp_dir().join(format!("storage-plus-test-{}", Uuid::new_v4()));
+        let storage = StorageImpl::new(&tmp_dir).with_drop_cache(true);
+        let data = vec![42u8; 256 * 1024];
+        let (path, n) = storage
+            .write_stream("dev", "big", &mut data.as_slice())
+            .await?;
+        assert_eq!(n, data.len() as i64);
+        drop_page_cache(&path).await?;
+        assert_eq!(storage.read_all("dev", "big").await?, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exists_distinguishes_missing_from_errors() {
+        let root = crate::test_support::temp_dir("exists");
+        let storage = StorageImpl::new(&root);
+        storage
+            .write_stream("dev", "here", &mut &b"x"[..])
+            .await
+            .unwrap();
+        assert!(storage.exists("dev", "here").await.unwrap());
+        assert!(!storage.exists("dev", "gone").await.unwrap());
+        assert!(!storage.exists("other-dev", "gone").await.unwrap());
+
+        // a parent that can't be traversed is an error, not "missing"; a file in place of
+        // the device directory is used because root ignores permission bits
+        fs::write(root.join("broken"), b"").await.unwrap();
+        assert!(storage.exists("broken", "obj").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn errors_are_classified() {
+        let storage = StorageImpl::new(crate::test_support::temp_dir("errors"));
+        let err = storage.read_all("dev", "missing").await.unwrap_err();
+        assert!(matches!(err, StorageError::NotFound { .. }), "{err}");
+        let err = storage.open_reader("dev", "missing").await.unwrap_err();
+        assert!(matches!(err, StorageError::NotFound { .. }), "{err}");
+
+        for (device, key) in [("dev", "../escape"), ("..", "obj"), ("dev", "")] {
+            let err = storage
+                .write_stream(device, key, &mut &b"x"[..])
+                .await
+                .unwrap_err();
+            assert!(matches!(err, StorageError::InvalidKey(_)), "{err}");
+        }
+
+        let full = io::Error::from_raw_os_error(nix::libc::ENOSPC);
+        let err = StorageError::io("/pool/dev/obj", full);
+        assert!(matches!(err, StorageError::StorageFull { .. }), "{err}");
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        let err = StorageError::io("/pool/dev/obj", denied);
+        assert!(
+            matches!(err, StorageError::PermissionDenied { .. }),
+            "{err}"
+        );
+        // still usable wherever anyhow is
+        let any: anyhow::Error = err.into();
+        assert!(any.downcast_ref::<StorageError>().is_some());
+    }
+
+    #[test]
+    fn dated_layout_shards_by_upload_day() {
+        let storage = StorageImpl::new("/pool");
+        // 2024-02-29T23:59:59Z
+        let path = layout_path(&storage, Layout::Dated, None, "dev", "k", 1_709_251_199).unwrap();
+        assert_eq!(path, PathBuf::from("/pool/dev/2024/02/29/k"));
+        let path = layout_path(&storage, Layout::Dated, Some("t"), "dev", "k", 0).unwrap();
+        assert_eq!(path, PathBuf::from("/pool/dev/t/1970/01/01/k"));
+        let path = layout_path(&storage, Layout::Flat, None, "dev", "k", 0).unwrap();
+        assert_eq!(path, storage.resolve_path("dev", "k").unwrap());
+    }
+
+    #[test]
+    fn tenant_segment_sits_below_device() {
+        let storage = StorageImpl::new("/pool");
+        assert_eq!(
+            storage
+                .resolve_tenant_path(Some("acme"), "dev", "obj")
+                .unwrap(),
+            PathBuf::from("/pool/dev/acme/obj")
+        );
+        assert_eq!(
+            storage.resolve_tenant_path(None, "dev", "obj").unwrap(),
+            PathBuf::from("/pool/dev/obj")
+        );
+        assert!(
+            storage
+                .resolve_tenant_path(Some(".."), "dev", "obj")
+                .is_err()
+        );
+        assert!(
+            storage
+                .resolve_tenant_path(Some("a/b"), "dev", "obj")
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn transient_publish_failures_are_retried() {
+        let target = Path::new("/pool/dev/k");
+        let calls = std::cell::Cell::new(0);
+        // fails with `kind` on the first `failures` calls, then succeeds
+        let flaky = |failures: u32, kind: io::ErrorKind| {
+            calls.set(0);
+            let calls = &calls;
+            move || {
+                calls.set(calls.get() + 1);
+                let failing = calls.get() <= failures;
+                async move { if failing { Err(kind.into()) } else { Ok(()) } }
+            }
+        };
+        let busy = io::ErrorKind::ResourceBusy;
+
+        retry_transient(target, 2, flaky(2, busy)).await.unwrap();
+        assert_eq!(calls.get(), 3);
+
+        let err = retry_transient(target, 2, flaky(3, busy))
+            .await
+            .unwrap_err();
+        assert_eq!((calls.get(), err.kind()), (3, busy));
+        assert!(
+            err.to_string().contains("gave up after 3 attempts"),
+            "{err}"
+        );
+
+        // a real failure, or retries turned off, gets exactly one try
+        let err = retry_transient(target, 2, flaky(1, io::ErrorKind::NotFound)).await;
+        assert_eq!(
+            (calls.get(), err.unwrap_err().kind()),
+            (1, io::ErrorKind::NotFound)
+        );
+        assert!(retry_transient(target, 0, flaky(1, busy)).await.is_err());
+        assert_eq!(calls.get(), 1);
+    }
 }
@@ -0,0 +1,271 @@
+use std::{
+    fmt, fs,
+    io::{Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+/// Captured result of an external command.
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    pub success: bool,
+    pub stdout: String,
+}
+
+/// A command killed for running longer than its timeout. Returned (inside `anyhow::Error`)
+/// by `System::run_with_timeout` so callers can tell a hang from an ordinary failure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandTimeout {
+    pub program: String,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for CommandTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} timed out after {:?}", self.program, self.timeout)
+    }
+}
+
+impl std::error::Error for CommandTimeout {}
+
+/// How often a command under a timeout is checked for exit.
+const TIMEOUT_POLL: Duration = Duration::from_millis(20);
+
+/// Size and free space of a mounted filesystem, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FsStats {
+    pub total_bytes: u64,
+    /// Space available to unprivileged users (excludes root-reserved blocks).
+    pub free_bytes: u64,
+}
+
+/// Host interactions needed by the mounter (external commands, kernel mount table).
+/// Abstracted so reconciliation logic can be exercised without root or real block devices.
+pub trait System: Send + Sync + 'static {
+    /// Run `program` with `args` to completion, capturing stdout.
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput>;
+
+    /// Like `run`, but kill the command and fail with `CommandTimeout` if it hasn't
+    /// exited within `timeout`.
+    fn run_with_timeout(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<CommandOutput>;
+
+    /// Contents of the kernel mount table in `/proc/mounts` format.
+    fn mount_table(&self) -> Result<String>;
+
+    /// Capacity of the filesystem mounted at `path` (`statvfs`).
+    fn fs_stats(&self, path: &Path) -> Result<FsStats>;
+
+    /// Create, write and remove a small file in `dir`; an error if the filesystem refuses.
+    fn probe_writable(&self, dir: &Path) -> Result<()>;
+}
+
+/// `System` backed by the real host.
+#[derive(Debug, Clone, Default)]
+pub struct HostSystem;
+
+impl System for HostSystem {
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let out = Command::new(program).args(args).output()?;
+        Ok(CommandOutput {
+            success: out.status.success(),
+            stdout: String::from_utf8_lossy(&out.stdout).into_owned(),
+        })
+    }
+
+    fn run_with_timeout(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<CommandOutput> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()?;
+        // drain stdout on the side so a chatty command can't block on a full pipe
+        let mut stdout = child.stdout.take();
+        let reader = thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(out) = stdout.as_mut() {
+                let _ = out.read_to_end(&mut buf);
+            }
+            buf
+        });
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(CommandTimeout {
+                    program: program.to_string(),
+                    timeout,
+                }
+                .into());
+            }
+            thread::sleep(TIMEOUT_POLL);
+        };
+        let stdout = reader.join().unwrap_or_default();
+        Ok(CommandOutput {
+            success: status.success(),
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        })
+    }
+
+    fn mount_table(&self) -> Result<String> {
+        Ok(fs::read_to_string("/proc/mounts")?)
+    }
+
+    fn fs_stats(&self, path: &Path) -> Result<FsStats> {
+        let st = nix::sys::statvfs::statvfs(path)?;
+        let frag = st.fragment_size() as u64;
+        Ok(FsStats {
+            total_bytes: st.blocks() as u64 * frag,
+            free_bytes: st.blocks_available() as u64 * frag,
+        })
+    }
+
+    fn probe_writable(&self, dir: &Path) -> Result<()> {
+        let probe = dir.join(format!(".write-probe-{}", std::process::id()));
+        let res = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&probe)
+            .and_then(|mut f| {
+                f.write_all(b"probe")?;
+                f.sync_all()
+            });
+        let _ = fs::remove_file(&probe);
+        Ok(res?)
+    }
+}
+
+/// Decode the octal escapes (`\040` for space, etc.) used by `/proc/mounts` fields.
+pub fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && i + 4 <= bytes.len()
+            && bytes[i + 1..i + 4]
+                .iter()
+                .all(|b| (b'0'..=b'7').contains(b))
+        {
+            let v = bytes[i + 1..i + 4]
+                .iter()
+                .fold(0u32, |acc, b| acc * 8 + (b - b'0') as u32);
+            out.push(v as u8);
+            i += 4;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Whether `path` is the root of a mounted filesystem: it sits on a different device than
+/// its parent (or is `/`). Cheap enough to poll, unlike reading the whole mount table.
+pub fn is_mount_point(path: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let own = fs::metadata(path)?;
+    let parent = fs::metadata(path.join(".."))?;
+    Ok(own.dev() != parent.dev() || own.ino() == parent.ino())
+}
+
+/// Device id of the filesystem holding `path`. A path that doesn't exist yet (a database
+/// about to be created) reports the filesystem of its nearest existing ancestor.
+pub fn filesystem_id(path: &Path) -> std::io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut at = path;
+    loop {
+        match fs::metadata(at) {
+            Ok(m) => return Ok(m.dev()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => match at.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => at = parent,
+                _ => return Err(e),
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether `a` and `b` are on the same filesystem (see `filesystem_id`).
+pub fn same_filesystem(a: &Path, b: &Path) -> std::io::Result<bool> {
+    Ok(filesystem_id(a)? == filesystem_id(b)?)
+}
+
+/// Parsed `(source, mount_point)` pairs from a mount table.
+pub fn parse_mount_table(table: &str) -> Vec<(String, String)> {
+    table
+        .lines()
+        .filter_map(|l| {
+            let mut it = l.split_whitespace();
+            let src = it.next()?;
+            let target = it.next()?;
+            Some((unescape_mount_field(src), unescape_mount_field(target)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_probe_leaves_nothing_behind() {
+        let dir = crate::test_support::temp_dir("probe");
+        HostSystem.probe_writable(&dir).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        assert!(HostSystem.probe_writable(&dir.join("missing")).is_err());
+    }
+
+    #[test]
+    fn filesystem_ids_tell_mounts_apart() {
+        let a = crate::test_support::temp_dir("fs-a");
+        let b = crate::test_support::temp_dir("fs-b");
+        assert!(same_filesystem(&a, &b).unwrap());
+        assert!(same_filesystem(&a, &a.join("not/yet/created.db")).unwrap());
+        // procfs is its own filesystem wherever tests run on Linux
+        assert!(!same_filesystem(&a, Path::new("/proc")).unwrap());
+    }
+
+    #[test]
+    fn hanging_command_is_killed_at_timeout() {
+        let started = Instant::now();
+        let err = HostSystem
+            .run_with_timeout("sleep", &["5"], Duration::from_millis(200))
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let timeout = err.downcast_ref::<CommandTimeout>().unwrap();
+        assert_eq!(timeout.program, "sleep");
+
+        let out = HostSystem
+            .run_with_timeout("echo", &["hi"], Duration::from_secs(5))
+            .unwrap();
+        assert!(out.success);
+        assert_eq!(out.stdout, "hi\n");
+    }
+
+    #[test]
+    fn plain_directory_is_not_a_mount_point() {
+        let dir = crate::test_support::temp_dir("mnt");
+        assert!(!is_mount_point(&dir).unwrap());
+        assert!(is_mount_point(Path::new("/")).unwrap());
+        assert!(is_mount_point(&dir.join("missing")).is_err());
+    }
+}
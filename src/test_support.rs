@@ -0,0 +1,210 @@
+//! Shared helpers for unit tests (temp dirs, throwaway databases, fake host).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow, bail};
+use uuid::Uuid;
+
+use crate::{
+    db::{Pool, establish_pool},
+    system::{CommandOutput, CommandTimeout, FsStats, System},
+};
+
+/// Fresh, empty directory under the system temp dir.
+pub fn temp_dir(label: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("storage-plus-{}-{}", label, Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).expect("create temp dir");
+    dir
+}
+
+/// Pool over a brand-new migrated SQLite file.
+pub fn temp_pool() -> Pool {
+    let dir = temp_dir("db");
+    establish_pool(&dir.join("test.db")).expect("establish pool")
+}
+
+/// Insert a device row that is joined and mounted, i.e. eligible for uploads.
+pub fn seed_device(pool: &Pool, devnode: &str, uuid: &str) {
+    use crate::repo::device_repo::{DeviceRepo, new_device_repo};
+    use crate::schema::devices;
+    use diesel::prelude::*;
+
+    new_device_repo(pool.clone())
+        .upsert_device(devnode, uuid, 1)
+        .expect("upsert device");
+    let mut conn = pool.get().expect("conn");
+    diesel::update(devices::table.filter(devices::uuid.eq(uuid)))
+        .set((devices::joined.eq(1), devices::mount_success.eq(1)))
+        .execute(&mut conn)
+        .expect("join device");
+}
+
+/// Scriptable `System`: canned command outputs keyed by program (or by a full command line,
+/// which takes precedence), one-shot outputs consumed before those, a settable mount table,
+/// per-path filesystem stats, programs that hang until their timeout, and a log of every
+/// invocation as `"program arg1 arg2"`.
+#[derive(Default)]
+pub struct FakeSystem {
+    pub outputs: Mutex<HashMap<String, CommandOutput>>,
+    pub queued: Mutex<HashMap<String, VecDeque<CommandOutput>>>,
+    pub hanging: Mutex<Vec<String>>,
+    pub mounts: Mutex<String>,
+    pub calls: Mutex<Vec<String>>,
+    pub fs_stats: Mutex<HashMap<PathBuf, FsStats>>,
+    pub read_only: Mutex<Vec<PathBuf>>,
+}
+
+impl FakeSystem {
+    pub fn set_output(&self, program: &str, success: bool, stdout: &str) {
+        self.outputs.lock().unwrap().insert(
+            program.to_string(),
+            CommandOutput {
+                success,
+                stdout: stdout.to_string(),
+            },
+        );
+    }
+
+    /// Answer the next call of `command` (a full command line) with this output, before
+    /// falling back to `set_output`. Queued outputs are used in order.
+    pub fn queue_output(&self, command: &str, success: bool, stdout: &str) {
+        self.queued
+            .lock()
+            .unwrap()
+            .entry(command.to_string())
+            .or_default()
+            .push_back(CommandOutput {
+                success,
+                stdout: stdout.to_string(),
+            });
+    }
+
+    /// Make `program` (or one full command line) never finish: `run_with_timeout`
+    /// fails with `CommandTimeout`.
+    pub fn set_hanging(&self, program: &str) {
+        self.hanging.lock().unwrap().push(program.to_string());
+    }
+
+    pub fn set_mounts(&self, table: &str) {
+        *self.mounts.lock().unwrap() = table.to_string();
+    }
+
+    pub fn set_fs_stats(&self, path: &Path, total_bytes: u64, free_bytes: u64) {
+        self.fs_stats.lock().unwrap().insert(
+            path.to_path_buf(),
+            FsStats {
+                total_bytes,
+                free_bytes,
+            },
+        );
+    }
+
+    /// Make `probe_writable` fail for `path`, as for a filesystem mounted read-only.
+    pub fn set_read_only(&self, path: &Path) {
+        self.read_only.lock().unwrap().push(path.to_path_buf());
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl System for FakeSystem {
+    fn run(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let mut line = program.to_string();
+        for a in args {
+            line.push(' ');
+            line.push_str(a);
+        }
+        let queued = self
+            .queued
+            .lock()
+            .unwrap()
+            .get_mut(&line)
+            .and_then(VecDeque::pop_front);
+        let outputs = self.outputs.lock().unwrap();
+        let out = queued.or_else(|| outputs.get(&line).or_else(|| outputs.get(program)).cloned());
+        self.calls.lock().unwrap().push(line);
+        Ok(out.unwrap_or_default())
+    }
+
+    fn run_with_timeout(
+        &self,
+        program: &str,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<CommandOutput> {
+        let line = format!("{program} {}", args.join(" "));
+        if self
+            .hanging
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|p| p == program || *p == line)
+        {
+            self.calls.lock().unwrap().push(format!("{line} (killed)"));
+            return Err(CommandTimeout {
+                program: program.to_string(),
+                timeout,
+            }
+            .into());
+        }
+        self.run(program, args)
+    }
+
+    fn mount_table(&self) -> Result<String> {
+        Ok(self.mounts.lock().unwrap().clone())
+    }
+
+    fn fs_stats(&self, path: &Path) -> Result<FsStats> {
+        self.fs_stats
+            .lock()
+            .unwrap()
+            .get(path)
+            .copied()
+            .ok_or_else(|| anyhow!("no filesystem at {:?}", path))
+    }
+
+    fn probe_writable(&self, dir: &Path) -> Result<()> {
+        if self.read_only.lock().unwrap().iter().any(|p| p == dir) {
+            bail!("read-only file system: {:?}", dir);
+        }
+        Ok(())
+    }
+}
+
+/// Logger that keeps every line as `"<request tag> <message>"` for assertions.
+struct CaptureLogger(Mutex<Vec<String>>);
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let line = format!("{} {}", crate::logging::request_tag(), record.args());
+        self.0.lock().unwrap().push(line);
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURE: std::sync::OnceLock<&'static CaptureLogger> = std::sync::OnceLock::new();
+
+/// Install the capturing logger (first call only) and return a snapshot of its lines.
+/// Tests run in parallel, so filter by something unique to the test.
+pub fn captured_logs() -> Vec<String> {
+    let logger = CAPTURE.get_or_init(|| {
+        let logger: &'static CaptureLogger =
+            Box::leak(Box::new(CaptureLogger(Mutex::new(Vec::new()))));
+        log::set_logger(logger).expect("no other logger in tests");
+        log::set_max_level(log::LevelFilter::Info);
+        logger
+    });
+    logger.0.lock().unwrap().clone()
+}
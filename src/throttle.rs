@@ -0,0 +1,88 @@
+//! Byte-rate limiting for response bodies, so one download can't saturate the uplink.
+
+use std::time::{Duration, Instant};
+
+use futures_util::{Stream, StreamExt};
+
+/// Most bytes that may go out back to back after an idle period.
+const MAX_BURST_BYTES: f64 = 64.0 * 1024.0;
+
+/// Token bucket refilled at a fixed byte rate. Sending more than the bucket holds puts it
+/// into debt, which the next `take` waits off, so oversized chunks are still paced.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        let burst = rate.min(MAX_BURST_BYTES);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// Wait until `n` bytes may be sent.
+    pub async fn take(&mut self, n: usize) {
+        self.refill();
+        if self.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.tokens / self.rate)).await;
+            self.refill();
+        }
+        self.tokens -= n as f64;
+    }
+}
+
+/// Pace `body` to at most `bytes_per_sec`, on average.
+pub fn throttle<S, B, E>(body: S, bytes_per_sec: u64) -> impl Stream<Item = Result<B, E>>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    futures_util::stream::unfold(
+        (body, TokenBucket::new(bytes_per_sec)),
+        |(mut body, mut bucket)| async move {
+            let item = body.next().await?;
+            if let Ok(chunk) = &item {
+                bucket.take(chunk.as_ref().len()).await;
+            }
+            Some((item, (body, bucket)))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn throttled_stream_takes_at_least_the_expected_time() {
+        let rate = 400_000;
+        let chunks: Vec<Result<Vec<u8>, ()>> = (0..50).map(|_| Ok(vec![0u8; 4_000])).collect();
+        let started = Instant::now();
+        let total: usize = throttle(futures_util::stream::iter(chunks), rate)
+            .map(|c| c.unwrap().len())
+            .fold(0, |acc, n| async move { acc + n })
+            .await;
+        let elapsed = started.elapsed().as_secs_f64();
+        assert_eq!(total, 200_000);
+        // the first burst goes out at once; the remainder is paced at `rate`
+        let expected = (total as f64 - MAX_BURST_BYTES) / rate as f64;
+        assert!(elapsed >= expected * 0.9, "{elapsed} < {expected}");
+        assert!(elapsed < expected + 1.0, "{elapsed} too slow");
+    }
+}